@@ -0,0 +1,684 @@
+//! AArch64 Linux backend producing GNU `as` assembly.
+//!
+//! Values live in 32-bit `w` registers. Like the x86-64 backend, the runtime
+//! uses raw system calls (`read` = 63, `write` = 64, `exit` = 93) instead
+//! of libc, and `_start` calls `main` before exiting.
+
+use crate::ast::BinaryOpKind;
+use crate::backend::{self, Backend};
+use crate::ir::{Inst, Program, VReg, Value};
+use crate::regalloc::{self, Location};
+use std::collections::BTreeMap;
+
+pub static PRELUDE: &str = r#"// Module : main
+    .text
+    .globl _start
+_start:
+    bl main
+    mov x0, #0
+    mov x8, #93
+    svc #0
+
+// read one byte from stdin into w0, or -1 at end of input
+read_byte:
+    sub sp, sp, #16
+    mov x0, #0
+    mov x1, sp
+    mov x2, #1
+    mov x8, #63
+    svc #0
+    cmp x0, #1
+    b.ne 1f
+    ldrb w0, [sp]
+    add sp, sp, #16
+    ret
+1:
+    mov w0, #-1
+    add sp, sp, #16
+    ret
+
+    .globl read
+read:
+    // call read integer
+    stp x29, x30, [sp, #-16]!
+    mov w3, #0
+    mov w4, #0
+1:
+    bl read_byte
+    cmn w0, #1
+    b.eq 3f
+    cmp w0, #32
+    b.ls 1b
+    cmp w0, #45
+    b.ne 2f
+    mov w4, #1
+    bl read_byte
+2:
+    sub w0, w0, #48
+    cmp w0, #9
+    b.hi 3f
+    mov w5, #10
+    madd w3, w3, w5, w0
+    bl read_byte
+    b 2b
+3:
+    mov w0, w3
+    cbz w4, 4f
+    neg w0, w0
+4:
+    ldp x29, x30, [sp], #16
+    ret
+
+    .globl write
+write:
+    sub sp, sp, #32
+    sxtw x3, w0
+    mov x4, x3
+    add x1, sp, #31
+    mov w5, #10
+    strb w5, [x1]
+    cmp x3, #0
+    b.ge 1f
+    neg x3, x3
+1:
+    udiv x6, x3, x5
+    msub x7, x6, x5, x3
+    add w7, w7, #48
+    sub x1, x1, #1
+    strb w7, [x1]
+    mov x3, x6
+    cbnz x3, 1b
+    cmp x4, #0
+    b.ge 2f
+    mov w7, #45
+    sub x1, x1, #1
+    strb w7, [x1]
+2:
+    add x2, sp, #32
+    sub x2, x2, x1
+    mov x0, #1
+    mov x8, #64
+    svc #0
+    add sp, sp, #32
+    ret
+"#;
+
+// `sub`/`add` of the frame size to sp; larger frames go through x16
+fn adjust_sp(op: &str, size: u32) -> String {
+    if size < 4096 {
+        format!("    {} sp, sp, #{}\n", op, size)
+    } else {
+        let mut buf = mov_imm("x16", size as i32).join("\n    ");
+        buf.insert_str(0, "    ");
+        buf.push_str(format!("\n    {} sp, sp, x16\n", op).as_str());
+        buf
+    }
+}
+
+// bytes reserved at the bottom of the frame for the saved x29 and x30
+const FRAME_HEADER: u32 = 16;
+
+// registers handed out by the allocator, as (64-bit, 32-bit) names. w16 and
+// w17 are kept as scratch; the runtime only clobbers x0 to x8.
+pub static ALLOCATABLE: [(&str, &str); 16] = [
+    ("x9", "w9"),
+    ("x10", "w10"),
+    ("x11", "w11"),
+    ("x12", "w12"),
+    ("x13", "w13"),
+    ("x14", "w14"),
+    ("x15", "w15"),
+    ("x19", "w19"),
+    ("x20", "w20"),
+    ("x21", "w21"),
+    ("x22", "w22"),
+    ("x23", "w23"),
+    ("x24", "w24"),
+    ("x25", "w25"),
+    ("x26", "w26"),
+    ("x27", "w27"),
+];
+
+// x9 to x15 are caller-saved, the rest must be preserved by `main`
+fn is_callee_saved(reg: usize) -> bool {
+    reg >= 7
+}
+
+// materialize a 32-bit constant: one `mov` when it is a (possibly inverted)
+// 16-bit value, otherwise `movz` and `movk`
+fn mov_imm(reg: &str, imm: i32) -> Vec<String> {
+    let bits = imm as u32;
+    if bits <= 0xffff || !bits <= 0xffff {
+        vec![format!("mov {}, #{}", reg, imm)]
+    } else {
+        vec![
+            format!("movz {}, #{}", reg, bits & 0xffff),
+            format!("movk {}, #{}, lsl #16", reg, bits >> 16),
+        ]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    /// Index into `ALLOCATABLE`.
+    Reg(usize),
+    /// Offset above `x29`.
+    Mem(u32),
+    Imm(i32),
+}
+
+#[derive(Debug, Default)]
+pub struct Aarch64Generator {
+    pub frame_size: u32,
+    pub saved_regs: Vec<usize>,
+    pub symbol_map: BTreeMap<String, Operand>,
+    pub asm: Vec<String>,
+    locations: Vec<Operand>,
+}
+
+impl Aarch64Generator {
+    pub fn new() -> Aarch64Generator {
+        Aarch64Generator::default()
+    }
+
+    fn operand(&self, value: Value) -> Operand {
+        match value {
+            Value::Reg(vreg) => self.locations[vreg as usize],
+            Value::Imm(imm) => Operand::Imm(imm),
+        }
+    }
+
+    // copy a value into the register `rd`
+    fn load_into(&mut self, value: Value, rd: &str) {
+        match self.operand(value) {
+            Operand::Reg(reg) if ALLOCATABLE[reg].1 == rd => {}
+            Operand::Reg(reg) => self.asm.push(format!("mov {}, {}", rd, ALLOCATABLE[reg].1)),
+            Operand::Mem(offset) => self.asm.push(format!("ldr {}, [x29, #{}]", rd, offset)),
+            Operand::Imm(imm) => self.asm.extend(mov_imm(rd, imm)),
+        }
+    }
+
+    // the w register to read `value` from: its allocated one, or `scratch`
+    // after `load_into` fills it
+    fn load(&mut self, value: Value, scratch: &'static str) -> &'static str {
+        match self.operand(value) {
+            Operand::Reg(reg) => ALLOCATABLE[reg].1,
+            _ => {
+                self.load_into(value, scratch);
+                scratch
+            }
+        }
+    }
+
+    // where to compute `vreg`: its allocated w register, or w16 when it
+    // lives in the frame, for `store` to write back
+    fn dest(&self, vreg: VReg) -> &'static str {
+        match self.locations[vreg as usize] {
+            Operand::Reg(reg) => ALLOCATABLE[reg].1,
+            _ => "w16",
+        }
+    }
+
+    // `str` w16 to the x29 frame slot of `vreg`, if it has one
+    fn store(&mut self, vreg: VReg) {
+        if let Operand::Mem(offset) = self.locations[vreg as usize] {
+            self.asm.push(format!("str w16, [x29, #{}]", offset));
+        }
+    }
+}
+
+impl Backend for Aarch64Generator {
+    type Instr = String;
+    type Error = String;
+
+    fn file_extension(&self) -> &'static str {
+        "s"
+    }
+
+    // run the register allocator and lay out the stack frame: header, then
+    // saved registers, then spill slots, rounded up to 16 bytes
+    fn assign_locations(&mut self, program: &Program) {
+        let allocation = regalloc::allocate(program, ALLOCATABLE.len());
+        self.saved_regs = allocation
+            .used_regs()
+            .into_iter()
+            .filter(|r| is_callee_saved(*r))
+            .collect();
+        let spill_base = FRAME_HEADER + 8 * self.saved_regs.len() as u32;
+        self.frame_size = (spill_base + 4 * allocation.spill_slots).next_multiple_of(16);
+
+        self.locations = (0..program.vreg_count)
+            .map(|vreg| match allocation.locations.get(&vreg) {
+                Some(Location::Reg(r)) => Operand::Reg(*r),
+                Some(Location::Spill(slot)) => Operand::Mem(spill_base + 4 * slot),
+                None => Operand::Imm(0),
+            })
+            .collect();
+        self.symbol_map = program
+            .vars
+            .iter()
+            .map(|(name, vreg)| (name.clone(), self.locations[*vreg as usize]))
+            .collect();
+    }
+
+    // `read` and `write` pass the value in w0, and arithmetic with a
+    // constant below 4096 takes it as an immediate, flipping add and sub
+    // for a negative one
+    fn lower_instruction(&mut self, inst: &Inst) -> Result<(), String> {
+        match inst {
+            Inst::Call { .. } => return Err(backend::NO_PROCEDURES.to_string()),
+            Inst::Read { dst } => {
+                self.asm.push("bl read".to_string());
+                match dst.map(|dst| self.locations[dst as usize]) {
+                    Some(Operand::Reg(reg)) => {
+                        self.asm.push(format!("mov {}, w0", ALLOCATABLE[reg].1))
+                    }
+                    Some(Operand::Mem(offset)) => {
+                        self.asm.push(format!("str w0, [x29, #{}]", offset))
+                    }
+                    _ => {}
+                }
+            }
+            Inst::Write { src } => {
+                self.load_into(*src, "w0");
+                self.asm.push("bl write".to_string());
+            }
+            Inst::Copy { dst, src } => {
+                let rd = self.dest(*dst);
+                self.load_into(*src, rd);
+                self.store(*dst);
+            }
+            Inst::Binary { op, dst, lhs, rhs } => {
+                // reg + imm, imm + reg and reg - imm take a 12-bit immediate
+                let immediate = match (op, lhs, rhs) {
+                    (BinaryOpKind::Add, Value::Reg(_), Value::Imm(imm)) => Some((*lhs, *imm)),
+                    (BinaryOpKind::Add, Value::Imm(imm), Value::Reg(_)) => Some((*rhs, *imm)),
+                    (BinaryOpKind::Sub, Value::Reg(_), Value::Imm(imm)) => {
+                        imm.checked_neg().map(|imm| (*lhs, imm))
+                    }
+                    _ => None,
+                }
+                .filter(|(_, imm)| imm.unsigned_abs() < 4096);
+                if let Some((src, imm)) = immediate {
+                    let rs = self.load(src, "w16");
+                    let rd = self.dest(*dst);
+                    let mnemonic = if imm < 0 { "sub" } else { "add" };
+                    self.asm.push(format!(
+                        "{} {}, {}, #{}",
+                        mnemonic,
+                        rd,
+                        rs,
+                        imm.unsigned_abs()
+                    ));
+                } else {
+                    let rs = self.load(*lhs, "w16");
+                    let rt = self.load(*rhs, "w17");
+                    let rd = self.dest(*dst);
+                    let mnemonic = match op {
+                        BinaryOpKind::Add => "add",
+                        BinaryOpKind::Sub => "sub",
+                    };
+                    self.asm
+                        .push(format!("{} {}, {}, {}", mnemonic, rd, rs, rt));
+                }
+                self.store(*dst);
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Vec<String> {
+        self.asm.iter().map(|c| format!("    {}", c)).collect()
+    }
+
+    fn prologue(&self) -> Vec<String> {
+        let mut buf = String::from(
+            "    .text
+    .globl main
+main:
+    // prologue area
+",
+        );
+        buf.push_str(adjust_sp("sub", self.frame_size).as_str());
+        buf.push_str("    stp x29, x30, [sp]\n    mov x29, sp\n");
+        for (i, reg) in self.saved_regs.iter().enumerate() {
+            buf.push_str(
+                format!(
+                    "    str {}, [x29, #{}]\n",
+                    ALLOCATABLE[*reg].0,
+                    FRAME_HEADER + 8 * i as u32
+                )
+                .as_str(),
+            );
+        }
+        backend::lines(&buf)
+    }
+
+    fn epilogue(&self) -> Vec<String> {
+        let mut buf = String::from("\n    // epilogue area\n");
+        for (i, reg) in self.saved_regs.iter().enumerate() {
+            buf.push_str(
+                format!(
+                    "    ldr {}, [x29, #{}]\n",
+                    ALLOCATABLE[*reg].0,
+                    FRAME_HEADER + 8 * i as u32
+                )
+                .as_str(),
+            );
+        }
+        buf.push_str("    mov sp, x29\n    ldp x29, x30, [sp]\n");
+        buf.push_str(adjust_sp("add", self.frame_size).as_str());
+        buf.push_str("    ret\n\n");
+        backend::lines(&buf)
+    }
+
+    fn runtime_support(&self) -> Vec<String> {
+        backend::lines(PRELUDE)
+    }
+
+    fn print(&self, listing: &[String]) -> String {
+        backend::print_lines(listing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::Emitter;
+    use crate::testing;
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn compile(source: &str) -> String {
+        Aarch64Generator::new()
+            .emit(&testing::lower_source(source))
+            .unwrap()
+    }
+
+    // split operands on the commas outside of brackets
+    fn operands(text: &str) -> Vec<String> {
+        let (mut parts, mut depth, mut current) = (Vec::new(), 0, String::new());
+        for c in text.chars() {
+            match c {
+                '[' => depth += 1,
+                ']' => depth -= 1,
+                ',' if depth == 0 => {
+                    parts.push(current.trim().to_string());
+                    current.clear();
+                    continue;
+                }
+                _ => {}
+            }
+            current.push(c);
+        }
+        if !current.trim().is_empty() {
+            parts.push(current.trim().to_string());
+        }
+        parts
+    }
+
+    /// Just enough of an AArch64 Linux machine to run what the backend
+    /// emits, runtime included.
+    struct Machine {
+        lines: Vec<(String, Vec<String>)>,
+        // x0 to x30, then sp
+        regs: [u64; 32],
+        // n, z, c, v
+        flags: (bool, bool, bool, bool),
+        memory: HashMap<u64, u8>,
+        input: Vec<u8>,
+        output: Vec<u8>,
+    }
+
+    impl Machine {
+        fn new(asm: &str, input: &str) -> Machine {
+            let lines = asm
+                .lines()
+                .map(|l| l.split("//").next().unwrap().trim())
+                .filter(|l| !l.is_empty() && !l.starts_with('.'))
+                .map(|l| match l.split_once(' ') {
+                    Some((op, rest)) => (op.to_string(), operands(rest)),
+                    None => (l.to_string(), Vec::new()),
+                })
+                .collect();
+            let mut regs = [0; 32];
+            regs[31] = 0x7fff_0000;
+            Machine {
+                lines,
+                regs,
+                flags: (false, false, false, false),
+                memory: HashMap::new(),
+                input: input.bytes().rev().collect(),
+                output: Vec::new(),
+            }
+        }
+
+        fn index(name: &str) -> usize {
+            match name {
+                "sp" => 31,
+                _ => name[1..].parse().unwrap(),
+            }
+        }
+
+        fn read(&self, name: &str) -> u64 {
+            if let Some(imm) = name.strip_prefix('#') {
+                return imm.parse::<i64>().unwrap() as u64;
+            }
+            match name {
+                "xzr" | "wzr" => 0,
+                _ if name.starts_with('w') => self.regs[Self::index(name)] & 0xffff_ffff,
+                _ => self.regs[Self::index(name)],
+            }
+        }
+
+        // writes to a `w` register clear the upper half
+        fn write(&mut self, name: &str, value: u64) {
+            let value = if name.starts_with('w') {
+                value & 0xffff_ffff
+            } else {
+                value
+            };
+            self.regs[Self::index(name)] = value;
+        }
+
+        // `[base]`, `[base, #imm]` or `[base, #imm]!`, and whether it
+        // writes the address back
+        fn address(&self, operand: &str) -> (u64, bool) {
+            let writeback = operand.ends_with('!');
+            let inner = operand.trim_end_matches('!');
+            let parts = operands(&inner[1..inner.len() - 1]);
+            let offset = parts.get(1).map_or(0, |imm| self.read(imm));
+            (self.read(&parts[0]).wrapping_add(offset), writeback)
+        }
+
+        fn load(&self, address: u64, size: u64) -> u64 {
+            (0..size).rev().fold(0, |value, i| {
+                value << 8 | *self.memory.get(&(address + i)).unwrap_or(&0) as u64
+            })
+        }
+
+        fn store(&mut self, address: u64, size: u64, value: u64) {
+            for i in 0..size {
+                self.memory.insert(address + i, (value >> (8 * i)) as u8);
+            }
+        }
+
+        fn compare(&mut self, a: u64, b: u64, wide: bool) {
+            let (bits, mask) = if wide {
+                (64, u64::MAX)
+            } else {
+                (32, 0xffff_ffff)
+            };
+            let (a, b) = (a & mask, b & mask);
+            let result = a.wrapping_sub(b) & mask;
+            let sign = |x: u64| x >> (bits - 1) & 1 == 1;
+            let overflow = sign(a) != sign(b) && sign(result) != sign(a);
+            self.flags = (sign(result), result == 0, a >= b, overflow);
+        }
+
+        fn label(&self, pc: usize, name: &str) -> usize {
+            let target = |i: &usize| self.lines[*i].0 == format!("{}:", &name[..name.len() - 1]);
+            if name.ends_with('b') && name[..name.len() - 1].parse::<u32>().is_ok() {
+                (0..pc).rev().find(target).unwrap()
+            } else if name.ends_with('f') && name[..name.len() - 1].parse::<u32>().is_ok() {
+                (pc..self.lines.len()).find(target).unwrap()
+            } else {
+                let label = format!("{}:", name);
+                self.lines.iter().position(|l| l.0 == label).unwrap()
+            }
+        }
+
+        fn run(&mut self) -> String {
+            let mut pc = self.label(0, "_start");
+            loop {
+                let (op, args) = self.lines[pc].clone();
+                pc += 1;
+                let wide = args.first().is_some_and(|a| !a.starts_with('w'));
+                match op.as_str() {
+                    "mov" | "movz" => self.write(&args[0], self.read(&args[1])),
+                    "movk" => {
+                        let value = self.read(&args[0]) & 0xffff | self.read(&args[1]) << 16;
+                        self.write(&args[0], value);
+                    }
+                    "add" => {
+                        let value = self.read(&args[1]).wrapping_add(self.read(&args[2]));
+                        self.write(&args[0], value);
+                    }
+                    "sub" => {
+                        let value = self.read(&args[1]).wrapping_sub(self.read(&args[2]));
+                        self.write(&args[0], value);
+                    }
+                    "neg" => self.write(&args[0], self.read(&args[1]).wrapping_neg()),
+                    "sxtw" => self.write(&args[0], self.read(&args[1]) as i32 as i64 as u64),
+                    "madd" | "msub" => {
+                        let product = self.read(&args[1]).wrapping_mul(self.read(&args[2]));
+                        let value = if op == "madd" {
+                            self.read(&args[3]).wrapping_add(product)
+                        } else {
+                            self.read(&args[3]).wrapping_sub(product)
+                        };
+                        self.write(&args[0], value);
+                    }
+                    "udiv" => {
+                        let value = self.read(&args[1]) / self.read(&args[2]);
+                        self.write(&args[0], value);
+                    }
+                    "cmp" => self.compare(self.read(&args[0]), self.read(&args[1]), wide),
+                    "cmn" => {
+                        let b = self.read(&args[1]).wrapping_neg();
+                        self.compare(self.read(&args[0]), b, wide)
+                    }
+                    "ldr" | "ldrb" | "str" | "strb" => {
+                        let (address, writeback) = self.address(&args[1]);
+                        let size = match (op.as_str(), wide) {
+                            ("ldrb" | "strb", _) => 1,
+                            (_, true) => 8,
+                            (_, false) => 4,
+                        };
+                        if op.starts_with("ldr") {
+                            self.write(&args[0], self.load(address, size));
+                        } else {
+                            self.store(address, size, self.read(&args[0]));
+                        }
+                        assert!(!writeback);
+                    }
+                    "stp" | "ldp" => {
+                        let (mut address, writeback) = self.address(&args[2]);
+                        let base = args[2][1..].split([',', ']']).next().unwrap();
+                        let base = base.to_string();
+                        if writeback {
+                            self.write(&base, address);
+                        }
+                        if args.len() == 4 {
+                            // post-index: access the base, then move it
+                            address = self.read(&base);
+                        }
+                        if op == "stp" {
+                            self.store(address, 8, self.read(&args[0]));
+                            self.store(address + 8, 8, self.read(&args[1]));
+                        } else {
+                            self.write(&args[0], self.load(address, 8));
+                            self.write(&args[1], self.load(address + 8, 8));
+                        }
+                        if args.len() == 4 {
+                            self.write(&base, address.wrapping_add(self.read(&args[3])));
+                        }
+                    }
+                    "b" => pc = self.label(pc, &args[0]),
+                    "bl" => {
+                        self.regs[30] = pc as u64;
+                        pc = self.label(pc, &args[0]);
+                    }
+                    "ret" => pc = self.regs[30] as usize,
+                    "cbz" | "cbnz" => {
+                        if (self.read(&args[0]) == 0) == (op == "cbz") {
+                            pc = self.label(pc, &args[1]);
+                        }
+                    }
+                    "svc" => match self.regs[8] {
+                        63 => match self.input.pop() {
+                            Some(byte) => {
+                                self.store(self.regs[1], 1, byte as u64);
+                                self.regs[0] = 1;
+                            }
+                            None => self.regs[0] = 0,
+                        },
+                        64 => {
+                            for i in 0..self.regs[2] {
+                                let byte = self.load(self.regs[1] + i, 1) as u8;
+                                self.output.push(byte);
+                            }
+                            self.regs[0] = self.regs[2];
+                        }
+                        93 => return String::from_utf8(self.output.clone()).unwrap(),
+                        call => panic!("unexpected system call {}", call),
+                    },
+                    op if op.starts_with("b.") => {
+                        let (n, z, c, v) = self.flags;
+                        let taken = match &op[2..] {
+                            "eq" => z,
+                            "ne" => !z,
+                            "ls" => !c || z,
+                            "hi" => c && !z,
+                            "ge" => n == v,
+                            cond => panic!("unexpected condition {}", cond),
+                        };
+                        if taken {
+                            pc = self.label(pc, &args[0]);
+                        }
+                    }
+                    op if op.ends_with(':') => {}
+                    op => panic!("unexpected instruction {}", op),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn handle_test_cases() {
+        for case in testing::test_cases() {
+            let output = Machine::new(&compile(case.source), &case.input_text()).run();
+            assert_eq!(output, case.expected_text());
+        }
+        // the runtime reads a sign
+        let test9 = compile(testing::test_case("test9").source);
+        let output = Machine::new(&test9, "-1").run();
+        assert_eq!(output, "3\n20\n1\n-1\n-123\n255\n0\n0\n");
+    }
+
+    #[test]
+    fn handle_wide_constants() {
+        assert_eq!(mov_imm("w0", 65535), vec!["mov w0, #65535"]);
+        assert_eq!(mov_imm("w0", -65536), vec!["mov w0, #-65536"]);
+        assert_eq!(
+            mov_imm("w0", 70000),
+            vec!["movz w0, #4464", "movk w0, #1, lsl #16"]
+        );
+        let output = Machine::new(
+            &compile("begin A := 70000 - 2147483647; write(A - 1, -2147483647 - 1); end"),
+            "",
+        )
+        .run();
+        assert_eq!(output, "-2147413648\n-2147483648\n");
+    }
+}
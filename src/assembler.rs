@@ -0,0 +1,285 @@
+//! Encodes a MIPS listing into machine code.
+//!
+//! The listing has its pseudo-instructions expanded first. Unlike SPIM and
+//! MARS, real hardware executes the instruction after a jump before the jump
+//! takes effect, so with `delay_slots` every jump and branch is followed by
+//! a `nop`, as GNU `as` does in its default reorder mode.
+
+use crate::mips::{self, Directive, Instr, Reg, DATA_BASE, TEXT_BASE};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    Text,
+    Data,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u32,
+    pub section: Section,
+    pub global: bool,
+}
+
+/// Machine code and data for a listing, laid out at `TEXT_BASE` and
+/// `DATA_BASE`.
+#[derive(Debug, Default)]
+pub struct Object {
+    pub text: Vec<u32>,
+    pub data: Vec<u32>,
+    pub symbols: Vec<Symbol>,
+    pub entry: u32,
+}
+
+const NOP: u32 = 0;
+
+pub fn assemble(listing: &[Instr], delay_slots: bool) -> Result<Object, String> {
+    let listing = mips::expand_pseudo(listing);
+    let errors = mips::validate(&listing);
+    if let Some(error) = errors.into_iter().next() {
+        return Err(error);
+    }
+
+    // first pass: addresses of labels, now that every instruction is real
+    let mut labels = BTreeMap::<String, (u32, Section)>::new();
+    let mut globals = Vec::new();
+    let mut section = Section::Text;
+    let (mut text, mut data) = (TEXT_BASE, DATA_BASE);
+    for instr in listing.iter() {
+        match instr {
+            Instr::Directive(Directive::Text) => section = Section::Text,
+            Instr::Directive(Directive::Data) => section = Section::Data,
+            Instr::Directive(Directive::Globl(name)) => globals.push(name.clone()),
+            Instr::Directive(Directive::Ktext | Directive::Kdata) => {
+                return Err("kernel segments are only for SPIM and MARS".to_string())
+            }
+            Instr::Directive(Directive::WordChar(_) | Directive::Word(_)) => data += 4,
+            Instr::Label(name) => {
+                let address = match section {
+                    Section::Text => text,
+                    Section::Data => data,
+                };
+                labels.insert(name.clone(), (address, section));
+            }
+            instr if instr.is_instruction() => {
+                text += if delay_slots && has_delay_slot(instr) {
+                    8
+                } else {
+                    4
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // second pass: encode
+    let mut object = Object::default();
+    for instr in listing.iter() {
+        match instr {
+            Instr::Directive(Directive::WordChar(c)) => object.data.push(*c as u32),
+            Instr::Directive(Directive::Word(value)) => object.data.push(*value as u32),
+            instr if instr.is_instruction() => {
+                let pc = TEXT_BASE + 4 * object.text.len() as u32;
+                object.text.push(encode(instr, pc, &labels)?);
+                if delay_slots && has_delay_slot(instr) {
+                    object.text.push(NOP);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    object.symbols = labels
+        .iter()
+        .map(|(name, (value, section))| Symbol {
+            name: name.clone(),
+            value: *value,
+            section: *section,
+            global: globals.contains(name),
+        })
+        .collect();
+    object.entry = labels
+        .get("main")
+        .map(|(address, _)| *address)
+        .ok_or("no `main` label")?;
+    Ok(object)
+}
+
+fn has_delay_slot(instr: &Instr) -> bool {
+    matches!(
+        instr,
+        Instr::Jal { .. } | Instr::Jr { .. } | Instr::Beq { .. } | Instr::Bne { .. }
+    )
+}
+
+fn r_type(rs: Reg, rt: Reg, rd: Reg, funct: u32) -> u32 {
+    (rs.0 as u32) << 21 | (rt.0 as u32) << 16 | (rd.0 as u32) << 11 | funct
+}
+
+fn i_type(opcode: u32, rs: Reg, rt: Reg, imm: i32) -> u32 {
+    opcode << 26 | (rs.0 as u32) << 21 | (rt.0 as u32) << 16 | (imm as u32 & 0xffff)
+}
+
+// encode one real instruction located at `pc`
+pub fn encode(
+    instr: &Instr,
+    pc: u32,
+    labels: &BTreeMap<String, (u32, Section)>,
+) -> Result<u32, String> {
+    let word = match *instr {
+        Instr::Add { rd, rs, rt } => r_type(rs, rt, rd, 0x20),
+        Instr::Addu { rd, rs, rt } => r_type(rs, rt, rd, 0x21),
+        Instr::Sub { rd, rs, rt } => r_type(rs, rt, rd, 0x22),
+        Instr::Subu { rd, rs, rt } => r_type(rs, rt, rd, 0x23),
+        Instr::Slt { rd, rs, rt } => r_type(rs, rt, rd, 0x2a),
+        Instr::Divu { rs, rt } => r_type(rs, rt, Reg::ZERO, 0x1b),
+        Instr::Mfhi { rd } => r_type(Reg::ZERO, Reg::ZERO, rd, 0x10),
+        Instr::Mflo { rd } => r_type(Reg::ZERO, Reg::ZERO, rd, 0x12),
+        Instr::Jr { rs } => r_type(rs, Reg::ZERO, Reg::ZERO, 0x08),
+        Instr::Syscall => 0x0c,
+        Instr::Addi { rt, rs, imm } => i_type(0x08, rs, rt, imm),
+        Instr::Addiu { rt, rs, imm } => i_type(0x09, rs, rt, imm),
+        Instr::Ori { rt, rs, imm } => i_type(0x0d, rs, rt, imm),
+        Instr::Lui { rt, imm } => i_type(0x0f, Reg::ZERO, rt, imm),
+        Instr::Lw { rt, offset, base } => i_type(0x23, base, rt, offset),
+        Instr::Sw { rt, offset, base } => i_type(0x2b, base, rt, offset),
+        Instr::Lbu { rt, offset, base } => i_type(0x24, base, rt, offset),
+        Instr::Sb { rt, offset, base } => i_type(0x28, base, rt, offset),
+        Instr::Beq { rs, rt, ref target } | Instr::Bne { rs, rt, ref target } => {
+            let address = match labels.get(target) {
+                Some((address, Section::Text)) => *address,
+                _ => return Err(format!("`{}`: no code label `{}`", instr, target)),
+            };
+            // counted in instructions from the delay slot
+            let offset = (address.wrapping_sub(pc + 4) as i32) >> 2;
+            if i16::try_from(offset).is_err() {
+                return Err(format!("`{}`: target out of range", instr));
+            }
+            let opcode = if matches!(instr, Instr::Beq { .. }) {
+                0x04
+            } else {
+                0x05
+            };
+            i_type(opcode, rs, rt, offset)
+        }
+        Instr::Jal { ref target } => {
+            let address = match labels.get(target) {
+                Some((address, Section::Text)) => *address,
+                _ => return Err(format!("`{}`: no code label `{}`", instr, target)),
+            };
+            // the target must lie in the same 256MB region as the delay slot
+            if (address ^ (pc + 4)) & 0xf000_0000 != 0 {
+                return Err(format!("`{}`: target out of range", instr));
+            }
+            0x03 << 26 | (address >> 2 & 0x03ff_ffff)
+        }
+        _ => return Err(format!("`{}` cannot be encoded", instr)),
+    };
+    Ok(word)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_one(instr: Instr) -> u32 {
+        encode(&instr, TEXT_BASE, &BTreeMap::new()).unwrap()
+    }
+
+    #[test]
+    fn handle_known_encodings() {
+        assert_eq!(
+            encode_one(Instr::Add {
+                rd: Reg::T0,
+                rs: Reg::T0,
+                rt: Reg::T1,
+            }),
+            0x0109_4020
+        );
+        assert_eq!(
+            encode_one(Instr::Sub {
+                rd: Reg::T2,
+                rs: Reg::T0,
+                rt: Reg::T3,
+            }),
+            0x010b_5022
+        );
+        assert_eq!(
+            encode_one(Instr::Addi {
+                rt: Reg::SP,
+                rs: Reg::SP,
+                imm: -32,
+            }),
+            0x23bd_ffe0
+        );
+        assert_eq!(
+            encode_one(Instr::Lw {
+                rt: Reg::RA,
+                offset: 20,
+                base: Reg::SP,
+            }),
+            0x8fbf_0014
+        );
+        assert_eq!(
+            encode_one(Instr::Sw {
+                rt: Reg::FP,
+                offset: 28,
+                base: Reg::SP,
+            }),
+            0xafbe_001c
+        );
+        assert_eq!(
+            encode_one(Instr::Lui {
+                rt: Reg::AT,
+                imm: 0x1001,
+            }),
+            0x3c01_1001
+        );
+        assert_eq!(encode_one(Instr::Jr { rs: Reg::RA }), 0x03e0_0008);
+        assert_eq!(encode_one(Instr::Syscall), 0x0000_000c);
+    }
+
+    #[test]
+    fn handle_label_resolution() {
+        let listing = vec![
+            Instr::Directive(Directive::Text),
+            Instr::Label("main".to_string()),
+            Instr::Jal {
+                target: "read".to_string(),
+            },
+            Instr::Move {
+                rd: Reg::T2,
+                rs: Reg::V0,
+            },
+            Instr::Label("read".to_string()),
+            Instr::Li {
+                rt: Reg::V0,
+                imm: 5,
+            },
+            Instr::Syscall,
+            Instr::Jr { rs: Reg::RA },
+            Instr::Directive(Directive::Data),
+            Instr::Label("newline".to_string()),
+            Instr::Directive(Directive::WordChar('\n')),
+        ];
+
+        let object = assemble(&listing, false).unwrap();
+        assert_eq!(object.entry, TEXT_BASE);
+        // jal 0x00400008
+        assert_eq!(object.text[0], 0x0c10_0002);
+        assert_eq!(object.text[1], 0x0002_5021);
+        assert_eq!(object.text[2], 0x2402_0005);
+        assert_eq!(object.data, vec![10]);
+
+        let object = assemble(&listing, true).unwrap();
+        // `read` moves past the delay slot after `jal`
+        assert_eq!(object.text[0], 0x0c10_0003);
+        assert_eq!(object.text[1], NOP);
+        assert_eq!(object.text.len(), 7);
+        let read = object.symbols.iter().find(|s| s.name == "read").unwrap();
+        assert_eq!(read.value, TEXT_BASE + 12);
+        let newline = object.symbols.iter().find(|s| s.name == "newline").unwrap();
+        assert_eq!((newline.value, newline.section), (DATA_BASE, Section::Data));
+    }
+}
@@ -1,6 +1,7 @@
+use crate::diagnostic::{Diagnostic, Span};
 use crate::lexer::{Token, TokenType};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum BinaryOpKind {
     Add,
     Sub,
@@ -15,6 +16,7 @@ pub enum SyscallKind {
 #[derive(Debug, Clone)]
 pub struct ExprAST {
     pub kind: ExprKind,
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
@@ -38,21 +40,93 @@ pub enum ExprKind {
         var: Box<ExprAST>,
         assign: Box<ExprAST>,
     },
+    /// A procedure call, `Name()`.
+    CallAST {
+        name: Box<str>,
+    },
+}
+
+/// `procedure Name; begin ... end;`
+#[derive(Debug, Clone)]
+pub struct Procedure {
+    pub name: Box<str>,
+    pub span: Span,
+    pub body: Vec<ExprAST>,
+}
+
+/// A whole source file: a program, or a module for programs and other
+/// modules to import. Both may declare globals and procedures.
+#[derive(Debug, Clone, Default)]
+pub struct Unit {
+    /// The name after `module`; `None` for a program.
+    pub module: Option<(Box<str>, Span)>,
+    pub imports: Vec<(Box<str>, Span)>,
+    /// Names declared with `var`.
+    pub globals: Vec<(Box<str>, Span)>,
+    pub procedures: Vec<Procedure>,
+    /// The statements between `begin` and `end` of a program.
+    pub main: Vec<ExprAST>,
+}
+
+impl Unit {
+    /// Whether the unit is a program without imports or declarations,
+    /// which every target can compile.
+    pub fn is_plain_program(&self) -> bool {
+        self.module.is_none()
+            && self.imports.is_empty()
+            && self.globals.is_empty()
+            && self.procedures.is_empty()
+    }
+}
+
+fn node(kind: ExprKind, span: Span) -> Box<ExprAST> {
+    Box::new(ExprAST { kind, span })
+}
+
+// how a token is named in error messages
+fn describe(token_type: &TokenType) -> String {
+    match token_type {
+        TokenType::Identifier { name } => format!("`{}`", name),
+        TokenType::IntLiteral { value } => format!("`{}`", value),
+        TokenType::ScanEof => "the end of the file".to_string(),
+        token_type => format!("`{}`", token_type.as_str()),
+    }
 }
 
+/// Parser for a whole program. Errors do not stop it: each one is recorded
+/// in `diagnostics` and parsing resumes at the next statement.
 pub struct ASTBuilder<I> {
     iter: I,
     current: Token,
     current_string: String,
+    // end of the last token consumed, where a missing token would go
+    prev_end: usize,
+    pub diagnostics: Vec<Diagnostic>,
 }
 
 impl<I: Iterator<Item = Token>> Iterator for ASTBuilder<I> {
     type Item = Token;
 
+    // comments, and characters the lexer already reported, are skipped
     fn next(&mut self) -> Option<Token> {
-        self.current = self.iter.next().unwrap_or(Token::eof());
+        if self.current.token_type != TokenType::ScanEof {
+            self.prev_end = self.prev_end.max(self.current.span().end);
+        }
+        loop {
+            self.current = self.iter.next().unwrap_or_else(|| {
+                let mut eof = Token::eof();
+                eof.offset = self.prev_end;
+                eof
+            });
+            if !matches!(
+                self.current.token_type,
+                TokenType::LineComment { .. } | TokenType::Unknown
+            ) {
+                break;
+            }
+        }
         self.current_string = self.current.token_type.as_str().to_string();
-        return Some(self.current.clone());
+        Some(self.current.clone())
     }
 }
 
@@ -62,70 +136,115 @@ impl<I: Iterator<Item = Token>> ASTBuilder<I> {
             iter,
             current: Token::unknown(),
             current_string: String::new(),
+            prev_end: 0,
+            diagnostics: Vec::new(),
         }
     }
 
+    // record an error at the current token
+    fn error<T>(&mut self, message: String) -> Option<T> {
+        self.diagnostics
+            .push(Diagnostic::error(self.current.span(), message));
+        None
+    }
+
+    fn expect(&mut self, token_type: TokenType, context: &str) -> Option<()> {
+        if self.current.token_type != token_type {
+            return self.error(format!(
+                "expected `{}` {}, found {}",
+                token_type.as_str(),
+                context,
+                describe(&self.current.token_type)
+            ));
+        }
+        self.next();
+        Some(())
+    }
+
+    // span from `start` to the end of the last consumed token
+    fn span_from(&self, start: usize) -> Span {
+        Span::new(start, self.prev_end)
+    }
+
     // <expression> -> <primary> <binary op rhs>
+    // <expression> -> OpPlus <primary> <binary op rhs>
     pub fn parse_expression(&mut self) -> Option<Box<ExprAST>> {
-        let lhs = self.parse_primary().unwrap_or(Box::new(ExprAST {
-            kind: ExprKind::IntLiteralExprAST { value: 0 },
-        }));
-        return self.parse_bin_op_rhs(lhs);
+        // a leading `+` is unary, and `+x` means `0 + x`
+        let lhs = if self.current.token_type == TokenType::OpPlus {
+            let start = self.current.span().start;
+            node(
+                ExprKind::IntLiteralExprAST { value: 0 },
+                Span::new(start, start),
+            )
+        } else {
+            self.parse_primary()?
+        };
+        self.parse_bin_op_rhs(lhs)
     }
 
     // <binary op rhs> -> {<add op> <primary>}
     pub fn parse_bin_op_rhs(&mut self, mut lhs: Box<ExprAST>) -> Option<Box<ExprAST>> {
         loop {
-            if !matches!(
-                self.current.token_type,
-                TokenType::OpPlus | TokenType::OpMinus
-            ) {
-                return Some(lhs);
-            }
             let bin_op = match self.current.token_type {
                 TokenType::OpPlus => BinaryOpKind::Add,
                 TokenType::OpMinus => BinaryOpKind::Sub,
-                _ => return None,
+                _ => return Some(lhs),
             };
             self.next();
             let rhs = self.parse_primary()?;
-            lhs = Box::new(ExprAST {
-                kind: ExprKind::BinaryExprAST {
+            let span = lhs.span.to(rhs.span);
+            lhs = node(
+                ExprKind::BinaryExprAST {
                     op: bin_op,
                     lhs,
                     rhs,
                 },
-            });
+                span,
+            );
         }
     }
 
     // <primary> -> Identifier
+    // <statement> -> (read | write) LeftParen [<expression> {Comma <expression>}] RightParen
     pub fn parse_identifier(&mut self) -> Option<Box<ExprAST>> {
+        let start = self.current.span().start;
         let calle = match self.current.clone().token_type {
             TokenType::Identifier { name } => {
                 self.next();
-                return Some(Box::new(ExprAST {
-                    kind: ExprKind::VariableExprAST { name },
-                }));
+                return Some(node(
+                    ExprKind::VariableExprAST { name },
+                    self.span_from(start),
+                ));
             }
             TokenType::Read => SyscallKind::Read,
             TokenType::Write => SyscallKind::Write,
-            _ => panic!(),
+            token_type => {
+                return self.error(format!("expected a name, found {}", describe(&token_type)))
+            }
         };
+        let context = format!("after `{}`", self.current_string);
         self.next();
-        self.next();
+        self.expect(TokenType::LeftParen, &context)?;
 
-        // '('
         let mut args = Vec::<ExprAST>::new();
         if self.current.token_type != TokenType::RightParen {
             loop {
                 let arg = self.parse_expression()?;
-                args.push(*arg);
+                match (&calle, &arg.kind) {
+                    (SyscallKind::Read, ExprKind::VariableExprAST { .. })
+                    | (SyscallKind::Write, _) => args.push(*arg),
+                    (SyscallKind::Read, _) => self
+                        .diagnostics
+                        .push(Diagnostic::error(arg.span, "`read` needs a variable")),
+                }
                 if self.current.token_type == TokenType::RightParen {
                     break;
                 }
                 if self.current.token_type != TokenType::Comma {
-                    panic!("Unexpected token: {:?}", self.current.token_type)
+                    return self.error(format!(
+                        "expected `,` or `)`, found {}",
+                        describe(&self.current.token_type)
+                    ));
                 }
                 self.next();
             }
@@ -134,29 +253,41 @@ impl<I: Iterator<Item = Token>> ASTBuilder<I> {
         // ')'
         self.next();
 
-        Some(Box::new(ExprAST {
-            kind: ExprKind::SyscallExprAST { calle, args },
-        }))
+        Some(node(
+            ExprKind::SyscallExprAST { calle, args },
+            self.span_from(start),
+        ))
     }
 
-    // <primary> -> IntLiteral
+    // <primary> -> [OpMinus] IntLiteral
     pub fn parse_int_literal(&mut self) -> Option<Box<ExprAST>> {
+        let start = self.current.span().start;
         let value = match self.current.token_type {
             TokenType::OpMinus => {
                 self.next();
                 match self.current.token_type {
-                    TokenType::IntLiteral { value } => -value,
-                    _ => panic!("Not an int literal"),
+                    TokenType::IntLiteral { value } => (value as i32).wrapping_neg(),
+                    _ => {
+                        return self.error(format!(
+                            "expected an integer literal after `-`, found {}",
+                            describe(&self.current.token_type)
+                        ))
+                    }
                 }
             }
-            TokenType::IntLiteral { value } => value,
-            _ => panic!("Not an int literal"),
+            TokenType::IntLiteral { value } => i32::try_from(value).unwrap_or_else(|_| {
+                let message = format!("`{}` is out of range, only `-{}` is allowed", value, value);
+                self.diagnostics
+                    .push(Diagnostic::error(self.current.span(), message));
+                0
+            }),
+            _ => return self.error("expected an integer literal".to_string()),
         };
-        let result = Box::new(ExprAST {
-            kind: ExprKind::IntLiteralExprAST { value },
-        });
         self.next();
-        Some(result)
+        Some(node(
+            ExprKind::IntLiteralExprAST { value },
+            self.span_from(start),
+        ))
     }
 
     // <primary> -> LeftParen <expression> RightParen
@@ -164,11 +295,8 @@ impl<I: Iterator<Item = Token>> ASTBuilder<I> {
         // eat '('
         self.next();
         let v = self.parse_expression()?;
-        if self.current.token_type != TokenType::RightParen {
-            panic!("Unexpected token: {:?}", self.current.token_type);
-        }
         // eat ')'
-        self.next();
+        self.expect(TokenType::RightParen, "to close `(`")?;
         Some(v)
     }
 
@@ -177,66 +305,231 @@ impl<I: Iterator<Item = Token>> ASTBuilder<I> {
     // <primary> -> LeftParen <expression> RightParen
     pub fn parse_primary(&mut self) -> Option<Box<ExprAST>> {
         match self.current.token_type {
-            TokenType::Read => self.parse_identifier(),
-            TokenType::Write => self.parse_identifier(),
             TokenType::Identifier { name: _ } => self.parse_identifier(),
-
             TokenType::IntLiteral { value: _ } => self.parse_int_literal(),
             TokenType::LeftParen => self.parse_paren(),
             TokenType::OpMinus => self.parse_int_literal(),
-            _ => None,
+            _ => self.error(format!(
+                "expected an expression, found {}",
+                describe(&self.current.token_type)
+            )),
         }
     }
 
+    // <statement> -> Identifier OpAssign <expression>
+    // <statement> -> Identifier LeftParen RightParen
     pub fn parse_assign(&mut self) -> Option<Box<ExprAST>> {
-        let id = match self.current.clone().token_type {
-            TokenType::Identifier { name } => Some(Box::new(ExprAST {
-                kind: ExprKind::VariableExprAST { name },
-            })),
-            _ => panic!(),
-        }?;
-        self.next();
-        self.next();
+        let start = self.current.span().start;
+        let id = self.parse_identifier()?;
+        if self.current.token_type == TokenType::LeftParen {
+            self.next();
+            self.expect(TokenType::RightParen, "in a call")?;
+            let ExprKind::VariableExprAST { name } = id.kind else {
+                unreachable!()
+            };
+            return Some(node(ExprKind::CallAST { name }, self.span_from(start)));
+        }
+        self.expect(TokenType::OpAssign, "in an assignment")?;
+        let assign = self.parse_expression()?;
 
-        Some(Box::new(ExprAST {
-            kind: ExprKind::AssignmentAST {
-                var: id,
-                assign: self.parse_expression()?,
-            },
-        }))
+        Some(node(
+            ExprKind::AssignmentAST { var: id, assign },
+            self.span_from(start),
+        ))
+    }
+
+    // <statement> -> <assignment> | <read> | <write>
+    pub fn parse_statement(&mut self) -> Option<Box<ExprAST>> {
+        match self.current.token_type {
+            TokenType::Identifier { name: _ } => self.parse_assign(),
+            TokenType::Read | TokenType::Write => self.parse_identifier(),
+            _ => self.error(format!(
+                "expected a statement, found {}",
+                describe(&self.current.token_type)
+            )),
+        }
     }
 
-    pub fn parse(&mut self) -> Vec<Box<ExprAST>> {
-        let mut p_vec = Vec::<Box<ExprAST>>::new();
-        let mut program_start = false;
+    // skip the rest of a broken statement
+    fn synchronize(&mut self) {
         loop {
-            self.next();
             match self.current.token_type {
-                TokenType::ScanEof => break,
-                TokenType::Begin => {
-                    program_start = true;
-                    continue;
-                }
-                TokenType::End => {
-                    program_start = false;
-                    continue;
+                TokenType::Semicolon => {
+                    self.next();
+                    return;
                 }
-                TokenType::Semicolon => continue,
-                TokenType::LineComment => continue,
+                TokenType::End | TokenType::ScanEof => return,
                 _ => {
-                    if !program_start {
-                        continue;
+                    self.next();
+                }
+            }
+        }
+    }
+
+    // {<statement> Semicolon}, up to `end` or the end of the input
+    fn parse_statement_list(&mut self, last_semicolon_optional: bool) -> Vec<ExprAST> {
+        let mut p_vec = Vec::<ExprAST>::new();
+        while !matches!(self.current.token_type, TokenType::End | TokenType::ScanEof) {
+            match self.parse_statement() {
+                Some(v) => {
+                    p_vec.push(*v);
+                    if last_semicolon_optional && self.current.token_type == TokenType::ScanEof {
+                        break;
+                    }
+                    let starts_statement = matches!(
+                        self.current.token_type,
+                        TokenType::Identifier { .. } | TokenType::Read | TokenType::Write
+                    );
+                    // a missing `;` before another statement loses nothing
+                    if self
+                        .expect(TokenType::Semicolon, "after the statement")
+                        .is_none()
+                        && !starts_statement
+                    {
+                        self.synchronize();
                     }
                 }
+                None => self.synchronize(),
             }
-            let v = match self.current.clone().token_type {
-                TokenType::Identifier { name: _ } => self.parse_assign(),
-                _ => self.parse_expression(),
-            };
-            p_vec.push(v.unwrap());
         }
         p_vec
     }
+
+    // <statements> -> {<statement> Semicolon} [<statement>]
+    /// Statements without `begin` and `end`, as typed into the REPL. The `;`
+    /// after the last one may be left out.
+    pub fn parse_statements(&mut self) -> Vec<ExprAST> {
+        self.next();
+        let p_vec = self.parse_statement_list(true);
+        if self.current.token_type == TokenType::End {
+            self.error::<()>("unexpected `end` without `begin`".to_string());
+        }
+        p_vec
+    }
+
+    // <program> -> begin {<statement> Semicolon} end, which the tests use
+    // for plain programs
+    #[cfg(test)]
+    pub fn parse(&mut self) -> Vec<ExprAST> {
+        self.next();
+        self.parse_program()
+    }
+
+    fn parse_program(&mut self) -> Vec<ExprAST> {
+        if self.current.token_type == TokenType::Begin {
+            self.next();
+        } else {
+            // parse the statements anyway
+            self.error::<()>(format!(
+                "expected `begin`, found {}",
+                describe(&self.current.token_type)
+            ));
+        }
+        let p_vec = self.parse_statement_list(false);
+        if self.current.token_type == TokenType::End {
+            self.next();
+            if self.current.token_type != TokenType::ScanEof {
+                self.error::<()>(format!(
+                    "unexpected {} after `end`",
+                    describe(&self.current.token_type)
+                ));
+            }
+        } else {
+            self.error::<()>("expected `end`, found the end of the file".to_string());
+        }
+        p_vec
+    }
+
+    fn parse_name(&mut self, context: &str) -> Option<(Box<str>, Span)> {
+        match self.current.token_type.clone() {
+            TokenType::Identifier { name } => {
+                let span = self.current.span();
+                self.next();
+                Some((name, span))
+            }
+            token_type => self.error(format!(
+                "expected a name {}, found {}",
+                context,
+                describe(&token_type)
+            )),
+        }
+    }
+
+    // <declaration> -> var Identifier {Comma Identifier} Semicolon
+    // <declaration> -> procedure Identifier Semicolon
+    //                  begin {<statement> Semicolon} end Semicolon
+    fn parse_declaration(&mut self, unit: &mut Unit) -> Option<()> {
+        match self.current.token_type {
+            TokenType::Var => {
+                self.next();
+                loop {
+                    let global = self.parse_name("after `var`")?;
+                    unit.globals.push(global);
+                    if self.current.token_type != TokenType::Comma {
+                        break;
+                    }
+                    self.next();
+                }
+                self.expect(TokenType::Semicolon, "after the variables")
+            }
+            TokenType::Procedure => {
+                self.next();
+                let (name, span) = self.parse_name("after `procedure`")?;
+                self.expect(TokenType::Semicolon, "after the procedure name")?;
+                self.expect(TokenType::Begin, "before the procedure body")?;
+                let body = self.parse_statement_list(false);
+                self.expect(TokenType::End, "after the procedure body")?;
+                self.expect(TokenType::Semicolon, "after the procedure")?;
+                unit.procedures.push(Procedure { name, span, body });
+                Some(())
+            }
+            _ => self.error(format!(
+                "expected `var` or `procedure`, found {}",
+                describe(&self.current.token_type)
+            )),
+        }
+    }
+
+    // <unit> -> module Identifier Semicolon {<import>} {<declaration>}
+    // <unit> -> {<import>} {<declaration>} <program>
+    // <import> -> import Identifier Semicolon
+    /// A program or a module.
+    pub fn parse_unit(&mut self) -> Unit {
+        self.next();
+        let mut unit = Unit::default();
+        if self.current.token_type == TokenType::Module {
+            self.next();
+            unit.module = self.parse_name("after `module`");
+            self.expect(TokenType::Semicolon, "after the module name");
+        }
+        while self.current.token_type == TokenType::Import {
+            self.next();
+            if let Some(import) = self.parse_name("after `import`") {
+                unit.imports.push(import);
+            }
+            self.expect(TokenType::Semicolon, "after the import");
+        }
+        // a program's declarations end at its `begin`
+        let program = unit.module.is_none();
+        while !(self.current.token_type == TokenType::ScanEof
+            || program && self.current.token_type == TokenType::Begin)
+        {
+            if self.parse_declaration(&mut unit).is_none() {
+                // resume at the next declaration
+                self.next();
+                while !matches!(
+                    self.current.token_type,
+                    TokenType::Var | TokenType::Procedure | TokenType::Begin | TokenType::ScanEof
+                ) {
+                    self.next();
+                }
+            }
+        }
+        if unit.module.is_none() {
+            unit.main = self.parse_program();
+        }
+        unit
+    }
 }
 
 #[cfg(test)]
@@ -260,4 +553,122 @@ mod tests {
         let mut builder = ASTBuilder::new(Box::new(iter));
         builder.parse();
     }
+
+    #[test]
+    fn handle_syntax_errors() {
+        let source = "begin\n  A := ;\n  read(A + 1);\n  write(A) -- done\n  B := -C;\nend";
+        let mut lexer = Lexer::new(source);
+        let iter = lexer.tokenize();
+        let mut builder = ASTBuilder::new(Box::new(iter));
+        let statements = builder.parse();
+        let errors: Vec<(&str, &str)> = builder
+            .diagnostics
+            .iter()
+            .map(|d| (&source[d.span.start..d.span.end], d.message.as_str()))
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                (";", "expected an expression, found `;`"),
+                ("A + 1", "`read` needs a variable"),
+                ("B", "expected `;` after the statement, found `B`"),
+                ("C", "expected an integer literal after `-`, found `C`"),
+            ]
+        );
+        // `read()` and `write(A)` survive
+        assert_eq!(statements.len(), 2);
+        assert_eq!(statements[1].span, Span::new(32, 40));
+    }
+
+    #[test]
+    fn handle_statements() {
+        let mut lexer = Lexer::new("read(A); A := A + 1;; write(A)");
+        let mut builder = ASTBuilder::new(lexer.tokenize());
+        let statements = builder.parse_statements();
+        assert_eq!(statements.len(), 3);
+        let messages: Vec<&str> = builder
+            .diagnostics
+            .iter()
+            .map(|d| d.message.as_str())
+            .collect();
+        assert_eq!(messages, ["expected a statement, found `;`"]);
+
+        let mut lexer = Lexer::new("write(1) end");
+        let mut builder = ASTBuilder::new(lexer.tokenize());
+        builder.parse_statements();
+        assert_eq!(builder.diagnostics.len(), 2);
+    }
+
+    #[test]
+    fn handle_units() {
+        let source = "module M; import N; var A, B; procedure P; begin A := B; Q(); end;";
+        let mut lexer = Lexer::new(source);
+        let mut builder = ASTBuilder::new(lexer.tokenize());
+        let unit = builder.parse_unit();
+        assert!(builder.diagnostics.is_empty());
+        assert_eq!(unit.module.as_ref().map(|(name, _)| &**name), Some("M"));
+        assert_eq!(unit.imports.len(), 1);
+        assert_eq!(unit.globals.len(), 2);
+        assert_eq!(unit.procedures[0].body.len(), 2);
+        assert!(!unit.is_plain_program());
+
+        let mut lexer = Lexer::new("var A; procedure P; begin A := 1; end; begin P(); end");
+        let mut builder = ASTBuilder::new(lexer.tokenize());
+        let unit = builder.parse_unit();
+        assert!(builder.diagnostics.is_empty());
+        assert_eq!((unit.globals.len(), unit.main.len()), (1, 1));
+        assert!(unit.module.is_none() && !unit.is_plain_program());
+
+        let mut lexer = Lexer::new("module M; var ; procedure P; begin end; var 1;");
+        let mut builder = ASTBuilder::new(lexer.tokenize());
+        let unit = builder.parse_unit();
+        assert_eq!(unit.procedures.len(), 1);
+        let messages: Vec<&str> = builder
+            .diagnostics
+            .iter()
+            .map(|d| d.message.as_str())
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                "expected a name after `var`, found `;`",
+                "expected a name after `var`, found `1`"
+            ]
+        );
+    }
+
+    #[test]
+    fn handle_int_range() {
+        let source = "begin A := -2147483648; B := 2147483648; C := 0 - 99999999999; end";
+        let mut lexer = Lexer::new(source);
+        lexer.recover = true;
+        let tokens = lexer.tokens();
+        let mut builder = ASTBuilder::new(tokens.into_iter());
+        let statements = builder.parse();
+        assert_eq!(statements.len(), 3);
+        assert!(matches!(
+            &statements[0].kind,
+            ExprKind::AssignmentAST { assign, .. }
+                if matches!(assign.kind, ExprKind::IntLiteralExprAST { value: i32::MIN })
+        ));
+        let errors: Vec<(&str, &str)> = lexer
+            .diagnostics
+            .iter()
+            .chain(builder.diagnostics.iter())
+            .map(|d| (&source[d.span.start..d.span.end], d.message.as_str()))
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                (
+                    "99999999999",
+                    "integer literal out of range, integers are 32 bits"
+                ),
+                (
+                    "2147483648",
+                    "`2147483648` is out of range, only `-2147483648` is allowed"
+                ),
+            ]
+        );
+    }
 }
@@ -0,0 +1,224 @@
+//! The interface the assembly backends implement, and the registry the
+//! driver looks them up in.
+//!
+//! A backend only describes the pieces of its output: the frame set up
+//! around `main`, how each IR instruction is lowered, and the runtime the
+//! program calls into. `lower` puts them together, so a new assembly target
+//! is a `Backend` impl plus an entry in `BACKENDS`. Targets whose output is
+//! not a listing of instructions (wasm32, C, LLVM IR, bytecode and the JVM)
+//! are not backends in this sense; the driver matches them by name.
+
+use crate::aarch64::Aarch64Generator;
+use crate::codegen::CodeGenerator;
+use crate::ir::{Inst, Program};
+use crate::opt::OptLevel;
+use crate::riscv::RiscvGenerator;
+use crate::x86_64::X86Generator;
+use std::fmt;
+
+/// The error of every target but MIPS for a program that calls a procedure.
+pub const NO_PROCEDURES: &str = "procedures are only supported on mips";
+
+/// An assembly target: MIPS, RISC-V, x86-64 or AArch64.
+pub trait Backend {
+    /// A line of output: typed for MIPS, plain text for the others.
+    type Instr: Clone;
+
+    /// Why a program cannot be lowered, `Infallible` when it always can.
+    type Error: fmt::Display;
+
+    /// Extension of the assembly files this backend writes, without a dot.
+    fn file_extension(&self) -> &'static str;
+
+    /// Allocate registers and lay out the stack frame for `program`.
+    fn assign_locations(&mut self, program: &Program);
+
+    /// Lower one IR instruction into the body of `main`.
+    fn lower_instruction(&mut self, inst: &Inst) -> Result<(), Self::Error>;
+
+    /// The body of `main`, once every instruction has been lowered.
+    fn finish(&mut self) -> Vec<Self::Instr>;
+
+    fn prologue(&self) -> Vec<Self::Instr>;
+
+    fn epilogue(&self) -> Vec<Self::Instr>;
+
+    /// `read`, `write` and anything else the program calls.
+    fn runtime_support(&self) -> Vec<Self::Instr>;
+
+    fn print(&self, listing: &[Self::Instr]) -> String;
+}
+
+/// Full listing for `program`: `main`, then the runtime.
+pub fn lower<B: Backend>(backend: &mut B, program: &Program) -> Result<Vec<B::Instr>, B::Error> {
+    backend.assign_locations(program);
+    for inst in program.insts.iter() {
+        backend.lower_instruction(inst)?;
+    }
+    let body = backend.finish();
+
+    let mut listing = backend.prologue();
+    listing.extend(body);
+    listing.extend(backend.epilogue());
+    listing.extend(backend.runtime_support());
+    Ok(listing)
+}
+
+/// Lines of a text snippet, for backends whose output is plain text.
+pub fn lines(text: &str) -> Vec<String> {
+    text.lines().map(str::to_string).collect()
+}
+
+/// Text of a listing made of plain lines.
+pub fn print_lines(listing: &[String]) -> String {
+    listing.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+/// A backend with its instruction type erased, as stored in the registry.
+pub trait Emitter {
+    fn emit(&mut self, program: &Program) -> Result<String, String>;
+
+    fn file_extension(&self) -> &'static str;
+}
+
+impl<B: Backend> Emitter for B {
+    fn emit(&mut self, program: &Program) -> Result<String, String> {
+        let listing = lower(self, program).map_err(|e| e.to_string())?;
+        Ok(self.print(&listing))
+    }
+
+    fn file_extension(&self) -> &'static str {
+        Backend::file_extension(self)
+    }
+}
+
+type Constructor = fn(OptLevel) -> Box<dyn Emitter>;
+
+/// Assembly backends by target name.
+pub static BACKENDS: [(&str, Constructor); 4] = [
+    ("mips", |opt_level| {
+        let mut cg = CodeGenerator::new();
+        cg.opt_level = opt_level;
+        Box::new(cg)
+    }),
+    ("riscv32", |_| Box::new(RiscvGenerator::new())),
+    ("x86_64", |_| Box::new(X86Generator::new())),
+    ("aarch64", |_| Box::new(Aarch64Generator::new())),
+];
+
+/// The registered name and constructor for target `name`.
+pub fn lookup(name: &str) -> Option<(&'static str, Constructor)> {
+    BACKENDS.iter().find(|(key, _)| *key == name).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::Value;
+    use crate::llvm::LlvmGenerator;
+    use crate::testing::lower_source;
+    use crate::{bytecode, c99, jvm, mips, wasm};
+    use std::convert::Infallible;
+
+    use super::*;
+
+    // lowers only writes of constants, enough to check how `lower` orders
+    // the pieces
+    #[derive(Default)]
+    struct Stack {
+        vregs: usize,
+        body: Vec<String>,
+    }
+
+    impl Backend for Stack {
+        type Instr = String;
+        type Error = Infallible;
+
+        fn file_extension(&self) -> &'static str {
+            "stack"
+        }
+
+        fn assign_locations(&mut self, program: &Program) {
+            self.vregs = program.vreg_count as usize;
+        }
+
+        fn lower_instruction(&mut self, inst: &Inst) -> Result<(), Infallible> {
+            if let Inst::Write {
+                src: Value::Imm(imm),
+            } = inst
+            {
+                self.body.push(format!("print {}", imm));
+            }
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Vec<String> {
+            std::mem::take(&mut self.body)
+        }
+
+        fn prologue(&self) -> Vec<String> {
+            vec![format!("enter {}", self.vregs)]
+        }
+
+        fn epilogue(&self) -> Vec<String> {
+            vec!["leave".to_string()]
+        }
+
+        fn runtime_support(&self) -> Vec<String> {
+            lines("print:\n    syscall")
+        }
+
+        fn print(&self, listing: &[String]) -> String {
+            print_lines(listing)
+        }
+    }
+
+    #[test]
+    fn handle_listing_order() {
+        let program = lower_source("begin read(A); write(1, 2); end");
+        assert_eq!(
+            Stack::default().emit(&program).unwrap(),
+            "enter 1\nprint 1\nprint 2\nleave\nprint:\n    syscall\n"
+        );
+    }
+
+    #[test]
+    fn handle_registry() {
+        let program = lower_source(include_str!("../TestCases/test5.m"));
+        for (name, new) in BACKENDS.iter() {
+            assert_eq!(lookup(name).map(|(key, _)| key), Some(*name));
+            let asm = new(OptLevel::O1).emit(&program).unwrap();
+            assert!(asm.contains("main:"), "{}", name);
+        }
+        assert!(lookup("sparc").is_none());
+
+        // the registered MIPS backend is the one `CodeGenerator` drives
+        let mut cg = CodeGenerator::new();
+        cg.opt_level = OptLevel::O1;
+        let listing = cg.assemble(&program);
+        let (_, new) = lookup("mips").unwrap();
+        assert_eq!(
+            new(OptLevel::O1).emit(&program).unwrap(),
+            mips::print(&listing)
+        );
+        assert_eq!(new(OptLevel::O0).file_extension(), "asm");
+
+        // only MIPS compiles procedures, the others refuse to call one
+        let mut program = lower_source("begin write(1); end");
+        program.insts.push(Inst::Call {
+            target: "p".to_string(),
+        });
+        for (name, new) in BACKENDS.iter() {
+            let asm = new(OptLevel::O0).emit(&program);
+            match *name {
+                "mips" => assert!(asm.unwrap().contains("jal p")),
+                _ => assert_eq!(asm.err().as_deref(), Some(NO_PROCEDURES), "{}", name),
+            }
+        }
+        let error = Some(NO_PROCEDURES.to_string());
+        assert_eq!(c99::generate(&program).err(), error);
+        assert_eq!(jvm::generate(&program, "A", "a.m").err(), error);
+        assert_eq!(LlvmGenerator::new().generate(&program, "a.m").err(), error);
+        assert_eq!(bytecode::compile(&program).err(), error);
+        assert_eq!(wasm::lower(&program).err(), error);
+    }
+}
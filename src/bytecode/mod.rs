@@ -0,0 +1,270 @@
+//! A compact stack bytecode for Micro, stored in `.mbc` files.
+//!
+//! Every virtual register becomes a numbered slot. Instructions push and pop
+//! `i32` values on an operand stack, and jump targets are instruction
+//! indices. The file layout is:
+//!
+//! ```text
+//! magic "\x7fMBC", version: u16
+//! slot count: u32, then per slot a name (u16 length + UTF-8)
+//! instruction count: u32, then per instruction an opcode byte followed by
+//! its operand, if any, as a little-endian i32 or u32
+//! ```
+
+pub mod vm;
+
+use crate::ast::BinaryOpKind;
+use crate::backend;
+use crate::ir::{Inst, Program, Value};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Halt,
+    Push(i32),
+    Load(u32),
+    Store(u32),
+    Add,
+    Sub,
+    /// Reads an integer and pushes it.
+    Read,
+    /// Pops an integer and prints it.
+    Write,
+    Pop,
+    Jump(u32),
+    /// Pops a value and jumps if it is zero.
+    JumpIfZero(u32),
+}
+
+const MAGIC: &[u8; 4] = b"\x7fMBC";
+const VERSION: u16 = 1;
+
+impl Op {
+    fn opcode(self) -> u8 {
+        match self {
+            Op::Halt => 0x00,
+            Op::Push(_) => 0x01,
+            Op::Load(_) => 0x02,
+            Op::Store(_) => 0x03,
+            Op::Add => 0x04,
+            Op::Sub => 0x05,
+            Op::Read => 0x06,
+            Op::Write => 0x07,
+            Op::Pop => 0x08,
+            Op::Jump(_) => 0x09,
+            Op::JumpIfZero(_) => 0x0a,
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Op::Halt => write!(f, "halt"),
+            Op::Push(imm) => write!(f, "push {}", imm),
+            Op::Load(slot) => write!(f, "load {}", slot),
+            Op::Store(slot) => write!(f, "store {}", slot),
+            Op::Add => write!(f, "add"),
+            Op::Sub => write!(f, "sub"),
+            Op::Read => write!(f, "read"),
+            Op::Write => write!(f, "write"),
+            Op::Pop => write!(f, "pop"),
+            Op::Jump(target) => write!(f, "jump {:04}", target),
+            Op::JumpIfZero(target) => write!(f, "jz {:04}", target),
+        }
+    }
+}
+
+/// A compiled program: slot names for debugging, and the code.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub slots: Vec<String>,
+    pub code: Vec<Op>,
+}
+
+pub fn compile(program: &Program) -> Result<Chunk, String> {
+    let slots = (0..program.vreg_count)
+        .map(|vreg| match program.var_name(vreg) {
+            Some(name) => name.to_string(),
+            None => format!("%{}", vreg),
+        })
+        .collect();
+    let mut code = Vec::new();
+    let push = |code: &mut Vec<Op>, value: Value| match value {
+        Value::Reg(vreg) => code.push(Op::Load(vreg)),
+        Value::Imm(imm) => code.push(Op::Push(imm)),
+    };
+    for inst in program.insts.iter() {
+        match inst {
+            Inst::Call { .. } => return Err(backend::NO_PROCEDURES.to_string()),
+            Inst::Read { dst } => {
+                code.push(Op::Read);
+                code.push(match dst {
+                    Some(dst) => Op::Store(*dst),
+                    None => Op::Pop,
+                });
+            }
+            Inst::Write { src } => {
+                push(&mut code, *src);
+                code.push(Op::Write);
+            }
+            Inst::Copy { dst, src } => {
+                push(&mut code, *src);
+                code.push(Op::Store(*dst));
+            }
+            Inst::Binary { op, dst, lhs, rhs } => {
+                push(&mut code, *lhs);
+                push(&mut code, *rhs);
+                code.push(match op {
+                    BinaryOpKind::Add => Op::Add,
+                    BinaryOpKind::Sub => Op::Sub,
+                });
+                code.push(Op::Store(*dst));
+            }
+        }
+    }
+    code.push(Op::Halt);
+    Ok(Chunk { slots, code })
+}
+
+impl Chunk {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(VERSION.to_le_bytes());
+        bytes.extend((self.slots.len() as u32).to_le_bytes());
+        for slot in self.slots.iter() {
+            bytes.extend((slot.len() as u16).to_le_bytes());
+            bytes.extend(slot.as_bytes());
+        }
+        bytes.extend((self.code.len() as u32).to_le_bytes());
+        for op in self.code.iter() {
+            bytes.push(op.opcode());
+            match *op {
+                Op::Push(imm) => bytes.extend(imm.to_le_bytes()),
+                Op::Load(operand)
+                | Op::Store(operand)
+                | Op::Jump(operand)
+                | Op::JumpIfZero(operand) => bytes.extend(operand.to_le_bytes()),
+                _ => {}
+            }
+        }
+        bytes
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Chunk, String> {
+        let mut reader = Reader { bytes, pos: 0 };
+        if reader.take(4)? != MAGIC {
+            return Err("not a Micro bytecode file".to_string());
+        }
+        let version = u16::from_le_bytes(reader.take(2)?.try_into().unwrap());
+        if version != VERSION {
+            return Err(format!("unsupported bytecode version {}", version));
+        }
+
+        let mut chunk = Chunk::default();
+        for _ in 0..reader.u32()? {
+            let len = u16::from_le_bytes(reader.take(2)?.try_into().unwrap());
+            let name = reader.take(len as usize)?;
+            let name = String::from_utf8(name.to_vec()).map_err(|_| "invalid slot name")?;
+            chunk.slots.push(name);
+        }
+        for _ in 0..reader.u32()? {
+            let op = match reader.take(1)?[0] {
+                0x00 => Op::Halt,
+                0x01 => Op::Push(reader.u32()? as i32),
+                0x02 => Op::Load(reader.u32()?),
+                0x03 => Op::Store(reader.u32()?),
+                0x04 => Op::Add,
+                0x05 => Op::Sub,
+                0x06 => Op::Read,
+                0x07 => Op::Write,
+                0x08 => Op::Pop,
+                0x09 => Op::Jump(reader.u32()?),
+                0x0a => Op::JumpIfZero(reader.u32()?),
+                opcode => return Err(format!("unknown opcode {:#04x}", opcode)),
+            };
+            chunk.code.push(op);
+        }
+        if reader.pos != bytes.len() {
+            return Err("trailing bytes after the code".to_string());
+        }
+        Ok(chunk)
+    }
+
+    pub fn disassemble(&self) -> String {
+        let mut buf = format!("; slots: {}\n", self.slots.join(", "));
+        for (index, op) in self.code.iter().enumerate() {
+            let line = format!("{:04}  {}", index, op);
+            match op {
+                Op::Load(slot) | Op::Store(slot) => {
+                    let name = self.slots.get(*slot as usize).map_or("?", |s| s.as_str());
+                    buf.push_str(format!("{:<20}; {}\n", line, name).as_str());
+                }
+                _ => buf.push_str(format!("{}\n", line).as_str()),
+            }
+        }
+        buf
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + len)
+            .ok_or("unexpected end of bytecode")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+
+    use super::*;
+
+    fn compile_source(source: &str) -> Chunk {
+        compile(&testing::lower_source(source)).unwrap()
+    }
+
+    #[test]
+    fn handle_round_trip() {
+        let mut chunk = compile_source(include_str!("../../TestCases/test9.m"));
+        chunk.code.splice(0..0, [Op::Jump(1), Op::JumpIfZero(0)]);
+        let bytes = chunk.serialize();
+        assert_eq!(&bytes[..6], b"\x7fMBC\x01\x00");
+        assert_eq!(Chunk::deserialize(&bytes), Ok(chunk));
+
+        assert!(Chunk::deserialize(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Chunk::deserialize(b"\x7fMBC\x02\x00").is_err());
+    }
+
+    #[test]
+    fn handle_disassembly() {
+        let chunk = compile_source("begin read(A); B := A - 70000; write(B); end");
+        assert_eq!(
+            chunk.disassemble(),
+            "; slots: A, B, %2
+0000  read
+0001  store 0       ; A
+0002  load 0        ; A
+0003  push 70000
+0004  sub
+0005  store 1       ; B
+0006  load 1        ; B
+0007  write
+0008  halt
+"
+        );
+    }
+}
@@ -0,0 +1,226 @@
+//! Interpreter for `Chunk`s.
+//!
+//! A chunk is verified once before it runs, the way the JVM does it: every
+//! slot and jump target must exist, and every instruction must be reached
+//! with the same stack depth along all paths, with enough operands for it.
+//! The dispatch loop itself then needs no checks besides the ones Rust
+//! inserts.
+
+use super::{Chunk, Op};
+use std::collections::VecDeque;
+use std::io::{BufRead, Write};
+
+// stack depth before `op` executes -> (operands it needs, depth after)
+fn effect(op: Op, depth: usize) -> (usize, usize) {
+    match op {
+        Op::Push(_) | Op::Load(_) | Op::Read => (0, depth + 1),
+        Op::Add | Op::Sub => (2, depth.saturating_sub(1)),
+        Op::Store(_) | Op::Write | Op::Pop | Op::JumpIfZero(_) => (1, depth.saturating_sub(1)),
+        Op::Halt | Op::Jump(_) => (0, depth),
+    }
+}
+
+/// Check that `chunk` is safe to run, returning the deepest the operand
+/// stack can get.
+pub fn verify(chunk: &Chunk) -> Result<usize, String> {
+    let mut depths: Vec<Option<usize>> = vec![None; chunk.code.len()];
+    let mut worklist = vec![(0, 0)];
+    let mut max_depth = 0;
+    while let Some((pc, depth)) = worklist.pop() {
+        let op = *chunk
+            .code
+            .get(pc)
+            .ok_or_else(|| format!("{:04}: execution runs past the end of the code", pc))?;
+        match depths[pc] {
+            Some(known) if known == depth => continue,
+            Some(known) => {
+                return Err(format!(
+                    "{:04}: stack depth is {} or {} depending on the path",
+                    pc, known, depth
+                ))
+            }
+            None => depths[pc] = Some(depth),
+        }
+        let (needed, after) = effect(op, depth);
+        if depth < needed {
+            return Err(format!("{:04}: `{}` needs {} operands", pc, op, needed));
+        }
+        max_depth = max_depth.max(after);
+        match op {
+            Op::Load(slot) | Op::Store(slot) if slot as usize >= chunk.slots.len() => {
+                return Err(format!("{:04}: no slot {}", pc, slot))
+            }
+            Op::Jump(target) | Op::JumpIfZero(target) if target as usize >= chunk.code.len() => {
+                return Err(format!("{:04}: jump to {:04} is out of range", pc, target))
+            }
+            _ => {}
+        }
+        match op {
+            Op::Halt => {}
+            Op::Jump(target) => worklist.push((target as usize, after)),
+            Op::JumpIfZero(target) => {
+                worklist.push((target as usize, after));
+                worklist.push((pc + 1, after));
+            }
+            _ => worklist.push((pc + 1, after)),
+        }
+    }
+    Ok(max_depth)
+}
+
+/// The input of a running program: whitespace separated integers, read a
+/// line at a time.
+pub struct Input<R> {
+    pub reader: R,
+    tokens: VecDeque<String>,
+    // the reader has nothing more
+    ended: bool,
+}
+
+impl<R: BufRead> Input<R> {
+    pub fn new(reader: R) -> Input<R> {
+        Input {
+            reader,
+            tokens: VecDeque::new(),
+            ended: false,
+        }
+    }
+
+    /// Whether the next `read_int` reads a line.
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    pub fn at_end(&self) -> bool {
+        self.ended
+    }
+
+    pub fn read_int(&mut self) -> Result<i32, String> {
+        loop {
+            if let Some(token) = self.tokens.pop_front() {
+                return token
+                    .parse()
+                    .map_err(|_| format!("read: `{}` is not an integer", token));
+            }
+            let mut line = String::new();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => {
+                    self.ended = true;
+                    return Err("read: unexpected end of input".to_string());
+                }
+                Ok(_) => self
+                    .tokens
+                    .extend(line.split_whitespace().map(str::to_string)),
+                Err(e) => return Err(format!("read: {}", e)),
+            }
+        }
+    }
+}
+
+/// Verify and run `chunk` until it halts.
+pub fn run(chunk: &Chunk, input: impl BufRead, mut output: impl Write) -> Result<(), String> {
+    let max_depth = verify(chunk)?;
+    let mut input = Input::new(input);
+    let mut slots = vec![0i32; chunk.slots.len()];
+    let mut stack: Vec<i32> = Vec::with_capacity(max_depth);
+    let mut pc = 0;
+    loop {
+        let op = chunk.code[pc];
+        pc += 1;
+        match op {
+            Op::Halt => return output.flush().map_err(|e| format!("write: {}", e)),
+            Op::Push(imm) => stack.push(imm),
+            Op::Load(slot) => stack.push(slots[slot as usize]),
+            Op::Store(slot) => slots[slot as usize] = stack.pop().unwrap(),
+            Op::Add => {
+                let rhs = stack.pop().unwrap();
+                let lhs = stack.last_mut().unwrap();
+                *lhs = lhs.wrapping_add(rhs);
+            }
+            Op::Sub => {
+                let rhs = stack.pop().unwrap();
+                let lhs = stack.last_mut().unwrap();
+                *lhs = lhs.wrapping_sub(rhs);
+            }
+            Op::Read => stack.push(input.read_int()?),
+            Op::Write => {
+                writeln!(output, "{}", stack.pop().unwrap()).map_err(|e| format!("write: {}", e))?
+            }
+            Op::Pop => {
+                stack.pop();
+            }
+            Op::Jump(target) => pc = target as usize,
+            Op::JumpIfZero(target) => {
+                if stack.pop().unwrap() == 0 {
+                    pc = target as usize;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode;
+    use crate::testing;
+
+    use super::*;
+
+    fn execute(chunk: &Chunk, input: &str) -> Result<String, String> {
+        let mut output = Vec::new();
+        run(chunk, input.as_bytes(), &mut output)?;
+        Ok(String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn handle_test_cases() {
+        for case in testing::test_cases() {
+            let chunk = bytecode::compile(&testing::lower_source(case.source)).unwrap();
+            assert_eq!(
+                execute(&chunk, &case.input_text()),
+                Ok(case.expected_text())
+            );
+        }
+    }
+
+    #[test]
+    fn handle_jumps() {
+        // write N, N - 1, ..., 1
+        let chunk = Chunk {
+            slots: vec!["N".to_string()],
+            code: vec![
+                Op::Read,
+                Op::Store(0),
+                Op::Load(0),
+                Op::JumpIfZero(10),
+                Op::Load(0),
+                Op::Write,
+                Op::Load(0),
+                Op::Push(1),
+                Op::Sub,
+                Op::Jump(1),
+                Op::Halt,
+            ],
+        };
+        assert_eq!(verify(&chunk), Ok(2));
+        assert_eq!(execute(&chunk, "3"), Ok("3\n2\n1\n".to_string()));
+        assert_eq!(
+            execute(&chunk, "x"),
+            Err("read: `x` is not an integer".to_string())
+        );
+    }
+
+    #[test]
+    fn handle_invalid_chunks() {
+        let chunk = |code: Vec<Op>| Chunk {
+            slots: vec!["A".to_string()],
+            code,
+        };
+        assert!(verify(&chunk(vec![Op::Add, Op::Halt])).is_err());
+        assert!(verify(&chunk(vec![Op::Load(1), Op::Halt])).is_err());
+        assert!(verify(&chunk(vec![Op::Push(1)])).is_err());
+        assert!(verify(&chunk(vec![Op::Jump(7)])).is_err());
+        // the loop would grow the stack forever
+        assert!(verify(&chunk(vec![Op::Push(1), Op::Jump(0)])).is_err());
+    }
+}
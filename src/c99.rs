@@ -0,0 +1,255 @@
+//! C99 backend: translates the IR into a standalone C program.
+//!
+//! Program variables keep their names, and temporaries are called `t_N`.
+//! Micro identifiers never contain `_`, so neither temporaries nor the
+//! variables renamed because they clash with C names can collide with them.
+//! Arithmetic goes through `unsigned` so that overflow wraps as it does in
+//! a machine register instead of being undefined.
+
+use crate::ast::BinaryOpKind;
+use crate::backend;
+use crate::ir::{Inst, Program, VReg, Value};
+use std::collections::BTreeSet;
+
+// runtime support, each piece included only when used so the output
+// compiles cleanly with -Wall
+static READ_INT: &str = r#"
+static int read_int(void)
+{
+    int value;
+    if (scanf("%d", &value) != 1) {
+        fprintf(stderr, "read: expected an integer\n");
+        exit(1);
+    }
+    return value;
+}
+"#;
+
+static WRAP_ADD: &str = r#"
+static int wrap_add(int a, int b)
+{
+    return (int)((unsigned)a + (unsigned)b);
+}
+"#;
+
+static WRAP_SUB: &str = r#"
+static int wrap_sub(int a, int b)
+{
+    return (int)((unsigned)a - (unsigned)b);
+}
+"#;
+
+// C keywords, plus names `main` refers to and object-like macros from the
+// included headers, which a local of the same name would break
+static RESERVED: [&str; 52] = [
+    "auto",
+    "break",
+    "case",
+    "char",
+    "const",
+    "continue",
+    "default",
+    "do",
+    "double",
+    "else",
+    "enum",
+    "extern",
+    "float",
+    "for",
+    "goto",
+    "if",
+    "inline",
+    "int",
+    "long",
+    "register",
+    "restrict",
+    "return",
+    "short",
+    "signed",
+    "sizeof",
+    "static",
+    "struct",
+    "switch",
+    "typedef",
+    "union",
+    "unsigned",
+    "void",
+    "volatile",
+    "while",
+    "main",
+    "printf",
+    "BUFSIZ",
+    "EOF",
+    "EXIT_FAILURE",
+    "EXIT_SUCCESS",
+    "FILENAME_MAX",
+    "FOPEN_MAX",
+    "MB_CUR_MAX",
+    "NULL",
+    "RAND_MAX",
+    "SEEK_CUR",
+    "SEEK_END",
+    "SEEK_SET",
+    "TMP_MAX",
+    "stderr",
+    "stdin",
+    "stdout",
+];
+
+fn name(program: &Program, vreg: VReg) -> String {
+    match program.var_name(vreg) {
+        Some(name) if RESERVED.contains(&name) => format!("{}_", name),
+        Some(name) => name.to_string(),
+        None => format!("t_{}", vreg),
+    }
+}
+
+fn value(program: &Program, value: Value) -> String {
+    match value {
+        Value::Reg(vreg) => name(program, vreg),
+        // `-2147483648` would be the negation of a `long` constant
+        Value::Imm(i32::MIN) => "(-2147483647 - 1)".to_string(),
+        Value::Imm(imm) => imm.to_string(),
+    }
+}
+
+pub fn generate(program: &Program) -> Result<String, String> {
+    let mut buf = String::from("#include <stdio.h>\n#include <stdlib.h>\n");
+    let uses = |f: fn(&Inst) -> bool| program.insts.iter().any(f);
+    if uses(|inst| matches!(inst, Inst::Read { .. })) {
+        buf.push_str(READ_INT);
+    }
+    if uses(|inst| {
+        matches!(
+            inst,
+            Inst::Binary {
+                op: BinaryOpKind::Add,
+                ..
+            }
+        )
+    }) {
+        buf.push_str(WRAP_ADD);
+    }
+    if uses(|inst| {
+        matches!(
+            inst,
+            Inst::Binary {
+                op: BinaryOpKind::Sub,
+                ..
+            }
+        )
+    }) {
+        buf.push_str(WRAP_SUB);
+    }
+
+    // only registers still referenced, as optimizations may drop some
+    let referenced: BTreeSet<VReg> = program
+        .insts
+        .iter()
+        .flat_map(|inst| inst.uses().into_iter().chain(inst.def()))
+        .collect();
+    buf.push_str("\nint main(void)\n{\n");
+    for (var, vreg) in program.vars.iter() {
+        if !referenced.contains(vreg) {
+            continue;
+        }
+        let name = name(program, *vreg);
+        if name == *var {
+            buf.push_str(format!("    int {} = 0;\n", name).as_str());
+        } else {
+            buf.push_str(format!("    int {} = 0; /* {} */\n", name, var).as_str());
+        }
+    }
+    let temps: Vec<String> = referenced
+        .iter()
+        .filter(|vreg| program.var_name(**vreg).is_none())
+        .map(|vreg| name(program, *vreg))
+        .collect();
+    if !temps.is_empty() {
+        buf.push_str(format!("    int {};\n", temps.join(", ")).as_str());
+    }
+    buf.push('\n');
+
+    for inst in program.insts.iter() {
+        let statement = match inst {
+            Inst::Call { .. } => return Err(backend::NO_PROCEDURES.to_string()),
+            Inst::Read { dst: Some(dst) } => format!("{} = read_int();", name(program, *dst)),
+            Inst::Read { dst: None } => "read_int();".to_string(),
+            Inst::Write { src } => format!("printf(\"%d\\n\", {});", value(program, *src)),
+            Inst::Copy { dst, src } => {
+                format!("{} = {};", name(program, *dst), value(program, *src))
+            }
+            Inst::Binary { op, dst, lhs, rhs } => {
+                let helper = match op {
+                    BinaryOpKind::Add => "wrap_add",
+                    BinaryOpKind::Sub => "wrap_sub",
+                };
+                format!(
+                    "{} = {}({}, {});",
+                    name(program, *dst),
+                    helper,
+                    value(program, *lhs),
+                    value(program, *rhs)
+                )
+            }
+        };
+        buf.push_str(format!("    {}\n", statement).as_str());
+    }
+    buf.push_str("    return 0;\n}\n");
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+    use std::io::Write;
+    use std::process::{self, Command, Stdio};
+    use std::{env, fs};
+
+    use super::*;
+
+    fn compile(source: &str) -> String {
+        generate(&testing::lower_source(source)).unwrap()
+    }
+
+    #[test]
+    fn handle_reserved_names() {
+        let c = compile("begin read(int); main := int - 1; write(main, 0 - 2147483647 - 1); end");
+        assert!(c.contains("    int int_ = 0; /* int */\n    int main_ = 0; /* main */\n"));
+        assert!(c.contains("    int_ = read_int();\n    main_ = wrap_sub(int_, 1);\n"));
+    }
+
+    #[test]
+    fn handle_native_run() {
+        if Command::new("cc").arg("--version").output().is_err() {
+            return;
+        }
+        let stem = env::temp_dir().join(format!("microc-c-{}", process::id()));
+        let (source, output) = (stem.with_extension("c"), stem.with_extension("out"));
+        for case in testing::test_cases() {
+            fs::write(&source, compile(case.source)).unwrap();
+            let status = Command::new("cc")
+                .args(["-std=c99", "-Wall", "-Werror", "-o"])
+                .args([&output, &source])
+                .status()
+                .unwrap();
+            assert!(status.success());
+            let mut child = Command::new(&output)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(case.input_text().as_bytes())
+                .unwrap();
+            let result = child.wait_with_output().unwrap();
+            let stdout = String::from_utf8(result.stdout).unwrap();
+            assert_eq!(stdout, case.expected_text(), "{}", case.name);
+        }
+        fs::remove_file(source).unwrap();
+        fs::remove_file(output).unwrap();
+    }
+}
@@ -39,7 +39,7 @@ pub fn is_identifier_continue(c: char) -> bool {
 }
 
 pub fn is_digit(c: char) -> bool {
-    matches!(c, '0'..='9')
+    c.is_ascii_digit()
 }
 
 pub fn is_expected(c: char) -> bool {
@@ -1,187 +1,939 @@
-use crate::ast::{ExprAST, ExprKind};
+use crate::ast::BinaryOpKind;
+use crate::backend::{self, Backend};
+use crate::diagnostic::{line_column, Span};
+use crate::ir::{Inst, Program, VReg, Value};
+use crate::mips::{self, Directive, Instr, Reg};
+use crate::opt::{OptLevel, Remark};
+use crate::peephole;
+use crate::regalloc::{self, Location};
 use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::ops::Range;
 
-pub static PRELUDE: &'static str = r#"# Module : main
-    .text
-    .globl read
-read:
-    # call read integer
-    li $v0 5
-    syscall
-    jr $ra
-    .data
-data_section_$$1:
-    .word '\n'
-    .text
-    .globl write
-write:
-    lw $t0, data_section_$$1
-    li $v0, 1
-    syscall
-    move $a0, $t0
-    li $v0, 11
-    syscall
-    jr $ra
-"#;
-
-// main function prologue
-pub fn main_prologue(stackframe_size: u32) -> String {
-    format!(
-        "
-    .text
-    .globl main
-main:
-    # prologue area
-    addi $sp, $sp, -{}
-    sw $ra, 20($sp)
-    sw $fp, 28($sp)
-    move $fp, $sp
-",
-        stackframe_size
-    )
-}
+// bytes reserved at the bottom of the frame for the saved $ra and $fp
+const FRAME_HEADER: u32 = 32;
 
-// main function epilogue
-pub fn main_epilogue(stackframe_size: u32) -> String {
-    format!(
-        "
-    # epilogue area
-    move $sp, $fp
-    lw $fp, 28($sp)
-    lw $ra, 20($sp)
-    addi $sp, $sp, {}
-    li $v0 10
-    syscall
-",
-        stackframe_size
-    )
-}
+// label of the newline character `write` prints
+const NEWLINE: &str = "data_section_$$1";
+
+// label of the message the exception handler prints
+const OVERFLOW: &str = "kdata_section_$$1";
+
+// registers handed out by the allocator. $t0 and $t1 are kept as scratch
+// registers for spilled operands and immediates, and `write` clobbers $t0.
+pub static ALLOCATABLE: [Reg; 16] = [
+    Reg::T2,
+    Reg::T3,
+    Reg::T4,
+    Reg::T5,
+    Reg::T6,
+    Reg::T7,
+    Reg::T8,
+    Reg::T9,
+    Reg::S0,
+    Reg::S1,
+    Reg::S2,
+    Reg::S3,
+    Reg::S4,
+    Reg::S5,
+    Reg::S6,
+    Reg::S7,
+];
 
 #[derive(Debug)]
 pub struct CodeGenerator {
+    pub opt_level: OptLevel,
+    pub expand_pseudo: bool,
+    /// Keep every variable in a stack slot of its own, for the debugger.
+    pub debug: bool,
+    /// Emit `addu` and `subu`, which wrap around on overflow like the other
+    /// targets, instead of `add` and `sub`, which raise an exception.
+    pub wrapping: bool,
+    /// Call Linux instead of SPIM: `read` and `write` go through the o32
+    /// `read` and `write` system calls, and `main` ends with `exit`.
+    pub linux: bool,
+    /// Lower the program as this procedure rather than `main`: it returns
+    /// with `jr $ra`, and the runtime is left to the program.
+    pub procedure: Option<String>,
+    /// The program's source. With it, the code of each statement starts
+    /// with a `# line N: <statement>` comment; SPIM and MARS have no `.loc`.
+    pub source: Option<String>,
     pub frame_size: u32,
-    pub frame_pointer: u32,
-    pub symbol_map: BTreeMap<String, u32>,
-    pub asm: Vec<String>,
+    pub saved_regs: Vec<Reg>,
+    pub symbol_map: BTreeMap<String, Operand>,
+    /// The listing index where the code of each statement starts, and the
+    /// statement. Exact without pseudo-instruction expansion, and after the
+    /// peephole pass only with `source`.
+    pub statements: Vec<(usize, Span)>,
+    /// Where the body of `main` is in the listing.
+    pub body: Range<usize>,
+    pub asm: Vec<Instr>,
+    pub remarks: Vec<Remark>,
+    locations: Vec<Operand>,
+    spans: Vec<Span>,
+    // labels of the globals
+    labels: BTreeMap<VReg, String>,
+    // IR instructions lowered so far
+    lowered: usize,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Operand {
-    MEM(u32),
-    IMM(i32),
+    Reg(Reg),
+    Mem(u32),
+    Imm(i32),
+    /// A global in `.data`, by the register of its variable.
+    Global(VReg),
+}
+
+impl Default for CodeGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CodeGenerator {
     pub fn new() -> CodeGenerator {
         CodeGenerator {
-            frame_size: 32,
-            frame_pointer: 32,
+            opt_level: OptLevel::O0,
+            expand_pseudo: false,
+            debug: false,
+            wrapping: false,
+            linux: false,
+            procedure: None,
+            source: None,
+            frame_size: FRAME_HEADER,
+            saved_regs: Vec::new(),
             symbol_map: BTreeMap::new(),
+            statements: Vec::new(),
+            body: 0..0,
             asm: Vec::new(),
+            remarks: Vec::new(),
+            locations: Vec::new(),
+            spans: Vec::new(),
+            labels: BTreeMap::new(),
+            lowered: 0,
         }
     }
 
-    pub fn generate(&mut self, statements: Vec<Box<ExprAST>>) -> String {
-        let mut buf = String::new();
+    // full listing for the program: main, then the runtime
+    pub fn assemble(&mut self, program: &Program) -> Vec<Instr> {
+        let Ok(listing) = backend::lower(self, program);
+        // statements were recorded by their index in the body of `main`
+        let start = self.prologue().len();
+        for (index, _) in self.statements.iter_mut() {
+            *index += start;
+        }
+        let end = listing.len() - self.epilogue().len() - self.runtime_support().len();
+        self.body = start..end;
+        if self.expand_pseudo {
+            return mips::expand_pseudo(&listing);
+        }
+        listing
+    }
 
-        for expr in statements.into_iter() {
-            self.generate_functions(expr);
+    /// Whether the overflow handler goes with this code: it is `main`'s, for
+    /// SPIM, and `add` and `sub` trap.
+    pub fn handles_overflow(&self) -> bool {
+        self.procedure.is_none() && !self.linux && !self.wrapping
+    }
+
+    /// What the words of the frame hold, by offset from `$fp`, once the
+    /// program has been assembled. Slots are named after the variables
+    /// spilled to them.
+    pub fn frame_layout(&self) -> Vec<(u32, String)> {
+        let mut layout = vec![
+            (20, "return address".to_string()),
+            (28, "caller's $fp".to_string()),
+        ];
+        for (i, reg) in self.saved_regs.iter().enumerate() {
+            layout.push((FRAME_HEADER + 4 * i as u32, format!("saved {}", reg)));
+        }
+        let spill_base = FRAME_HEADER + 4 * self.saved_regs.len() as u32;
+        for offset in (spill_base..self.frame_size).step_by(4) {
+            let names: Vec<&str> = self
+                .symbol_map
+                .iter()
+                .filter(|(_, operand)| **operand == Operand::Mem(offset))
+                .map(|(name, _)| name.as_str())
+                .collect();
+            let slot = match names.is_empty() {
+                true => "temporary".to_string(),
+                false => names.join(", "),
+            };
+            layout.push((offset, slot));
         }
+        layout
+    }
 
-        buf.push_str(main_prologue(self.frame_pointer).as_str());
-        for c in self.asm.iter() {
-            buf.push_str("    ");
-            buf.push_str(c.as_str());
-            buf.push('\n');
+    fn operand(&self, value: Value) -> Operand {
+        match value {
+            Value::Reg(vreg) => self.locations[vreg as usize],
+            Value::Imm(imm) => Operand::Imm(imm),
         }
-        buf.push_str(main_epilogue(self.frame_pointer).as_str());
-        buf.push_str(PRELUDE);
-        buf
     }
 
-    // gen write, read and assignment function
-    pub fn generate_functions(&mut self, expr: Box<ExprAST>) {
-        match expr.kind {
-            ExprKind::SyscallExprAST { calle, args } => match calle {
-                crate::ast::SyscallKind::Read => {
-                    for e in args.into_iter() {
-                        if let Operand::MEM(offset) = self.codegen(Box::new(e)) {
-                            self.asm.push("jal read".to_string());
-                            self.asm.push(format!("sw $v0, {}($fp)", offset));
-                        }
-                    }
+    // bring a value into a register, using `scratch` if it is not in one
+    fn load(&mut self, value: Value, scratch: Reg) -> Reg {
+        match self.operand(value) {
+            Operand::Reg(reg) => reg,
+            Operand::Mem(offset) => {
+                self.asm.push(Instr::Lw {
+                    rt: scratch,
+                    offset: offset as i32,
+                    base: Reg::FP,
+                });
+                scratch
+            }
+            Operand::Imm(imm) => {
+                self.asm.push(Instr::Li { rt: scratch, imm });
+                scratch
+            }
+            Operand::Global(vreg) => {
+                let label = self.labels[&vreg].clone();
+                self.asm.push(Instr::La { rt: scratch, label });
+                self.asm.push(Instr::Lw {
+                    rt: scratch,
+                    offset: 0,
+                    base: scratch,
+                });
+                scratch
+            }
+        }
+    }
+
+    // register an instruction should write `vreg` into
+    fn dest(&self, vreg: VReg) -> Reg {
+        match self.locations[vreg as usize] {
+            Operand::Reg(reg) => reg,
+            _ => Reg::T0,
+        }
+    }
+
+    // write a result from `rt` back to memory, if that is where `vreg` lives;
+    // the address of a global goes through $t1, free once results are made
+    fn store_from(&mut self, vreg: VReg, rt: Reg) {
+        match self.locations[vreg as usize] {
+            Operand::Mem(offset) => self.asm.push(Instr::Sw {
+                rt,
+                offset: offset as i32,
+                base: Reg::FP,
+            }),
+            Operand::Global(vreg) => {
+                let label = self.labels[&vreg].clone();
+                self.asm.push(Instr::La { rt: Reg::T1, label });
+                self.asm.push(Instr::Sw {
+                    rt,
+                    offset: 0,
+                    base: Reg::T1,
+                });
+            }
+            Operand::Reg(_) | Operand::Imm(_) => {}
+        }
+    }
+
+    // write a spilled result from the scratch register back to its slot
+    fn store(&mut self, vreg: VReg) {
+        self.store_from(vreg, Reg::T0);
+    }
+}
+
+impl Backend for CodeGenerator {
+    type Instr = Instr;
+    type Error = Infallible;
+
+    fn file_extension(&self) -> &'static str {
+        "asm"
+    }
+
+    // run the register allocator and lay out the stack frame:
+    // header, then saved callee-saved registers, then spill slots
+    fn assign_locations(&mut self, program: &Program) {
+        // a callee clobbers the $t registers, so values that live across
+        // calls can only be kept in $s registers
+        let calls = program
+            .insts
+            .iter()
+            .any(|inst| matches!(inst, Inst::Call { .. }));
+        let registers = match calls {
+            true => &ALLOCATABLE[8..],
+            false => &ALLOCATABLE[..],
+        };
+        let mut allocation = regalloc::allocate(program, registers.len());
+        // globals live in `.data`, and with `debug` every variable gets a slot
+        let moved: Vec<VReg> = match self.debug {
+            true => program.vars.values().copied().collect(),
+            false => program.globals.iter().copied().collect(),
+        };
+        allocation.locations.retain(|vreg, _| !moved.contains(vreg));
+        self.saved_regs = allocation
+            .used_regs()
+            .into_iter()
+            .map(|r| registers[r])
+            .filter(|r| r.is_callee_saved())
+            .collect();
+        let spill_base = FRAME_HEADER + 4 * self.saved_regs.len() as u32;
+        let mut slots = allocation.spill_slots;
+
+        self.locations = (0..program.vreg_count)
+            .map(|vreg| match allocation.locations.get(&vreg) {
+                Some(Location::Reg(r)) => Operand::Reg(registers[*r]),
+                Some(Location::Spill(slot)) => Operand::Mem(spill_base + 4 * slot),
+                // never referenced, e.g. removed by an optimization
+                None => Operand::Imm(0),
+            })
+            .collect();
+        for vreg in moved {
+            self.locations[vreg as usize] = match program.globals.contains(&vreg) {
+                true => Operand::Global(vreg),
+                false => {
+                    slots += 1;
+                    Operand::Mem(spill_base + 4 * (slots - 1))
+                }
+            };
+        }
+        self.labels = program
+            .vars
+            .iter()
+            .filter(|(_, vreg)| program.globals.contains(vreg))
+            .map(|(name, vreg)| (*vreg, name.clone()))
+            .collect();
+        self.spans = program.spans.clone();
+        self.frame_size = spill_base + 4 * slots;
+        self.symbol_map = program
+            .vars
+            .iter()
+            .map(|(name, vreg)| (name.clone(), self.locations[*vreg as usize]))
+            .collect();
+    }
+
+    // gen write, read, copy and arithmetic instructions
+    fn lower_instruction(&mut self, inst: &Inst) -> Result<(), Infallible> {
+        if let Some(span) = self.spans.get(self.lowered).copied() {
+            if self.statements.last().map(|(_, last)| *last) != Some(span) {
+                self.statements.push((self.asm.len(), span));
+                if let Some(source) = &self.source {
+                    let text = source[span.start..span.end].split_whitespace();
+                    let line = line_column(source, span.start).0;
+                    let comment = format!("line {}: {}", line, text.collect::<Vec<_>>().join(" "));
+                    self.asm.push(Instr::Comment(comment));
                 }
-                crate::ast::SyscallKind::Write => {
-                    for e in args.into_iter() {
-                        match self.codegen(Box::new(e)) {
-                            Operand::MEM(offset) => {
-                                self.asm.push(format!("lw $a0, {}($fp)", offset))
-                            }
-                            Operand::IMM(imm) => self.asm.push(format!("li $a0, {}", imm)),
-                        }
-                        self.asm.push("jal write".to_string());
+            }
+        }
+        self.lowered += 1;
+        match inst {
+            Inst::Read { dst } => {
+                self.asm.push(Instr::Jal {
+                    target: "read".to_string(),
+                });
+                if let Some(dst) = *dst {
+                    match self.locations[dst as usize] {
+                        Operand::Reg(rd) => self.asm.push(Instr::Move { rd, rs: Reg::V0 }),
+                        _ => self.store_from(dst, Reg::V0),
                     }
                 }
-            },
-            ExprKind::AssignmentAST { var, assign } => {
-                let mut left_side = 0;
-                if let Operand::MEM(offset) = self.codegen(var) {
-                    left_side = offset;
+            }
+            Inst::Write { src } => {
+                match self.operand(*src) {
+                    Operand::Reg(rs) => self.asm.push(Instr::Move { rd: Reg::A0, rs }),
+                    Operand::Mem(offset) => self.asm.push(Instr::Lw {
+                        rt: Reg::A0,
+                        offset: offset as i32,
+                        base: Reg::FP,
+                    }),
+                    Operand::Imm(imm) => self.asm.push(Instr::Li { rt: Reg::A0, imm }),
+                    Operand::Global(_) => {
+                        self.load(*src, Reg::A0);
+                    }
                 }
-                match self.codegen(assign) {
-                    Operand::MEM(offset) => self.asm.push(format!("lw $t0, {}($fp)", offset)),
-                    Operand::IMM(imm) => self.asm.push(format!("li $t0, {}", imm)),
+                self.asm.push(Instr::Jal {
+                    target: "write".to_string(),
+                });
+            }
+            Inst::Copy { dst, src } => {
+                let rd = self.dest(*dst);
+                match self.operand(*src) {
+                    Operand::Reg(rs) => self.asm.push(Instr::Move { rd, rs }),
+                    Operand::Mem(offset) => self.asm.push(Instr::Lw {
+                        rt: rd,
+                        offset: offset as i32,
+                        base: Reg::FP,
+                    }),
+                    Operand::Imm(imm) => self.asm.push(Instr::Li { rt: rd, imm }),
+                    Operand::Global(_) => {
+                        self.load(*src, rd);
+                    }
                 }
-                self.asm.push(format!("sw $t0, {}($fp)", left_side));
+                self.store(*dst);
             }
-            _ => panic!(),
+            Inst::Binary { op, dst, lhs, rhs } => {
+                let rs = self.load(*lhs, Reg::T0);
+                let rt = self.load(*rhs, Reg::T1);
+                let rd = self.dest(*dst);
+                self.asm.push(match (op, self.wrapping) {
+                    (BinaryOpKind::Add, false) => Instr::Add { rd, rs, rt },
+                    (BinaryOpKind::Sub, false) => Instr::Sub { rd, rs, rt },
+                    (BinaryOpKind::Add, true) => Instr::Addu { rd, rs, rt },
+                    (BinaryOpKind::Sub, true) => Instr::Subu { rd, rs, rt },
+                });
+                self.store(*dst);
+            }
+            Inst::Call { target } => self.asm.push(Instr::Jal {
+                target: target.clone(),
+            }),
         }
+        Ok(())
     }
 
-    // recursive parse expression AST
-    pub fn codegen(&mut self, expr: Box<ExprAST>) -> Operand {
-        match expr.kind {
-            ExprKind::VariableExprAST { name } => Operand::MEM(
-                self.symbol_map
-                    .entry(name.to_string())
-                    .or_insert_with(|| {
-                        self.frame_pointer += 4;
-                        self.frame_pointer - 4
-                    })
-                    .clone(),
-            ),
-            ExprKind::IntLiteralExprAST { value } => Operand::IMM(value.clone()),
-            ExprKind::BinaryExprAST { op, lhs, rhs } => {
-                let left_hand_side = self.codegen(lhs);
-                let right_hand_side = self.codegen(rhs);
-
-                match left_hand_side {
-                    Operand::MEM(offset) => self.asm.push(format!("lw $t0, {}($fp)", offset)),
-                    Operand::IMM(imm) => self.asm.push(format!("li $t0, {}", imm)),
-                }
-                match right_hand_side {
-                    Operand::MEM(offset) => self.asm.push(format!("lw $t1, {}($fp)", offset)),
-                    Operand::IMM(imm) => self.asm.push(format!("li $t1, {}", imm)),
+    fn finish(&mut self) -> Vec<Instr> {
+        if self.opt_level >= OptLevel::O1 {
+            peephole::run(&mut self.asm, &mut self.remarks);
+            if self.source.is_some() {
+                // code moved: statements start at their `line N` comments,
+                // one per statement and in the same order
+                let comments = (0..self.asm.len()).filter(
+                    |i| matches!(&self.asm[*i], Instr::Comment(text) if text.starts_with("line ")),
+                );
+                for ((index, _), comment) in self.statements.iter_mut().zip(comments) {
+                    *index = comment;
                 }
+            }
+        }
+        self.asm.clone()
+    }
 
-                match op {
-                    crate::ast::BinaryOpKind::Add => self.asm.push("add $t0, $t0, $t1".to_string()),
-                    crate::ast::BinaryOpKind::Sub => self.asm.push("sub $t0, $t0, $t1".to_string()),
-                }
+    fn prologue(&self) -> Vec<Instr> {
+        let name = self.procedure.as_deref().unwrap_or("main");
+        let mut asm = vec![
+            Instr::Directive(Directive::Text),
+            Instr::Directive(Directive::Globl(name.to_string())),
+            Instr::Label(name.to_string()),
+            Instr::Comment("prologue area".to_string()),
+            Instr::Addi {
+                rt: Reg::SP,
+                rs: Reg::SP,
+                imm: -(self.frame_size as i32),
+            },
+            Instr::Sw {
+                rt: Reg::RA,
+                offset: 20,
+                base: Reg::SP,
+            },
+            Instr::Sw {
+                rt: Reg::FP,
+                offset: 28,
+                base: Reg::SP,
+            },
+            Instr::Move {
+                rd: Reg::FP,
+                rs: Reg::SP,
+            },
+        ];
+        for (i, reg) in self.saved_regs.iter().enumerate() {
+            asm.push(Instr::Sw {
+                rt: *reg,
+                offset: (FRAME_HEADER + 4 * i as u32) as i32,
+                base: Reg::FP,
+            });
+        }
+        asm
+    }
 
-                self.asm
-                    .push(format!("sw $t0, {}($fp)", self.frame_pointer));
-                self.frame_pointer += 4;
-                Operand::MEM(self.frame_pointer - 4)
-            }
-            _ => panic!(),
+    fn epilogue(&self) -> Vec<Instr> {
+        let mut asm = vec![Instr::Comment("epilogue area".to_string())];
+        for (i, reg) in self.saved_regs.iter().enumerate() {
+            asm.push(Instr::Lw {
+                rt: *reg,
+                offset: (FRAME_HEADER + 4 * i as u32) as i32,
+                base: Reg::FP,
+            });
+        }
+        asm.extend([
+            Instr::Move {
+                rd: Reg::SP,
+                rs: Reg::FP,
+            },
+            Instr::Lw {
+                rt: Reg::FP,
+                offset: 28,
+                base: Reg::SP,
+            },
+            Instr::Lw {
+                rt: Reg::RA,
+                offset: 20,
+                base: Reg::SP,
+            },
+            Instr::Addi {
+                rt: Reg::SP,
+                rs: Reg::SP,
+                imm: self.frame_size as i32,
+            },
+        ]);
+        match self.procedure {
+            Some(_) => asm.push(Instr::Jr { rs: Reg::RA }),
+            None if self.linux => asm.extend([
+                Instr::Li {
+                    rt: Reg::A0,
+                    imm: 0,
+                },
+                Instr::Li {
+                    rt: Reg::V0,
+                    imm: SYS_EXIT,
+                },
+                Instr::Syscall,
+            ]),
+            None => asm.extend([
+                Instr::Li {
+                    rt: Reg::V0,
+                    imm: 10,
+                },
+                Instr::Syscall,
+            ]),
+        }
+        asm
+    }
+
+    // runtime support: `read` returns an integer in $v0, `write` prints $a0
+    // followed by a newline. Unless arithmetic wraps, an exception handler
+    // stops the program on overflow, which SPIM would report and go on from.
+    fn runtime_support(&self) -> Vec<Instr> {
+        if self.procedure.is_some() {
+            return Vec::new();
+        }
+        if self.linux {
+            return linux_runtime();
+        }
+        let globl = |name: &str| Instr::Directive(Directive::Globl(name.to_string()));
+        let mut asm = vec![
+            Instr::Comment("Module : main".to_string()),
+            Instr::Directive(Directive::Text),
+            globl("read"),
+            Instr::Label("read".to_string()),
+            Instr::Comment("call read integer".to_string()),
+            Instr::Li {
+                rt: Reg::V0,
+                imm: 5,
+            },
+            Instr::Syscall,
+            Instr::Jr { rs: Reg::RA },
+            Instr::Directive(Directive::Data),
+            Instr::Label(NEWLINE.to_string()),
+            Instr::Directive(Directive::WordChar('\n')),
+            Instr::Directive(Directive::Text),
+            globl("write"),
+            Instr::Label("write".to_string()),
+            Instr::LwLabel {
+                rt: Reg::T0,
+                label: NEWLINE.to_string(),
+            },
+            Instr::Li {
+                rt: Reg::V0,
+                imm: 1,
+            },
+            Instr::Syscall,
+            Instr::Move {
+                rd: Reg::A0,
+                rs: Reg::T0,
+            },
+            Instr::Li {
+                rt: Reg::V0,
+                imm: 11,
+            },
+            Instr::Syscall,
+            Instr::Jr { rs: Reg::RA },
+        ];
+        if self.handles_overflow() && traps(&self.asm) {
+            asm.extend(overflow_handler());
+        }
+        asm
+    }
+
+    fn print(&self, listing: &[Instr]) -> String {
+        mips::print(listing)
+    }
+}
+
+/// Whether `listing` has an `add` or `sub`, which raise an exception on
+/// overflow.
+pub fn traps(listing: &[Instr]) -> bool {
+    listing
+        .iter()
+        .any(|instr| matches!(instr, Instr::Add { .. } | Instr::Sub { .. }))
+}
+
+/// The kernel's exception handler. The only exception the program raises
+/// is arithmetic overflow, after which it prints a message and exits.
+pub fn overflow_handler() -> Vec<Instr> {
+    vec![
+        Instr::Directive(Directive::Kdata),
+        Instr::Label(OVERFLOW.to_string()),
+        Instr::Directive(Directive::Asciiz(
+            "\nmicroc: arithmetic overflow\n".to_string(),
+        )),
+        Instr::Directive(Directive::Ktext),
+        Instr::La {
+            rt: Reg::A0,
+            label: OVERFLOW.to_string(),
+        },
+        Instr::Li {
+            rt: Reg::V0,
+            imm: 4,
+        },
+        Instr::Syscall,
+        Instr::Li {
+            rt: Reg::V0,
+            imm: 10,
+        },
+        Instr::Syscall,
+    ]
+}
+
+// Linux o32 system call numbers
+const SYS_EXIT: i32 = 4001;
+const SYS_READ: i32 = 4003;
+const SYS_WRITE: i32 = 4004;
+
+// label of the 12 bytes `read` and `write` pass to the kernel, enough for
+// "-2147483648\n"
+const IO_BUFFER: &str = "data_section_$$2";
+// label of the message `read` prints before it exits, one `.word` per char
+const BAD_INPUT: &str = "data_section_$$3";
+
+// registers the kernel may change across a system call, which the code
+// calling `read` and `write` expects to keep
+const SYSCALL_CLOBBERED: [Reg; 8] = [
+    Reg::T2,
+    Reg::T3,
+    Reg::T4,
+    Reg::T5,
+    Reg::T6,
+    Reg::T7,
+    Reg::T8,
+    Reg::T9,
+];
+
+// `read` and `write` on top of the Linux system calls. Both save $ra, the
+// registers in `SYSCALL_CLOBBERED` and $s0 to $s2, which they use for
+// their own values. `read` skips blanks and takes an optional `-` and
+// digits; anything else, or the end of the input, prints a message to
+// stderr and exits with status 1.
+fn linux_runtime() -> Vec<Instr> {
+    let label = |name: &str| Instr::Label(name.to_string());
+    let li = |rt: Reg, imm: i32| Instr::Li { rt, imm };
+    let la = |rt: Reg, label: &str| Instr::La {
+        rt,
+        label: label.to_string(),
+    };
+    let beq = |rs: Reg, rt: Reg, target: &str| Instr::Beq {
+        rs,
+        rt,
+        target: target.to_string(),
+    };
+    let bne = |rs: Reg, rt: Reg, target: &str| Instr::Bne {
+        rs,
+        rt,
+        target: target.to_string(),
+    };
+    let jal = |target: &str| Instr::Jal {
+        target: target.to_string(),
+    };
+    let saved: Vec<Reg> = [Reg::RA]
+        .into_iter()
+        .chain(SYSCALL_CLOBBERED)
+        .chain([Reg::S0, Reg::S1, Reg::S2])
+        .collect();
+    let frame = 4 * saved.len() as i32;
+    let save = || {
+        let mut asm = vec![Instr::Addiu {
+            rt: Reg::SP,
+            rs: Reg::SP,
+            imm: -frame,
+        }];
+        for (i, reg) in saved.iter().enumerate() {
+            asm.push(Instr::Sw {
+                rt: *reg,
+                offset: 4 * i as i32,
+                base: Reg::SP,
+            });
+        }
+        asm
+    };
+    let restore = || {
+        let mut asm = Vec::new();
+        for (i, reg) in saved.iter().enumerate() {
+            asm.push(Instr::Lw {
+                rt: *reg,
+                offset: 4 * i as i32,
+                base: Reg::SP,
+            });
         }
+        asm.extend([
+            Instr::Addiu {
+                rt: Reg::SP,
+                rs: Reg::SP,
+                imm: frame,
+            },
+            Instr::Jr { rs: Reg::RA },
+        ]);
+        asm
+    };
+    let globl = |name: &str| Instr::Directive(Directive::Globl(name.to_string()));
+
+    let mut asm = vec![
+        Instr::Comment("Module : main".to_string()),
+        Instr::Directive(Directive::Data),
+        label(IO_BUFFER),
+        Instr::Directive(Directive::Word(0)),
+        Instr::Directive(Directive::Word(0)),
+        Instr::Directive(Directive::Word(0)),
+        label(BAD_INPUT),
+    ];
+    for c in "microc: expected an integer on the input\n".chars() {
+        asm.push(Instr::Directive(Directive::WordChar(c)));
     }
+    asm.extend([
+        Instr::Directive(Directive::Word(0)),
+        Instr::Directive(Directive::Text),
+        globl("read"),
+        label("read"),
+        Instr::Comment("call read integer".to_string()),
+    ]);
+    asm.extend(save());
+    asm.extend([
+        // $s0 is the value, $s1 whether it is negative, $s2 whether a
+        // digit was read
+        li(Reg::S0, 0),
+        li(Reg::S1, 0),
+        li(Reg::S2, 0),
+        label("read_blank"),
+        jal("read_char"),
+        li(Reg::T0, -1),
+        beq(Reg::V0, Reg::T0, "read_error"),
+        li(Reg::T0, ' ' as i32 + 1),
+        Instr::Slt {
+            rd: Reg::T0,
+            rs: Reg::V0,
+            rt: Reg::T0,
+        },
+        bne(Reg::T0, Reg::ZERO, "read_blank"),
+        li(Reg::T0, '-' as i32),
+        bne(Reg::V0, Reg::T0, "read_digit"),
+        li(Reg::S1, 1),
+        jal("read_char"),
+        label("read_digit"),
+        Instr::Addiu {
+            rt: Reg::T0,
+            rs: Reg::V0,
+            imm: -('0' as i32),
+        },
+        Instr::Slt {
+            rd: Reg::T1,
+            rs: Reg::T0,
+            rt: Reg::ZERO,
+        },
+        bne(Reg::T1, Reg::ZERO, "read_end"),
+        li(Reg::T1, 10),
+        Instr::Slt {
+            rd: Reg::T1,
+            rs: Reg::T0,
+            rt: Reg::T1,
+        },
+        beq(Reg::T1, Reg::ZERO, "read_end"),
+        // $s0 = 10 * $s0 + digit, as 2 * $s0 + 8 * $s0
+        Instr::Addu {
+            rd: Reg::T1,
+            rs: Reg::S0,
+            rt: Reg::S0,
+        },
+        Instr::Addu {
+            rd: Reg::S0,
+            rs: Reg::T1,
+            rt: Reg::T1,
+        },
+        Instr::Addu {
+            rd: Reg::S0,
+            rs: Reg::S0,
+            rt: Reg::S0,
+        },
+        Instr::Addu {
+            rd: Reg::S0,
+            rs: Reg::S0,
+            rt: Reg::T1,
+        },
+        Instr::Addu {
+            rd: Reg::S0,
+            rs: Reg::S0,
+            rt: Reg::T0,
+        },
+        li(Reg::S2, 1),
+        jal("read_char"),
+        beq(Reg::ZERO, Reg::ZERO, "read_digit"),
+        label("read_end"),
+        beq(Reg::S2, Reg::ZERO, "read_error"),
+        beq(Reg::S1, Reg::ZERO, "read_done"),
+        Instr::Subu {
+            rd: Reg::S0,
+            rs: Reg::ZERO,
+            rt: Reg::S0,
+        },
+        label("read_done"),
+        Instr::Move {
+            rd: Reg::V0,
+            rs: Reg::S0,
+        },
+    ]);
+    asm.extend(restore());
+    asm.extend([
+        // the message goes out a char at a time, from $s0
+        label("read_error"),
+        la(Reg::S0, BAD_INPUT),
+        label("read_error_char"),
+        Instr::Lw {
+            rt: Reg::T0,
+            offset: 0,
+            base: Reg::S0,
+        },
+        beq(Reg::T0, Reg::ZERO, "read_exit"),
+        la(Reg::A1, IO_BUFFER),
+        Instr::Sb {
+            rt: Reg::T0,
+            offset: 0,
+            base: Reg::A1,
+        },
+        li(Reg::A0, 2),
+        li(Reg::A2, 1),
+        li(Reg::V0, SYS_WRITE),
+        Instr::Syscall,
+        Instr::Addiu {
+            rt: Reg::S0,
+            rs: Reg::S0,
+            imm: 4,
+        },
+        beq(Reg::ZERO, Reg::ZERO, "read_error_char"),
+        label("read_exit"),
+        li(Reg::A0, 1),
+        li(Reg::V0, SYS_EXIT),
+        Instr::Syscall,
+        // the next byte of stdin in $v0, or -1 at its end or on an error
+        label("read_char"),
+        li(Reg::A0, 0),
+        la(Reg::A1, IO_BUFFER),
+        li(Reg::A2, 1),
+        li(Reg::V0, SYS_READ),
+        Instr::Syscall,
+        bne(Reg::A3, Reg::ZERO, "read_eof"),
+        beq(Reg::V0, Reg::ZERO, "read_eof"),
+        la(Reg::A1, IO_BUFFER),
+        Instr::Lbu {
+            rt: Reg::V0,
+            offset: 0,
+            base: Reg::A1,
+        },
+        Instr::Jr { rs: Reg::RA },
+        label("read_eof"),
+        li(Reg::V0, -1),
+        Instr::Jr { rs: Reg::RA },
+        globl("write"),
+        label("write"),
+    ]);
+    asm.extend(save());
+    asm.extend([
+        // digits go into the buffer from its end, lowest first: $s0 is the
+        // value without its sign, $s1 the next free byte
+        Instr::Move {
+            rd: Reg::S0,
+            rs: Reg::A0,
+        },
+        Instr::Slt {
+            rd: Reg::S2,
+            rs: Reg::A0,
+            rt: Reg::ZERO,
+        },
+        beq(Reg::S2, Reg::ZERO, "write_digits"),
+        Instr::Subu {
+            rd: Reg::S0,
+            rs: Reg::ZERO,
+            rt: Reg::A0,
+        },
+        label("write_digits"),
+        la(Reg::S1, IO_BUFFER),
+        Instr::Addiu {
+            rt: Reg::S1,
+            rs: Reg::S1,
+            imm: 11,
+        },
+        li(Reg::T0, '\n' as i32),
+        Instr::Sb {
+            rt: Reg::T0,
+            offset: 0,
+            base: Reg::S1,
+        },
+        li(Reg::T1, 10),
+        label("write_digit"),
+        Instr::Divu {
+            rs: Reg::S0,
+            rt: Reg::T1,
+        },
+        Instr::Mfhi { rd: Reg::T0 },
+        Instr::Mflo { rd: Reg::S0 },
+        Instr::Addiu {
+            rt: Reg::T0,
+            rs: Reg::T0,
+            imm: '0' as i32,
+        },
+        Instr::Addiu {
+            rt: Reg::S1,
+            rs: Reg::S1,
+            imm: -1,
+        },
+        Instr::Sb {
+            rt: Reg::T0,
+            offset: 0,
+            base: Reg::S1,
+        },
+        bne(Reg::S0, Reg::ZERO, "write_digit"),
+        beq(Reg::S2, Reg::ZERO, "write_out"),
+        li(Reg::T0, '-' as i32),
+        Instr::Addiu {
+            rt: Reg::S1,
+            rs: Reg::S1,
+            imm: -1,
+        },
+        Instr::Sb {
+            rt: Reg::T0,
+            offset: 0,
+            base: Reg::S1,
+        },
+        label("write_out"),
+        li(Reg::A0, 1),
+        Instr::Move {
+            rd: Reg::A1,
+            rs: Reg::S1,
+        },
+        la(Reg::A2, IO_BUFFER),
+        Instr::Addiu {
+            rt: Reg::A2,
+            rs: Reg::A2,
+            imm: 12,
+        },
+        Instr::Subu {
+            rd: Reg::A2,
+            rs: Reg::A2,
+            rt: Reg::S1,
+        },
+        li(Reg::V0, SYS_WRITE),
+        Instr::Syscall,
+    ]);
+    asm.extend(restore());
+    asm
 }
 
 #[cfg(test)]
 mod tests {
     use crate::ast::ASTBuilder;
+    use crate::ir;
     use crate::lexer::Lexer;
+    use crate::testing;
 
     use super::*;
 
@@ -249,8 +1001,59 @@ mod tests {
         );
         let iter = lexer.tokenize();
         let mut builder = ASTBuilder::new(Box::new(iter));
+        let program = ir::lower(builder.parse());
         let mut cg = CodeGenerator::new();
-        let asm = cg.generate(builder.parse());
-        println!("{}", asm);
+        let listing = cg.assemble(&program);
+        assert!(mips::validate(&listing).is_empty());
+        println!("{}", mips::print(&listing));
+        // every `A_i` stays live until the final write, so some must spill
+        assert!(cg.frame_size > FRAME_HEADER + 4 * cg.saved_regs.len() as u32);
+        assert_eq!(cg.saved_regs.len(), 8);
+    }
+
+    #[test]
+    fn handle_line_comments() {
+        let source = "begin\n  read(A);\n  B := A +\n    1; write(B - 2);\nend";
+        let assemble = |annotate: bool| {
+            let mut program = testing::lower_source(source);
+            crate::opt::optimize(&mut program, OptLevel::O2);
+            let mut cg = CodeGenerator::new();
+            cg.opt_level = OptLevel::O2;
+            cg.source = annotate.then(|| source.to_string());
+            cg.assemble(&program)
+        };
+        let (annotated, plain): (Vec<Instr>, Vec<Instr>) = assemble(true)
+            .into_iter()
+            .partition(|instr| matches!(instr, Instr::Comment(text) if text.starts_with("line ")));
+        assert_eq!(
+            annotated,
+            [
+                "line 2: read(A)",
+                "line 3: B := A + 1",
+                "line 4: write(B - 2)"
+            ]
+            .map(|text| Instr::Comment(text.to_string()))
+        );
+        // the peephole pass sees through the comments
+        assert_eq!(plain, assemble(false));
+    }
+
+    #[test]
+    fn handle_overflow_handler() {
+        let assemble = |source: &str, wrapping: bool| {
+            let mut cg = CodeGenerator::new();
+            cg.wrapping = wrapping;
+            mips::print(&cg.assemble(&testing::lower_source(source)))
+        };
+        let source = "begin read(A); write(A + 1); end";
+        let trapping = assemble(source, false);
+        let handler = trapping.find("    .ktext 0x80000180\n").unwrap();
+        assert!(trapping[handler..].contains("    la $a0, kdata_section_$$1\n    li $v0, 4\n"));
+        let program = testing::lower_source(source);
+        let expanded = mips::expand_pseudo(&CodeGenerator::new().assemble(&program));
+        assert!(mips::print(&expanded).contains("    lui $at, 0x9000\n    ori $a0, $at, 0x0\n"));
+        assert!(!assemble(source, true).contains(".ktext"));
+        // nothing to handle without an `add` or `sub`
+        assert!(!assemble("begin read(A); write(A, 1); end", false).contains(".ktext"));
     }
 }
@@ -0,0 +1,411 @@
+//! Lossless concrete syntax tree.
+//!
+//! Unlike the AST, the tree keeps every token of the source, with its
+//! whitespace and comments attached as trivia, so printing a node gives back
+//! the exact input. Parsing never fails: tokens that do not fit the grammar end
+//! up in `Error` nodes, and a missing expression is an empty `Error` node.
+//! The parser only builds the tree; `ASTBuilder` reports the errors.
+
+use crate::lexer::{Lexer, SyntaxToken, TokenType};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    /// The whole file: its declarations, then for a program `begin`, the
+    /// statements and `end`, and last the `ScanEof` token.
+    Program,
+    /// `module M ;`
+    Module,
+    /// `import M ;`
+    Import,
+    /// `var X, ... ;`
+    Var,
+    /// `procedure P ; begin`, the statements, `end ;`
+    Procedure,
+    /// `P ( ) ;`
+    Call,
+    /// `X := <expr> ;`
+    Assignment,
+    /// `read ( X, ... ) ;`
+    Read,
+    /// `write ( <expr>, ... ) ;`
+    Write,
+    /// `<expr> + <primary>` or `<expr> - <primary>`
+    Binary,
+    /// A leading `+`.
+    Unary,
+    Paren,
+    /// An integer, with its `-` if it has one.
+    Literal,
+    Name,
+    Error,
+}
+
+#[derive(Debug, Clone)]
+pub enum Element {
+    Node(Node),
+    Token(SyntaxToken),
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub kind: NodeKind,
+    pub children: Vec<Element>,
+}
+
+impl Element {
+    /// The tokens in source order.
+    pub fn tokens(&self) -> Vec<&SyntaxToken> {
+        match self {
+            Element::Node(node) => node.tokens(),
+            Element::Token(token) => vec![token],
+        }
+    }
+}
+
+impl Node {
+    fn new(kind: NodeKind) -> Node {
+        Node {
+            kind,
+            children: Vec::new(),
+        }
+    }
+
+    /// The tokens in source order.
+    pub fn tokens(&self) -> Vec<&SyntaxToken> {
+        self.children.iter().flat_map(Element::tokens).collect()
+    }
+
+    /// One line per node and token, with the trivia of each token, for
+    /// `--emit=cst`.
+    pub fn dump(&self) -> String {
+        let mut out = String::new();
+        self.dump_into(0, &mut out);
+        out
+    }
+
+    fn dump_into(&self, depth: usize, out: &mut String) {
+        out.push_str(&format!("{}{:?}\n", "  ".repeat(depth), self.kind));
+        for child in self.children.iter() {
+            let token = match child {
+                Element::Node(node) => {
+                    node.dump_into(depth + 1, out);
+                    continue;
+                }
+                Element::Token(token) => token,
+            };
+            out.push_str(&format!("{}{:?}", "  ".repeat(depth + 1), &*token.text));
+            for (side, trivia) in [("leading", &token.leading), ("trailing", &token.trailing)] {
+                if !trivia.is_empty() {
+                    let text: String = trivia.iter().map(|t| &*t.text).collect();
+                    out.push_str(&format!(" {} {:?}", side, text));
+                }
+            }
+            out.push('\n');
+        }
+    }
+}
+
+/// The source the node was parsed from, trivia included.
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for token in self.tokens() {
+            write!(f, "{}", token)?;
+        }
+        Ok(())
+    }
+}
+
+struct Parser {
+    // in reverse, so the current token is the last one
+    tokens: Vec<SyntaxToken>,
+}
+
+impl Parser {
+    fn current(&self) -> &TokenType {
+        &self.tokens.last().unwrap().token.token_type
+    }
+
+    fn at(&self, token_type: TokenType) -> bool {
+        *self.current() == token_type
+    }
+
+    // move the current token into `node`; `ScanEof` stays for the program
+    fn bump(&mut self, node: &mut Node) {
+        if !self.at(TokenType::ScanEof) {
+            node.children
+                .push(Element::Token(self.tokens.pop().unwrap()));
+        }
+    }
+
+    fn eat(&mut self, node: &mut Node, token_type: TokenType) {
+        if self.at(token_type) {
+            self.bump(node);
+        }
+    }
+
+    fn eat_name(&mut self, node: &mut Node) {
+        if matches!(self.current(), TokenType::Identifier { name: _ }) {
+            self.bump(node);
+        }
+    }
+
+    fn at_statement(&self) -> bool {
+        matches!(
+            self.current(),
+            TokenType::Identifier { .. } | TokenType::Read | TokenType::Write
+        )
+    }
+
+    // <expression> -> [OpPlus] <primary> {<add op> <primary>}
+    fn expression(&mut self) -> Node {
+        let mut lhs = match self.current() {
+            TokenType::OpPlus => {
+                let mut unary = Node::new(NodeKind::Unary);
+                self.bump(&mut unary);
+                unary.children.push(Element::Node(self.primary()));
+                unary
+            }
+            _ => self.primary(),
+        };
+        while matches!(self.current(), TokenType::OpPlus | TokenType::OpMinus) {
+            let mut binary = Node::new(NodeKind::Binary);
+            binary.children.push(Element::Node(lhs));
+            self.bump(&mut binary);
+            binary.children.push(Element::Node(self.primary()));
+            lhs = binary;
+        }
+        lhs
+    }
+
+    fn primary(&mut self) -> Node {
+        let kind = match self.current() {
+            TokenType::Identifier { name: _ } => NodeKind::Name,
+            TokenType::IntLiteral { value: _ } | TokenType::OpMinus => NodeKind::Literal,
+            TokenType::LeftParen => {
+                let mut paren = Node::new(NodeKind::Paren);
+                self.bump(&mut paren);
+                paren.children.push(Element::Node(self.expression()));
+                self.eat(&mut paren, TokenType::RightParen);
+                return paren;
+            }
+            // missing
+            _ => return Node::new(NodeKind::Error),
+        };
+        let minus = self.at(TokenType::OpMinus);
+        let mut node = Node::new(kind);
+        self.bump(&mut node);
+        if minus && matches!(self.current(), TokenType::IntLiteral { value: _ }) {
+            self.bump(&mut node);
+        }
+        node
+    }
+
+    fn statement(&mut self) -> Node {
+        let mut node = match self.current() {
+            TokenType::Identifier { name: _ } => {
+                let name = Element::Node(self.primary());
+                if self.at(TokenType::LeftParen) {
+                    let mut node = Node::new(NodeKind::Call);
+                    node.children.push(name);
+                    self.bump(&mut node);
+                    self.eat(&mut node, TokenType::RightParen);
+                    node
+                } else {
+                    let mut node = Node::new(NodeKind::Assignment);
+                    node.children.push(name);
+                    self.eat(&mut node, TokenType::OpAssign);
+                    node.children.push(Element::Node(self.expression()));
+                    node
+                }
+            }
+            TokenType::Read | TokenType::Write => {
+                let kind = match self.current() {
+                    TokenType::Read => NodeKind::Read,
+                    _ => NodeKind::Write,
+                };
+                let mut node = Node::new(kind);
+                self.bump(&mut node);
+                self.eat(&mut node, TokenType::LeftParen);
+                if !self.at(TokenType::RightParen) {
+                    loop {
+                        node.children.push(Element::Node(self.expression()));
+                        if !self.at(TokenType::Comma) {
+                            break;
+                        }
+                        self.bump(&mut node);
+                    }
+                }
+                self.eat(&mut node, TokenType::RightParen);
+                node
+            }
+            _ => {
+                // not a statement: skip it, `;` included
+                let mut node = Node::new(NodeKind::Error);
+                self.skip(&mut node);
+                self.eat(&mut node, TokenType::Semicolon);
+                return node;
+            }
+        };
+        if !self.at(TokenType::Semicolon) && !self.at_statement() {
+            let mut error = Node::new(NodeKind::Error);
+            self.skip(&mut error);
+            if !error.children.is_empty() {
+                node.children.push(Element::Node(error));
+            }
+        }
+        self.eat(&mut node, TokenType::Semicolon);
+        node
+    }
+
+    // the rest of a broken statement, up to its `;`
+    fn skip(&mut self, error: &mut Node) {
+        while !matches!(
+            self.current(),
+            TokenType::Semicolon | TokenType::End | TokenType::ScanEof
+        ) {
+            self.bump(error);
+        }
+    }
+
+    // begin {<statement> Semicolon} end, into `node`
+    fn body(&mut self, node: &mut Node) {
+        self.eat(node, TokenType::Begin);
+        while !matches!(self.current(), TokenType::End | TokenType::ScanEof) {
+            node.children.push(Element::Node(self.statement()));
+        }
+        self.eat(node, TokenType::End);
+    }
+
+    // `module`, `import`, `var` or `procedure` with the rest of its line
+    fn declaration(&mut self) -> Node {
+        let kind = match self.current() {
+            TokenType::Module => NodeKind::Module,
+            TokenType::Import => NodeKind::Import,
+            TokenType::Var => NodeKind::Var,
+            _ => NodeKind::Procedure,
+        };
+        let mut node = Node::new(kind);
+        self.bump(&mut node);
+        self.eat_name(&mut node);
+        while kind == NodeKind::Var && self.at(TokenType::Comma) {
+            self.bump(&mut node);
+            self.eat_name(&mut node);
+        }
+        self.eat(&mut node, TokenType::Semicolon);
+        if kind == NodeKind::Procedure {
+            self.body(&mut node);
+            self.eat(&mut node, TokenType::Semicolon);
+        }
+        node
+    }
+
+    // <unit> -> {<declaration>} [begin {<statement> Semicolon} end]
+    fn program(&mut self) -> Node {
+        let mut program = Node::new(NodeKind::Program);
+        let module = self.at(TokenType::Module);
+        while matches!(
+            self.current(),
+            TokenType::Module | TokenType::Import | TokenType::Var | TokenType::Procedure
+        ) {
+            program.children.push(Element::Node(self.declaration()));
+        }
+        if !module {
+            self.body(&mut program);
+        }
+        if !self.at(TokenType::ScanEof) {
+            let mut error = Node::new(NodeKind::Error);
+            while !self.at(TokenType::ScanEof) {
+                self.bump(&mut error);
+            }
+            program.children.push(Element::Node(error));
+        }
+        program
+            .children
+            .push(Element::Token(self.tokens.pop().unwrap()));
+        program
+    }
+}
+
+/// The tree of a whole program or module. Lexer errors become `Unknown`
+/// tokens.
+pub fn parse(source: &str) -> Node {
+    let mut lexer = Lexer::new(source);
+    lexer.recover = true;
+    let mut tokens = lexer.tokenize_with_trivia();
+    tokens.reverse();
+    Parser { tokens }.program()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handle_tree_shape() {
+        let tree = parse("begin -- go\n  B := +(A - -1) + 2;\n  write(B, );\nend");
+        assert_eq!(
+            tree.dump(),
+            r#"Program
+  "begin" trailing " -- go"
+  Assignment
+    Name
+      "B" leading "\n  " trailing " "
+    ":=" trailing " "
+    Binary
+      Unary
+        "+"
+        Paren
+          "("
+          Binary
+            Name
+              "A" trailing " "
+            "-" trailing " "
+            Literal
+              "-"
+              "1"
+          ")" trailing " "
+      "+" trailing " "
+      Literal
+        "2"
+    ";"
+  Write
+    "write" leading "\n  "
+    "("
+    Name
+      "B"
+    "," trailing " "
+    Error
+    ")"
+    ";"
+  "end" leading "\n"
+  ""
+"#
+        );
+    }
+
+    #[test]
+    fn handle_round_trip() {
+        let mut sources: Vec<String> = (1..=10)
+            .map(|i| std::fs::read_to_string(format!("TestCases/test{}.m", i)).unwrap())
+            .collect();
+        sources.extend(
+            [
+                "",
+                "  -- only a comment",
+                "begin\r\n  A := 1;\r\nend\r\n",
+                "begin A := ; read(1 +) $ B := 3 write(A; end end -- extra",
+                "A := 1; end begin ) 99999999999 :",
+                "begin write(-);\n\tC := ((1);\n",
+                "begin ;; 1 2; ) end",
+                "module M; import N;\nvar A, B; -- globals\nprocedure P; begin A := B; P(); end;",
+                "var A; procedure P; begin A := 1 end begin P(; end",
+                "module ; var , 1; procedure begin",
+            ]
+            .map(String::from),
+        );
+        for source in sources.iter() {
+            assert_eq!(&parse(source).to_string(), source);
+        }
+    }
+}
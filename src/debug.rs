@@ -0,0 +1,286 @@
+//! `microc debug`: step through a program a statement at a time.
+//!
+//! The program is compiled without optimizations and with every variable in
+//! a stack slot of its own, then runs on the simulator. Execution stops
+//! where a statement starts, which the code generator records by listing
+//! index, and variables are read from their slots in the frame. Commands and
+//! the input of the program's `read`s come from the same stream; `(input)`
+//! marks where the program waits for a number.
+
+use crate::bytecode::vm::Input;
+use crate::codegen::{CodeGenerator, Operand};
+use crate::diagnostic::line_column;
+use crate::ir::Program;
+use crate::mips::Reg;
+use crate::sim::Machine;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "\
+break <line>   stop before the statements on a line
+delete <line>  remove the breakpoint on a line
+step           run to the next statement
+continue       run to the next breakpoint or the end of the program
+print <name>   the value of a variable
+vars           every variable and its value
+list           the source, with `*` on breakpoints and `>` on the next statement
+quit           leave, as does the end of the input
+";
+
+struct Session<'a, R, W> {
+    source: &'a str,
+    machine: Machine,
+    input: Input<R>,
+    output: W,
+    // the line of each statement, by the listing index it starts at
+    lines: BTreeMap<usize, usize>,
+    // variables, by their offset from `$fp`
+    slots: BTreeMap<String, u32>,
+    // `$fp` once the prologue has run
+    frame: u32,
+    breakpoints: BTreeSet<usize>,
+}
+
+impl<R: BufRead, W: Write> Session<'_, R, W> {
+    fn prompt(&mut self) -> io::Result<Option<String>> {
+        write!(self.output, "(debug) ")?;
+        self.output.flush()?;
+        let mut line = String::new();
+        if self.input.reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(line.trim().to_string()))
+    }
+
+    // the line the program is stopped at, if it is at a statement
+    fn current_line(&self) -> Option<usize> {
+        match self.machine.halted {
+            true => None,
+            false => self.lines.get(&self.machine.pc).copied(),
+        }
+    }
+
+    fn show(&mut self, line: usize) -> io::Result<()> {
+        let text = self.source.lines().nth(line - 1).unwrap_or("").trim();
+        writeln!(self.output, "line {}: {}", line, text)
+    }
+
+    // run until the start of a statement whose line `stop` accepts
+    fn resume(&mut self, stop: impl Fn(&Self, usize) -> bool) -> io::Result<()> {
+        if self.machine.halted {
+            return writeln!(self.output, "error: the program has exited");
+        }
+        loop {
+            let reading = self.machine.reads_next();
+            if reading && self.input.is_empty() {
+                write!(self.output, "(input) ")?;
+                self.output.flush()?;
+            }
+            if let Err(e) = self.machine.step(&mut self.input, &mut self.output) {
+                // the `read` runs again with what comes next
+                if reading && !self.input.at_end() {
+                    writeln!(self.output, "error: {}", e)?;
+                    continue;
+                }
+                self.machine.halted = true;
+                return writeln!(self.output, "error: {}", e);
+            }
+            if self.machine.halted {
+                return writeln!(self.output, "the program exited");
+            }
+            if let Some(line) = self.current_line() {
+                self.frame = self.machine.reg(Reg::FP) as u32;
+                if stop(self, line) {
+                    return self.show(line);
+                }
+            }
+        }
+    }
+
+    fn value(&self, name: &str) -> Result<i32, String> {
+        let offset = self
+            .slots
+            .get(name)
+            .ok_or_else(|| format!("no variable `{}`", name))?;
+        self.machine.load(self.frame.wrapping_add(*offset))
+    }
+
+    // handle a command; false to leave
+    fn command(&mut self, line: &str) -> io::Result<bool> {
+        let (name, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let rest = rest.trim();
+        match name {
+            "break" | "b" | "delete" | "d" => {
+                let Ok(line) = rest.parse::<usize>() else {
+                    writeln!(self.output, "error: expected a line number")?;
+                    return Ok(true);
+                };
+                let message = if name.starts_with('b') {
+                    match self.lines.values().any(|l| *l == line) {
+                        true => {
+                            self.breakpoints.insert(line);
+                            format!("breakpoint at line {}", line)
+                        }
+                        false => format!("error: no statement on line {}", line),
+                    }
+                } else {
+                    match self.breakpoints.remove(&line) {
+                        true => format!("deleted the breakpoint at line {}", line),
+                        false => format!("error: no breakpoint at line {}", line),
+                    }
+                };
+                writeln!(self.output, "{}", message)?;
+            }
+            "step" | "s" => self.resume(|_, _| true)?,
+            "continue" | "c" => self.resume(|session, line| session.breakpoints.contains(&line))?,
+            "print" | "p" => match self.value(rest) {
+                Ok(value) => writeln!(self.output, "{} = {}", rest, value)?,
+                Err(e) => writeln!(self.output, "error: {}", e)?,
+            },
+            "vars" => {
+                let names: Vec<String> = self.slots.keys().cloned().collect();
+                if names.is_empty() {
+                    writeln!(self.output, "no variables")?;
+                }
+                for name in names {
+                    match self.value(&name) {
+                        Ok(value) => writeln!(self.output, "{} = {}", name, value)?,
+                        Err(e) => writeln!(self.output, "error: {}", e)?,
+                    }
+                }
+            }
+            "list" | "l" => {
+                let current = self.current_line();
+                for (line, text) in (1..).zip(self.source.lines()) {
+                    let marker = if current == Some(line) {
+                        '>'
+                    } else if self.breakpoints.contains(&line) {
+                        '*'
+                    } else {
+                        ' '
+                    };
+                    writeln!(self.output, "{}{:>4} {}", marker, line, text)?;
+                }
+            }
+            "help" => write!(self.output, "{}", HELP)?,
+            "quit" | "q" => return Ok(false),
+            _ => writeln!(self.output, "error: unknown command `{}`, see help", name)?,
+        }
+        Ok(true)
+    }
+}
+
+/// Debug `program`, lowered from `source`, reading commands from `input`
+/// until the end of it or `quit`.
+pub fn run(
+    source: &str,
+    program: &Program,
+    input: impl BufRead,
+    output: impl Write,
+) -> io::Result<()> {
+    let mut cg = CodeGenerator::new();
+    cg.debug = true;
+    let listing = cg.assemble(program);
+    let machine = Machine::new(listing).map_err(io::Error::other)?;
+    let mut session = Session {
+        source,
+        machine,
+        input: Input::new(input),
+        output,
+        lines: cg
+            .statements
+            .iter()
+            .map(|(index, span)| (*index, line_column(source, span.start).0))
+            .collect(),
+        slots: cg
+            .symbol_map
+            .iter()
+            .filter_map(|(name, operand)| match operand {
+                Operand::Mem(offset) => Some((name.clone(), *offset)),
+                _ => None,
+            })
+            .collect(),
+        frame: 0,
+        breakpoints: BTreeSet::new(),
+    };
+    writeln!(session.output, "microc debug, help for commands")?;
+    // stop at the first statement
+    session.resume(|_, _| true)?;
+    while let Some(line) = session.prompt()? {
+        if line.is_empty() {
+            continue;
+        }
+        if !session.command(&line)? {
+            return Ok(());
+        }
+    }
+    writeln!(session.output)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+
+    use super::*;
+
+    fn session(source: &str, input: &str) -> String {
+        let program = testing::lower_source(source);
+        let mut output = Vec::new();
+        run(source, &program, input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn handle_session() {
+        let source =
+            "begin\n  read(A);\n  B := A + 1; write(B);\n  C := B - A;\n  write(C);\nend\n";
+        let output = session(
+            source,
+            "break 4\nbreak 6\ncontinue\n41\nprint B\nprint C\nstep\nvars\nlist\ndelete 4\nc\nc\n",
+        );
+        assert_eq!(
+            output,
+            "microc debug, help for commands
+line 2: read(A);
+(debug) breakpoint at line 4
+(debug) error: no statement on line 6
+(debug) (input) 42
+line 4: C := B - A;
+(debug) B = 42
+(debug) C = 0
+(debug) line 5: write(C);
+(debug) A = 41
+B = 42
+C = 1
+(debug)     1 begin
+    2   read(A);
+    3   B := A + 1; write(B);
+*   4   C := B - A;
+>   5   write(C);
+    6 end
+(debug) deleted the breakpoint at line 4
+(debug) 1
+the program exited
+(debug) error: the program has exited
+(debug) \n"
+        );
+    }
+
+    #[test]
+    fn handle_errors() {
+        let output = session(
+            "begin read(A); A := A + 1; end",
+            "s\nx\n2147483647\ns\nbogus\nq\n",
+        );
+        assert_eq!(
+            output,
+            "microc debug, help for commands
+line 1: begin read(A); A := A + 1; end
+(debug) (input) error: `syscall`: read: `x` is not an integer
+(input) line 1: begin read(A); A := A + 1; end
+(debug) error: `add $t0, $t0, $t1`: arithmetic overflow
+(debug) error: unknown command `bogus`, see help
+(debug) "
+        );
+    }
+}
@@ -0,0 +1,110 @@
+//! Errors and warnings tied to a range of the source.
+
+/// Byte range `start..end` of the source.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+
+    /// The smallest span covering both.
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+
+    pub fn contains(self, offset: usize) -> bool {
+        self.start <= offset && offset <= self.end
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub span: Span,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn error(span: Span, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            span,
+            message: message.into(),
+        }
+    }
+
+    pub fn warning(span: Span, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            span,
+            message: message.into(),
+        }
+    }
+
+    /// The message with the offending line and a caret under the span, in
+    /// the format the lexer uses for its errors.
+    pub fn render(&self, source: &str) -> String {
+        let (line, column) = line_column(source, self.span.start);
+        let text = source.lines().nth(line - 1).unwrap_or("");
+        let width = source[self.span.start..self.span.end.min(source.len())]
+            .lines()
+            .next()
+            .map_or(0, |first| first.chars().count())
+            .max(1);
+        let kind = match self.severity {
+            Severity::Error => "syntax error",
+            Severity::Warning => "warning",
+        };
+        format!(
+            "microc: [{}] {}\n    --> {}:{}\n      |\n{:>5} |{}\n      |{}{}\n",
+            kind,
+            self.message,
+            line,
+            column,
+            line,
+            text,
+            " ".repeat(column - 1),
+            "^".repeat(width)
+        )
+    }
+}
+
+/// One-based line and column (in characters) of a byte offset.
+pub fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let line = before.matches('\n').count() + 1;
+    (line, before[line_start..].chars().count() + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handle_render() {
+        let source = "begin\n  A := ;\nend";
+        let diagnostic = Diagnostic::error(Span::new(13, 14), "expected an expression");
+        assert_eq!(line_column(source, 13), (2, 8));
+        assert_eq!(
+            diagnostic.render(source),
+            "microc: [syntax error] expected an expression
+    --> 2:8
+      |
+    2 |  A := ;
+      |       ^
+"
+        );
+    }
+}
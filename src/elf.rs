@@ -0,0 +1,356 @@
+//! ELF32 executable writer for assembled MIPS objects.
+//!
+//! The output has one loadable segment for `.text` at `TEXT_BASE` and one
+//! for `.data` at `DATA_BASE`, plus a symbol table naming every label so
+//! the result can be inspected with `readelf` or `objdump`. Programs built
+//! with `CodeGenerator::linux` call Linux through the o32 system calls, so
+//! they run on MIPS Linux or under `qemu-mips`.
+
+use crate::assembler::{Object, Section};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Endian {
+    #[default]
+    Big,
+    Little,
+}
+
+const PAGE: u32 = 0x1000;
+const EHDR_SIZE: u32 = 52;
+const PHDR_SIZE: u32 = 32;
+const SHDR_SIZE: u32 = 40;
+const SYM_SIZE: u32 = 16;
+
+const ET_EXEC: u16 = 2;
+const EM_MIPS: u16 = 8;
+const EF_MIPS_ABI_O32: u32 = 0x1000;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHF_WRITE: u32 = 1;
+const SHF_ALLOC: u32 = 2;
+const SHF_EXECINSTR: u32 = 4;
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+// section header indices
+const TEXT_INDEX: u16 = 1;
+const DATA_INDEX: u16 = 2;
+const STRTAB_INDEX: u32 = 4;
+
+struct Writer {
+    buf: Vec<u8>,
+    endian: Endian,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        match self.endian {
+            Endian::Big => self.buf.extend(value.to_be_bytes()),
+            Endian::Little => self.buf.extend(value.to_le_bytes()),
+        }
+    }
+
+    fn u32(&mut self, value: u32) {
+        match self.endian {
+            Endian::Big => self.buf.extend(value.to_be_bytes()),
+            Endian::Little => self.buf.extend(value.to_le_bytes()),
+        }
+    }
+
+    fn pad_to(&mut self, offset: u32) {
+        self.buf.resize(offset as usize, 0);
+    }
+
+    fn offset(&self) -> u32 {
+        self.buf.len() as u32
+    }
+}
+
+// null-terminated string table; returns the offset of each added name
+#[derive(Default)]
+struct StringTable {
+    bytes: Vec<u8>,
+}
+
+impl StringTable {
+    fn add(&mut self, name: &str) -> u32 {
+        if self.bytes.is_empty() {
+            self.bytes.push(0);
+        }
+        let offset = self.bytes.len() as u32;
+        self.bytes.extend(name.as_bytes());
+        self.bytes.push(0);
+        offset
+    }
+}
+
+fn align(value: u32, to: u32) -> u32 {
+    value.div_ceil(to) * to
+}
+
+pub fn write_executable(object: &Object, endian: Endian) -> Vec<u8> {
+    use crate::mips::{DATA_BASE, TEXT_BASE};
+
+    let text_size = 4 * object.text.len() as u32;
+    let data_size = 4 * object.data.len() as u32;
+    let text_offset = PAGE;
+    let data_offset = align(text_offset + text_size, PAGE);
+
+    // symbols: the null symbol, then locals, then globals
+    let mut strtab = StringTable::default();
+    strtab.add("");
+    let mut symbols: Vec<_> = object.symbols.iter().collect();
+    symbols.sort_by_key(|s| s.global);
+    let first_global = 1 + symbols.iter().filter(|s| !s.global).count() as u32;
+
+    let mut shstrtab = StringTable::default();
+    let names = [
+        shstrtab.add(".text"),
+        shstrtab.add(".data"),
+        shstrtab.add(".symtab"),
+        shstrtab.add(".strtab"),
+        shstrtab.add(".shstrtab"),
+    ];
+
+    let mut w = Writer {
+        buf: Vec::new(),
+        endian,
+    };
+
+    // ELF header
+    w.buf.extend([0x7f, b'E', b'L', b'F', 1]);
+    w.u8(match endian {
+        Endian::Big => 2,
+        Endian::Little => 1,
+    });
+    w.u8(1);
+    w.pad_to(16);
+    w.u16(ET_EXEC);
+    w.u16(EM_MIPS);
+    w.u32(1);
+    w.u32(object.entry);
+    w.u32(EHDR_SIZE);
+    let shoff_at = w.offset();
+    w.u32(0);
+    w.u32(EF_MIPS_ABI_O32);
+    w.u16(EHDR_SIZE as u16);
+    w.u16(PHDR_SIZE as u16);
+    w.u16(2);
+    w.u16(SHDR_SIZE as u16);
+    w.u16(6);
+    w.u16(5);
+
+    // program headers
+    for (offset, address, size, flags) in [
+        (text_offset, TEXT_BASE, text_size, PF_R | PF_X),
+        (data_offset, DATA_BASE, data_size, PF_R | PF_W),
+    ] {
+        w.u32(PT_LOAD);
+        w.u32(offset);
+        w.u32(address);
+        w.u32(address);
+        w.u32(size);
+        w.u32(size);
+        w.u32(flags);
+        w.u32(PAGE);
+    }
+
+    w.pad_to(text_offset);
+    for word in object.text.iter() {
+        w.u32(*word);
+    }
+    w.pad_to(data_offset);
+    for word in object.data.iter() {
+        w.u32(*word);
+    }
+
+    let symtab_offset = align(w.offset(), 4);
+    w.pad_to(symtab_offset);
+    w.pad_to(symtab_offset + SYM_SIZE);
+    for symbol in symbols.iter() {
+        w.u32(strtab.add(&symbol.name));
+        w.u32(symbol.value);
+        w.u32(0);
+        let (kind, index) = match symbol.section {
+            Section::Text => (STT_FUNC, TEXT_INDEX),
+            Section::Data => (STT_OBJECT, DATA_INDEX),
+        };
+        let bind = if symbol.global { STB_GLOBAL } else { STB_LOCAL };
+        w.u8(bind << 4 | kind);
+        w.u8(0);
+        w.u16(index);
+    }
+    let symtab_size = w.offset() - symtab_offset;
+
+    let strtab_offset = w.offset();
+    w.buf.extend(&strtab.bytes);
+    let shstrtab_offset = w.offset();
+    w.buf.extend(&shstrtab.bytes);
+
+    let shoff = align(w.offset(), 4);
+    w.pad_to(shoff);
+    let shoff_bytes = match endian {
+        Endian::Big => shoff.to_be_bytes(),
+        Endian::Little => shoff.to_le_bytes(),
+    };
+    w.buf[shoff_at as usize..shoff_at as usize + 4].copy_from_slice(&shoff_bytes);
+
+    // section headers: name, type, flags, addr, offset, size, link, info,
+    // addralign, entsize
+    let sections = [
+        [0; 10],
+        [
+            names[0],
+            SHT_PROGBITS,
+            SHF_ALLOC | SHF_EXECINSTR,
+            TEXT_BASE,
+            text_offset,
+            text_size,
+            0,
+            0,
+            4,
+            0,
+        ],
+        [
+            names[1],
+            SHT_PROGBITS,
+            SHF_ALLOC | SHF_WRITE,
+            DATA_BASE,
+            data_offset,
+            data_size,
+            0,
+            0,
+            4,
+            0,
+        ],
+        [
+            names[2],
+            SHT_SYMTAB,
+            0,
+            0,
+            symtab_offset,
+            symtab_size,
+            STRTAB_INDEX,
+            first_global,
+            4,
+            SYM_SIZE,
+        ],
+        [
+            names[3],
+            SHT_STRTAB,
+            0,
+            0,
+            strtab_offset,
+            strtab.bytes.len() as u32,
+            0,
+            0,
+            1,
+            0,
+        ],
+        [
+            names[4],
+            SHT_STRTAB,
+            0,
+            0,
+            shstrtab_offset,
+            shstrtab.bytes.len() as u32,
+            0,
+            0,
+            1,
+            0,
+        ],
+    ];
+    for header in sections.iter() {
+        for field in header.iter() {
+            w.u32(*field);
+        }
+    }
+    w.buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Symbol;
+    use crate::mips::{DATA_BASE, TEXT_BASE};
+
+    fn object() -> Object {
+        Object {
+            text: vec![0x2402_000a, 0x0000_000c],
+            data: vec![10],
+            symbols: vec![Symbol {
+                name: "main".to_string(),
+                value: TEXT_BASE,
+                section: Section::Text,
+                global: true,
+            }],
+            entry: TEXT_BASE,
+        }
+    }
+
+    #[test]
+    fn handle_big_endian() {
+        let elf = write_executable(&object(), Endian::Big);
+        assert_eq!(&elf[..7], &[0x7f, b'E', b'L', b'F', 1, 2, 1]);
+        // e_machine and e_entry
+        assert_eq!(&elf[18..20], &[0, 8]);
+        assert_eq!(&elf[24..28], &TEXT_BASE.to_be_bytes());
+        assert_eq!(&elf[0x1000..0x1008], &[0x24, 0x02, 0, 0x0a, 0, 0, 0, 0x0c]);
+        // section headers are last and `.shstrtab` names `.text`
+        let shoff = u32::from_be_bytes(elf[32..36].try_into().unwrap()) as usize;
+        assert_eq!(elf.len(), shoff + 6 * SHDR_SIZE as usize);
+    }
+
+    #[test]
+    fn handle_little_endian() {
+        let elf = write_executable(&object(), Endian::Little);
+        assert_eq!(elf[5], 1);
+        assert_eq!(&elf[18..20], &[8, 0]);
+        assert_eq!(&elf[0x1000..0x1004], &[0x0a, 0, 0x02, 0x24]);
+    }
+
+    #[test]
+    fn handle_linux_program() {
+        // how the runtime behaves is the simulator's to check; here the
+        // segments must load exactly what the assembler produced
+        let mut program = crate::testing::lower_source("begin read(A, B); write(A + B); end");
+        crate::opt::optimize(&mut program, crate::opt::OptLevel::O1);
+        let mut cg = crate::codegen::CodeGenerator::new();
+        cg.linux = true;
+        let object = crate::assembler::assemble(&cg.assemble(&program), true).unwrap();
+        let main = object.symbols.iter().find(|s| s.name == "main").unwrap();
+        for endian in [Endian::Big, Endian::Little] {
+            let elf = write_executable(&object, endian);
+            let word = |at: usize| {
+                let bytes = elf[at..at + 4].try_into().unwrap();
+                match endian {
+                    Endian::Big => u32::from_be_bytes(bytes),
+                    Endian::Little => u32::from_le_bytes(bytes),
+                }
+            };
+            assert_eq!(word(24), main.value);
+            let phoff = word(28) as usize;
+            for (i, (address, words)) in [(TEXT_BASE, &object.text), (DATA_BASE, &object.data)]
+                .into_iter()
+                .enumerate()
+            {
+                let header = phoff + PHDR_SIZE as usize * i;
+                let (offset, size) = (word(header + 4) as usize, word(header + 16) as usize);
+                assert_eq!((word(header + 8), size), (address, 4 * words.len()));
+                let loaded: Vec<u32> = (0..words.len()).map(|j| word(offset + 4 * j)).collect();
+                assert_eq!(&loaded, words);
+            }
+        }
+    }
+}
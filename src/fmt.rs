@@ -0,0 +1,395 @@
+//! `microc fmt`: reprint a program in the canonical layout.
+//!
+//! Statements go one per line, indented by four spaces, with spaces around
+//! `:=` and the operators and none inside parentheses. Parentheses the
+//! grammar does not need are dropped, and argument lists too long for a line
+//! wrap under the opening parenthesis. Comments are kept: a comment after
+//! code stays at the end of its line, one on its own line stays before the
+//! statement that follows it. Runs of blank lines become a single one.
+//! Declarations go one per line, and procedure bodies are laid out like the
+//! program's.
+
+use crate::ast::{ASTBuilder, BinaryOpKind, ExprAST, ExprKind, SyscallKind};
+use crate::cst::{self, Element, NodeKind};
+use crate::diagnostic::{Diagnostic, Span};
+use crate::lexer::{Lexer, SyntaxToken, TokenType, Trivia, TriviaKind};
+
+const INDENT: &str = "    ";
+const MAX_WIDTH: usize = 80;
+
+struct Comment {
+    text: String,
+    blank_before: bool,
+}
+
+// whether `trivia` ends with a blank line
+fn blank_line_before(trivia: &[Trivia]) -> bool {
+    trivia
+        .last()
+        .is_some_and(|t| t.kind == TriviaKind::Whitespace && t.text.matches('\n').count() >= 2)
+}
+
+fn comments(trivia: &[Trivia]) -> Vec<Comment> {
+    let mut comments = Vec::new();
+    for (i, t) in trivia.iter().enumerate() {
+        if t.kind == TriviaKind::Comment {
+            comments.push(Comment {
+                text: t.text.trim_end().to_string(),
+                blank_before: blank_line_before(&trivia[..i]),
+            });
+        }
+    }
+    comments
+}
+
+// the comments around a declaration, `begin`, a statement or `end`
+#[derive(Default)]
+struct Attached {
+    // before it, including those inside a statement, which move above it
+    leading: Vec<Comment>,
+    // after code on one of its lines
+    trailing: Vec<Comment>,
+    blank_before: bool,
+}
+
+fn attached(tokens: &[&SyntaxToken]) -> Attached {
+    let mut attached = Attached {
+        blank_before: blank_line_before(&tokens[0].leading),
+        ..Attached::default()
+    };
+    for token in tokens.iter() {
+        attached.leading.extend(comments(&token.leading));
+        attached.trailing.extend(comments(&token.trailing));
+    }
+    attached
+}
+
+// an expression as it appears as the right operand of `+` or `-`
+fn operand(expr: &ExprAST) -> String {
+    match expr.kind {
+        ExprKind::BinaryExprAST { .. } => format!("({})", expression(expr)),
+        _ => expression(expr),
+    }
+}
+
+fn expression(expr: &ExprAST) -> String {
+    match &expr.kind {
+        ExprKind::IntLiteralExprAST { value } => value.to_string(),
+        ExprKind::VariableExprAST { name } => name.to_string(),
+        ExprKind::CallAST { name } => format!("{}()", name),
+        ExprKind::BinaryExprAST { op, lhs, rhs } => {
+            // the parser reads a leading `+x` as `0 + x` with an empty `0`
+            if let ExprKind::IntLiteralExprAST { value: 0 } = lhs.kind {
+                if lhs.span.start == lhs.span.end {
+                    return format!("+{}", operand(rhs));
+                }
+            }
+            let op = match op {
+                BinaryOpKind::Add => "+",
+                BinaryOpKind::Sub => "-",
+            };
+            format!("{} {} {}", expression(lhs), op, operand(rhs))
+        }
+        // statements, which `statement` prints
+        _ => String::new(),
+    }
+}
+
+// the lines of a statement, including its `;`
+fn statement(stmt: &ExprAST) -> Vec<String> {
+    let (calle, args) = match &stmt.kind {
+        ExprKind::AssignmentAST { var, assign } => {
+            return vec![format!(
+                "{}{} := {};",
+                INDENT,
+                expression(var),
+                expression(assign)
+            )]
+        }
+        ExprKind::SyscallExprAST { calle, args } => (calle, args),
+        _ => return vec![format!("{}{};", INDENT, expression(stmt))],
+    };
+    let head = match calle {
+        SyscallKind::Read => format!("{}read(", INDENT),
+        SyscallKind::Write => format!("{}write(", INDENT),
+    };
+    let args: Vec<String> = args.iter().map(expression).collect();
+    let line = format!("{}{});", head, args.join(", "));
+    if line.chars().count() <= MAX_WIDTH {
+        return vec![line];
+    }
+
+    // fill each line, continuing under the first argument
+    let mut lines = Vec::new();
+    let mut line = head.clone();
+    for (i, arg) in args.iter().enumerate() {
+        let arg = match i + 1 == args.len() {
+            true => format!("{});", arg),
+            false => format!("{},", arg),
+        };
+        let fresh = line.len() == head.len();
+        if !fresh && line.chars().count() + 1 + arg.chars().count() > MAX_WIDTH {
+            lines.push(line);
+            line = " ".repeat(head.len());
+        } else if !fresh {
+            line.push(' ');
+        }
+        line.push_str(&arg);
+    }
+    lines.push(line);
+    lines
+}
+
+// `module M;`, `import M;` or `var A, B;`
+fn declaration(tokens: &[&SyntaxToken]) -> String {
+    let names: Vec<&str> = tokens
+        .iter()
+        .filter(|t| matches!(t.token.token_type, TokenType::Identifier { .. }))
+        .map(|t| &*t.text)
+        .collect();
+    format!("{} {};", tokens[0].text, names.join(", "))
+}
+
+#[derive(Default)]
+struct Printer {
+    lines: Vec<String>,
+    // no blank line right after `begin` or a procedure heading
+    after_begin: bool,
+}
+
+impl Printer {
+    fn blank_line(&mut self) {
+        if !self.after_begin && self.lines.last().is_some_and(|l| !l.is_empty()) {
+            self.lines.push(String::new());
+        }
+    }
+
+    fn comments(&mut self, comments: &[Comment], indent: &str) {
+        for comment in comments.iter() {
+            if comment.blank_before {
+                self.blank_line();
+            }
+            self.lines.push(format!("{}{}", indent, comment.text));
+            self.after_begin = false;
+        }
+    }
+
+    // code lines, with the first trailing comment on the last line
+    fn code(&mut self, lines: Vec<String>, attached: &Attached, indent: &str) {
+        self.lines.extend(lines);
+        self.after_begin = false;
+        if let Some((first, rest)) = attached.trailing.split_first() {
+            let last = self.lines.last_mut().unwrap();
+            last.push(' ');
+            last.push_str(&first.text);
+            self.comments(rest, indent);
+        }
+    }
+
+    // a line at the start of a line, with the comments before it
+    fn line(&mut self, text: String, tokens: &[&SyntaxToken]) {
+        let attached = attached(tokens);
+        self.comments(&attached.leading, "");
+        if attached.blank_before {
+            self.blank_line();
+        }
+        self.code(vec![text], &attached, "");
+    }
+
+    // `begin` and the statements after it
+    fn body(&mut self, begin: &SyntaxToken, body: &[Element], statements: &[ExprAST]) {
+        let begin = attached(&[begin]);
+        self.comments(&begin.leading, "");
+        if begin.blank_before {
+            self.blank_line();
+        }
+        self.code(vec!["begin".to_string()], &begin, INDENT);
+        self.after_begin = true;
+        for (stmt, element) in statements.iter().zip(body.iter()) {
+            let attached = attached(&element.tokens());
+            self.comments(&attached.leading, INDENT);
+            if attached.blank_before {
+                self.blank_line();
+            }
+            self.code(statement(stmt), &attached, INDENT);
+        }
+    }
+
+    fn end(&mut self, text: &str, tokens: &[&SyntaxToken]) {
+        let end = attached(tokens);
+        self.comments(&end.leading, INDENT);
+        self.after_begin = false;
+        self.code(vec![text.to_string()], &end, "");
+    }
+}
+
+// the token of a tree element that is one
+fn token(element: &Element) -> &SyntaxToken {
+    match element {
+        Element::Token(token) => token,
+        Element::Node(_) => unreachable!("a node where the tree has a token"),
+    }
+}
+
+/// The program or module in the canonical layout, or the errors that stop
+/// it from being parsed.
+pub fn format(source: &str) -> Result<String, Vec<Diagnostic>> {
+    let mut lexer = Lexer::new(source);
+    lexer.recover = true;
+    let tokens = lexer.tokens();
+    let mut builder = ASTBuilder::new(tokens.into_iter());
+    let unit = builder.parse_unit();
+    let mut diagnostics = lexer.diagnostics;
+    diagnostics.append(&mut builder.diagnostics);
+    if !diagnostics.is_empty() {
+        diagnostics.sort_by_key(|d| d.span);
+        return Err(diagnostics);
+    }
+
+    // with no errors the tree is the declarations, then for a program
+    // `begin`, the statements and `end`, and `ScanEof`, which leads with
+    // the comments after the last of them
+    let tree = cst::parse(source);
+    let (eof, elements) = tree.children.split_last().unwrap();
+    let after_end = comments(&eof.tokens()[0].leading);
+
+    let mut printer = Printer::default();
+    let mut procedures = unit.procedures.iter();
+    for (i, element) in elements.iter().enumerate() {
+        let node = match element {
+            Element::Node(node) => node,
+            Element::Token(begin) => {
+                let (end, body) = elements[i + 1..].split_last().unwrap();
+                printer.body(begin, body, &unit.main);
+                printer.end("end", &end.tokens());
+                break;
+            }
+        };
+        let tokens = node.tokens();
+        if node.kind != NodeKind::Procedure {
+            printer.line(declaration(&tokens), &tokens);
+            continue;
+        }
+        // `procedure P ;`, `begin`, the statements, `end ;`
+        let procedure = procedures.next().unwrap();
+        let (heading, rest) = node.children.split_at(3);
+        let (body, end) = rest[1..].split_at(rest.len() - 3);
+        let heading: Vec<&SyntaxToken> = heading.iter().map(token).collect();
+        printer.line(format!("procedure {};", procedure.name), &heading);
+        printer.after_begin = true;
+        printer.body(token(&rest[0]), body, &procedure.body);
+        printer.end("end;", &end.iter().map(token).collect::<Vec<_>>());
+    }
+    printer.comments(&after_end, "");
+
+    let mut text = printer.lines.join("\n");
+    text.push('\n');
+    Ok(text)
+}
+
+/// The span of the first line that differs, for `--check`.
+pub fn first_difference(source: &str, formatted: &str) -> Option<Span> {
+    let mut offset = 0;
+    let mut expected = formatted.split_inclusive('\n');
+    for line in source.split_inclusive('\n') {
+        if expected.next() != Some(line) {
+            return Some(Span::new(offset, offset + line.trim_end().len()));
+        }
+        offset += line.len();
+    }
+    expected
+        .next()
+        .map(|_| Span::new(source.len(), source.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handle_canonical_layout() {
+        let source = "-- header\n\n\nbegin -- main\n  read ( A,B );  \n\n\n\
+                      -- bump\n\tB:=A+( +5 )-(A-(1)) ; -- twice\nC := ((-2));write(C);\n  -- done\nend\n-- eof";
+        let formatted = format(source).unwrap();
+        assert_eq!(
+            formatted,
+            "-- header
+
+begin -- main
+    read(A, B);
+
+    -- bump
+    B := A + (+5) - (A - 1); -- twice
+    C := -2;
+    write(C);
+    -- done
+end
+-- eof
+"
+        );
+        assert_eq!(format(&formatted).unwrap(), formatted);
+        assert_eq!(
+            first_difference(source, &formatted),
+            Some(Span::new(11, 11))
+        );
+        assert_eq!(first_difference(&formatted, &formatted), None);
+    }
+
+    #[test]
+    fn handle_write_wrapping() {
+        let source = "begin A := 1; write(2-A, 3-A, 4-A, 5-A, 6-A, 7-A, 8-A, 9-A, \
+                      10-A, 11-A, 12-A, 13-A, 14-A, A+1, 100+200, 100-200); end";
+        let formatted = format(source).unwrap();
+        assert_eq!(
+            formatted,
+            "begin
+    A := 1;
+    write(2 - A, 3 - A, 4 - A, 5 - A, 6 - A, 7 - A, 8 - A, 9 - A, 10 - A,
+          11 - A, 12 - A, 13 - A, 14 - A, A + 1, 100 + 200, 100 - 200);
+end
+"
+        );
+        assert!(formatted.lines().all(|l| l.len() <= MAX_WIDTH));
+
+        let errors = format("begin A := ; end").unwrap_err();
+        assert_eq!(errors[0].message, "expected an expression, found `;`");
+    }
+
+    #[test]
+    fn handle_declarations() {
+        let source = "var X; procedure p; begin X := 1; end; begin p(); write(X); end";
+        let formatted = format(source).unwrap();
+        assert_eq!(
+            formatted,
+            "var X;
+procedure p;
+begin
+    X := 1;
+end;
+begin
+    p();
+    write(X);
+end
+"
+        );
+        assert_eq!(format(&formatted).unwrap(), formatted);
+
+        let source = "module  M ;import N;\n-- state\nvar X,Y ;\n\n\
+                      procedure p ; begin -- bump\nX:=X+Z; end ;";
+        let formatted = format(source).unwrap();
+        assert_eq!(
+            formatted,
+            "module M;
+import N;
+-- state
+var X, Y;
+
+procedure p;
+begin -- bump
+    X := X + Z;
+end;
+"
+        );
+        assert_eq!(format(&formatted).unwrap(), formatted);
+    }
+}
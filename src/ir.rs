@@ -0,0 +1,258 @@
+use crate::ast::{BinaryOpKind, ExprAST, ExprKind, SyscallKind};
+use crate::diagnostic::Span;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Virtual register. Every program variable owns one, and every
+/// intermediate result gets a fresh one.
+pub type VReg = u32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
+    Reg(VReg),
+    Imm(i32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inst {
+    Copy {
+        dst: VReg,
+        src: Value,
+    },
+    Binary {
+        op: BinaryOpKind,
+        dst: VReg,
+        lhs: Value,
+        rhs: Value,
+    },
+    /// Reads an integer; `dst` is `None` when the value is never used.
+    Read {
+        dst: Option<VReg>,
+    },
+    Write {
+        src: Value,
+    },
+    /// Calls a procedure, which may read and write any global.
+    Call {
+        target: String,
+    },
+}
+
+impl Inst {
+    /// The register written by this instruction, if any.
+    pub fn def(&self) -> Option<VReg> {
+        match self {
+            Inst::Copy { dst, .. } | Inst::Binary { dst, .. } => Some(*dst),
+            Inst::Read { dst } => *dst,
+            Inst::Write { .. } | Inst::Call { .. } => None,
+        }
+    }
+
+    /// The registers read by this instruction.
+    pub fn uses(&self) -> Vec<VReg> {
+        let values = match self {
+            Inst::Copy { src, .. } | Inst::Write { src } => vec![*src],
+            Inst::Binary { lhs, rhs, .. } => vec![*lhs, *rhs],
+            Inst::Read { .. } | Inst::Call { .. } => vec![],
+        };
+        values
+            .into_iter()
+            .filter_map(|v| match v {
+                Value::Reg(r) => Some(r),
+                Value::Imm(_) => None,
+            })
+            .collect()
+    }
+}
+
+/// A straight-line Micro program in three-address form.
+#[derive(Debug, Default)]
+pub struct Program {
+    pub insts: Vec<Inst>,
+    /// The statement each instruction comes from, when the program was
+    /// lowered from source.
+    pub spans: Vec<Span>,
+    pub vars: BTreeMap<String, VReg>,
+    /// Variables that are globals, named by their labels, rather than
+    /// locals. Their values must be in memory around calls and at the end.
+    pub globals: BTreeSet<VReg>,
+    pub vreg_count: u32,
+}
+
+impl Program {
+    pub fn new_vreg(&mut self) -> VReg {
+        self.vreg_count += 1;
+        self.vreg_count - 1
+    }
+
+    pub fn var(&mut self, name: &str) -> VReg {
+        if let Some(r) = self.vars.get(name) {
+            return *r;
+        }
+        let r = self.new_vreg();
+        self.vars.insert(name.to_string(), r);
+        r
+    }
+
+    /// Name of the variable owning `reg`, if it is not a temporary.
+    pub fn var_name(&self, reg: VReg) -> Option<&str> {
+        self.vars
+            .iter()
+            .find(|(_, r)| **r == reg)
+            .map(|(name, _)| name.as_str())
+    }
+
+    // print an instruction with variable names, and `%n` for temporaries
+    pub fn format_inst(&self, inst: &Inst) -> String {
+        let reg = |r: VReg| match self.var_name(r) {
+            Some(name) => name.to_string(),
+            None => format!("%{}", r),
+        };
+        let value = |v: Value| match v {
+            Value::Reg(r) => reg(r),
+            Value::Imm(imm) => imm.to_string(),
+        };
+        match inst {
+            Inst::Copy { dst, src } => format!("{} := {}", reg(*dst), value(*src)),
+            Inst::Binary { op, dst, lhs, rhs } => {
+                let op = match op {
+                    BinaryOpKind::Add => "+",
+                    BinaryOpKind::Sub => "-",
+                };
+                format!("{} := {} {} {}", reg(*dst), value(*lhs), op, value(*rhs))
+            }
+            Inst::Read { dst: Some(dst) } => format!("read({})", reg(*dst)),
+            Inst::Read { dst: None } => "read()".to_string(),
+            Inst::Write { src } => format!("write({})", value(*src)),
+            Inst::Call { target } => format!("{}()", target),
+        }
+    }
+}
+
+// lower statements into three-address code
+pub fn lower(statements: Vec<ExprAST>) -> Program {
+    lower_with_globals(statements, &BTreeSet::new())
+}
+
+/// Lower a program or procedure body in which the variables named in
+/// `globals` are globals.
+pub fn lower_with_globals(statements: Vec<ExprAST>, globals: &BTreeSet<String>) -> Program {
+    let mut program = Program::default();
+    for stmt in statements.into_iter() {
+        let span = stmt.span;
+        lower_statement(&mut program, stmt);
+        program.spans.resize(program.insts.len(), span);
+    }
+    program.globals = program
+        .vars
+        .iter()
+        .filter(|(name, _)| globals.contains(*name))
+        .map(|(_, vreg)| *vreg)
+        .collect();
+    program
+}
+
+fn lower_statement(program: &mut Program, stmt: ExprAST) {
+    match stmt.kind {
+        ExprKind::SyscallExprAST { calle, args } => match calle {
+            SyscallKind::Read => {
+                for e in args.into_iter() {
+                    if let ExprKind::VariableExprAST { name } = e.kind {
+                        let dst = program.var(&name);
+                        program.insts.push(Inst::Read { dst: Some(dst) });
+                    }
+                }
+            }
+            SyscallKind::Write => {
+                for e in args.into_iter() {
+                    let src = lower_expr(program, e);
+                    program.insts.push(Inst::Write { src });
+                }
+            }
+        },
+        ExprKind::AssignmentAST { var, assign } => {
+            let dst = match var.kind {
+                ExprKind::VariableExprAST { name } => program.var(&name),
+                _ => panic!(),
+            };
+            let src = lower_expr(program, *assign);
+            // write the last computation straight into the variable
+            // instead of going through a temporary
+            if let Value::Reg(tmp) = src {
+                let is_temp = program.var_name(tmp).is_none();
+                if let Some(Inst::Binary { dst: last, .. }) = program.insts.last_mut() {
+                    if *last == tmp && is_temp {
+                        *last = dst;
+                        return;
+                    }
+                }
+            }
+            program.insts.push(Inst::Copy { dst, src });
+        }
+        ExprKind::CallAST { name } => program.insts.push(Inst::Call {
+            target: name.to_string(),
+        }),
+        _ => panic!(),
+    }
+}
+
+fn lower_expr(program: &mut Program, expr: ExprAST) -> Value {
+    match expr.kind {
+        ExprKind::VariableExprAST { name } => Value::Reg(program.var(&name)),
+        ExprKind::IntLiteralExprAST { value } => Value::Imm(value),
+        ExprKind::BinaryExprAST { op, lhs, rhs } => {
+            let lhs = lower_expr(program, *lhs);
+            let rhs = lower_expr(program, *rhs);
+            let dst = program.new_vreg();
+            program.insts.push(Inst::Binary { op, dst, lhs, rhs });
+            Value::Reg(dst)
+        }
+        _ => panic!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::lower_source;
+
+    use super::*;
+
+    #[test]
+    fn handle_assign_into_variable() {
+        let program = lower_source(r#"begin read(a); b := a + 1; end"#);
+        let a = program.vars["a"];
+        let b = program.vars["b"];
+        assert_eq!(
+            program.insts,
+            vec![
+                Inst::Read { dst: Some(a) },
+                Inst::Binary {
+                    op: BinaryOpKind::Add,
+                    dst: b,
+                    lhs: Value::Reg(a),
+                    rhs: Value::Imm(1),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn handle_variable_copy() {
+        let program = lower_source(r#"begin a := 1; b := a; write(b); end"#);
+        let a = program.vars["a"];
+        let b = program.vars["b"];
+        assert_eq!(
+            program.insts,
+            vec![
+                Inst::Copy {
+                    dst: a,
+                    src: Value::Imm(1),
+                },
+                Inst::Copy {
+                    dst: b,
+                    src: Value::Reg(a),
+                },
+                Inst::Write { src: Value::Reg(b) },
+            ]
+        );
+    }
+}
@@ -0,0 +1,731 @@
+//! JVM backend writing a `.class` file directly.
+//!
+//! The class has a `main` method where every virtual register is an `int`
+//! local (shifted by one, as local 0 holds `args`), and a private `readInt`
+//! helper that parses a decimal integer from `System.in`. `write` is a call
+//! to `System.out.println(int)`. The class file targets Java 8, so methods
+//! with branches carry a `StackMapTable`; only `readInt` has any.
+
+use crate::ast::BinaryOpKind;
+use crate::backend;
+use crate::ir::{Inst, Program, VReg, Value};
+use std::collections::{BTreeSet, HashMap};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Constant {
+    Utf8(String),
+    Integer(i32),
+    Class(u16),
+    NameAndType(u16, u16),
+    Fieldref(u16, u16),
+    Methodref(u16, u16),
+}
+
+/// Constant pool with deduplicated entries, indexed from 1.
+#[derive(Debug, Default)]
+struct ConstantPool {
+    entries: Vec<Constant>,
+    indices: HashMap<Constant, u16>,
+}
+
+impl ConstantPool {
+    fn add(&mut self, constant: Constant) -> u16 {
+        if let Some(index) = self.indices.get(&constant) {
+            return *index;
+        }
+        self.entries.push(constant.clone());
+        let index = self.entries.len() as u16;
+        self.indices.insert(constant, index);
+        index
+    }
+
+    fn utf8(&mut self, text: &str) -> u16 {
+        self.add(Constant::Utf8(text.to_string()))
+    }
+
+    fn class(&mut self, name: &str) -> u16 {
+        let name = self.utf8(name);
+        self.add(Constant::Class(name))
+    }
+
+    fn name_and_type(&mut self, name: &str, descriptor: &str) -> u16 {
+        let (name, descriptor) = (self.utf8(name), self.utf8(descriptor));
+        self.add(Constant::NameAndType(name, descriptor))
+    }
+
+    fn field(&mut self, class: &str, name: &str, descriptor: &str) -> u16 {
+        let (class, name_and_type) = (self.class(class), self.name_and_type(name, descriptor));
+        self.add(Constant::Fieldref(class, name_and_type))
+    }
+
+    fn method(&mut self, class: &str, name: &str, descriptor: &str) -> u16 {
+        let (class, name_and_type) = (self.class(class), self.name_and_type(name, descriptor));
+        self.add(Constant::Methodref(class, name_and_type))
+    }
+
+    fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend((self.entries.len() as u16 + 1).to_be_bytes());
+        for constant in self.entries.iter() {
+            match constant {
+                Constant::Utf8(text) => {
+                    // modified UTF-8 only differs for NUL and supplementary
+                    // characters, neither of which appear in identifiers
+                    buf.push(1);
+                    buf.extend((text.len() as u16).to_be_bytes());
+                    buf.extend(text.as_bytes());
+                }
+                Constant::Integer(value) => {
+                    buf.push(3);
+                    buf.extend(value.to_be_bytes());
+                }
+                Constant::Class(name) => {
+                    buf.push(7);
+                    buf.extend(name.to_be_bytes());
+                }
+                Constant::NameAndType(name, descriptor) => {
+                    buf.push(12);
+                    buf.extend(name.to_be_bytes());
+                    buf.extend(descriptor.to_be_bytes());
+                }
+                Constant::Fieldref(class, name_and_type) => {
+                    buf.push(9);
+                    buf.extend(class.to_be_bytes());
+                    buf.extend(name_and_type.to_be_bytes());
+                }
+                Constant::Methodref(class, name_and_type) => {
+                    buf.push(10);
+                    buf.extend(class.to_be_bytes());
+                    buf.extend(name_and_type.to_be_bytes());
+                }
+            }
+        }
+    }
+}
+
+mod opcode {
+    pub const ICONST_0: u8 = 0x03;
+    pub const ICONST_1: u8 = 0x04;
+    pub const BIPUSH: u8 = 0x10;
+    pub const SIPUSH: u8 = 0x11;
+    pub const LDC: u8 = 0x12;
+    pub const LDC_W: u8 = 0x13;
+    pub const ILOAD: u8 = 0x15;
+    pub const ILOAD_0: u8 = 0x1a;
+    pub const ISTORE: u8 = 0x36;
+    pub const ISTORE_0: u8 = 0x3b;
+    pub const POP: u8 = 0x57;
+    pub const IADD: u8 = 0x60;
+    pub const ISUB: u8 = 0x64;
+    pub const IMUL: u8 = 0x68;
+    pub const INEG: u8 = 0x74;
+    pub const IFEQ: u8 = 0x99;
+    pub const IFLT: u8 = 0x9b;
+    pub const IF_ICMPNE: u8 = 0xa0;
+    pub const IF_ICMPGT: u8 = 0xa3;
+    pub const IF_ICMPLE: u8 = 0xa4;
+    pub const GOTO: u8 = 0xa7;
+    pub const IRETURN: u8 = 0xac;
+    pub const RETURN: u8 = 0xb1;
+    pub const GETSTATIC: u8 = 0xb2;
+    pub const INVOKEVIRTUAL: u8 = 0xb6;
+    pub const INVOKESTATIC: u8 = 0xb8;
+    pub const WIDE: u8 = 0xc4;
+}
+
+/// Verification type of a local in a stack map frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VerificationType {
+    Top = 0,
+    Integer = 1,
+}
+
+/// Bytecode for one method, with labels for branches.
+#[derive(Debug, Default)]
+struct CodeBuilder {
+    code: Vec<u8>,
+    labels: Vec<Option<u16>>,
+    // (offset of the branch instruction, offset of its operand, label)
+    fixups: Vec<(u16, usize, usize)>,
+    // (label, locals at that label) for the StackMapTable
+    frames: Vec<(usize, Vec<VerificationType>)>,
+}
+
+impl CodeBuilder {
+    fn op(&mut self, opcode: u8) {
+        self.code.push(opcode);
+    }
+
+    fn op_u8(&mut self, opcode: u8, operand: u8) {
+        self.code.extend([opcode, operand]);
+    }
+
+    fn op_u16(&mut self, opcode: u8, operand: u16) {
+        self.code.push(opcode);
+        self.code.extend(operand.to_be_bytes());
+    }
+
+    fn label(&mut self) -> usize {
+        self.labels.push(None);
+        self.labels.len() - 1
+    }
+
+    // place `label` here; it is a branch target, so record its frame
+    fn bind(&mut self, label: usize, locals: &[VerificationType]) {
+        self.labels[label] = Some(self.code.len() as u16);
+        self.frames.push((label, locals.to_vec()));
+    }
+
+    fn branch(&mut self, opcode: u8, label: usize) {
+        let at = self.code.len() as u16;
+        self.code.push(opcode);
+        self.fixups.push((at, self.code.len(), label));
+        self.code.extend([0, 0]);
+    }
+
+    // local variable access, with the short and `wide` forms
+    fn local(&mut self, short: u8, long: u8, index: u16) {
+        match index {
+            0..=3 => self.op(short + index as u8),
+            4..=255 => self.op_u8(long, index as u8),
+            _ => {
+                self.op(opcode::WIDE);
+                self.op_u16(long, index);
+            }
+        }
+    }
+
+    fn push_int(&mut self, pool: &mut ConstantPool, value: i32) {
+        match value {
+            -1..=5 => self.op((opcode::ICONST_0 as i32 + value) as u8),
+            -128..=127 => self.op_u8(opcode::BIPUSH, value as u8),
+            -32768..=32767 => self.op_u16(opcode::SIPUSH, value as u16),
+            _ => match pool.add(Constant::Integer(value)) {
+                index @ 0..=255 => self.op_u8(opcode::LDC, index as u8),
+                index => self.op_u16(opcode::LDC_W, index),
+            },
+        }
+    }
+
+    // resolve branches, returning the code and its StackMapTable entries
+    fn finish(mut self) -> (Vec<u8>, Vec<u8>) {
+        for (at, operand, label) in self.fixups.iter() {
+            let target = self.labels[*label].expect("unbound label");
+            let offset = (target as i32 - *at as i32) as i16;
+            self.code[*operand..*operand + 2].copy_from_slice(&offset.to_be_bytes());
+        }
+
+        let mut frames: Vec<(u16, Vec<VerificationType>)> = self
+            .frames
+            .iter()
+            .map(|(label, locals)| (self.labels[*label].unwrap(), locals.clone()))
+            .collect();
+        frames.sort_by_key(|(offset, _)| *offset);
+        let mut table = Vec::new();
+        table.extend((frames.len() as u16).to_be_bytes());
+        // the implicit initial frame of a static method with no arguments
+        let (mut previous_offset, mut previous_locals) = (None, Vec::new());
+        for (offset, locals) in frames {
+            let delta = match previous_offset {
+                Some(previous) => offset - previous - 1,
+                None => offset,
+            };
+            if locals == previous_locals && delta < 64 {
+                table.push(delta as u8);
+            } else if locals == previous_locals {
+                table.push(251);
+                table.extend(delta.to_be_bytes());
+            } else if locals.len() > previous_locals.len()
+                && locals.len() - previous_locals.len() <= 3
+                && locals.starts_with(&previous_locals)
+            {
+                table.push(251 + (locals.len() - previous_locals.len()) as u8);
+                table.extend(delta.to_be_bytes());
+                for local in locals[previous_locals.len()..].iter() {
+                    table.push(*local as u8);
+                }
+            } else {
+                table.push(255);
+                table.extend(delta.to_be_bytes());
+                table.extend((locals.len() as u16).to_be_bytes());
+                table.extend(locals.iter().map(|local| *local as u8));
+                // empty operand stack
+                table.extend(0u16.to_be_bytes());
+            }
+            (previous_offset, previous_locals) = (Some(offset), locals);
+        }
+        (self.code, table)
+    }
+}
+
+struct Method {
+    access: u16,
+    name: &'static str,
+    descriptor: &'static str,
+    max_stack: u16,
+    max_locals: u16,
+    code: Vec<u8>,
+    stack_map: Vec<u8>,
+}
+
+const ACC_PUBLIC: u16 = 0x0001;
+const ACC_PRIVATE: u16 = 0x0002;
+const ACC_STATIC: u16 = 0x0008;
+const ACC_SUPER: u16 = 0x0020;
+
+// static int readInt(): skip whitespace, then an optional `-` and digits.
+// locals: 0 the current character, 1 the value, 2 whether it is negative
+fn read_int(pool: &mut ConstantPool) -> Method {
+    use opcode::*;
+    use VerificationType::{Integer, Top};
+
+    let stdin = pool.field("java/lang/System", "in", "Ljava/io/InputStream;");
+    let read = pool.method("java/io/InputStream", "read", "()I");
+    let mut b = CodeBuilder::default();
+    let read_char = |b: &mut CodeBuilder| {
+        b.op_u16(GETSTATIC, stdin);
+        b.op_u16(INVOKEVIRTUAL, read);
+        b.op(ISTORE_0);
+    };
+    let (skip, digit, done, positive) = (b.label(), b.label(), b.label(), b.label());
+
+    b.op(ICONST_0);
+    b.op(ISTORE_0 + 1);
+    b.op(ICONST_0);
+    b.op(ISTORE_0 + 2);
+    b.bind(skip, &[Top, Integer, Integer]);
+    read_char(&mut b);
+    b.op(ILOAD_0);
+    b.branch(IFLT, done);
+    b.op(ILOAD_0);
+    b.op_u8(BIPUSH, b' ');
+    b.branch(IF_ICMPLE, skip);
+    b.op(ILOAD_0);
+    b.op_u8(BIPUSH, b'-');
+    b.branch(IF_ICMPNE, digit);
+    b.op(ICONST_1);
+    b.op(ISTORE_0 + 2);
+    read_char(&mut b);
+
+    b.bind(digit, &[Integer, Integer, Integer]);
+    b.op(ILOAD_0);
+    b.op_u8(BIPUSH, b'0');
+    b.op(ISUB);
+    b.op(ISTORE_0);
+    b.op(ILOAD_0);
+    b.branch(IFLT, done);
+    b.op(ILOAD_0);
+    b.op_u8(BIPUSH, 9);
+    b.branch(IF_ICMPGT, done);
+    b.op(ILOAD_0 + 1);
+    b.op_u8(BIPUSH, 10);
+    b.op(IMUL);
+    b.op(ILOAD_0);
+    b.op(IADD);
+    b.op(ISTORE_0 + 1);
+    read_char(&mut b);
+    b.branch(GOTO, digit);
+
+    b.bind(done, &[Integer, Integer, Integer]);
+    b.op(ILOAD_0 + 2);
+    b.branch(IFEQ, positive);
+    b.op(ILOAD_0 + 1);
+    b.op(INEG);
+    b.op(IRETURN);
+    b.bind(positive, &[Integer, Integer, Integer]);
+    b.op(ILOAD_0 + 1);
+    b.op(IRETURN);
+
+    let (code, stack_map) = b.finish();
+    Method {
+        access: ACC_PRIVATE | ACC_STATIC,
+        name: "readInt",
+        descriptor: "()I",
+        max_stack: 2,
+        max_locals: 3,
+        code,
+        stack_map,
+    }
+}
+
+// public static void main(String[] args) running the program
+fn main_method(program: &Program, class: &str, pool: &mut ConstantPool) -> Result<Method, String> {
+    use opcode::*;
+
+    let stdout = pool.field("java/lang/System", "out", "Ljava/io/PrintStream;");
+    let println = pool.method("java/io/PrintStream", "println", "(I)V");
+    let read_int = pool.method(class, "readInt", "()I");
+    let local = |vreg: VReg| vreg as u16 + 1;
+    let mut b = CodeBuilder::default();
+
+    // the verifier rejects reading a local before it is assigned, so
+    // registers that can be read first start out as zero
+    let mut assigned = BTreeSet::new();
+    for inst in program.insts.iter() {
+        for vreg in inst.uses() {
+            if assigned.insert(vreg) {
+                b.op(ICONST_0);
+                b.local(ISTORE_0, ISTORE, local(vreg));
+            }
+        }
+        assigned.extend(inst.def());
+    }
+
+    let mut push = |b: &mut CodeBuilder, value: Value| match value {
+        Value::Reg(vreg) => b.local(ILOAD_0, ILOAD, local(vreg)),
+        Value::Imm(imm) => b.push_int(pool, imm),
+    };
+    for inst in program.insts.iter() {
+        match inst {
+            Inst::Call { .. } => return Err(backend::NO_PROCEDURES.to_string()),
+            Inst::Read { dst } => {
+                b.op_u16(INVOKESTATIC, read_int);
+                match dst {
+                    Some(dst) => b.local(ISTORE_0, ISTORE, local(*dst)),
+                    None => b.op(POP),
+                }
+            }
+            Inst::Write { src } => {
+                b.op_u16(GETSTATIC, stdout);
+                push(&mut b, *src);
+                b.op_u16(INVOKEVIRTUAL, println);
+            }
+            Inst::Copy { dst, src } => {
+                push(&mut b, *src);
+                b.local(ISTORE_0, ISTORE, local(*dst));
+            }
+            Inst::Binary { op, dst, lhs, rhs } => {
+                push(&mut b, *lhs);
+                push(&mut b, *rhs);
+                b.op(match op {
+                    BinaryOpKind::Add => IADD,
+                    BinaryOpKind::Sub => ISUB,
+                });
+                b.local(ISTORE_0, ISTORE, local(*dst));
+            }
+        }
+    }
+    b.op(RETURN);
+
+    let (code, stack_map) = b.finish();
+    Ok(Method {
+        access: ACC_PUBLIC | ACC_STATIC,
+        name: "main",
+        descriptor: "([Ljava/lang/String;)V",
+        max_stack: 2,
+        max_locals: program.vreg_count as u16 + 1,
+        code,
+        stack_map,
+    })
+}
+
+/// Class name for an output path: its file stem, without the characters
+/// class names cannot contain.
+pub fn class_name(path: &str) -> String {
+    let stem = std::path::Path::new(path)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("Main");
+    stem.replace(['.', ';', '[', '/'], "_")
+}
+
+pub fn generate(program: &Program, class: &str, source_file: &str) -> Result<Vec<u8>, String> {
+    let mut pool = ConstantPool::default();
+    let this_class = pool.class(class);
+    let super_class = pool.class("java/lang/Object");
+    let methods = [main_method(program, class, &mut pool)?, read_int(&mut pool)];
+    let (code_name, stack_map_name) = (pool.utf8("Code"), pool.utf8("StackMapTable"));
+    let method_names: Vec<(u16, u16)> = methods
+        .iter()
+        .map(|method| (pool.utf8(method.name), pool.utf8(method.descriptor)))
+        .collect();
+    let (source_file_name, source_file) = (pool.utf8("SourceFile"), pool.utf8(source_file));
+
+    let mut buf = vec![0xca, 0xfe, 0xba, 0xbe];
+    // version 52.0 (Java 8)
+    buf.extend(0u16.to_be_bytes());
+    buf.extend(52u16.to_be_bytes());
+    pool.write(&mut buf);
+    buf.extend((ACC_PUBLIC | ACC_SUPER).to_be_bytes());
+    buf.extend(this_class.to_be_bytes());
+    buf.extend(super_class.to_be_bytes());
+    // no interfaces, no fields
+    buf.extend([0, 0, 0, 0]);
+
+    buf.extend((methods.len() as u16).to_be_bytes());
+    for (method, (name, descriptor)) in methods.iter().zip(method_names) {
+        buf.extend(method.access.to_be_bytes());
+        buf.extend(name.to_be_bytes());
+        buf.extend(descriptor.to_be_bytes());
+        buf.extend(1u16.to_be_bytes());
+
+        let has_frames = method.stack_map != [0, 0];
+        let mut code = Vec::new();
+        code.extend(method.max_stack.to_be_bytes());
+        code.extend(method.max_locals.to_be_bytes());
+        code.extend((method.code.len() as u32).to_be_bytes());
+        code.extend(&method.code);
+        // no exception handlers
+        code.extend(0u16.to_be_bytes());
+        if has_frames {
+            code.extend(1u16.to_be_bytes());
+            code.extend(stack_map_name.to_be_bytes());
+            code.extend((method.stack_map.len() as u32).to_be_bytes());
+            code.extend(&method.stack_map);
+        } else {
+            code.extend(0u16.to_be_bytes());
+        }
+        buf.extend(code_name.to_be_bytes());
+        buf.extend((code.len() as u32).to_be_bytes());
+        buf.extend(code);
+    }
+
+    buf.extend(1u16.to_be_bytes());
+    buf.extend(source_file_name.to_be_bytes());
+    buf.extend(2u32.to_be_bytes());
+    buf.extend(source_file.to_be_bytes());
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+
+    use super::*;
+
+    struct ParsedMethod {
+        name: String,
+        descriptor: String,
+        max_locals: u16,
+        code: Vec<u8>,
+        // attribute name -> contents, for the Code attribute's attributes
+        attributes: Vec<(String, Vec<u8>)>,
+    }
+
+    struct ParsedClass {
+        // utf8 entries by pool index, others as None
+        utf8: Vec<Option<String>>,
+        integers: Vec<i32>,
+        this_class: String,
+        methods: Vec<ParsedMethod>,
+    }
+
+    struct Reader<'a>(&'a [u8]);
+
+    impl Reader<'_> {
+        fn take(&mut self, len: usize) -> &[u8] {
+            let (bytes, rest) = self.0.split_at(len);
+            self.0 = rest;
+            bytes
+        }
+
+        fn u8(&mut self) -> u8 {
+            self.take(1)[0]
+        }
+
+        fn u16(&mut self) -> u16 {
+            u16::from_be_bytes(self.take(2).try_into().unwrap())
+        }
+
+        fn u32(&mut self) -> u32 {
+            u32::from_be_bytes(self.take(4).try_into().unwrap())
+        }
+    }
+
+    fn parse(bytes: &[u8]) -> ParsedClass {
+        let mut r = Reader(bytes);
+        assert_eq!(r.u32(), 0xcafebabe);
+        assert_eq!((r.u16(), r.u16()), (0, 52));
+
+        let count = r.u16();
+        let (mut utf8, mut integers, mut classes) = (vec![None], Vec::new(), Vec::new());
+        for index in 1..count {
+            utf8.push(None);
+            match r.u8() {
+                1 => {
+                    let len = r.u16() as usize;
+                    utf8[index as usize] = Some(String::from_utf8(r.take(len).to_vec()).unwrap());
+                }
+                3 => integers.push(r.u32() as i32),
+                7 => classes.push((index, r.u16())),
+                9 | 10 | 12 => {
+                    r.u32();
+                }
+                tag => panic!("unexpected constant tag {}", tag),
+            }
+        }
+        let name = |utf8: &[Option<String>], index: u16| utf8[index as usize].clone().unwrap();
+        let class_name = |index: u16| {
+            let (_, name_index) = classes.iter().find(|(i, _)| *i == index).unwrap();
+            name(&utf8, *name_index)
+        };
+
+        assert_eq!(r.u16(), ACC_PUBLIC | ACC_SUPER);
+        let this_class = class_name(r.u16());
+        assert_eq!(class_name(r.u16()), "java/lang/Object");
+        assert_eq!((r.u16(), r.u16()), (0, 0));
+
+        let mut methods = Vec::new();
+        for _ in 0..r.u16() {
+            r.u16();
+            let (method_name, descriptor) = (name(&utf8, r.u16()), name(&utf8, r.u16()));
+            assert_eq!(r.u16(), 1);
+            assert_eq!(name(&utf8, r.u16()), "Code");
+            let len = r.u32() as usize;
+            let mut code = Reader(r.take(len));
+            let (_, max_locals) = (code.u16(), code.u16());
+            let code_len = code.u32() as usize;
+            let bytecode = code.take(code_len).to_vec();
+            assert_eq!(code.u16(), 0);
+            let mut attributes = Vec::new();
+            for _ in 0..code.u16() {
+                let attribute = name(&utf8, code.u16());
+                let len = code.u32() as usize;
+                attributes.push((attribute, code.take(len).to_vec()));
+            }
+            assert!(code.0.is_empty());
+            methods.push(ParsedMethod {
+                name: method_name,
+                descriptor,
+                max_locals,
+                code: bytecode,
+                attributes,
+            });
+        }
+        assert_eq!(r.u16(), 1);
+        assert_eq!(name(&utf8, r.u16()), "SourceFile");
+        assert_eq!(r.u32(), 2);
+        r.u16();
+        assert!(r.0.is_empty());
+        ParsedClass {
+            utf8,
+            integers,
+            this_class,
+            methods,
+        }
+    }
+
+    fn compile(source: &str, class: &str) -> ParsedClass {
+        parse(&generate(&testing::lower_source(source), class, "test.m").unwrap())
+    }
+
+    #[test]
+    fn handle_class_structure() {
+        let class = compile("begin read(A); B := A - 70000; write(B, 7, Z); end", "Test");
+        assert_eq!(class.this_class, "Test");
+        assert_eq!(class.integers, [70000]);
+        assert!(class.utf8.contains(&Some("test.m".to_string())));
+
+        let main = &class.methods[0];
+        assert_eq!(
+            (main.name.as_str(), main.descriptor.as_str()),
+            ("main", "([Ljava/lang/String;)V")
+        );
+        assert_eq!(main.max_locals, 5);
+        assert!(main.attributes.is_empty());
+        let ops: Vec<u8> = {
+            use opcode::*;
+            let mut ops = Vec::new();
+            let mut pc = 0;
+            while pc < main.code.len() {
+                let op = main.code[pc];
+                ops.push(op);
+                pc += match op {
+                    GETSTATIC | INVOKEVIRTUAL | INVOKESTATIC | SIPUSH => 3,
+                    BIPUSH | LDC | ILOAD | ISTORE => 2,
+                    _ => 1,
+                };
+            }
+            ops
+        };
+        assert_eq!(
+            ops,
+            [
+                // Z is read without being assigned
+                opcode::ICONST_0,
+                opcode::ISTORE,
+                opcode::INVOKESTATIC,
+                opcode::ISTORE_0 + 1,
+                opcode::ILOAD_0 + 1,
+                opcode::LDC,
+                opcode::ISUB,
+                opcode::ISTORE_0 + 2,
+                opcode::GETSTATIC,
+                opcode::ILOAD_0 + 2,
+                opcode::INVOKEVIRTUAL,
+                opcode::GETSTATIC,
+                opcode::BIPUSH,
+                opcode::INVOKEVIRTUAL,
+                opcode::GETSTATIC,
+                opcode::ILOAD,
+                opcode::INVOKEVIRTUAL,
+                opcode::RETURN,
+            ]
+        );
+    }
+
+    #[test]
+    fn handle_stack_map_frames() {
+        let class = compile("begin read(A); end", "Test");
+        let read_int = &class.methods[1];
+        assert_eq!(
+            (read_int.name.as_str(), read_int.descriptor.as_str()),
+            ("readInt", "()I")
+        );
+        assert_eq!(read_int.attributes.len(), 1);
+        let (name, table) = &read_int.attributes[0];
+        assert_eq!(name, "StackMapTable");
+
+        let mut r = Reader(table);
+        let mut offset = -1;
+        let mut frames = Vec::new();
+        for _ in 0..r.u16() {
+            let frame_type = r.u8();
+            let delta = match frame_type {
+                0..=63 => frame_type as u16,
+                252..=254 => {
+                    let delta = r.u16();
+                    r.take(frame_type as usize - 251);
+                    delta
+                }
+                255 => {
+                    let delta = r.u16();
+                    let locals = r.u16() as usize;
+                    assert_eq!(r.take(locals), [1, 1, 1]);
+                    assert_eq!(r.u16(), 0);
+                    delta
+                }
+                _ => panic!("unexpected frame type {}", frame_type),
+            };
+            offset += delta as i32 + 1;
+            frames.push((frame_type, offset));
+        }
+        assert!(r.0.is_empty());
+        // the skip loop adds the locals, the digit loop has them all assigned
+        assert_eq!(
+            frames.iter().map(|(t, _)| *t).collect::<Vec<_>>()[..2],
+            [254, 255]
+        );
+        assert_eq!(frames.len(), 4);
+        // every frame starts an instruction that a branch in the code targets
+        for (_, offset) in frames.iter() {
+            assert!((*offset as usize) < read_int.code.len());
+        }
+    }
+
+    #[test]
+    fn handle_wide_locals() {
+        let mut source = String::from("begin ");
+        for i in 0..300 {
+            source.push_str(format!("V{} := {}; ", i, i).as_str());
+        }
+        source.push_str("write(V299); end");
+        let main = &compile(&source, "Wide").methods[0];
+        assert_eq!(main.max_locals, 301);
+        // `istore 300` needs the wide form
+        assert!(main
+            .code
+            .windows(4)
+            .any(|w| w == [opcode::WIDE, opcode::ISTORE, 0x01, 0x2c]));
+        assert_eq!(class_name("out/My.Prog.class"), "My_Prog");
+    }
+}
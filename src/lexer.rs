@@ -1,6 +1,8 @@
+use std::fmt;
 use std::str::Chars;
 
 use crate::char_utils;
+use crate::diagnostic::{Diagnostic, Span};
 
 const EOF_CHAR: char = '\0';
 
@@ -11,8 +13,12 @@ pub enum TokenType {
     End,
     Read,
     Write,
+    Module,
+    Import,
+    Var,
+    Procedure,
     Identifier { name: Box<str> },
-    IntLiteral { value: i32 },
+    IntLiteral { value: u32 },
     LeftParen,
     RightParen,
     Semicolon,
@@ -20,7 +26,7 @@ pub enum TokenType {
     OpAssign,
     OpPlus,
     OpMinus,
-    LineComment,
+    LineComment { text: Box<str> },
     Unknown,
     ScanEof,
 }
@@ -34,6 +40,10 @@ impl TokenType {
             TokenType::End => "end",
             TokenType::Read => "read",
             TokenType::Write => "write",
+            TokenType::Module => "module",
+            TokenType::Import => "import",
+            TokenType::Var => "var",
+            TokenType::Procedure => "procedure",
             TokenType::Identifier { name: _ } => "Identifier",
             TokenType::IntLiteral { value: _ } => "IntLiteral",
             TokenType::LeftParen => "(",
@@ -42,7 +52,7 @@ impl TokenType {
             TokenType::OpAssign => ":=",
             TokenType::OpPlus => "+",
             TokenType::OpMinus => "-",
-            TokenType::LineComment => "LineComment",
+            TokenType::LineComment { text: _ } => "LineComment",
             TokenType::Unknown => "Unknown",
             TokenType::ScanEof => "ScanEof",
         }
@@ -55,17 +65,17 @@ pub struct Token {
     length: u32,
     // line: usize,
     // column: usize,
-    // offset: usize,
+    pub offset: usize,
 }
 
 impl Token {
-    pub fn new(_lexer: &Lexer) -> Token {
+    pub fn new(lexer: &Lexer) -> Token {
         Token {
             token_type: TokenType::Unknown,
             length: 0,
-            // line: _lexer.line,
-            // column: _lexer.column,
-            // offset: _lexer.offset,
+            // line: lexer.line,
+            // column: lexer.column,
+            offset: lexer.offset,
         }
     }
 
@@ -75,7 +85,7 @@ impl Token {
             length: 0,
             // line: 0,
             // column: 0,
-            // offset: 0,
+            offset: 0,
         }
     }
 
@@ -85,10 +95,14 @@ impl Token {
             length: 0,
             // line: 0,
             // column: 0,
-            // offset: 0,
+            offset: 0,
         }
     }
 
+    pub fn span(&self) -> Span {
+        Span::new(self.offset, self.offset + self.length as usize)
+    }
+
     pub fn set_type(&mut self, token_type: TokenType) {
         self.token_type = token_type
     }
@@ -98,6 +112,43 @@ impl Token {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriviaKind {
+    Whitespace,
+    Comment,
+}
+
+/// Whitespace or a comment, which the parser does not see.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub text: Box<str>,
+}
+
+/// A token with its text and the trivia around it. Trailing trivia runs to
+/// the end of the token's line, and everything after it, the newline
+/// included, leads the next token.
+#[derive(Debug, Clone)]
+pub struct SyntaxToken {
+    pub token: Token,
+    pub text: Box<str>,
+    pub leading: Vec<Trivia>,
+    pub trailing: Vec<Trivia>,
+}
+
+impl fmt::Display for SyntaxToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for trivia in self.leading.iter() {
+            f.write_str(&trivia.text)?;
+        }
+        f.write_str(&self.text)?;
+        for trivia in self.trailing.iter() {
+            f.write_str(&trivia.text)?;
+        }
+        Ok(())
+    }
+}
+
 pub struct Lexer<'a> {
     len_remaining: usize,
     source: &'a str,
@@ -106,6 +157,10 @@ pub struct Lexer<'a> {
     line: usize,
     column: usize,
     offset: usize,
+    /// Turn errors into `Unknown` tokens and `diagnostics` instead of
+    /// panicking.
+    pub recover: bool,
+    pub diagnostics: Vec<Diagnostic>,
 }
 
 impl<'a> Lexer<'a> {
@@ -117,6 +172,8 @@ impl<'a> Lexer<'a> {
             line: 1,
             column: 1,
             offset: 0,
+            recover: false,
+            diagnostics: Vec::new(),
         }
     }
 
@@ -184,7 +241,7 @@ impl<'a> Lexer<'a> {
     }
 
     pub fn next_token(&mut self) -> Token {
-        let mut token = Token::new(&self);
+        let mut token = Token::new(self);
 
         let first_char = match self.bump() {
             Some(c) => c,
@@ -200,7 +257,9 @@ impl<'a> Lexer<'a> {
                 '-' => {
                     self.bump();
                     self.eat_while(|c| c != '\n');
-                    TokenType::LineComment
+                    TokenType::LineComment {
+                        text: self.get_token_string().into(),
+                    }
                 }
                 _ => TokenType::OpMinus,
             },
@@ -216,6 +275,10 @@ impl<'a> Lexer<'a> {
                     "end" => TokenType::End,
                     "read" => TokenType::Read,
                     "write" => TokenType::Write,
+                    "module" => TokenType::Module,
+                    "import" => TokenType::Import,
+                    "var" => TokenType::Var,
+                    "procedure" => TokenType::Procedure,
                     _ => TokenType::Identifier {
                         name: token_string.into(),
                     },
@@ -223,8 +286,14 @@ impl<'a> Lexer<'a> {
             }
             '0'..='9' => {
                 self.eat_while(char_utils::is_digit);
-                TokenType::IntLiteral {
-                    value: self.get_token_string().parse::<i32>().unwrap(),
+                // 2147483648 is only in range after `-`, which the parser checks
+                match self.get_token_string().parse::<u32>() {
+                    Ok(value) if value <= 1 << 31 => TokenType::IntLiteral { value },
+                    _ => {
+                        self.error("integer literal out of range, integers are 32 bits");
+                        // parsing goes on as if it were 0
+                        TokenType::IntLiteral { value: 0 }
+                    }
                 }
             }
             '(' => TokenType::LeftParen,
@@ -236,13 +305,13 @@ impl<'a> Lexer<'a> {
                     self.bump();
                     TokenType::OpAssign
                 }
-                _ => panic!(),
+                _ => self.error("expected `:=`"),
             },
             '+' => TokenType::OpPlus,
             // Only compile when `usize` is larger or equal to 32 bit.
             _ => {
                 self.eat_until(char_utils::is_expected);
-                self.syntax_error("unexpected char(s)")
+                self.error("unexpected char(s)")
             }
         };
         token.set_type(token_type);
@@ -252,7 +321,7 @@ impl<'a> Lexer<'a> {
     }
 
     /// Creates an iterator that produces tokens from the input string.
-    pub fn tokenize(&'a mut self) -> impl Iterator<Item = Token> + '_ {
+    pub fn tokenize(&mut self) -> impl Iterator<Item = Token> + use<'a, '_> {
         std::iter::from_fn(move || {
             let mut token = self.next_token();
             loop {
@@ -269,9 +338,77 @@ impl<'a> Lexer<'a> {
             }
         })
     }
+
+    /// The tokens up to the end, whitespace left out, collected so that the
+    /// lexer is free to be asked for its `diagnostics`.
+    pub fn tokens(&mut self) -> Vec<Token> {
+        self.tokenize().collect()
+    }
+
+    /// Every token with its trivia attached, so that their text adds up to
+    /// the source. The last token is `ScanEof`, which leads with whatever
+    /// follows the last real token.
+    pub fn tokenize_with_trivia(&mut self) -> Vec<SyntaxToken> {
+        let mut tokens: Vec<SyntaxToken> = Vec::new();
+        let mut leading = Vec::new();
+        loop {
+            let token = self.next_token();
+            let span = token.span();
+            let text: Box<str> = self.source[span.start..span.end].into();
+            let kind = match token.token_type {
+                TokenType::Whitespace => TriviaKind::Whitespace,
+                TokenType::LineComment { text: _ } => TriviaKind::Comment,
+                _ => {
+                    let eof = token.token_type == TokenType::ScanEof;
+                    tokens.push(SyntaxToken {
+                        token,
+                        text,
+                        leading: std::mem::take(&mut leading),
+                        trailing: Vec::new(),
+                    });
+                    if eof {
+                        return tokens;
+                    }
+                    continue;
+                }
+            };
+            // still on the line of the last token
+            let last = match tokens.last_mut() {
+                Some(last) if leading.is_empty() => last,
+                _ => {
+                    leading.push(Trivia { kind, text });
+                    continue;
+                }
+            };
+            match text.find('\n') {
+                Some(newline) => {
+                    if newline > 0 {
+                        last.trailing.push(Trivia {
+                            kind,
+                            text: text[..newline].into(),
+                        });
+                    }
+                    leading.push(Trivia {
+                        kind,
+                        text: text[newline..].into(),
+                    });
+                }
+                None => last.trailing.push(Trivia { kind, text }),
+            }
+        }
+    }
 }
 
 impl Lexer<'_> {
+    fn error(&mut self, msg: &str) -> TokenType {
+        if !self.recover {
+            self.syntax_error(msg)
+        }
+        let span = Span::new(self.offset, self.offset + self.token_length() as usize);
+        self.diagnostics.push(Diagnostic::error(span, msg));
+        TokenType::Unknown
+    }
+
     fn syntax_error(&mut self, msg: &str) -> ! {
         let len = self.token_length() as usize;
         let lines: Vec<&str> = self.source.lines().collect();
@@ -374,9 +511,40 @@ mod tests {
         lexer.test_loop();
     }
 
+    #[test]
+    fn handle_int_range() {
+        let mut lexer = Lexer::new("2147483648 99999999999 4294967296");
+        lexer.recover = true;
+        let values: Vec<TokenType> = lexer.tokens().into_iter().map(|t| t.token_type).collect();
+        assert_eq!(
+            values,
+            [2147483648, 0, 0].map(|value| TokenType::IntLiteral { value })
+        );
+        let spans: Vec<Span> = lexer.diagnostics.iter().map(|d| d.span).collect();
+        assert_eq!(spans, [Span::new(11, 22), Span::new(23, 33)]);
+    }
+
     #[test]
     fn handle_a_plus_b() {
         let mut lexer = Lexer::new(r#"  begin read(a, b); write(a + b); end"#);
         lexer.print_token_list();
     }
+
+    #[test]
+    fn handle_trivia() {
+        let source = "-- one\nbegin  -- two\n\n  A := 1;\nend\n";
+        let tokens = Lexer::new(source).tokenize_with_trivia();
+        let text: String = tokens.iter().map(|t| t.to_string()).collect();
+        assert_eq!(text, source);
+
+        let trivia =
+            |list: &[Trivia]| -> Vec<String> { list.iter().map(|t| t.text.to_string()).collect() };
+        assert_eq!(trivia(&tokens[0].leading), ["-- one", "\n"]);
+        assert_eq!(trivia(&tokens[0].trailing), ["  ", "-- two"]);
+        assert_eq!(trivia(&tokens[1].leading), ["\n\n  "]);
+        assert_eq!(tokens[1].trailing[0].kind, TriviaKind::Whitespace);
+        let eof = tokens.last().unwrap();
+        assert_eq!(eof.token.token_type, TokenType::ScanEof);
+        assert_eq!(trivia(&eof.leading), ["\n"]);
+    }
 }
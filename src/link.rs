@@ -0,0 +1,141 @@
+//! `microc link`: put the MIPS assembly of a program and the modules it
+//! imports together into one file for SPIM or MARS.
+//!
+//! The files are read as text, so hand-written ones link too. Every label
+//! used must be defined exactly once, and a label used in another file than
+//! the one defining it must be exported there with `.globl`.
+
+use std::collections::BTreeMap;
+
+#[derive(Default)]
+struct Symbols<'a> {
+    // label, by the file defining it
+    defined: Vec<(&'a str, &'a str)>,
+    exported: Vec<(&'a str, &'a str)>,
+    used: Vec<(&'a str, &'a str)>,
+}
+
+// the labels `text` defines, exports and uses
+fn scan<'a>(file: &'a str, text: &'a str, symbols: &mut Symbols<'a>) {
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if let Some(label) = line.strip_suffix(':') {
+            symbols.defined.push((label, file));
+            continue;
+        }
+        let (mnemonic, operands) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let operands: Vec<&str> = operands.split(',').map(str::trim).collect();
+        let label = match (mnemonic, operands.as_slice()) {
+            (".globl", [label]) => {
+                symbols.exported.push((label, file));
+                continue;
+            }
+            ("jal", [target]) => Some(*target),
+            ("lw" | "sw" | "la", [_, address]) => Some(*address),
+            _ => None,
+        };
+        // `lw $t0, 4($fp)` reads the stack, not a label
+        if let Some(label) = label.filter(|l| !l.contains('(') && l.parse::<i32>().is_err()) {
+            symbols.used.push((label, file));
+        }
+    }
+}
+
+/// The files, named and with their contents, in one listing; or one message
+/// per label that is missing, defined twice, or not exported.
+pub fn link(files: &[(String, String)]) -> Result<String, Vec<String>> {
+    let mut symbols = Symbols::default();
+    for (file, text) in files.iter() {
+        scan(file, text, &mut symbols);
+    }
+    let mut errors = Vec::new();
+    let mut defined = BTreeMap::new();
+    for (label, file) in symbols.defined.iter() {
+        match defined.get(label) {
+            Some(first) if first == file => {
+                errors.push(format!("`{}` is defined twice in `{}`", label, file))
+            }
+            Some(first) => errors.push(format!(
+                "`{}` is defined in both `{}` and `{}`",
+                label, first, file
+            )),
+            None => _ = defined.insert(*label, *file),
+        }
+    }
+    if !defined.contains_key("main") {
+        errors.push("no file defines `main`".to_string());
+    }
+    let mut reported = Vec::new();
+    for (label, file) in symbols.used.iter() {
+        if reported.contains(&(label, file)) {
+            continue;
+        }
+        reported.push((label, file));
+        match defined.get(label) {
+            None => errors.push(format!("`{}`, used in `{}`, is not defined", label, file)),
+            Some(home) if home != file && !symbols.exported.contains(&(label, home)) => errors
+                .push(format!(
+                    "`{}`, used in `{}`, is not exported with `.globl` in `{}`",
+                    label, file, home
+                )),
+            Some(_) => {}
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let mut linked = String::new();
+    for (file, text) in files.iter() {
+        linked.push_str(&format!("# {}\n", file));
+        linked.push_str(text);
+        if !text.ends_with('\n') {
+            linked.push('\n');
+        }
+    }
+    Ok(linked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str, text: &str) -> (String, String) {
+        (name.to_string(), text.to_string())
+    }
+
+    #[test]
+    fn handle_link() {
+        let main = file(
+            "main.s",
+            "    .globl main\nmain:\n    jal M.P\n    lw $t0, M.G\n    lw $t1, 4($fp)\n",
+        );
+        let module = file(
+            "m.s",
+            "    .globl M.P\nM.P:\n    sw $t0, M.G # store\n    jr $ra\n    .data\n    .globl M.G\nM.G:\n    .word 0\n",
+        );
+        let linked = link(&[main.clone(), module.clone()]).unwrap();
+        assert!(linked.starts_with("# main.s\n    .globl main\n"));
+        assert!(linked.contains("# m.s\n    .globl M.P\n"));
+
+        let copy = file("copy.s", "M.P:\n    jr $ra\nM.G:\n    .word 0\n");
+        assert_eq!(
+            link(&[main.clone(), module.clone(), copy]),
+            Err(vec![
+                "`M.P` is defined in both `m.s` and `copy.s`".to_string(),
+                "`M.G` is defined in both `m.s` and `copy.s`".to_string(),
+            ])
+        );
+        let hidden = file("n.s", "M.P:\n    jr $ra\n");
+        assert_eq!(
+            link(&[main, hidden]),
+            Err(vec![
+                "`M.P`, used in `main.s`, is not exported with `.globl` in `n.s`".to_string(),
+                "`M.G`, used in `main.s`, is not defined".to_string(),
+            ])
+        );
+        assert_eq!(
+            link(&[module]),
+            Err(vec!["no file defines `main`".to_string()])
+        );
+    }
+}
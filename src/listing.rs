@@ -0,0 +1,131 @@
+//! `--emit=listing`: the MIPS code interleaved with the source it comes
+//! from.
+//!
+//! A header lays out the stack frame and says which variables live in
+//! registers. In `main`, the code of each statement follows the source lines
+//! up to it and a count of its instructions. Everything added is a comment,
+//! so the listing still assembles.
+
+use crate::codegen::{CodeGenerator, Operand};
+use crate::diagnostic::line_column;
+use crate::mips::{self, Instr};
+
+/// The listing of `listing`, assembled by `cg` with `source` set.
+pub fn render(source: &str, cg: &CodeGenerator, listing: &[Instr]) -> String {
+    let mut out = format!("# frame: {} bytes\n", cg.frame_size);
+    for (offset, slot) in cg.frame_layout() {
+        out.push_str(&format!("#   {:>3}($fp)  {}\n", offset, slot));
+    }
+    let (mut registers, mut globals, mut unused) = (Vec::new(), Vec::new(), Vec::new());
+    for (name, operand) in cg.symbol_map.iter() {
+        match operand {
+            Operand::Reg(reg) => registers.push(format!("{} {}", name, reg)),
+            Operand::Global(_) => globals.push(name.as_str()),
+            Operand::Imm(_) => unused.push(name.as_str()),
+            Operand::Mem(_) => {}
+        }
+    }
+    if !registers.is_empty() {
+        out.push_str(&format!("# in registers: {}\n", registers.join(", ")));
+    }
+    if !globals.is_empty() {
+        out.push_str(&format!("# in .data: {}\n", globals.join(", ")));
+    }
+    if !unused.is_empty() {
+        out.push_str(&format!("# optimized out: {}\n", unused.join(", ")));
+    }
+
+    let lines: Vec<&str> = source.lines().collect();
+    // source lines printed so far
+    let mut shown = 0;
+    let mut show_until = |out: &mut String, line: usize| {
+        for (i, text) in lines.iter().enumerate().take(line).skip(shown) {
+            out.push_str(&format!("#{:>4} | {}\n", i + 1, text.trim_end()));
+        }
+        shown = shown.max(line);
+    };
+    let mut statements = cg.statements.iter().enumerate().peekable();
+    for (i, instr) in listing.iter().enumerate() {
+        if i == cg.body.end {
+            show_until(&mut out, lines.len());
+        }
+        if let Some((k, (start, span))) = statements.next_if(|(_, (start, _))| *start == i) {
+            let end = cg
+                .statements
+                .get(k + 1)
+                .map_or(cg.body.end, |(next, _)| *next);
+            let count = listing[*start..end]
+                .iter()
+                .filter(|instr| instr.is_instruction())
+                .count();
+            show_until(&mut out, line_column(source, span.start).0);
+            let text: Vec<&str> = source[span.start..span.end].split_whitespace().collect();
+            let plural = if count == 1 { "" } else { "s" };
+            out.push_str(&format!(
+                "    # {}: {} instruction{}\n",
+                text.join(" "),
+                count,
+                plural
+            ));
+            // in place of the `line N:` comment
+            if let Instr::Comment(_) = instr {
+                continue;
+            }
+        }
+        out.push_str(&mips::print(std::slice::from_ref(instr)));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::opt::{self, OptLevel};
+    use crate::testing;
+
+    use super::*;
+
+    #[test]
+    fn handle_listing() {
+        let source = "-- sum\nbegin\n  read(A, B); C := A + B;\n  write(C - 1);\nend\n";
+        let mut program = testing::lower_source(source);
+        opt::optimize(&mut program, OptLevel::O2);
+        let mut cg = CodeGenerator::new();
+        cg.opt_level = OptLevel::O2;
+        cg.source = Some(source.to_string());
+        let listing = cg.assemble(&program);
+        let rendered = render(source, &cg, &listing);
+        let main_end = rendered.find("    # epilogue area").unwrap();
+        assert_eq!(
+            &rendered[..main_end],
+            "# frame: 32 bytes
+#    20($fp)  return address
+#    28($fp)  caller's $fp
+# in registers: A $t2, B $t3, C $t3
+    .text
+    .globl main
+main:
+    # prologue area
+    addi $sp, $sp, -32
+    sw $ra, 20($sp)
+    sw $fp, 28($sp)
+    move $fp, $sp
+#   1 | -- sum
+#   2 | begin
+#   3 |   read(A, B); C := A + B;
+    # read(A, B): 4 instructions
+    jal read
+    move $t2, $v0
+    jal read
+    move $t3, $v0
+    # C := A + B: 1 instruction
+    add $t3, $t2, $t3
+#   4 |   write(C - 1);
+    # write(C - 1): 3 instructions
+    addi $t3, $t3, -1
+    move $a0, $t3
+    jal write
+#   5 | end
+"
+        );
+    }
+}
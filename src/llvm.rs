@@ -0,0 +1,181 @@
+//! LLVM IR backend producing a textual `.ll` module.
+//!
+//! Each program variable gets an `alloca` in the entry block and is
+//! accessed with `load`/`store`, the way clang emits locals before `mem2reg`
+//! runs. Temporaries are defined once, so they map directly onto SSA values.
+//! Every SSA name carries a `.N` suffix, which Micro identifiers cannot
+//! have, so it never clashes with an alloca. The entry block is unlabelled
+//! for the same reason. `read_int` and `write_int` are external, provided
+//! by `RUNTIME` or any other C implementation.
+
+use crate::ast::BinaryOpKind;
+use crate::backend;
+use crate::ir::{Inst, Program, VReg, Value};
+use std::collections::BTreeMap;
+
+/// C implementation of the functions the module declares.
+pub static RUNTIME: &str = r#"#include <stdio.h>
+#include <stdlib.h>
+
+int read_int(void)
+{
+    int value;
+    if (scanf("%d", &value) != 1) {
+        fprintf(stderr, "read: expected an integer\n");
+        exit(1);
+    }
+    return value;
+}
+
+void write_int(int value)
+{
+    printf("%d\n", value);
+}
+"#;
+
+#[derive(Debug, Default)]
+pub struct LlvmGenerator {
+    pub body: Vec<String>,
+    // SSA value or constant currently standing for each temporary
+    temps: BTreeMap<VReg, String>,
+    next_value: u32,
+}
+
+impl LlvmGenerator {
+    pub fn new() -> LlvmGenerator {
+        LlvmGenerator::default()
+    }
+
+    pub fn generate(&mut self, program: &Program, source_name: &str) -> Result<String, String> {
+        let mut buf = format!(
+            "; ModuleID = '{0}'\nsource_filename = \"{0}\"\n\n\
+             declare i32 @read_int()\ndeclare void @write_int(i32)\n\n\
+             define i32 @main() {{\n",
+            source_name
+        );
+        for name in program.vars.keys() {
+            buf.push_str(format!("  %{} = alloca i32\n", name).as_str());
+        }
+        for name in program.vars.keys() {
+            buf.push_str(format!("  store i32 0, ptr %{}\n", name).as_str());
+        }
+        for inst in program.insts.iter() {
+            self.generate_instruction(program, inst)?;
+        }
+        for line in self.body.iter() {
+            buf.push_str(format!("  {}\n", line).as_str());
+        }
+        buf.push_str("  ret i32 0\n}\n");
+        Ok(buf)
+    }
+
+    // calls to the C runtime for `read` and `write`, loads and stores of
+    // variable allocas, and SSA names for temporaries
+    pub fn generate_instruction(&mut self, program: &Program, inst: &Inst) -> Result<(), String> {
+        match inst {
+            Inst::Call { .. } => return Err(backend::NO_PROCEDURES.to_string()),
+            Inst::Read { dst } => {
+                let value = self.fresh("read");
+                self.body.push(format!("{} = call i32 @read_int()", value));
+                if let Some(dst) = dst {
+                    self.define(program, *dst, value);
+                }
+            }
+            Inst::Write { src } => {
+                let value = self.value(program, *src);
+                self.body
+                    .push(format!("call void @write_int(i32 {})", value));
+            }
+            Inst::Copy { dst, src } => {
+                let value = self.value(program, *src);
+                self.define(program, *dst, value);
+            }
+            Inst::Binary { op, dst, lhs, rhs } => {
+                let (lhs, rhs) = (self.value(program, *lhs), self.value(program, *rhs));
+                let value = self.fresh(program.var_name(*dst).unwrap_or("t"));
+                let op = match op {
+                    BinaryOpKind::Add => "add",
+                    BinaryOpKind::Sub => "sub",
+                };
+                self.body
+                    .push(format!("{} = {} i32 {}, {}", value, op, lhs, rhs));
+                self.define(program, *dst, value);
+            }
+        }
+        Ok(())
+    }
+
+    // a new SSA name, unique thanks to the counter suffix
+    fn fresh(&mut self, hint: &str) -> String {
+        self.next_value += 1;
+        format!("%{}.{}", hint, self.next_value)
+    }
+
+    // operand text for `value`, loading variables from their alloca
+    fn value(&mut self, program: &Program, value: Value) -> String {
+        match value {
+            Value::Imm(imm) => imm.to_string(),
+            Value::Reg(vreg) => match program.var_name(vreg) {
+                Some(name) => {
+                    let loaded = self.fresh(name);
+                    self.body
+                        .push(format!("{} = load i32, ptr %{}", loaded, name));
+                    loaded
+                }
+                None => self.temps[&vreg].clone(),
+            },
+        }
+    }
+
+    // make `vreg` hold `value`: a store for variables, a name for temporaries
+    fn define(&mut self, program: &Program, vreg: VReg, value: String) {
+        match program.var_name(vreg) {
+            Some(name) => self
+                .body
+                .push(format!("store i32 {}, ptr %{}", value, name)),
+            None => {
+                self.temps.insert(vreg, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+
+    use super::*;
+
+    fn compile(source: &str) -> String {
+        LlvmGenerator::new()
+            .generate(&testing::lower_source(source), "test.m")
+            .unwrap()
+    }
+
+    #[test]
+    fn handle_allocas() {
+        let ll = compile("begin read(A); B := (A + 1) - 2; write(B, 7); end");
+        assert!(ll.contains("define i32 @main() {\n  %A = alloca i32\n  %B = alloca i32\n"));
+        assert!(ll.contains(
+            "  %read.1 = call i32 @read_int()
+  store i32 %read.1, ptr %A
+  %A.2 = load i32, ptr %A
+  %t.3 = add i32 %A.2, 1
+  %B.4 = sub i32 %t.3, 2
+  store i32 %B.4, ptr %B
+  %B.5 = load i32, ptr %B
+  call void @write_int(i32 %B.5)
+  call void @write_int(i32 7)
+  ret i32 0
+"
+        ));
+    }
+
+    #[test]
+    fn handle_declarations() {
+        let ll = compile("begin end");
+        assert!(ll.starts_with("; ModuleID = 'test.m'\nsource_filename = \"test.m\"\n"));
+        assert!(ll.contains("declare i32 @read_int()\ndeclare void @write_int(i32)\n"));
+        assert!(ll.ends_with("define i32 @main() {\n  ret i32 0\n}\n"));
+    }
+}
@@ -0,0 +1,347 @@
+//! What the server knows about one document: its diagnostics, where each
+//! variable occurs, where the MIPS backend keeps it at -O0, and the `$fp`
+//! slot `microc debug` gives it.
+
+use crate::ast::{ASTBuilder, ExprAST, ExprKind, SyscallKind};
+use crate::codegen::{CodeGenerator, Operand};
+use crate::diagnostic::{line_column, Diagnostic, Severity, Span};
+use crate::ir;
+use crate::lexer::Lexer;
+use crate::module;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Occurrence {
+    pub name: String,
+    /// The procedure the variable is local to; `None` for the program's
+    /// own variables and for globals.
+    pub procedure: Option<String>,
+    pub span: Span,
+    /// Whether the statement assigns the variable here.
+    pub assigned: bool,
+    /// Whether this is the name in a global's `var` declaration.
+    pub declared: bool,
+    /// The statement the occurrence is part of.
+    pub statement: Span,
+}
+
+impl Occurrence {
+    // whether both are occurrences of the same variable
+    fn same(&self, other: &Occurrence) -> bool {
+        self.name == other.name && self.procedure == other.procedure
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Analysis {
+    /// Lexer, parser and semantic diagnostics, in source order.
+    pub diagnostics: Vec<Diagnostic>,
+    /// Every variable occurrence, in source order.
+    pub occurrences: Vec<Occurrence>,
+    /// Where each local variable lives at -O0, by its procedure and name,
+    /// when the unit compiles.
+    pub locations: BTreeMap<(Option<String>, String), Operand>,
+    /// The frame slot the debugger keeps each local variable in, by its
+    /// procedure and name, when the unit compiles.
+    pub slots: BTreeMap<(Option<String>, String), u32>,
+    /// The label of each global the unit declares, by name.
+    pub globals: BTreeMap<String, String>,
+}
+
+// variable occurrences in an expression, which are all reads
+fn collect_reads(expr: &ExprAST, statement: Span, out: &mut Vec<Occurrence>) {
+    match &expr.kind {
+        ExprKind::VariableExprAST { name } => out.push(Occurrence {
+            name: name.to_string(),
+            procedure: None,
+            span: expr.span,
+            assigned: false,
+            declared: false,
+            statement,
+        }),
+        ExprKind::BinaryExprAST { lhs, rhs, .. } => {
+            collect_reads(lhs, statement, out);
+            collect_reads(rhs, statement, out);
+        }
+        _ => {}
+    }
+}
+
+impl Analysis {
+    pub fn new(source: &str) -> Analysis {
+        let mut lexer = Lexer::new(source);
+        lexer.recover = true;
+        let tokens = lexer.tokens();
+        let mut builder = ASTBuilder::new(tokens.into_iter());
+        let unit = builder.parse_unit();
+
+        let mut analysis = Analysis::default();
+        analysis.diagnostics.append(&mut lexer.diagnostics);
+        analysis.diagnostics.append(&mut builder.diagnostics);
+
+        // the names other modules declare are only known to the compiler
+        let imported = !unit.imports.is_empty();
+        let globals: BTreeSet<&str> = unit.globals.iter().map(|(name, _)| &**name).collect();
+        // a declaration counts as the global's definition
+        for (name, span) in unit.globals.iter() {
+            analysis.occurrences.push(Occurrence {
+                name: name.to_string(),
+                procedure: None,
+                span: *span,
+                assigned: true,
+                declared: true,
+                statement: *span,
+            });
+            analysis
+                .globals
+                .insert(name.to_string(), module::label(&unit, name));
+        }
+        for procedure in unit.procedures.iter() {
+            analysis.body(Some(&procedure.name), &procedure.body, &globals, imported);
+        }
+        analysis.body(None, &unit.main, &globals, imported);
+        analysis.occurrences.sort_by_key(|o| o.span);
+
+        let compiles = |analysis: &Analysis| {
+            !imported
+                && !analysis
+                    .diagnostics
+                    .iter()
+                    .any(|d| d.severity == Severity::Error)
+        };
+        let mut resolved = unit.clone();
+        if compiles(&analysis) {
+            analysis
+                .diagnostics
+                .extend(module::resolve(&mut resolved, &[]));
+        }
+        analysis.diagnostics.sort_by_key(|d| d.span);
+
+        if compiles(&analysis) {
+            let labels: BTreeSet<String> = unit
+                .globals
+                .iter()
+                .map(|(name, _)| module::label(&unit, name))
+                .collect();
+            let procedures = resolved
+                .procedures
+                .into_iter()
+                .map(|p| (Some(p.name.to_string()), p.body));
+            for (procedure, body) in procedures.chain([(None, resolved.main)]) {
+                let program = ir::lower_with_globals(body, &labels);
+                // once as compiled, once as `microc debug` lays out the frame
+                for debug in [false, true] {
+                    let mut cg = CodeGenerator::new();
+                    cg.debug = debug;
+                    cg.procedure = procedure.as_ref().map(|p| module::label(&unit, p));
+                    cg.assemble(&program);
+                    for (name, operand) in cg.symbol_map {
+                        let key = (procedure.clone(), name);
+                        match (debug, operand) {
+                            _ if labels.contains(&key.1) => {}
+                            (false, _) => {
+                                analysis.locations.insert(key, operand);
+                            }
+                            (true, Operand::Mem(offset)) => {
+                                analysis.slots.insert(key, offset);
+                            }
+                            (true, _) => {}
+                        }
+                    }
+                }
+            }
+        }
+        analysis
+    }
+
+    // the occurrences in a procedure or the program, with a warning for
+    // each local variable read before it is assigned
+    fn body(
+        &mut self,
+        procedure: Option<&str>,
+        statements: &[ExprAST],
+        globals: &BTreeSet<&str>,
+        imported: bool,
+    ) {
+        // reads come before the assignment in `A := A + 1`, so collect each
+        // statement in evaluation order, then sort
+        let mut assigned = BTreeSet::new();
+        for stmt in statements.iter() {
+            let mut reads = Vec::new();
+            let mut writes = Vec::new();
+            match &stmt.kind {
+                ExprKind::AssignmentAST { var, assign } => {
+                    collect_reads(assign, stmt.span, &mut reads);
+                    collect_reads(var, stmt.span, &mut writes);
+                }
+                ExprKind::SyscallExprAST { calle, args } => {
+                    let out = match calle {
+                        SyscallKind::Read => &mut writes,
+                        SyscallKind::Write => &mut reads,
+                    };
+                    for arg in args.iter() {
+                        collect_reads(arg, stmt.span, out);
+                    }
+                }
+                _ => {}
+            }
+            for occurrence in reads.iter_mut().chain(writes.iter_mut()) {
+                if !globals.contains(occurrence.name.as_str()) {
+                    occurrence.procedure = procedure.map(str::to_string);
+                }
+            }
+            // globals start out as 0, and with imports any name may be one
+            for read in reads.iter() {
+                let local = !imported && !globals.contains(read.name.as_str());
+                if local && !assigned.contains(&read.name) {
+                    self.diagnostics.push(Diagnostic::warning(
+                        read.span,
+                        format!("`{}` is used before it is assigned", read.name),
+                    ));
+                }
+            }
+            for write in writes.iter_mut() {
+                write.assigned = true;
+                assigned.insert(write.name.clone());
+            }
+            self.occurrences.extend(reads);
+            self.occurrences.extend(writes);
+        }
+    }
+
+    /// The variable occurrence at byte `offset`, if any.
+    pub fn occurrence_at(&self, offset: usize) -> Option<&Occurrence> {
+        self.occurrences.iter().find(|o| o.span.contains(offset))
+    }
+
+    /// The first assignment of the variable `occurrence` is of.
+    pub fn definition(&self, occurrence: &Occurrence) -> Option<&Occurrence> {
+        self.occurrences
+            .iter()
+            .find(|o| o.same(occurrence) && o.assigned)
+    }
+
+    pub fn references<'a>(
+        &'a self,
+        occurrence: &'a Occurrence,
+    ) -> impl Iterator<Item = &'a Occurrence> {
+        self.occurrences.iter().filter(|o| o.same(occurrence))
+    }
+
+    /// One occurrence per variable, its first assignment if there is one.
+    pub fn symbols(&self) -> Vec<&Occurrence> {
+        let mut seen = BTreeSet::new();
+        self.occurrences
+            .iter()
+            .filter(|o| seen.insert((&o.procedure, o.name.as_str())))
+            .map(|o| self.definition(o).unwrap_or(o))
+            .collect()
+    }
+
+    /// Markdown describing the variable at `offset`.
+    pub fn hover(&self, source: &str, offset: usize) -> Option<(String, Span)> {
+        let occurrence = self.occurrence_at(offset)?;
+        let name = occurrence.name.as_str();
+        let mut text = format!("`{}`", name);
+        let key = (occurrence.procedure.clone(), name.to_string());
+        let global = self.globals.get(name).filter(|_| key.0.is_none());
+        if let Some(label) = global {
+            text.push_str(format!(": global `{}`, in `.data`", label).as_str());
+        }
+        match self.locations.get(&key) {
+            Some(Operand::Mem(offset)) => {
+                text.push_str(format!(": stack offset {} (`{}($fp)`)", offset, offset).as_str())
+            }
+            Some(Operand::Reg(reg)) => text.push_str(format!(": register `{}`", reg).as_str()),
+            Some(Operand::Imm(_)) => text.push_str(": not stored, its value is never used"),
+            Some(Operand::Global(_)) | None => {}
+        }
+        if let Some(offset) = self.slots.get(&key) {
+            text.push_str(format!("; debugger frame slot `{}($fp)`", offset).as_str());
+        }
+        match self.definition(occurrence) {
+            Some(def) if def.declared => {
+                let (line, _) = line_column(source, def.span.start);
+                text.push_str(format!("\n\ndeclared on line {}, starts out as 0", line).as_str());
+            }
+            Some(def) => {
+                let (line, _) = line_column(source, def.statement.start);
+                text.push_str(
+                    format!(
+                        "\n\nfirst assigned on line {}:\n```micro\n{}\n```",
+                        line,
+                        &source[def.statement.start..def.statement.end]
+                    )
+                    .as_str(),
+                );
+            }
+            None => text.push_str("\n\nnever assigned"),
+        }
+        Some((text, occurrence.span))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handle_occurrences() {
+        let source = "begin\n  write(B);\n  read(A, B);\n  A := A + B;\nend\n";
+        let analysis = Analysis::new(source);
+        let warnings: Vec<&str> = analysis
+            .diagnostics
+            .iter()
+            .map(|d| d.message.as_str())
+            .collect();
+        assert_eq!(warnings, ["`B` is used before it is assigned"]);
+
+        let a = analysis.occurrence_at(source.find("A +").unwrap()).unwrap();
+        let a: Vec<(&str, bool)> = analysis
+            .references(a)
+            .map(|o| (&source[o.span.start..o.span.end], o.assigned))
+            .collect();
+        assert_eq!(a, [("A", true), ("A", true), ("A", false)]);
+        let b = analysis.occurrence_at(source.find('B').unwrap()).unwrap();
+        let def = analysis.definition(b).unwrap();
+        assert_eq!(
+            &source[def.statement.start..def.statement.end],
+            "read(A, B)"
+        );
+
+        let (text, _) = analysis.hover(source, source.find("A +").unwrap()).unwrap();
+        assert!(text.starts_with("`A`: register `$t3`; debugger frame slot `32($fp)`"));
+        assert!(text.ends_with("first assigned on line 3:\n```micro\nread(A, B)\n```"));
+        let names: Vec<&str> = analysis.symbols().iter().map(|o| o.name.as_str()).collect();
+        assert_eq!(names, ["B", "A"]);
+    }
+
+    #[test]
+    fn handle_procedures() {
+        let source = "var X;\nprocedure p;\nbegin\n  A := X;\n  X := A;\nend;\n\
+                      begin\n  p();\n  write(X, A);\nend\n";
+        let analysis = Analysis::new(source);
+        let warnings: Vec<&str> = analysis
+            .diagnostics
+            .iter()
+            .map(|d| d.message.as_str())
+            .collect();
+        assert_eq!(warnings, ["`A` is used before it is assigned"]);
+
+        // the program's `A` is not the procedure's
+        let a = analysis.occurrence_at(source.rfind('A').unwrap()).unwrap();
+        assert_eq!(analysis.references(a).count(), 1);
+        let x = analysis.occurrence_at(source.rfind('X').unwrap()).unwrap();
+        assert_eq!(analysis.definition(x).unwrap().span, Span::new(4, 5));
+        assert_eq!(analysis.references(x).count(), 4);
+        let (text, _) = analysis.hover(source, source.rfind('X').unwrap()).unwrap();
+        assert_eq!(
+            text,
+            "`X`: global `main.X`, in `.data`\n\ndeclared on line 1, starts out as 0"
+        );
+        let (text, _) = analysis
+            .hover(source, source.find("A :=").unwrap())
+            .unwrap();
+        assert!(text.starts_with("`A`: register `$t3`; debugger frame slot `32($fp)`"));
+    }
+}
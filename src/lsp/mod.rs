@@ -0,0 +1,586 @@
+//! Language server for Micro over stdio (`microc lsp`).
+//!
+//! Documents are synchronized in full and analyzed again on every change,
+//! which is instant at the size of a Micro program. The server publishes
+//! diagnostics and answers hover, go-to-definition, find-references and
+//! document-symbol requests. A variable is "defined" by its first
+//! assignment, either `X := ...` or `read(X)`.
+
+pub mod analysis;
+pub mod json;
+
+use crate::diagnostic::{Severity, Span};
+use analysis::{Analysis, Occurrence};
+use json::Json;
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+// `SymbolKind.Variable`
+const SYMBOL_VARIABLE: usize = 13;
+
+/// Read one message framed by a `Content-Length` header, or `None` at the
+/// end of the input.
+#[cfg(test)]
+pub fn read_message(input: &mut impl BufRead) -> Result<Option<Json>, String> {
+    match read_body(input)? {
+        Some(body) => parse_body(body).map(Some),
+        None => Ok(None),
+    }
+}
+
+// the body of the next message, still to be parsed
+fn read_body(input: &mut impl BufRead) -> Result<Option<Vec<u8>>, String> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line).map_err(|e| e.to_string())? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let length = length.ok_or("message without a Content-Length header")?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body).map_err(|e| e.to_string())?;
+    Ok(Some(body))
+}
+
+fn parse_body(body: Vec<u8>) -> Result<Json, String> {
+    let body = String::from_utf8(body).map_err(|_| "message is not UTF-8")?;
+    Json::parse(&body)
+}
+
+fn response(id: Json, result: Result<Json, (i64, String)>) -> Json {
+    let mut response = vec![
+        ("jsonrpc".to_string(), "2.0".into()),
+        ("id".to_string(), id),
+    ];
+    match result {
+        Ok(result) => response.push(("result".to_string(), result)),
+        Err((code, text)) => response.push((
+            "error".to_string(),
+            Json::object([
+                ("code", Json::Number(code as f64)),
+                ("message", text.into()),
+            ]),
+        )),
+    }
+    Json::Object(response)
+}
+
+pub fn write_message(output: &mut impl Write, message: &Json) -> Result<(), String> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+        .and_then(|_| output.flush())
+        .map_err(|e| e.to_string())
+}
+
+// LSP positions count UTF-16 code units within a line
+fn position(text: &str, offset: usize) -> Json {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    Json::object([
+        ("line", before.matches('\n').count().into()),
+        (
+            "character",
+            before[line_start..].encode_utf16().count().into(),
+        ),
+    ])
+}
+
+fn range(text: &str, span: Span) -> Json {
+    Json::object([
+        ("start", position(text, span.start)),
+        ("end", position(text, span.end)),
+    ])
+}
+
+// byte offset of an LSP position, clamped to the text
+fn offset(text: &str, position: &Json) -> Option<usize> {
+    let line = position.get("line")?.as_u64()? as usize;
+    let character = position.get("character")?.as_u64()? as usize;
+    let line_start = match line {
+        0 => 0,
+        _ => text.match_indices('\n').nth(line - 1)?.0 + 1,
+    };
+    let mut units = 0;
+    for (i, c) in text[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return Some(line_start + i);
+        }
+        units += c.len_utf16();
+    }
+    Some(text.len())
+}
+
+#[derive(Default)]
+struct Server {
+    documents: HashMap<String, String>,
+    shutdown: bool,
+}
+
+impl Server {
+    fn publish_diagnostics(&self, uri: &str, output: &mut impl Write) -> Result<(), String> {
+        let diagnostics = match self.documents.get(uri) {
+            Some(text) => Analysis::new(text)
+                .diagnostics
+                .iter()
+                .map(|d| {
+                    let severity = match d.severity {
+                        Severity::Error => 1,
+                        Severity::Warning => 2,
+                    };
+                    Json::object([
+                        ("range", range(text, d.span)),
+                        ("severity", Json::Number(severity as f64)),
+                        ("source", "microc".into()),
+                        ("message", d.message.as_str().into()),
+                    ])
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        let notification = Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("method", "textDocument/publishDiagnostics".into()),
+            (
+                "params",
+                Json::object([("uri", uri.into()), ("diagnostics", diagnostics.into())]),
+            ),
+        ]);
+        write_message(output, &notification)
+    }
+
+    // the document, its analysis and the byte offset a position request
+    // points at
+    fn locate<'a>(&'a self, params: &'a Json) -> Option<(&'a str, &'a String, Analysis, usize)> {
+        let uri = params.get("textDocument")?.get("uri")?.as_str()?;
+        let text = self.documents.get(uri)?;
+        let offset = offset(text, params.get("position")?)?;
+        Some((uri, text, Analysis::new(text), offset))
+    }
+
+    fn location(uri: &str, text: &str, occurrence: &Occurrence) -> Json {
+        Json::object([("uri", uri.into()), ("range", range(text, occurrence.span))])
+    }
+
+    // the result of a request, or an error code and message
+    fn request(&mut self, method: &str, params: &Json) -> Result<Json, (i64, String)> {
+        let missing = || {
+            (
+                INVALID_PARAMS,
+                format!("invalid parameters for `{}`", method),
+            )
+        };
+        match method {
+            "initialize" => Ok(Json::object([
+                (
+                    "capabilities",
+                    Json::object([
+                        // full document sync
+                        ("textDocumentSync", Json::Number(1.0)),
+                        ("hoverProvider", true.into()),
+                        ("definitionProvider", true.into()),
+                        ("referencesProvider", true.into()),
+                        ("documentSymbolProvider", true.into()),
+                    ]),
+                ),
+                (
+                    "serverInfo",
+                    Json::object([
+                        ("name", "microc".into()),
+                        ("version", env!("CARGO_PKG_VERSION").into()),
+                    ]),
+                ),
+            ])),
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Json::Null)
+            }
+            "textDocument/hover" => {
+                let (_, text, analysis, offset) = self.locate(params).ok_or_else(missing)?;
+                Ok(match analysis.hover(text, offset) {
+                    Some((value, span)) => Json::object([
+                        (
+                            "contents",
+                            Json::object([("kind", "markdown".into()), ("value", value.into())]),
+                        ),
+                        ("range", range(text, span)),
+                    ]),
+                    None => Json::Null,
+                })
+            }
+            "textDocument/definition" => {
+                let (uri, text, analysis, offset) = self.locate(params).ok_or_else(missing)?;
+                Ok(analysis
+                    .occurrence_at(offset)
+                    .and_then(|o| analysis.definition(o))
+                    .map_or(Json::Null, |def| Server::location(uri, text, def)))
+            }
+            "textDocument/references" => {
+                let (uri, text, analysis, offset) = self.locate(params).ok_or_else(missing)?;
+                let include_declaration = params
+                    .get("context")
+                    .and_then(|c| c.get("includeDeclaration"))
+                    .and_then(Json::as_bool)
+                    .unwrap_or(true);
+                let Some(occurrence) = analysis.occurrence_at(offset) else {
+                    return Ok(Json::Null);
+                };
+                let definition = analysis.definition(occurrence);
+                Ok(analysis
+                    .references(occurrence)
+                    .filter(|o| include_declaration || Some(*o) != definition)
+                    .map(|o| Server::location(uri, text, o))
+                    .collect::<Vec<Json>>()
+                    .into())
+            }
+            "textDocument/documentSymbol" => {
+                let uri = params
+                    .get("textDocument")
+                    .and_then(|d| d.get("uri"))
+                    .and_then(Json::as_str)
+                    .ok_or_else(missing)?;
+                let text = self.documents.get(uri).ok_or_else(missing)?;
+                let analysis = Analysis::new(text);
+                Ok(analysis
+                    .symbols()
+                    .into_iter()
+                    .map(|o| {
+                        Json::object([
+                            ("name", o.name.as_str().into()),
+                            ("kind", SYMBOL_VARIABLE.into()),
+                            ("range", range(text, o.statement)),
+                            ("selectionRange", range(text, o.span)),
+                        ])
+                    })
+                    .collect::<Vec<Json>>()
+                    .into())
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("unknown method `{}`", method))),
+        }
+    }
+
+    // returns the document whose diagnostics changed, if any
+    fn notification(&mut self, method: &str, params: &Json) -> Option<String> {
+        let document = params.get("textDocument")?;
+        let uri = document.get("uri")?.as_str()?.to_string();
+        match method {
+            "textDocument/didOpen" => {
+                let text = document.get("text")?.as_str()?;
+                self.documents.insert(uri.clone(), text.to_string());
+            }
+            "textDocument/didChange" => {
+                // with full sync the last change holds the whole text
+                let changes = params.get("contentChanges")?.as_array()?;
+                let text = changes.last()?.get("text")?.as_str()?;
+                self.documents.insert(uri.clone(), text.to_string());
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+            }
+            _ => return None,
+        }
+        Some(uri)
+    }
+}
+
+/// Serve requests until the client sends `exit` or closes the input. A
+/// message that is not JSON gets a parse error, without an id, and the
+/// server carries on.
+pub fn serve(mut input: impl BufRead, mut output: impl Write) -> Result<(), String> {
+    let mut server = Server::default();
+    while let Some(body) = read_body(&mut input)? {
+        let message = match parse_body(body) {
+            Ok(message) => message,
+            Err(text) => {
+                let error = response(Json::Null, Err((PARSE_ERROR, text)));
+                write_message(&mut output, &error)?;
+                continue;
+            }
+        };
+        let method = message.get("method").and_then(Json::as_str).unwrap_or("");
+        let params = message.get("params").cloned().unwrap_or(Json::Null);
+        match message.get("id") {
+            Some(id) => {
+                let result = server.request(method, &params);
+                write_message(&mut output, &response(id.clone(), result))?;
+            }
+            None if method == "exit" => {
+                return match server.shutdown {
+                    true => Ok(()),
+                    false => Err("exit before shutdown".to_string()),
+                }
+            }
+            None => {
+                if let Some(uri) = server.notification(method, &params) {
+                    server.publish_diagnostics(&uri, &mut output)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // run a scripted session and return everything the server sent
+    fn session(messages: &[Json]) -> Vec<Json> {
+        let mut input = Vec::new();
+        for message in messages.iter() {
+            write_message(&mut input, message).unwrap();
+        }
+        let mut output = Vec::new();
+        serve(input.as_slice(), &mut output).unwrap();
+        let mut output = output.as_slice();
+        let mut replies = Vec::new();
+        while let Some(reply) = read_message(&mut output).unwrap() {
+            replies.push(reply);
+        }
+        replies
+    }
+
+    fn request(id: usize, method: &str, params: Json) -> Json {
+        Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("id", id.into()),
+            ("method", method.into()),
+            ("params", params),
+        ])
+    }
+
+    fn notification(method: &str, params: Json) -> Json {
+        Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("method", method.into()),
+            ("params", params),
+        ])
+    }
+
+    fn at(line: usize, character: usize) -> Json {
+        Json::object([
+            (
+                "textDocument",
+                Json::object([("uri", "file:///a.m".into())]),
+            ),
+            (
+                "position",
+                Json::object([("line", line.into()), ("character", character.into())]),
+            ),
+        ])
+    }
+
+    fn open(text: &str) -> Json {
+        notification(
+            "textDocument/didOpen",
+            Json::object([(
+                "textDocument",
+                Json::object([
+                    ("uri", "file:///a.m".into()),
+                    ("languageId", "micro".into()),
+                    ("version", 1usize.into()),
+                    ("text", text.into()),
+                ]),
+            )]),
+        )
+    }
+
+    fn shutdown(id: usize) -> [Json; 2] {
+        [
+            request(id, "shutdown", Json::Null),
+            notification("exit", Json::Null),
+        ]
+    }
+
+    #[test]
+    fn handle_diagnostics() {
+        let change = notification(
+            "textDocument/didChange",
+            Json::object([
+                (
+                    "textDocument",
+                    Json::object([("uri", "file:///a.m".into()), ("version", 2usize.into())]),
+                ),
+                (
+                    "contentChanges",
+                    vec![Json::object([("text", "begin A := 1; end".into())])].into(),
+                ),
+            ]),
+        );
+        let [shutdown, exit] = shutdown(2);
+        let replies = session(&[
+            request(1, "initialize", Json::object([])),
+            notification("initialized", Json::object([])),
+            open("-- é\nbegin\n  A := B + ;\n  write(A) $\nend"),
+            change,
+            shutdown,
+            exit,
+        ]);
+        assert_eq!(replies.len(), 4);
+        let capabilities = replies[0]
+            .get("result")
+            .unwrap()
+            .get("capabilities")
+            .unwrap();
+        assert_eq!(capabilities.get("hoverProvider"), Some(&Json::Bool(true)));
+
+        let published = replies[1].get("params").unwrap();
+        assert_eq!(
+            published.get("uri").and_then(Json::as_str),
+            Some("file:///a.m")
+        );
+        let diagnostics: Vec<String> = published
+            .get("diagnostics")
+            .and_then(Json::as_array)
+            .unwrap()
+            .iter()
+            .map(|d| {
+                let start = d.get("range").unwrap().get("start").unwrap();
+                format!(
+                    "{}:{} {} {}",
+                    start.get("line").unwrap(),
+                    start.get("character").unwrap(),
+                    d.get("severity").unwrap(),
+                    d.get("message").and_then(Json::as_str).unwrap()
+                )
+            })
+            .collect();
+        assert_eq!(
+            diagnostics,
+            [
+                "2:11 1 expected an expression, found `;`",
+                "3:8 2 `A` is used before it is assigned",
+                "3:11 1 unexpected char(s)",
+                "4:0 1 expected `;` after the statement, found `end`",
+            ]
+        );
+        // the fixed text clears them
+        let published = replies[2].get("params").unwrap();
+        assert_eq!(published.get("diagnostics"), Some(&Json::Array(Vec::new())));
+        assert_eq!(replies[3].get("result"), Some(&Json::Null));
+    }
+
+    #[test]
+    fn handle_modules() {
+        let [shutdown, exit] = shutdown(2);
+        let replies = session(&[
+            request(1, "initialize", Json::object([])),
+            open("module M;\nvar X, Y;\nprocedure p;\nbegin\n  X := Y;\nend;\n"),
+            open("var X;\nprocedure p;\nbegin\n  X := 1;\nend;\nbegin\n  q();\n  X();\nend\n"),
+            shutdown,
+            exit,
+        ]);
+        let messages = |reply: &Json| -> Vec<String> {
+            let diagnostics = reply.get("params").unwrap().get("diagnostics").unwrap();
+            diagnostics
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|d| d.get("message").and_then(Json::as_str).unwrap().to_string())
+                .collect()
+        };
+        assert!(messages(&replies[1]).is_empty());
+        assert_eq!(
+            messages(&replies[2]),
+            ["no procedure `q`", "`X` is a variable, not a procedure"]
+        );
+    }
+
+    #[test]
+    fn handle_navigation() {
+        let text = "begin\n  read(A);\n  B := A + 1;\n  A := B - A;\n  write(A, B);\nend\n";
+        let [shutdown, exit] = shutdown(7);
+        let replies = session(&[
+            request(1, "initialize", Json::object([])),
+            open(text),
+            request(2, "textDocument/hover", at(3, 12)),
+            request(3, "textDocument/definition", at(4, 11)),
+            request(4, "textDocument/references", at(1, 7)),
+            request(5, "textDocument/documentSymbol", at(0, 0)),
+            request(6, "textDocument/rename", at(1, 7)),
+            shutdown,
+            exit,
+        ]);
+        let result = |id: usize| {
+            let reply = replies
+                .iter()
+                .find(|r| r.get("id").and_then(Json::as_u64) == Some(id as u64))
+                .unwrap();
+            reply.get("result").cloned().unwrap_or(Json::Null)
+        };
+        let start = |range: &Json| {
+            let start = range.get("start").unwrap();
+            (
+                start.get("line").and_then(Json::as_u64).unwrap(),
+                start.get("character").and_then(Json::as_u64).unwrap(),
+            )
+        };
+
+        let hover = result(2);
+        let value = hover.get("contents").unwrap().get("value").unwrap();
+        let first = value.as_str().unwrap().lines().next().unwrap();
+        assert!(first.starts_with("`A`: register `$"), "{}", first);
+        assert!(first.contains("; debugger frame slot `"), "{}", first);
+        assert!(value
+            .as_str()
+            .unwrap()
+            .ends_with("first assigned on line 2:\n```micro\nread(A)\n```"));
+        assert_eq!(start(hover.get("range").unwrap()), (3, 11));
+
+        // `B` in `write(A, B)` goes to `B := A + 1`
+        assert_eq!(start(result(3).get("range").unwrap()), (2, 2));
+
+        let references: Vec<(u64, u64)> = result(4)
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|l| start(l.get("range").unwrap()))
+            .collect();
+        assert_eq!(references, [(1, 7), (2, 7), (3, 2), (3, 11), (4, 8)]);
+
+        let symbols = result(5);
+        let symbols: Vec<(&str, (u64, u64))> = symbols
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|s| {
+                (
+                    s.get("name").and_then(Json::as_str).unwrap(),
+                    start(s.get("selectionRange").unwrap()),
+                )
+            })
+            .collect();
+        assert_eq!(symbols, [("A", (1, 7)), ("B", (2, 2))]);
+
+        let error = replies[6].get("error").unwrap();
+        assert_eq!(error.get("code"), Some(&Json::Number(-32601.0)));
+
+        // a message that is not JSON is answered, and the session goes on
+        let mut input = b"Content-Length: 5\r\n\r\n{oops".to_vec();
+        let [shutdown, exit] = self::shutdown(2);
+        for message in [request(1, "initialize", Json::object([])), shutdown, exit] {
+            write_message(&mut input, &message).unwrap();
+        }
+        let mut output = Vec::new();
+        serve(input.as_slice(), &mut output).unwrap();
+        let mut output = output.as_slice();
+        let error = read_message(&mut output).unwrap().unwrap();
+        assert_eq!(error.get("id"), Some(&Json::Null));
+        let code = error.get("error").unwrap().get("code");
+        assert_eq!(code, Some(&Json::Number(-32700.0)));
+        let reply = read_message(&mut output).unwrap().unwrap();
+        assert!(reply.get("result").is_some());
+    }
+}
@@ -1,25 +1,637 @@
+mod aarch64;
+mod assembler;
 mod ast;
+mod backend;
+mod bytecode;
+mod c99;
 mod char_utils;
 mod codegen;
+mod cst;
+mod debug;
+mod diagnostic;
+mod elf;
+mod fmt;
+mod ir;
+mod jvm;
 mod lexer;
+mod link;
+mod listing;
+mod llvm;
+mod lsp;
+mod mips;
+mod module;
+mod opt;
+mod peephole;
+mod regalloc;
+mod repl;
+mod riscv;
+mod sim;
+#[cfg(test)]
+mod testing;
+mod wasm;
+mod x86_64;
 
+use std::collections::BTreeSet;
 use std::env;
 use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
 
-use crate::ast::ASTBuilder;
+use crate::backend::Backend;
 use crate::codegen::CodeGenerator;
-use crate::lexer::Lexer;
+use crate::diagnostic::Diagnostic;
+use crate::elf::Endian;
+use crate::llvm::LlvmGenerator;
+use crate::opt::OptLevel;
+
+const USAGE: &str =
+    "usage: microc [-O0|-O1|-O2] [-v] [-g] [--emit=asm|elf|wasm|mbc|runtime|cst|listing] [-o <output>]
+              [--target=mips|riscv32|x86_64|aarch64|wasm32|c|llvm|bytecode|jvm]
+              [--expand-pseudo] [--overflow=trap|wrap] [--endian=big|little] <file.m>
+       microc build [-O0|-O1|-O2] [-v] [-o <output>] <file.m>
+       microc run [-O0|-O1|-O2] [-v] [--target=bytecode|mips] [--overflow=trap|wrap]
+                  <file.m|file.mbc>
+       microc debug <file.m>
+       microc link [-o <output>] <file.s>...
+       microc disasm <file.mbc>
+       microc fmt [--check] [-o <output>] <file.m>
+       microc repl
+       microc lsp";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Command {
+    #[default]
+    Compile,
+    // link a native executable
+    Build,
+    // execute on the bytecode VM or the MIPS simulator
+    Run,
+    // step through the program on the MIPS simulator
+    Debug,
+    // put the assembly of a program and its modules together
+    Link,
+    Disasm,
+    // reprint the source in the canonical layout
+    Fmt,
+    // run statements as they are typed
+    Repl,
+    // serve the language server protocol over stdio
+    Lsp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    // an assembly backend from `backend::BACKENDS`
+    Asm(&'static str),
+    Wasm32,
+    C,
+    Llvm,
+    Bytecode,
+    Jvm,
+}
+
+const MIPS: Target = Target::Asm("mips");
+const X86_64: Target = Target::Asm("x86_64");
+
+impl Default for Target {
+    fn default() -> Self {
+        MIPS
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Emit {
+    #[default]
+    Asm,
+    // a MIPS Linux executable
+    Elf,
+    Wasm,
+    Mbc,
+    // the C runtime an LLVM module links against
+    Runtime,
+    // the concrete syntax tree, for any target
+    Cst,
+    // MIPS code interleaved with the source, with the frame layout
+    Listing,
+}
+
+#[derive(Debug, Default)]
+struct Options {
+    command: Command,
+    file_path: String,
+    // the files to link
+    inputs: Vec<String>,
+    output: Option<String>,
+    opt_level: OptLevel,
+    verbose: u8,
+    target: Target,
+    expand_pseudo: bool,
+    // annotate the MIPS code with the source lines it comes from
+    line_info: bool,
+    // `--overflow=wrap` or `--overflow=trap`, when given
+    wrapping: Option<bool>,
+    // `run` without `--target`, which uses MIPS for what bytecode cannot run
+    default_target: bool,
+    // `fmt` only reports whether the file is formatted
+    check: bool,
+    emit: Emit,
+    endian: Endian,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options::default();
+    let mut file_path = None;
+    let mut args = args.iter().peekable();
+    match args.peek().map(|arg| arg.as_str()) {
+        Some("build") => {
+            options.command = Command::Build;
+            options.target = X86_64;
+        }
+        Some("run") => {
+            options.command = Command::Run;
+            options.target = Target::Bytecode;
+            options.default_target = true;
+        }
+        Some("debug") => options.command = Command::Debug,
+        Some("link") => options.command = Command::Link,
+        Some("disasm") => options.command = Command::Disasm,
+        Some("fmt") => options.command = Command::Fmt,
+        Some("repl") => options.command = Command::Repl,
+        Some("lsp") => options.command = Command::Lsp,
+        _ => {}
+    }
+    if options.command != Command::Compile {
+        args.next();
+    }
+    while let Some(arg) = args.next() {
+        if let Some(level) = arg.strip_prefix("-O") {
+            options.opt_level = OptLevel::from_flag(level)
+                .ok_or_else(|| format!("unknown optimization level `{}`", arg))?;
+        } else if arg == "-v" {
+            options.verbose += 1;
+        } else if let Some(target) = arg.strip_prefix("--target=") {
+            options.default_target = false;
+            options.target = match target {
+                "wasm32" => Target::Wasm32,
+                "c" => Target::C,
+                "llvm" => Target::Llvm,
+                "bytecode" => Target::Bytecode,
+                "jvm" => Target::Jvm,
+                _ => match backend::lookup(target) {
+                    Some((name, _)) => Target::Asm(name),
+                    None => return Err(format!("unknown target `{}`", target)),
+                },
+            };
+        } else if arg == "-g" {
+            options.line_info = true;
+        } else if let Some(overflow) = arg.strip_prefix("--overflow=") {
+            options.wrapping = match overflow {
+                "trap" => Some(false),
+                "wrap" => Some(true),
+                _ => return Err(format!("unknown overflow behavior `{}`", overflow)),
+            };
+        } else if arg == "--expand-pseudo" {
+            options.expand_pseudo = true;
+        } else if arg == "--check" && options.command == Command::Fmt {
+            options.check = true;
+        } else if arg == "-o" {
+            options.output = Some(args.next().ok_or("`-o` needs a file name")?.clone());
+        } else if let Some(emit) = arg.strip_prefix("--emit=") {
+            options.emit = match emit {
+                "asm" => Emit::Asm,
+                "elf" => Emit::Elf,
+                "wasm" => Emit::Wasm,
+                "mbc" => Emit::Mbc,
+                "runtime" => Emit::Runtime,
+                "cst" => Emit::Cst,
+                "listing" => Emit::Listing,
+                _ => return Err(format!("unknown output kind `{}`", emit)),
+            };
+        } else if let Some(endian) = arg.strip_prefix("--endian=") {
+            options.endian = match endian {
+                "big" => Endian::Big,
+                "little" => Endian::Little,
+                _ => return Err(format!("unknown byte order `{}`", endian)),
+            };
+        } else if arg.starts_with('-') {
+            return Err(format!("unknown option `{}`", arg));
+        } else if options.command == Command::Link {
+            options.inputs.push(arg.clone());
+        } else if file_path.replace(arg.clone()).is_some() {
+            return Err("more than one input file".to_string());
+        }
+    }
+    // only the MIPS simulator knows about overflow
+    if options.default_target && options.wrapping.is_some() {
+        options.target = MIPS;
+    }
+    match file_path {
+        Some(file_path) => options.file_path = file_path,
+        // the runtime does not depend on the program
+        None if options.emit == Emit::Runtime => {}
+        None if matches!(options.command, Command::Repl | Command::Lsp) => {}
+        None if options.command == Command::Link && !options.inputs.is_empty() => {}
+        None => return Err("no input file".to_string()),
+    }
+    let ignores_overflow = matches!(
+        options.command,
+        Command::Debug
+            | Command::Link
+            | Command::Disasm
+            | Command::Fmt
+            | Command::Repl
+            | Command::Lsp
+    ) || options.emit == Emit::Cst
+        || options.file_path.ends_with(".mbc");
+    if ignores_overflow && options.wrapping.is_some() {
+        return Err("`--overflow` only applies to compiling and running MIPS code".to_string());
+    }
+    if options.target != MIPS
+        && (options.expand_pseudo
+            || options.line_info
+            || options.wrapping.is_some()
+            || options.emit == Emit::Elf)
+    {
+        return Err(
+            "`--expand-pseudo`, `-g`, `--overflow` and `--emit=elf` need `--target=mips`"
+                .to_string(),
+        );
+    }
+    if options.emit == Emit::Listing && (options.target != MIPS || options.expand_pseudo) {
+        return Err("`--emit=listing` needs `--target=mips` without `--expand-pseudo`".to_string());
+    }
+    if options.target != Target::Wasm32 && options.emit == Emit::Wasm {
+        return Err("`--emit=wasm` needs `--target=wasm32`".to_string());
+    }
+    if options.target != Target::Llvm && options.emit == Emit::Runtime {
+        return Err("`--emit=runtime` needs `--target=llvm`".to_string());
+    }
+    if options.target != Target::Bytecode && options.emit == Emit::Mbc {
+        return Err("`--emit=mbc` needs `--target=bytecode`".to_string());
+    }
+    if options.command == Command::Build && options.target != X86_64 {
+        return Err("`build` only supports `--target=x86_64`".to_string());
+    }
+    if options.command == Command::Run && !matches!(options.target, Target::Bytecode | MIPS) {
+        return Err("`run` only supports `--target=bytecode` and `--target=mips`".to_string());
+    }
+    if options.command == Command::Debug && options.target != MIPS {
+        return Err("`debug` only supports `--target=mips`".to_string());
+    }
+    Ok(options)
+}
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut options = parse_args(&args).unwrap_or_else(|e| {
+        eprintln!("microc: {}\n{}", e, USAGE);
+        process::exit(1);
+    });
+
+    if options.command == Command::Repl {
+        if let Err(e) = repl::run(io::stdin().lock(), io::stdout().lock()) {
+            eprintln!("microc: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+    if options.command == Command::Lsp {
+        if let Err(e) = lsp::serve(io::stdin().lock(), io::stdout().lock()) {
+            eprintln!("microc: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+    if options.emit == Emit::Runtime {
+        return print_or_write(options.output, llvm::RUNTIME);
+    }
+    if options.command == Command::Link {
+        return link_files(&options);
+    }
+    if options.command == Command::Disasm || options.file_path.ends_with(".mbc") {
+        let chunk = load_chunk(&options.file_path);
+        if options.command == Command::Run {
+            return run_chunk(&chunk);
+        }
+        return print_or_write(options.output, &chunk.disassemble());
+    }
 
-    let file_path = &args[1];
-    let content = fs::read_to_string(file_path).expect("Should have been able to read the file");
+    let content =
+        fs::read_to_string(&options.file_path).expect("Should have been able to read the file");
+    if options.command == Command::Fmt {
+        return format_source(&options, &content);
+    }
+    if options.emit == Emit::Cst {
+        return print_or_write(options.output, &cst::parse(&content).dump());
+    }
+
+    let module = module::load(Path::new(&options.file_path), &content).unwrap_or_else(|e| {
+        eprint!("{}", e);
+        process::exit(1);
+    });
+    if !module.unit.is_plain_program() {
+        // bytecode has no procedures or globals
+        if options.default_target {
+            options.target = MIPS;
+        }
+        return compile_module(&options, module);
+    }
+    let mut program = ir::lower(module.unit.main);
+    if options.command == Command::Debug {
+        if let Err(e) = debug::run(&content, &program, io::stdin().lock(), io::stdout().lock()) {
+            eprintln!("microc: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+    let remarks = opt::optimize(&mut program, options.opt_level);
+    if options.verbose > 0 {
+        for remark in remarks.iter() {
+            eprintln!("microc: {}", remark);
+        }
+    }
+
+    match options.target {
+        Target::Asm("mips") => {}
+        Target::Bytecode => {
+            let chunk = generated(bytecode::compile(&program));
+            if options.command == Command::Run {
+                return run_chunk(&chunk);
+            }
+            if options.emit == Emit::Mbc {
+                let path = options.output.unwrap_or_else(|| "a.mbc".to_string());
+                return write_output(&path, &chunk.serialize());
+            }
+            return print_or_write(options.output, &chunk.disassemble());
+        }
+        Target::Llvm => {
+            let ll = generated(LlvmGenerator::new().generate(&program, &options.file_path));
+            return print_or_write(options.output, &ll);
+        }
+        Target::Jvm => {
+            // the class is named after the file, as `java` expects
+            let path = options.output.unwrap_or_else(|| {
+                let stem = std::path::Path::new(&options.file_path).with_extension("class");
+                stem.file_name().unwrap().to_string_lossy().into_owned()
+            });
+            let source_file = std::path::Path::new(&options.file_path)
+                .file_name()
+                .map_or(options.file_path.clone(), |name| {
+                    name.to_string_lossy().into_owned()
+                });
+            let class = generated(jvm::generate(
+                &program,
+                &jvm::class_name(&path),
+                &source_file,
+            ));
+            return write_output(&path, &class);
+        }
+        Target::C => return print_or_write(options.output, &generated(c99::generate(&program))),
+        Target::Wasm32 => {
+            let module = generated(wasm::lower(&program));
+            if options.emit == Emit::Wasm {
+                let path = options.output.unwrap_or_else(|| "a.wasm".to_string());
+                return write_output(&path, &module.to_binary());
+            }
+            return print_or_write(options.output, &module.to_wat());
+        }
+        Target::Asm(name) => {
+            let (_, new) = backend::lookup(name).unwrap();
+            let mut backend = new(options.opt_level);
+            let asm = generated(backend.emit(&program));
+            if options.command != Command::Build {
+                let output = into_dir(options.output, &options.file_path, backend.file_extension());
+                return print_or_write(output, &asm);
+            }
+            let path = options.output.unwrap_or_else(|| "a.out".to_string());
+            if let Err(e) = x86_64::build_executable(&asm, &path) {
+                eprintln!("microc: {}", e);
+                process::exit(1);
+            }
+            return;
+        }
+    }
 
-    let mut lexer = Lexer::new(content.as_str());
-    let iter = lexer.tokenize();
-    let mut builder = ASTBuilder::new(Box::new(iter));
     let mut cg = CodeGenerator::new();
-    let asm = cg.generate(builder.parse());
-    println!("{}", asm);
+    cg.opt_level = options.opt_level;
+    cg.expand_pseudo = options.expand_pseudo;
+    cg.wrapping = options.wrapping == Some(true);
+    cg.linux = options.emit == Emit::Elf;
+    // runtime errors name the line they happen on
+    if options.line_info || options.emit == Emit::Listing || options.command == Command::Run {
+        cg.source = Some(content.clone());
+    }
+    let listing = cg.assemble(&program);
+    if options.verbose > 0 {
+        for remark in cg.remarks.iter() {
+            eprintln!("microc: {}", remark);
+        }
+    }
+    let errors = mips::validate(&listing);
+    if !errors.is_empty() {
+        for error in errors.iter() {
+            eprintln!("microc: [internal error] {}", error);
+        }
+        process::exit(1);
+    }
+
+    if options.command == Command::Run {
+        if let Err(e) = sim::run(listing, io::stdin().lock(), io::stdout().lock()) {
+            eprintln!("microc: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+    match options.emit {
+        Emit::Asm => {
+            let output = into_dir(options.output, &options.file_path, cg.file_extension());
+            print_or_write(output, &mips::print(&listing))
+        }
+        Emit::Elf => write_elf(&options, &listing),
+        Emit::Listing => print_or_write(options.output, &listing::render(&content, &cg, &listing)),
+        // handled above, or rejected by `parse_args` for mips
+        Emit::Wasm | Emit::Mbc | Emit::Runtime | Emit::Cst => unreachable!(),
+    }
+}
+
+// compile a module, or a program with declarations or imports, to MIPS
+// assembly, or run it with the modules it needs
+fn compile_module(options: &Options, module: module::Module) {
+    // labels of other files only get addresses once linked
+    let linked = module.unit.module.is_none() && module.imports.is_empty();
+    let error = if options.target != MIPS {
+        Some("declarations and imports need `--target=mips`")
+    } else if options.command == Command::Debug {
+        Some("`debug` does not support declarations and imports")
+    } else if options.emit == Emit::Listing {
+        Some("`--emit=listing` does not support declarations and imports")
+    } else if !linked && (options.expand_pseudo || options.emit == Emit::Elf) {
+        Some("`--expand-pseudo` and `--emit=elf` do not support modules and imports")
+    } else {
+        None
+    };
+    if let Some(error) = error {
+        eprintln!("microc: {}", error);
+        process::exit(1);
+    }
+    let mut cg = CodeGenerator::new();
+    cg.opt_level = options.opt_level;
+    cg.wrapping = options.wrapping == Some(true);
+    cg.linux = options.emit == Emit::Elf;
+    // runtime errors name the line they happen on
+    if options.line_info || options.command == Command::Run {
+        cg.source = Some(module.source.clone());
+    }
+    let mut remarks = Vec::new();
+    let (mut listing, externals) = if options.command == Command::Run {
+        let path = Path::new(&options.file_path);
+        let modules = module::load_all(path, module).unwrap_or_else(|e| {
+            eprint!("{}", e);
+            process::exit(1);
+        });
+        let listing = modules
+            .iter()
+            .flat_map(|module| module.assemble(&cg, &mut remarks))
+            .collect();
+        (listing, BTreeSet::new())
+    } else {
+        (module.assemble(&cg, &mut remarks), module.externals())
+    };
+    if options.verbose > 0 {
+        for remark in remarks.iter() {
+            eprintln!("microc: {}", remark);
+        }
+    }
+    let errors = mips::validate_unit(&listing, &externals);
+    if !errors.is_empty() {
+        for error in errors.iter() {
+            eprintln!("microc: [internal error] {}", error);
+        }
+        process::exit(1);
+    }
+    if options.command == Command::Run {
+        if let Err(e) = sim::run(listing, io::stdin().lock(), io::stdout().lock()) {
+            eprintln!("microc: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+    if options.expand_pseudo {
+        listing = mips::expand_pseudo(&listing);
+    }
+    match options.emit {
+        Emit::Elf => write_elf(options, &listing),
+        _ => {
+            let output = into_dir(
+                options.output.clone(),
+                &options.file_path,
+                cg.file_extension(),
+            );
+            print_or_write(output, &mips::print(&listing));
+        }
+    }
+}
+
+fn write_elf(options: &Options, listing: &[mips::Instr]) {
+    let object = assembler::assemble(listing, true).unwrap_or_else(|e| {
+        eprintln!("microc: [internal error] {}", e);
+        process::exit(1);
+    });
+    let path = options
+        .output
+        .clone()
+        .unwrap_or_else(|| "a.out".to_string());
+    write_output(&path, &elf::write_executable(&object, options.endian));
+}
+
+fn link_files(options: &Options) {
+    let files: Vec<(String, String)> = options
+        .inputs
+        .iter()
+        .map(|path| match fs::read_to_string(path) {
+            Ok(text) => (path.clone(), text),
+            Err(e) => {
+                eprintln!("microc: cannot read `{}`: {}", path, e);
+                process::exit(1);
+            }
+        })
+        .collect();
+    match link::link(&files) {
+        Ok(linked) => print_or_write(options.output.clone(), &linked),
+        Err(errors) => {
+            for error in errors.iter() {
+                eprintln!("microc: {}", error);
+            }
+            process::exit(1);
+        }
+    }
+}
+
+fn format_source(options: &Options, content: &str) {
+    let formatted = fmt::format(content).unwrap_or_else(|diagnostics| {
+        for diagnostic in diagnostics.iter() {
+            eprint!("{}", diagnostic.render(content));
+        }
+        process::exit(1);
+    });
+    if !options.check {
+        return print_or_write(options.output.clone(), &formatted);
+    }
+    if let Some(span) = fmt::first_difference(content, &formatted) {
+        let message = format!("`{}` is not formatted", options.file_path);
+        eprint!("{}", Diagnostic::warning(span, message).render(content));
+        process::exit(1);
+    }
+}
+
+fn load_chunk(path: &str) -> bytecode::Chunk {
+    let bytes = fs::read(path).unwrap_or_else(|e| {
+        eprintln!("microc: cannot read `{}`: {}", path, e);
+        process::exit(1);
+    });
+    bytecode::Chunk::deserialize(&bytes).unwrap_or_else(|e| {
+        eprintln!("microc: `{}`: {}", path, e);
+        process::exit(1);
+    })
+}
+
+fn run_chunk(chunk: &bytecode::Chunk) {
+    if let Err(e) = bytecode::vm::run(chunk, io::stdin().lock(), io::stdout().lock()) {
+        eprintln!("microc: {}", e);
+        process::exit(1);
+    }
+}
+
+// an output directory stands for `<input stem>.<extension>` inside it
+// the output of a generator, or its error and exit
+fn generated<T>(result: Result<T, String>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("microc: {}", e);
+        process::exit(1);
+    })
+}
+
+fn into_dir(output: Option<String>, input: &str, extension: &str) -> Option<String> {
+    let dir = std::path::Path::new(output.as_ref()?);
+    if !dir.is_dir() {
+        return output;
+    }
+    let stem = std::path::Path::new(input).file_stem()?;
+    let path = dir.join(stem).with_extension(extension);
+    Some(path.to_string_lossy().into_owned())
+}
+
+fn print_or_write(output: Option<String>, asm: &str) {
+    match output {
+        Some(path) => write_output(&path, asm.as_bytes()),
+        None => print!("{}", asm),
+    }
+}
+
+fn write_output(path: &str, contents: &[u8]) {
+    if let Err(e) = fs::write(path, contents) {
+        eprintln!("microc: cannot write `{}`: {}", path, e);
+        process::exit(1);
+    }
 }
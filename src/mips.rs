@@ -0,0 +1,507 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// A MIPS general purpose register, by number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Reg(pub u8);
+
+impl Reg {
+    pub const ZERO: Reg = Reg(0);
+    pub const AT: Reg = Reg(1);
+    pub const V0: Reg = Reg(2);
+    pub const A0: Reg = Reg(4);
+    pub const A1: Reg = Reg(5);
+    pub const A2: Reg = Reg(6);
+    pub const A3: Reg = Reg(7);
+    pub const T0: Reg = Reg(8);
+    pub const T1: Reg = Reg(9);
+    pub const T2: Reg = Reg(10);
+    pub const T3: Reg = Reg(11);
+    pub const T4: Reg = Reg(12);
+    pub const T5: Reg = Reg(13);
+    pub const T6: Reg = Reg(14);
+    pub const T7: Reg = Reg(15);
+    pub const S0: Reg = Reg(16);
+    pub const S1: Reg = Reg(17);
+    pub const S2: Reg = Reg(18);
+    pub const S3: Reg = Reg(19);
+    pub const S4: Reg = Reg(20);
+    pub const S5: Reg = Reg(21);
+    pub const S6: Reg = Reg(22);
+    pub const S7: Reg = Reg(23);
+    pub const T8: Reg = Reg(24);
+    pub const T9: Reg = Reg(25);
+    pub const SP: Reg = Reg(29);
+    pub const FP: Reg = Reg(30);
+    pub const RA: Reg = Reg(31);
+
+    pub fn name(self) -> &'static str {
+        const NAMES: [&str; 32] = [
+            "zero", "at", "v0", "v1", "a0", "a1", "a2", "a3", "t0", "t1", "t2", "t3", "t4", "t5",
+            "t6", "t7", "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7", "t8", "t9", "k0", "k1",
+            "gp", "sp", "fp", "ra",
+        ];
+        NAMES[self.0 as usize]
+    }
+
+    pub fn is_callee_saved(self) -> bool {
+        (16..=23).contains(&self.0)
+    }
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "${}", self.name())
+    }
+}
+
+/// Assembler directives understood by SPIM and MARS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Directive {
+    Text,
+    Data,
+    Globl(String),
+    /// A `.word` written as a character literal, e.g. `'\n'`.
+    WordChar(char),
+    Word(i32),
+    /// Kernel code, at the address SPIM and MARS jump to on an exception.
+    Ktext,
+    Kdata,
+    Asciiz(String),
+}
+
+impl fmt::Display for Directive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Directive::Text => write!(f, ".text"),
+            Directive::Data => write!(f, ".data"),
+            Directive::Globl(name) => write!(f, ".globl {}", name),
+            Directive::WordChar(c) => write!(f, ".word '{}'", c.escape_default()),
+            Directive::Word(value) => write!(f, ".word {}", value),
+            Directive::Ktext => write!(f, ".ktext {:#x}", EXCEPTION_HANDLER),
+            Directive::Kdata => write!(f, ".kdata"),
+            Directive::Asciiz(text) => write!(f, ".asciiz \"{}\"", text.escape_default()),
+        }
+    }
+}
+
+/// One line of MIPS assembly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instr {
+    Label(String),
+    Directive(Directive),
+    Comment(String),
+
+    // pseudo-instructions
+    Li { rt: Reg, imm: i32 },
+    Move { rd: Reg, rs: Reg },
+    LwLabel { rt: Reg, label: String },
+    La { rt: Reg, label: String },
+
+    Add { rd: Reg, rs: Reg, rt: Reg },
+    Addu { rd: Reg, rs: Reg, rt: Reg },
+    Sub { rd: Reg, rs: Reg, rt: Reg },
+    Subu { rd: Reg, rs: Reg, rt: Reg },
+    Addi { rt: Reg, rs: Reg, imm: i32 },
+    Addiu { rt: Reg, rs: Reg, imm: i32 },
+    Ori { rt: Reg, rs: Reg, imm: i32 },
+    Lui { rt: Reg, imm: i32 },
+    Lw { rt: Reg, offset: i32, base: Reg },
+    Sw { rt: Reg, offset: i32, base: Reg },
+    Jal { target: String },
+    Jr { rs: Reg },
+    Syscall,
+
+    // only in the Linux runtime
+    Slt { rd: Reg, rs: Reg, rt: Reg },
+    Divu { rs: Reg, rt: Reg },
+    Mfhi { rd: Reg },
+    Mflo { rd: Reg },
+    Lbu { rt: Reg, offset: i32, base: Reg },
+    Sb { rt: Reg, offset: i32, base: Reg },
+    Beq { rs: Reg, rt: Reg, target: String },
+    Bne { rs: Reg, rt: Reg, target: String },
+}
+
+impl Instr {
+    /// Labels, directives and comments produce no machine code.
+    pub fn is_instruction(&self) -> bool {
+        !matches!(
+            self,
+            Instr::Label(_) | Instr::Directive(_) | Instr::Comment(_)
+        )
+    }
+
+    /// The register written by this instruction, if any.
+    pub fn def(&self) -> Option<Reg> {
+        match self {
+            Instr::Li { rt, .. }
+            | Instr::LwLabel { rt, .. }
+            | Instr::La { rt, .. }
+            | Instr::Addi { rt, .. }
+            | Instr::Addiu { rt, .. }
+            | Instr::Ori { rt, .. }
+            | Instr::Lui { rt, .. }
+            | Instr::Lw { rt, .. }
+            | Instr::Lbu { rt, .. } => Some(*rt),
+            Instr::Move { rd, .. }
+            | Instr::Add { rd, .. }
+            | Instr::Addu { rd, .. }
+            | Instr::Sub { rd, .. }
+            | Instr::Subu { rd, .. }
+            | Instr::Slt { rd, .. }
+            | Instr::Mfhi { rd }
+            | Instr::Mflo { rd } => Some(*rd),
+            Instr::Jal { .. } => Some(Reg::RA),
+            Instr::Syscall => Some(Reg::V0),
+            _ => None,
+        }
+    }
+
+    /// The registers read by this instruction. Calls into the runtime
+    /// read their argument from `$a0`.
+    pub fn uses(&self) -> Vec<Reg> {
+        match self {
+            Instr::Move { rs, .. }
+            | Instr::Addi { rs, .. }
+            | Instr::Addiu { rs, .. }
+            | Instr::Ori { rs, .. }
+            | Instr::Jr { rs } => vec![*rs],
+            Instr::Add { rs, rt, .. }
+            | Instr::Addu { rs, rt, .. }
+            | Instr::Sub { rs, rt, .. }
+            | Instr::Subu { rs, rt, .. }
+            | Instr::Slt { rs, rt, .. }
+            | Instr::Divu { rs, rt }
+            | Instr::Beq { rs, rt, .. }
+            | Instr::Bne { rs, rt, .. } => vec![*rs, *rt],
+            Instr::Lw { base, .. } | Instr::Lbu { base, .. } => vec![*base],
+            Instr::Sw { rt, base, .. } | Instr::Sb { rt, base, .. } => vec![*rt, *base],
+            Instr::Jal { .. } => vec![Reg::A0],
+            Instr::Syscall => vec![Reg::V0, Reg::A0],
+            _ => vec![],
+        }
+    }
+}
+
+impl fmt::Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instr::Label(name) => write!(f, "{}:", name),
+            Instr::Directive(directive) => write!(f, "{}", directive),
+            Instr::Comment(text) => write!(f, "# {}", text),
+            Instr::Li { rt, imm } => write!(f, "li {}, {}", rt, imm),
+            Instr::Move { rd, rs } => write!(f, "move {}, {}", rd, rs),
+            Instr::LwLabel { rt, label } => write!(f, "lw {}, {}", rt, label),
+            Instr::La { rt, label } => write!(f, "la {}, {}", rt, label),
+            Instr::Add { rd, rs, rt } => write!(f, "add {}, {}, {}", rd, rs, rt),
+            Instr::Addu { rd, rs, rt } => write!(f, "addu {}, {}, {}", rd, rs, rt),
+            Instr::Subu { rd, rs, rt } => write!(f, "subu {}, {}, {}", rd, rs, rt),
+            Instr::Sub { rd, rs, rt } => write!(f, "sub {}, {}, {}", rd, rs, rt),
+            Instr::Addi { rt, rs, imm } => write!(f, "addi {}, {}, {}", rt, rs, imm),
+            Instr::Addiu { rt, rs, imm } => write!(f, "addiu {}, {}, {}", rt, rs, imm),
+            Instr::Ori { rt, rs, imm } => write!(f, "ori {}, {}, {:#x}", rt, rs, imm),
+            Instr::Lui { rt, imm } => write!(f, "lui {}, {:#x}", rt, imm),
+            Instr::Lw { rt, offset, base } => write!(f, "lw {}, {}({})", rt, offset, base),
+            Instr::Sw { rt, offset, base } => write!(f, "sw {}, {}({})", rt, offset, base),
+            Instr::Jal { target } => write!(f, "jal {}", target),
+            Instr::Jr { rs } => write!(f, "jr {}", rs),
+            Instr::Syscall => write!(f, "syscall"),
+            Instr::Slt { rd, rs, rt } => write!(f, "slt {}, {}, {}", rd, rs, rt),
+            Instr::Divu { rs, rt } => write!(f, "divu {}, {}", rs, rt),
+            Instr::Mfhi { rd } => write!(f, "mfhi {}", rd),
+            Instr::Mflo { rd } => write!(f, "mflo {}", rd),
+            Instr::Lbu { rt, offset, base } => write!(f, "lbu {}, {}({})", rt, offset, base),
+            Instr::Sb { rt, offset, base } => write!(f, "sb {}, {}({})", rt, offset, base),
+            Instr::Beq { rs, rt, target } => write!(f, "beq {}, {}, {}", rs, rt, target),
+            Instr::Bne { rs, rt, target } => write!(f, "bne {}, {}, {}", rs, rt, target),
+        }
+    }
+}
+
+// print a listing as assembly text: labels flush left, everything else
+// indented by four spaces
+pub fn print(listing: &[Instr]) -> String {
+    let mut buf = String::new();
+    for instr in listing.iter() {
+        match instr {
+            Instr::Label(_) => buf.push_str(format!("{}\n", instr).as_str()),
+            _ => buf.push_str(format!("    {}\n", instr).as_str()),
+        }
+    }
+    buf
+}
+
+/// Start of the data segment in SPIM and MARS.
+pub const DATA_BASE: u32 = 0x1001_0000;
+
+/// Start of the text segment in SPIM and MARS.
+pub const TEXT_BASE: u32 = 0x0040_0000;
+
+/// Where SPIM and MARS jump on an exception.
+pub const EXCEPTION_HANDLER: u32 = 0x8000_0180;
+
+/// Start of the kernel data segment in SPIM and MARS.
+pub const KDATA_BASE: u32 = 0x9000_0000;
+
+// assign an address to every label: text labels count machine
+// instructions from `TEXT_BASE`, data labels count words from `DATA_BASE`.
+// pseudo-instructions must have been expanded for text addresses to be
+// exact.
+pub fn layout(listing: &[Instr]) -> BTreeMap<String, u32> {
+    let mut labels = BTreeMap::new();
+    // the text, data, kernel text and kernel data counters, and the one in
+    // use
+    let mut counters = [TEXT_BASE, DATA_BASE, EXCEPTION_HANDLER, KDATA_BASE];
+    let mut segment = 0;
+    for instr in listing.iter() {
+        match instr {
+            Instr::Directive(Directive::Text) => segment = 0,
+            Instr::Directive(Directive::Data) => segment = 1,
+            Instr::Directive(Directive::Ktext) => segment = 2,
+            Instr::Directive(Directive::Kdata) => segment = 3,
+            Instr::Directive(Directive::WordChar(_) | Directive::Word(_)) => counters[segment] += 4,
+            Instr::Directive(Directive::Asciiz(text)) => counters[segment] += text.len() as u32 + 1,
+            Instr::Label(name) => {
+                labels.insert(name.clone(), counters[segment]);
+            }
+            instr if instr.is_instruction() => counters[segment] += 4,
+            _ => {}
+        }
+    }
+    labels
+}
+
+// lower pseudo-instructions into real ones the way MARS does, using `$at`
+// for intermediate addresses
+pub fn expand_pseudo(listing: &[Instr]) -> Vec<Instr> {
+    let labels = layout(listing);
+    let address = |label: &str| labels.get(label).copied().unwrap_or(0);
+    let mut expanded = Vec::with_capacity(listing.len());
+    for instr in listing.iter() {
+        match instr {
+            Instr::Li { rt, imm } => expanded.extend(load_immediate(*rt, *imm)),
+            Instr::LwLabel { rt, label } => {
+                // the offset is sign-extended, so round the upper half up
+                // when the lower half is negative
+                let address = address(label);
+                expanded.push(Instr::Lui {
+                    rt: Reg::AT,
+                    imm: (address.wrapping_add(0x8000) >> 16) as i32,
+                });
+                expanded.push(Instr::Lw {
+                    rt: *rt,
+                    offset: address as u16 as i16 as i32,
+                    base: Reg::AT,
+                });
+            }
+            Instr::La { rt, label } => {
+                let address = address(label);
+                expanded.push(Instr::Lui {
+                    rt: Reg::AT,
+                    imm: (address >> 16) as i32,
+                });
+                expanded.push(Instr::Ori {
+                    rt: *rt,
+                    rs: Reg::AT,
+                    imm: (address & 0xffff) as i32,
+                });
+            }
+            Instr::Move { rd, rs } => expanded.push(Instr::Addu {
+                rd: *rd,
+                rs: Reg::ZERO,
+                rt: *rs,
+            }),
+            Instr::Addi { rt, rs, imm } if i16::try_from(*imm).is_err() => {
+                expanded.extend(load_immediate(Reg::AT, *imm));
+                expanded.push(Instr::Add {
+                    rd: *rt,
+                    rs: *rs,
+                    rt: Reg::AT,
+                });
+            }
+            instr => expanded.push(instr.clone()),
+        }
+    }
+    expanded
+}
+
+fn load_immediate(rt: Reg, imm: i32) -> Vec<Instr> {
+    if i16::try_from(imm).is_ok() {
+        vec![Instr::Addiu {
+            rt,
+            rs: Reg::ZERO,
+            imm,
+        }]
+    } else if u16::try_from(imm).is_ok() {
+        vec![Instr::Ori {
+            rt,
+            rs: Reg::ZERO,
+            imm,
+        }]
+    } else {
+        vec![
+            Instr::Lui {
+                rt: Reg::AT,
+                imm: (imm as u32 >> 16) as i32,
+            },
+            Instr::Ori {
+                rt,
+                rs: Reg::AT,
+                imm: (imm as u32 & 0xffff) as i32,
+            },
+        ]
+    }
+}
+
+// check operand ranges and label references, returning one message per
+// problem found
+pub fn validate(listing: &[Instr]) -> Vec<String> {
+    validate_unit(listing, &BTreeSet::new())
+}
+
+/// `validate` for a module compiled on its own, which may use the labels in
+/// `externals` without defining them.
+pub fn validate_unit(listing: &[Instr], externals: &BTreeSet<String>) -> Vec<String> {
+    let mut errors = Vec::new();
+    let mut defined = BTreeMap::<&str, usize>::new();
+    for (i, instr) in listing.iter().enumerate() {
+        if let Instr::Label(name) = instr {
+            if defined.insert(name.as_str(), i).is_some() {
+                errors.push(format!("label `{}` is defined more than once", name));
+            }
+        }
+    }
+
+    for instr in listing.iter() {
+        let (signed, unsigned) = match instr {
+            Instr::Addi { imm, .. } | Instr::Addiu { imm, .. } => (Some(*imm), None),
+            Instr::Lw { offset, .. }
+            | Instr::Sw { offset, .. }
+            | Instr::Lbu { offset, .. }
+            | Instr::Sb { offset, .. } => (Some(*offset), None),
+            Instr::Ori { imm, .. } | Instr::Lui { imm, .. } => (None, Some(*imm)),
+            _ => (None, None),
+        };
+        if signed.is_some_and(|imm| i16::try_from(imm).is_err()) {
+            errors.push(format!(
+                "`{}`: immediate does not fit in 16 signed bits",
+                instr
+            ));
+        }
+        if unsigned.is_some_and(|imm| u16::try_from(imm).is_err()) {
+            errors.push(format!(
+                "`{}`: immediate does not fit in 16 unsigned bits",
+                instr
+            ));
+        }
+        let target = match instr {
+            Instr::Jal { target } | Instr::Beq { target, .. } | Instr::Bne { target, .. } => {
+                Some(target)
+            }
+            Instr::LwLabel { label, .. } | Instr::La { label, .. } => Some(label),
+            _ => None,
+        };
+        if let Some(target) = target {
+            if !defined.contains_key(target.as_str()) && !externals.contains(target) {
+                errors.push(format!("`{}`: label `{}` is not defined", instr, target));
+            }
+        }
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handle_printing() {
+        let listing = vec![
+            Instr::Directive(Directive::Data),
+            Instr::Label("newline".to_string()),
+            Instr::Directive(Directive::WordChar('\n')),
+            Instr::Directive(Directive::Text),
+            Instr::Label("main".to_string()),
+            Instr::Comment("prologue area".to_string()),
+            Instr::Sw {
+                rt: Reg::RA,
+                offset: 20,
+                base: Reg::SP,
+            },
+            Instr::LwLabel {
+                rt: Reg::T0,
+                label: "newline".to_string(),
+            },
+        ];
+        assert_eq!(
+            print(&listing),
+            r#"    .data
+newline:
+    .word '\n'
+    .text
+main:
+    # prologue area
+    sw $ra, 20($sp)
+    lw $t0, newline
+"#
+        );
+    }
+
+    #[test]
+    fn handle_pseudo_expansion() {
+        let listing = vec![
+            Instr::Li {
+                rt: Reg::T0,
+                imm: -5,
+            },
+            Instr::Li {
+                rt: Reg::T1,
+                imm: 0x12345,
+            },
+            Instr::Move {
+                rd: Reg::A0,
+                rs: Reg::T0,
+            },
+            Instr::Addi {
+                rt: Reg::SP,
+                rs: Reg::SP,
+                imm: -40000,
+            },
+        ];
+        let expanded = expand_pseudo(&listing);
+        assert!(validate(&expanded).is_empty());
+        assert_eq!(
+            print(&expanded),
+            "    addiu $t0, $zero, -5
+    lui $at, 0x1
+    ori $t1, $at, 0x2345
+    addu $a0, $zero, $t0
+    lui $at, 0xffff
+    ori $at, $at, 0x63c0
+    add $sp, $sp, $at
+"
+        );
+    }
+
+    #[test]
+    fn handle_invalid_listing() {
+        let listing = vec![
+            Instr::Addi {
+                rt: Reg::T0,
+                rs: Reg::T0,
+                imm: 32768,
+            },
+            Instr::Jal {
+                target: "print".to_string(),
+            },
+        ];
+        assert_eq!(
+            validate(&listing),
+            vec![
+                "`addi $t0, $t0, 32768`: immediate does not fit in 16 signed bits".to_string(),
+                "`jal print`: label `print` is not defined".to_string(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,544 @@
+//! Declarations and modules. Before its `begin`, a program may declare
+//! globals with `var` and procedures with `procedure P; begin ... end;`,
+//! and files starting with `module M;` hold only such declarations, for
+//! programs and other modules to `import M;`.
+//!
+//! A variable declared with `var` at the top of a program or module is a
+//! global: it lives in `.data` and `main` and every procedure of the file
+//! share it. Any other variable is local to the body using it, and lives in
+//! that body's frame, so two procedures never see each other's locals.
+//! Micro has no conditionals, so a procedure calling itself, directly or
+//! through others, would never return; such calls are rejected.
+//!
+//! Each file compiles on its own to MIPS assembly. An import reads `M.m`
+//! next to the importing file for the names it declares, and names are
+//! resolved to labels qualified by their module, `M.P` and `M.G`, which the
+//! defining module exports with `.globl`. `link` then puts the files of a
+//! program together.
+
+use crate::ast::{ASTBuilder, ExprAST, ExprKind, Unit};
+use crate::codegen::{self, CodeGenerator};
+use crate::diagnostic::{Diagnostic, Span};
+use crate::ir;
+use crate::lexer::Lexer;
+use crate::mips::{Directive, Instr};
+use crate::opt::{self, Remark};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Symbol {
+    Global(String),
+    Procedure(String),
+}
+
+/// A unit with its names resolved to labels, and the imports it uses.
+#[derive(Debug)]
+pub struct Module {
+    pub source: String,
+    pub unit: Unit,
+    pub imports: Vec<Unit>,
+}
+
+/// Parse `content`, read from `path`, read the interfaces of its imports
+/// and resolve its names. Errors come back rendered against `content`.
+pub fn load(path: &Path, content: &str) -> Result<Module, String> {
+    let (mut unit, mut diagnostics) = parse(content);
+    let mut imports = Vec::new();
+    if diagnostics.is_empty() {
+        let dir = path.parent().unwrap_or(Path::new("."));
+        imports = read_imports(&unit, dir, &mut diagnostics);
+    }
+    if diagnostics.is_empty() {
+        diagnostics = resolve(&mut unit, &imports);
+    }
+    if !diagnostics.is_empty() {
+        return Err(diagnostics.iter().map(|d| d.render(content)).collect());
+    }
+    Ok(Module {
+        source: content.to_string(),
+        unit,
+        imports,
+    })
+}
+
+// the unit in `content`, with the errors of both the lexer and the parser
+fn parse(content: &str) -> (Unit, Vec<Diagnostic>) {
+    let mut lexer = Lexer::new(content);
+    lexer.recover = true;
+    let mut builder = ASTBuilder::new(lexer.tokens().into_iter());
+    let unit = builder.parse_unit();
+    let mut diagnostics = lexer.diagnostics;
+    diagnostics.append(&mut builder.diagnostics);
+    diagnostics.sort_by_key(|d| d.span);
+    (unit, diagnostics)
+}
+
+// the modules `unit` imports, from `<name>.m` in `dir`
+fn read_imports(unit: &Unit, dir: &Path, diagnostics: &mut Vec<Diagnostic>) -> Vec<Unit> {
+    let mut imports = Vec::new();
+    let mut seen = BTreeSet::new();
+    for (name, span) in unit.imports.iter() {
+        if !seen.insert(name) {
+            diagnostics.push(Diagnostic::error(
+                *span,
+                format!("`{}` is imported twice", name),
+            ));
+            continue;
+        }
+        if unit
+            .module
+            .as_ref()
+            .is_some_and(|(module, _)| module == name)
+        {
+            diagnostics.push(Diagnostic::error(*span, "a module cannot import itself"));
+            continue;
+        }
+        let file = format!("{}.m", name);
+        let content = match fs::read_to_string(dir.join(&file)) {
+            Ok(content) => content,
+            Err(e) => {
+                diagnostics.push(Diagnostic::error(
+                    *span,
+                    format!("cannot read `{}`: {}", file, e),
+                ));
+                continue;
+            }
+        };
+        let (import, errors) = parse(&content);
+        let message = match &import.module {
+            _ if !errors.is_empty() => {
+                format!("`{}` has errors, compile it to see them", file)
+            }
+            Some((module, _)) if module == name => {
+                imports.push(import);
+                continue;
+            }
+            Some((module, _)) => format!("`{}` is module `{}`, not `{}`", file, module, name),
+            None => format!("`{}` is a program, not a module", file),
+        };
+        diagnostics.push(Diagnostic::error(*span, message));
+    }
+    imports
+}
+
+/// The label of something `unit` declares, qualified by the module name,
+/// or by `main` in a program.
+pub fn label(unit: &Unit, name: &str) -> String {
+    let module = unit.module.as_ref().map_or("main", |(module, _)| module);
+    format!("{}.{}", module, name)
+}
+
+// the names a unit declares, by the symbols they stand for
+fn declarations(unit: &Unit) -> BTreeMap<String, (Symbol, Span)> {
+    let label = |name: &str| label(unit, name);
+    let globals = unit
+        .globals
+        .iter()
+        .map(|(name, span)| (name, Symbol::Global(label(name)), *span));
+    let procedures = unit
+        .procedures
+        .iter()
+        .map(|p| (&p.name, Symbol::Procedure(label(&p.name)), p.span));
+    let mut declared = BTreeMap::new();
+    for (name, symbol, span) in globals.chain(procedures) {
+        declared.entry(name.to_string()).or_insert((symbol, span));
+    }
+    declared
+}
+
+struct Scope {
+    own: BTreeMap<String, (Symbol, Span)>,
+    // exported names, with the modules exporting them
+    imported: BTreeMap<String, Vec<(String, Symbol)>>,
+}
+
+impl Scope {
+    // the symbol `name` stands for, `None` for a local variable
+    fn lookup(&self, name: &str, span: Span) -> Result<Option<Symbol>, Diagnostic> {
+        if let Some((symbol, _)) = self.own.get(name) {
+            return Ok(Some(symbol.clone()));
+        }
+        match self.imported.get(name).map(Vec::as_slice) {
+            None | Some([]) => Ok(None),
+            Some([(_, symbol)]) => Ok(Some(symbol.clone())),
+            Some([(first, _), (second, _), ..]) => Err(Diagnostic::error(
+                span,
+                format!(
+                    "`{}` is ambiguous, both `{}` and `{}` declare it",
+                    name, first, second
+                ),
+            )),
+        }
+    }
+
+    // rewrite the names in `expr` to labels
+    fn resolve(&self, expr: &mut ExprAST, diagnostics: &mut Vec<Diagnostic>) {
+        let span = expr.span;
+        match &mut expr.kind {
+            ExprKind::VariableExprAST { name } => match self.lookup(name, span) {
+                Ok(Some(Symbol::Global(label))) => *name = label.into(),
+                Ok(Some(Symbol::Procedure(_))) => diagnostics.push(Diagnostic::error(
+                    span,
+                    format!("`{}` is a procedure, not a variable", name),
+                )),
+                Ok(None) => {}
+                Err(e) => diagnostics.push(e),
+            },
+            ExprKind::CallAST { name } => match self.lookup(name, span) {
+                Ok(Some(Symbol::Procedure(label))) => *name = label.into(),
+                Ok(Some(Symbol::Global(_))) => diagnostics.push(Diagnostic::error(
+                    span,
+                    format!("`{}` is a variable, not a procedure", name),
+                )),
+                Ok(None) => {
+                    diagnostics.push(Diagnostic::error(span, format!("no procedure `{}`", name)))
+                }
+                Err(e) => diagnostics.push(e),
+            },
+            ExprKind::BinaryExprAST { lhs, rhs, .. } => {
+                self.resolve(lhs, diagnostics);
+                self.resolve(rhs, diagnostics);
+            }
+            ExprKind::SyscallExprAST { args, .. } => {
+                for arg in args.iter_mut() {
+                    self.resolve(arg, diagnostics);
+                }
+            }
+            ExprKind::AssignmentAST { var, assign } => {
+                self.resolve(var, diagnostics);
+                self.resolve(assign, diagnostics);
+            }
+            ExprKind::IntLiteralExprAST { .. } => {}
+        }
+    }
+}
+
+/// Rewrite the names of globals and procedures in `unit` to their labels,
+/// the unit's own declarations hiding those of `imports`.
+pub fn resolve(unit: &mut Unit, imports: &[Unit]) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let own = declarations(unit);
+    let declared = unit
+        .globals
+        .iter()
+        .map(|(name, span)| (name, *span))
+        .chain(unit.procedures.iter().map(|p| (&p.name, p.span)));
+    for (name, span) in declared {
+        if own[&**name].1 != span {
+            diagnostics.push(Diagnostic::error(
+                span,
+                format!("`{}` is already declared", name),
+            ));
+        }
+    }
+    let mut imported = BTreeMap::<String, Vec<(String, Symbol)>>::new();
+    for import in imports.iter() {
+        let module = import.module.as_ref().map_or("", |(name, _)| name);
+        for (name, (symbol, _)) in declarations(import) {
+            imported
+                .entry(name)
+                .or_default()
+                .push((module.to_string(), symbol));
+        }
+    }
+    let scope = Scope { own, imported };
+    let bodies = unit.procedures.iter_mut().map(|p| &mut p.body);
+    let mut main = std::mem::take(&mut unit.main);
+    for body in bodies.chain([&mut main]) {
+        for stmt in body.iter_mut() {
+            scope.resolve(stmt, &mut diagnostics);
+        }
+    }
+    unit.main = main;
+    diagnostics.extend(recursive_calls(unit));
+    diagnostics
+}
+
+// an error at each call that leads back to the procedure making it
+fn recursive_calls(unit: &Unit) -> Vec<Diagnostic> {
+    // the procedures of the unit each one calls, by label, with the spans
+    // and names of the calls
+    let calls: BTreeMap<String, Vec<(&str, Span, &str)>> = unit
+        .procedures
+        .iter()
+        .map(|p| {
+            let calls = p.body.iter().filter_map(|stmt| match &stmt.kind {
+                ExprKind::CallAST { name } => {
+                    let callee = unit
+                        .procedures
+                        .iter()
+                        .find(|q| label(unit, &q.name) == **name);
+                    callee.map(|q| (&**name, stmt.span, &*q.name))
+                }
+                _ => None,
+            });
+            (label(unit, &p.name), calls.collect())
+        })
+        .collect();
+    let reaches = |from: &str, to: &str| {
+        let mut seen = BTreeSet::new();
+        let mut pending = vec![from];
+        while let Some(next) = pending.pop() {
+            if next == to {
+                return true;
+            }
+            if seen.insert(next) {
+                pending.extend(calls[next].iter().map(|(callee, _, _)| *callee));
+            }
+        }
+        false
+    };
+    let mut diagnostics = Vec::new();
+    for p in unit.procedures.iter() {
+        let caller = label(unit, &p.name);
+        for (callee, span, name) in calls[&caller].iter() {
+            if reaches(callee, &caller) {
+                let message = match *callee == caller {
+                    true => format!("`{}` calls itself, so it never returns", name),
+                    false => format!("`{}` calls `{}` back, so it never returns", name, p.name),
+                };
+                diagnostics.push(Diagnostic::error(*span, message));
+            }
+        }
+    }
+    diagnostics
+}
+
+impl Module {
+    // labels of the globals the unit can see
+    fn globals(&self) -> BTreeSet<String> {
+        [&self.unit]
+            .into_iter()
+            .chain(self.imports.iter())
+            .flat_map(|unit| declarations(unit).into_values())
+            .filter_map(|(symbol, _)| match symbol {
+                Symbol::Global(label) => Some(label),
+                Symbol::Procedure(_) => None,
+            })
+            .collect()
+    }
+
+    /// Labels the unit uses without defining: what its imports declare, and
+    /// in a module the runtime, which comes with the program.
+    pub fn externals(&self) -> BTreeSet<String> {
+        let mut externals: BTreeSet<String> = self
+            .imports
+            .iter()
+            .flat_map(|unit| declarations(unit).into_values())
+            .map(|(symbol, _)| match symbol {
+                Symbol::Global(label) | Symbol::Procedure(label) => label,
+            })
+            .collect();
+        if self.unit.module.is_some() {
+            externals.extend(["read".to_string(), "write".to_string()]);
+        }
+        externals
+    }
+
+    /// The MIPS code of the unit: `main` for a program, then a procedure
+    /// for each one declared, then the globals in `.data`, exported from
+    /// modules. `cg` holds the options to compile each of them with; with
+    /// its `source` set, the code is annotated with the unit's own lines.
+    pub fn assemble(&self, cg: &CodeGenerator, remarks: &mut Vec<Remark>) -> Vec<Instr> {
+        let globals = self.globals();
+        let mut bodies = Vec::new();
+        if self.unit.module.is_none() {
+            bodies.push((None, self.unit.main.clone()));
+        }
+        for procedure in self.unit.procedures.iter() {
+            let label = label(&self.unit, &procedure.name);
+            bodies.push((Some(label), procedure.body.clone()));
+        }
+        let mut listing = Vec::new();
+        for (procedure, body) in bodies {
+            let mut program = ir::lower_with_globals(body, &globals);
+            remarks.extend(opt::optimize(&mut program, cg.opt_level));
+            let mut generator = CodeGenerator::new();
+            generator.opt_level = cg.opt_level;
+            generator.wrapping = cg.wrapping;
+            generator.linux = cg.linux;
+            generator.source = cg.source.as_ref().map(|_| self.source.clone());
+            generator.procedure = procedure;
+            listing.extend(generator.assemble(&program));
+            remarks.append(&mut generator.remarks);
+        }
+        if !self.unit.globals.is_empty() {
+            listing.push(Instr::Directive(Directive::Data));
+        }
+        for (name, _) in self.unit.globals.iter() {
+            let label = label(&self.unit, name);
+            if self.unit.module.is_some() {
+                listing.push(Instr::Directive(Directive::Globl(label.clone())));
+            }
+            listing.push(Instr::Label(label));
+            listing.push(Instr::Directive(Directive::Word(0)));
+        }
+        // `main` brings the overflow handler when its own code traps; the
+        // procedures may too, and so may the modules it imports, which are
+        // compiled on their own
+        let handled = listing.contains(&Instr::Directive(Directive::Ktext));
+        let traps = codegen::traps(&listing) || !self.unit.imports.is_empty();
+        if self.unit.module.is_none() && cg.handles_overflow() && traps && !handled {
+            listing.extend(codegen::overflow_handler());
+        }
+        listing
+    }
+}
+
+/// `module` and every module it needs, directly or through other modules,
+/// each read from `<name>.m` next to `path`.
+pub fn load_all(path: &Path, module: Module) -> Result<Vec<Module>, String> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut pending: Vec<Box<str>> = module
+        .unit
+        .imports
+        .iter()
+        .map(|(name, _)| name.clone())
+        .collect();
+    let mut seen: BTreeSet<Box<str>> = pending.iter().cloned().collect();
+    let mut modules = vec![module];
+    while let Some(name) = pending.pop() {
+        let file = dir.join(format!("{}.m", name));
+        let content = fs::read_to_string(&file)
+            .map_err(|e| format!("microc: cannot read `{}`: {}\n", file.display(), e))?;
+        let module = load(&file, &content)
+            .map_err(|e| format!("microc: in `{}`:\n{}", file.display(), e))?;
+        for (import, _) in module.unit.imports.iter() {
+            if seen.insert(import.clone()) {
+                pending.push(import.clone());
+            }
+        }
+        modules.push(module);
+    }
+    Ok(modules)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::opt::OptLevel;
+    use crate::sim;
+
+    use super::*;
+
+    fn parse(source: &str) -> Unit {
+        let mut lexer = Lexer::new(source);
+        let mut builder = ASTBuilder::new(Box::new(lexer.tokenize()));
+        let unit = builder.parse_unit();
+        assert!(builder.diagnostics.is_empty());
+        unit
+    }
+
+    fn module(source: &str, imports: &[&str]) -> Result<Module, Vec<String>> {
+        let mut unit = parse(source);
+        let imports: Vec<Unit> = imports.iter().map(|source| parse(source)).collect();
+        let diagnostics = resolve(&mut unit, &imports);
+        if !diagnostics.is_empty() {
+            return Err(diagnostics.into_iter().map(|d| d.message).collect());
+        }
+        Ok(Module {
+            source: source.to_string(),
+            unit,
+            imports,
+        })
+    }
+
+    const COUNTER: &str = "module Counter; var Count, Step;
+        procedure Reset; begin Count := 0; Step := 1; end;
+        procedure Bump; begin Count := Count + Step; end;";
+
+    #[test]
+    fn handle_modules() {
+        let main = "import Counter; begin
+            read(A); Reset(); Step := A; Bump(); Bump();
+            B := A + 1; write(Count); write(B);
+            Count := Count - 1; Bump(); write(Count);
+        end";
+        let main = module(main, &[COUNTER]).unwrap();
+        let counter = module(COUNTER, &[]).unwrap();
+        let externals = ["read", "write"].map(String::from).into();
+        assert_eq!(counter.externals(), externals);
+        for opt_level in [OptLevel::O0, OptLevel::O2] {
+            let mut cg = CodeGenerator::new();
+            cg.opt_level = opt_level;
+            let mut listing = main.assemble(&cg, &mut Vec::new());
+            assert!(crate::mips::validate_unit(&listing, &main.externals()).is_empty());
+            listing.extend(counter.assemble(&cg, &mut Vec::new()));
+            assert!(crate::mips::validate(&listing).is_empty());
+            let mut output = Vec::new();
+            sim::run(listing, "5".as_bytes(), &mut output).unwrap();
+            assert_eq!(String::from_utf8(output).unwrap(), "10\n6\n14\n");
+        }
+    }
+
+    #[test]
+    fn handle_resolve_errors() {
+        let other = "module Other; var Count; procedure Bump; begin end;";
+        let errors = module(
+            "import Counter; import Other; begin Reset := 1; Count(); Bump(); Missing(); end",
+            &[COUNTER, other],
+        );
+        assert_eq!(
+            errors.unwrap_err(),
+            [
+                "`Reset` is a procedure, not a variable",
+                "`Count` is ambiguous, both `Counter` and `Other` declare it",
+                "`Bump` is ambiguous, both `Counter` and `Other` declare it",
+                "no procedure `Missing`",
+            ]
+        );
+        // a module's own names hide imported ones
+        let own = "module Own; import Counter; var Count; procedure P; begin Count := 1; Reset(); end; var P;";
+        assert_eq!(
+            module(own, &[COUNTER]).unwrap_err(),
+            ["`P` is already declared"]
+        );
+        let own = module(
+            "module Own; import Counter; var Count; procedure P; begin Count := 1; end;",
+            &[COUNTER],
+        )
+        .unwrap();
+        let ExprKind::AssignmentAST { var, .. } = &own.unit.procedures[0].body[0].kind else {
+            unreachable!()
+        };
+        assert!(matches!(&var.kind, ExprKind::VariableExprAST { name } if &**name == "Own.Count"));
+
+        // with no conditionals, recursion never ends
+        let recursive = "procedure P; begin P(); end;
+            procedure Q; begin R(); end; procedure R; begin write(1); Q(); end;
+            procedure S; begin Q(); end; begin S(); end";
+        assert_eq!(
+            module(recursive, &[]).unwrap_err(),
+            [
+                "`P` calls itself, so it never returns",
+                "`R` calls `Q` back, so it never returns",
+                "`Q` calls `R` back, so it never returns",
+            ]
+        );
+    }
+
+    #[test]
+    fn handle_program_globals() {
+        let source = "var Total;
+            procedure Add; begin read(X); Total := Total + X; end;
+            begin Total := 100; X := 7; Add(); Add(); write(Total, X); end";
+        let main = module(source, &[]).unwrap();
+        assert!(main.externals().is_empty());
+        for opt_level in [OptLevel::O0, OptLevel::O2] {
+            let mut cg = CodeGenerator::new();
+            cg.opt_level = opt_level;
+            let listing = main.assemble(&cg, &mut Vec::new());
+            assert!(listing.contains(&Instr::Label("main.Total".to_string())));
+            // only `Add` adds, and `main` handles its overflow
+            assert!(listing.contains(&Instr::Directive(Directive::Ktext)));
+            assert!(crate::mips::validate(&listing).is_empty());
+            // `X` in `main` and `X` in `Add` are different variables
+            for listing in [crate::mips::expand_pseudo(&listing), listing] {
+                let mut output = Vec::new();
+                sim::run(listing, "1 2".as_bytes(), &mut output).unwrap();
+                assert_eq!(String::from_utf8(output).unwrap(), "103\n7\n");
+            }
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! Dead store elimination.
+//!
+//! Micro programs are straight-line code, so every instruction is reachable
+//! and liveness is a single backward sweep: only globals are live after the
+//! last instruction and before a call, a definition kills its register and
+//! a use makes it live.
+//! Instructions defining a register that is not live are removed. `read`
+//! is kept for its side effect on the input stream, only its result is
+//! dropped. Arithmetic has none: a dead `add` is removed even when it would
+//! overflow, since every target but trapping MIPS wraps.
+
+use super::Remark;
+use crate::ir::{Inst, Program, VReg};
+use std::collections::BTreeSet;
+
+pub fn run(program: &mut Program, remarks: &mut Vec<Remark>) {
+    let first_remark = remarks.len();
+    let mut live: BTreeSet<VReg> = program.globals.clone();
+    let mut kept = Vec::with_capacity(program.insts.len());
+    let spans = std::mem::take(&mut program.spans);
+
+    for (i, inst) in std::mem::take(&mut program.insts)
+        .into_iter()
+        .enumerate()
+        .rev()
+    {
+        let dead = match inst.def() {
+            Some(dst) => !live.remove(&dst),
+            None => false,
+        };
+        if dead {
+            let text = program.format_inst(&inst);
+            if let Inst::Read { .. } = inst {
+                remarks.push(Remark {
+                    pass: "dce",
+                    message: format!("discarded result of `{}`", text),
+                });
+                kept.push(Inst::Read { dst: None });
+                program.spans.extend(spans.get(i));
+            } else {
+                remarks.push(Remark {
+                    pass: "dce",
+                    message: format!("removed dead store `{}`", text),
+                });
+            }
+            continue;
+        }
+        live.extend(inst.uses());
+        if let Inst::Call { .. } = inst {
+            live.extend(program.globals.iter());
+        }
+        kept.push(inst);
+        program.spans.extend(spans.get(i));
+    }
+
+    kept.reverse();
+    program.insts = kept;
+    program.spans.reverse();
+    remarks[first_remark..].reverse();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::Value;
+    use crate::testing::lower_source;
+
+    use super::*;
+
+    #[test]
+    fn handle_overwritten_store() {
+        let mut program = lower_source(r#"begin A := 1; B := A + 1; A := 2; write(A); end"#);
+        let mut remarks = Vec::new();
+        run(&mut program, &mut remarks);
+        let a = program.vars["A"];
+        assert_eq!(
+            program.insts,
+            vec![
+                Inst::Copy {
+                    dst: a,
+                    src: Value::Imm(2),
+                },
+                Inst::Write { src: Value::Reg(a) },
+            ]
+        );
+        assert_eq!(remarks.len(), 2);
+        assert_eq!(remarks[0].to_string(), "[dce] removed dead store `A := 1`");
+        assert_eq!(
+            remarks[1].to_string(),
+            "[dce] removed dead store `B := A + 1`"
+        );
+    }
+
+    #[test]
+    fn handle_unused_read() {
+        let mut program = lower_source(r#"begin read(A, C); write(C); end"#);
+        let mut remarks = Vec::new();
+        run(&mut program, &mut remarks);
+        let c = program.vars["C"];
+        assert_eq!(
+            program.insts,
+            vec![
+                Inst::Read { dst: None },
+                Inst::Read { dst: Some(c) },
+                Inst::Write { src: Value::Reg(c) },
+            ]
+        );
+        assert_eq!(
+            remarks[0].to_string(),
+            "[dce] discarded result of `read(A)`"
+        );
+    }
+}
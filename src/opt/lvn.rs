@@ -0,0 +1,266 @@
+//! Local value numbering with algebraic simplification.
+//!
+//! Every value computed by the program gets a number; registers holding the
+//! same number hold the same value. Operands are replaced by the first
+//! register still holding their value (or by an immediate when the value is
+//! a known constant), constant expressions are folded, `x - x`, `x + 0`,
+//! `0 + x` and `x - 0` are simplified, and an expression whose value was
+//! already computed becomes a copy. The copies this leaves behind are cleaned
+//! up by dead store elimination.
+
+use super::Remark;
+use crate::ast::BinaryOpKind;
+use crate::ir::{Inst, Program, VReg, Value};
+use std::collections::HashMap;
+
+type ValueNumber = u32;
+
+#[derive(Default)]
+struct ValueTable {
+    next: ValueNumber,
+    of_reg: HashMap<VReg, ValueNumber>,
+    holders: HashMap<ValueNumber, Vec<VReg>>,
+    of_const: HashMap<i32, ValueNumber>,
+    consts: HashMap<ValueNumber, i32>,
+    exprs: HashMap<(BinaryOpKind, ValueNumber, ValueNumber), ValueNumber>,
+}
+
+impl ValueTable {
+    fn fresh(&mut self) -> ValueNumber {
+        self.next += 1;
+        self.next - 1
+    }
+
+    fn constant(&mut self, imm: i32) -> ValueNumber {
+        if let Some(vn) = self.of_const.get(&imm) {
+            return *vn;
+        }
+        let vn = self.fresh();
+        self.of_const.insert(imm, vn);
+        self.consts.insert(vn, imm);
+        vn
+    }
+
+    fn value(&mut self, value: Value) -> ValueNumber {
+        match value {
+            Value::Imm(imm) => self.constant(imm),
+            Value::Reg(reg) => {
+                // registers read before any write get a value of their own
+                if let Some(vn) = self.of_reg.get(&reg) {
+                    return *vn;
+                }
+                let vn = self.fresh();
+                self.assign(reg, vn);
+                vn
+            }
+        }
+    }
+
+    fn assign(&mut self, reg: VReg, vn: ValueNumber) {
+        self.of_reg.insert(reg, vn);
+        self.holders.entry(vn).or_default().push(reg);
+    }
+
+    // cheapest operand carrying `vn`: an immediate, or the oldest register
+    // that still holds it
+    fn operand(&self, vn: ValueNumber) -> Option<Value> {
+        if let Some(imm) = self.consts.get(&vn) {
+            return Some(Value::Imm(*imm));
+        }
+        self.holders
+            .get(&vn)?
+            .iter()
+            .find(|r| self.of_reg.get(r) == Some(&vn))
+            .map(|r| Value::Reg(*r))
+    }
+}
+
+fn fold(op: BinaryOpKind, lhs: i32, rhs: i32) -> Option<i32> {
+    // leave overflowing expressions for the backend, which wraps or, on
+    // MIPS with `add` and `sub`, traps; dead ones are still removed by dce
+    match op {
+        BinaryOpKind::Add => lhs.checked_add(rhs),
+        BinaryOpKind::Sub => lhs.checked_sub(rhs),
+    }
+}
+
+pub fn run(program: &mut Program, remarks: &mut Vec<Remark>) {
+    let mut table = ValueTable::default();
+    let mut kept = Vec::with_capacity(program.insts.len());
+    let spans = std::mem::take(&mut program.spans);
+
+    for (i, inst) in std::mem::take(&mut program.insts).into_iter().enumerate() {
+        let before = program.format_inst(&inst);
+        let rewritten = match inst {
+            Inst::Read { dst } => {
+                if let Some(dst) = dst {
+                    let vn = table.fresh();
+                    table.assign(dst, vn);
+                }
+                Some(inst)
+            }
+            Inst::Write { src } => {
+                let vn = table.value(src);
+                Some(Inst::Write {
+                    src: table.operand(vn).unwrap_or(src),
+                })
+            }
+            // the callee may change any global, so forget everything
+            Inst::Call { .. } => {
+                table = ValueTable::default();
+                Some(inst)
+            }
+            Inst::Copy { dst, src } => {
+                let vn = table.value(src);
+                let src = table.operand(vn).unwrap_or(src);
+                if table.of_reg.get(&dst) == Some(&vn) {
+                    None
+                } else {
+                    table.assign(dst, vn);
+                    Some(Inst::Copy { dst, src })
+                }
+            }
+            Inst::Binary { op, dst, lhs, rhs } => {
+                let lhs_vn = table.value(lhs);
+                let rhs_vn = table.value(rhs);
+                let lhs = table.operand(lhs_vn).unwrap_or(lhs);
+                let rhs = table.operand(rhs_vn).unwrap_or(rhs);
+                let zero = table.constant(0);
+
+                let simplified = match (op, lhs, rhs) {
+                    (_, Value::Imm(l), Value::Imm(r)) => fold(op, l, r).map(Value::Imm),
+                    (BinaryOpKind::Sub, _, _) if lhs_vn == rhs_vn => Some(Value::Imm(0)),
+                    (_, _, _) if rhs_vn == zero => Some(lhs),
+                    (BinaryOpKind::Add, _, _) if lhs_vn == zero => Some(rhs),
+                    _ => None,
+                };
+                // addition commutes, so `A + B` and `B + A` share a number
+                let key = match op {
+                    BinaryOpKind::Add => (op, lhs_vn.min(rhs_vn), lhs_vn.max(rhs_vn)),
+                    BinaryOpKind::Sub => (op, lhs_vn, rhs_vn),
+                };
+
+                let (vn, inst) = match simplified {
+                    Some(src) => (table.value(src), Inst::Copy { dst, src }),
+                    None => match table.exprs.get(&key).copied() {
+                        Some(vn) => match table.operand(vn) {
+                            Some(src) => (vn, Inst::Copy { dst, src }),
+                            None => (vn, Inst::Binary { op, dst, lhs, rhs }),
+                        },
+                        None => {
+                            let vn = table.fresh();
+                            table.exprs.insert(key, vn);
+                            (vn, Inst::Binary { op, dst, lhs, rhs })
+                        }
+                    },
+                };
+                if table.of_reg.get(&dst) == Some(&vn) {
+                    None
+                } else {
+                    table.assign(dst, vn);
+                    Some(inst)
+                }
+            }
+        };
+
+        match rewritten {
+            Some(inst) => {
+                let after = program.format_inst(&inst);
+                if after != before {
+                    remarks.push(Remark {
+                        pass: "lvn",
+                        message: format!("rewrote `{}` to `{}`", before, after),
+                    });
+                }
+                kept.push(inst);
+                program.spans.extend(spans.get(i));
+            }
+            None => remarks.push(Remark {
+                pass: "lvn",
+                message: format!("removed redundant `{}`", before),
+            }),
+        }
+    }
+
+    program.insts = kept;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::opt::{optimize, OptLevel};
+    use crate::testing;
+
+    use super::*;
+
+    fn optimize_source(source: &str) -> Program {
+        let mut program = testing::lower_source(source);
+        optimize(&mut program, OptLevel::O1);
+        program
+    }
+
+    #[test]
+    fn handle_self_subtraction() {
+        let program = optimize_source(r#"begin read(A, B); D := A + B - (A - A); write(D); end"#);
+        let a = program.vars["A"];
+        let b = program.vars["B"];
+        let sum = match program.insts[2] {
+            Inst::Binary { dst, .. } => dst,
+            _ => panic!("expected `A + B`"),
+        };
+        assert_eq!(
+            program.insts,
+            vec![
+                Inst::Read { dst: Some(a) },
+                Inst::Read { dst: Some(b) },
+                Inst::Binary {
+                    op: BinaryOpKind::Add,
+                    dst: sum,
+                    lhs: Value::Reg(a),
+                    rhs: Value::Reg(b),
+                },
+                Inst::Write {
+                    src: Value::Reg(sum)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn handle_repeated_expression() {
+        let program = optimize_source(r#"begin read(A, B); write(A + B, B + A + 0); end"#);
+        let a = program.vars["A"];
+        let b = program.vars["B"];
+        let sum = match program.insts[2] {
+            Inst::Binary { dst, .. } => dst,
+            _ => panic!("expected the sum to be computed once"),
+        };
+        assert_eq!(program.insts.len(), 5);
+        assert_eq!(
+            program.insts[2],
+            Inst::Binary {
+                op: BinaryOpKind::Add,
+                dst: sum,
+                lhs: Value::Reg(a),
+                rhs: Value::Reg(b),
+            }
+        );
+        assert_eq!(
+            program.insts[3],
+            Inst::Write {
+                src: Value::Reg(sum)
+            }
+        );
+        assert_eq!(
+            program.insts[4],
+            Inst::Write {
+                src: Value::Reg(sum)
+            }
+        );
+    }
+
+    #[test]
+    fn handle_constant_folding() {
+        let program = optimize_source(r#"begin A := 1; B := A + 2; write(B - 1); end"#);
+        assert_eq!(program.insts, vec![Inst::Write { src: Value::Imm(2) }]);
+    }
+}
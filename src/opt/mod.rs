@@ -0,0 +1,48 @@
+mod dce;
+mod lvn;
+
+use crate::ir::Program;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptLevel {
+    #[default]
+    O0,
+    O1,
+    O2,
+}
+
+impl OptLevel {
+    // parse the digit following `-O`
+    pub fn from_flag(level: &str) -> Option<OptLevel> {
+        match level {
+            "0" => Some(OptLevel::O0),
+            "1" => Some(OptLevel::O1),
+            "2" => Some(OptLevel::O2),
+            _ => None,
+        }
+    }
+}
+
+/// A note about what an optimization pass changed, shown with `-v`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Remark {
+    pub pass: &'static str,
+    pub message: String,
+}
+
+impl fmt::Display for Remark {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}", self.pass, self.message)
+    }
+}
+
+// run the IR passes enabled at `level`
+pub fn optimize(program: &mut Program, level: OptLevel) -> Vec<Remark> {
+    let mut remarks = Vec::new();
+    if level >= OptLevel::O1 {
+        lvn::run(program, &mut remarks);
+        dce::run(program, &mut remarks);
+    }
+    remarks
+}
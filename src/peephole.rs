@@ -0,0 +1,277 @@
+//! Peephole optimizations over the emitted MIPS instructions.
+//!
+//! Each rule looks at an instruction and its successor and rewrites them
+//! until nothing changes:
+//!
+//! - `move $x, $x` is removed;
+//! - `sw $r, N($fp)` followed by `lw $s, N($fp)` keeps the store and turns
+//!   the load into `move $s, $r` (or drops it when `$s` is `$r`);
+//! - `li $t, imm` followed by `add`/`sub` reading `$t` becomes a single
+//!   `addi` when `imm` fits in 16 bits and `$t` is not read afterwards, and
+//!   likewise `addu`/`subu` becomes `addiu`.
+//!
+//! Comments between the two are skipped, so annotating the output does not
+//! change the code. A label between them stops the rule, since code may
+//! jump to it.
+
+use crate::mips::{Instr, Reg};
+use crate::opt::Remark;
+
+pub fn run(asm: &mut Vec<Instr>, remarks: &mut Vec<Remark>) {
+    let mut changed = true;
+    while changed {
+        changed = false;
+        let mut i = 0;
+        while i < asm.len() {
+            if let Some(message) = rewrite(asm, i) {
+                remarks.push(Remark {
+                    pass: "peephole",
+                    message,
+                });
+                changed = true;
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+// try every rule at `i`, describing the rewrite if one applied
+fn rewrite(asm: &mut Vec<Instr>, i: usize) -> Option<String> {
+    if let Instr::Move { rd, rs } = asm[i] {
+        if rd == rs {
+            let removed = asm.remove(i);
+            return Some(format!("removed `{}`", removed));
+        }
+    }
+
+    let j = (i + 1..asm.len()).find(|&j| !matches!(asm[j], Instr::Comment(_)))?;
+    let (current, next) = (asm[i].clone(), asm[j].clone());
+    match (&current, &next) {
+        (
+            Instr::Sw { rt, offset, base },
+            Instr::Lw {
+                rt: load_rt,
+                offset: load_offset,
+                base: load_base,
+            },
+        ) if offset == load_offset && base == load_base && rt != base => {
+            let message = format!("forwarded `{}` to `{}`", current, next);
+            if rt == load_rt {
+                asm.remove(j);
+            } else {
+                asm[j] = Instr::Move {
+                    rd: *load_rt,
+                    rs: *rt,
+                };
+            }
+            Some(message)
+        }
+        (Instr::Li { rt: tmp, imm }, _) => {
+            let (tmp, imm) = (*tmp, *imm);
+            let wrapping = matches!(next, Instr::Addu { .. } | Instr::Subu { .. });
+            let folded = match next {
+                Instr::Add { rd, rs, rt } | Instr::Addu { rd, rs, rt }
+                    if rt == tmp && rs != tmp =>
+                {
+                    Some((rd, rs, Some(imm)))
+                }
+                Instr::Add { rd, rs, rt } | Instr::Addu { rd, rs, rt }
+                    if rs == tmp && rt != tmp =>
+                {
+                    Some((rd, rt, Some(imm)))
+                }
+                Instr::Sub { rd, rs, rt } | Instr::Subu { rd, rs, rt }
+                    if rt == tmp && rs != tmp =>
+                {
+                    Some((rd, rs, imm.checked_neg()))
+                }
+                _ => None,
+            };
+            let (rd, rs, imm) = match folded {
+                Some((rd, rs, Some(imm))) if i16::try_from(imm).is_ok() => (rd, rs, imm),
+                _ => return None,
+            };
+            if rd != tmp && !is_dead_after(asm, j, tmp) {
+                return None;
+            }
+            let addi = match wrapping {
+                true => Instr::Addiu { rt: rd, rs, imm },
+                false => Instr::Addi { rt: rd, rs, imm },
+            };
+            let message = format!("folded `{}` and `{}` into `{}`", current, next, addi);
+            asm[j] = addi;
+            asm.remove(i);
+            Some(message)
+        }
+        _ => None,
+    }
+}
+
+// whether `reg` is overwritten or never read again after `asm[index]`,
+// as far as the code runs straight on
+fn is_dead_after(asm: &[Instr], index: usize, reg: Reg) -> bool {
+    for instr in asm[index + 1..].iter() {
+        if matches!(
+            instr,
+            Instr::Label(_) | Instr::Jr { .. } | Instr::Beq { .. } | Instr::Bne { .. }
+        ) || instr.uses().contains(&reg)
+        {
+            return false;
+        }
+        if instr.def() == Some(reg) {
+            return true;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn optimize(mut asm: Vec<Instr>) -> Vec<Instr> {
+        run(&mut asm, &mut Vec::new());
+        asm
+    }
+
+    #[test]
+    fn handle_store_then_load() {
+        let asm = optimize(vec![
+            Instr::Li {
+                rt: Reg::T0,
+                imm: 70000,
+            },
+            Instr::Sw {
+                rt: Reg::T0,
+                offset: 36,
+                base: Reg::FP,
+            },
+            Instr::Lw {
+                rt: Reg::T0,
+                offset: 36,
+                base: Reg::FP,
+            },
+            Instr::Lw {
+                rt: Reg::A0,
+                offset: 36,
+                base: Reg::FP,
+            },
+            Instr::Move {
+                rd: Reg::T2,
+                rs: Reg::T2,
+            },
+        ]);
+        assert_eq!(
+            asm,
+            vec![
+                Instr::Li {
+                    rt: Reg::T0,
+                    imm: 70000,
+                },
+                Instr::Sw {
+                    rt: Reg::T0,
+                    offset: 36,
+                    base: Reg::FP,
+                },
+                Instr::Move {
+                    rd: Reg::A0,
+                    rs: Reg::T0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn handle_immediate_folding() {
+        let asm = optimize(vec![
+            Instr::Li {
+                rt: Reg::T1,
+                imm: 5,
+            },
+            Instr::Sub {
+                rd: Reg::T2,
+                rs: Reg::T3,
+                rt: Reg::T1,
+            },
+            Instr::Li {
+                rt: Reg::T1,
+                imm: 40000,
+            },
+            Instr::Add {
+                rd: Reg::T2,
+                rs: Reg::T1,
+                rt: Reg::T2,
+            },
+        ]);
+        assert_eq!(
+            asm,
+            vec![
+                Instr::Addi {
+                    rt: Reg::T2,
+                    rs: Reg::T3,
+                    imm: -5,
+                },
+                Instr::Li {
+                    rt: Reg::T1,
+                    imm: 40000,
+                },
+                Instr::Add {
+                    rd: Reg::T2,
+                    rs: Reg::T1,
+                    rt: Reg::T2,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn handle_live_immediate() {
+        let asm = vec![
+            Instr::Li {
+                rt: Reg::T0,
+                imm: 1,
+            },
+            Instr::Add {
+                rd: Reg::T2,
+                rs: Reg::T3,
+                rt: Reg::T0,
+            },
+            Instr::Sw {
+                rt: Reg::T0,
+                offset: 32,
+                base: Reg::FP,
+            },
+        ];
+        assert_eq!(optimize(asm.clone()), asm);
+    }
+
+    #[test]
+    fn handle_labels() {
+        let asm = vec![
+            Instr::Sw {
+                rt: Reg::T2,
+                offset: 32,
+                base: Reg::FP,
+            },
+            Instr::Label("again".to_string()),
+            Instr::Lw {
+                rt: Reg::T3,
+                offset: 32,
+                base: Reg::FP,
+            },
+            Instr::Li {
+                rt: Reg::T0,
+                imm: 1,
+            },
+            Instr::Comment("line 2: A := A + 1".to_string()),
+            Instr::Label("twice".to_string()),
+            Instr::Add {
+                rd: Reg::T3,
+                rs: Reg::T3,
+                rt: Reg::T0,
+            },
+        ];
+        assert_eq!(optimize(asm.clone()), asm);
+    }
+}
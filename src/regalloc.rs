@@ -0,0 +1,189 @@
+use crate::ir::{Program, VReg};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    /// Index into the target's list of allocatable registers.
+    Reg(usize),
+    /// Index of a 4-byte spill slot in the stack frame.
+    Spill(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interval {
+    pub vreg: VReg,
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Allocation {
+    pub locations: BTreeMap<VReg, Location>,
+    pub spill_slots: u32,
+}
+
+impl Allocation {
+    pub fn location(&self, vreg: VReg) -> Location {
+        self.locations[&vreg]
+    }
+
+    /// Allocatable registers that were handed out at least once, in order.
+    pub fn used_regs(&self) -> Vec<usize> {
+        let mut regs: Vec<usize> = self
+            .locations
+            .values()
+            .filter_map(|l| match l {
+                Location::Reg(r) => Some(*r),
+                Location::Spill(_) => None,
+            })
+            .collect();
+        regs.sort_unstable();
+        regs.dedup();
+        regs
+    }
+}
+
+// compute one live interval per virtual register, sorted by start point.
+// instruction `i` reads its operands at `2i` and writes its result at `2i + 1`,
+// so a register dying at `i` can be reused for the result of `i`.
+pub fn live_intervals(program: &Program) -> Vec<Interval> {
+    let mut ranges = BTreeMap::<VReg, (usize, usize)>::new();
+    let mut touch = |vreg: VReg, point: usize| {
+        let range = ranges.entry(vreg).or_insert((point, point));
+        range.0 = range.0.min(point);
+        range.1 = range.1.max(point);
+    };
+    for (i, inst) in program.insts.iter().enumerate() {
+        for vreg in inst.uses() {
+            touch(vreg, 2 * i);
+        }
+        if let Some(vreg) = inst.def() {
+            touch(vreg, 2 * i + 1);
+        }
+    }
+    let mut intervals: Vec<Interval> = ranges
+        .into_iter()
+        .map(|(vreg, (start, end))| Interval { vreg, start, end })
+        .collect();
+    intervals.sort_by_key(|i| (i.start, i.vreg));
+    intervals
+}
+
+// linear scan register allocation (Poletto & Sarkar). when every register is
+// taken, the interval that ends last is spilled to the stack for its whole
+// lifetime. spill slots are reused once their interval has ended.
+pub fn allocate(program: &Program, num_regs: usize) -> Allocation {
+    let mut allocation = Allocation::default();
+    let mut free_regs: Vec<usize> = (0..num_regs).rev().collect();
+    let mut free_slots: Vec<u32> = Vec::new();
+    // (end, vreg) of intervals currently holding a register or a slot
+    let mut active: Vec<(usize, VReg)> = Vec::new();
+    let mut spilled: Vec<(usize, u32)> = Vec::new();
+
+    for interval in live_intervals(program) {
+        active.retain(|&(end, vreg)| {
+            if end < interval.start {
+                if let Location::Reg(r) = allocation.location(vreg) {
+                    free_regs.push(r);
+                }
+                false
+            } else {
+                true
+            }
+        });
+        spilled.retain(|&(end, slot)| {
+            if end < interval.start {
+                free_slots.push(slot);
+                false
+            } else {
+                true
+            }
+        });
+
+        if let Some(r) = free_regs.pop() {
+            allocation.locations.insert(interval.vreg, Location::Reg(r));
+            active.push((interval.end, interval.vreg));
+            continue;
+        }
+
+        let furthest = active
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, (end, _))| *end)
+            .map(|(i, _)| i);
+        match furthest {
+            Some(i) if active[i].0 > interval.end => {
+                let (end, victim) = active.swap_remove(i);
+                let slot = take_slot(&mut free_slots, &mut allocation.spill_slots);
+                let reg = allocation.locations[&victim];
+                allocation.locations.insert(victim, Location::Spill(slot));
+                allocation.locations.insert(interval.vreg, reg);
+                spilled.push((end, slot));
+                active.push((interval.end, interval.vreg));
+            }
+            _ => {
+                let slot = take_slot(&mut free_slots, &mut allocation.spill_slots);
+                allocation
+                    .locations
+                    .insert(interval.vreg, Location::Spill(slot));
+                spilled.push((interval.end, slot));
+            }
+        }
+    }
+    allocation
+}
+
+fn take_slot(free_slots: &mut Vec<u32>, spill_slots: &mut u32) -> u32 {
+    free_slots.pop().unwrap_or_else(|| {
+        *spill_slots += 1;
+        *spill_slots - 1
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::BinaryOpKind;
+    use crate::ir::{Inst, Value};
+
+    use super::*;
+
+    // r0 := read; r1 := read; r2 := r0 + r1; write r2
+    fn sum_program() -> Program {
+        Program {
+            insts: vec![
+                Inst::Read { dst: Some(0) },
+                Inst::Read { dst: Some(1) },
+                Inst::Binary {
+                    op: BinaryOpKind::Add,
+                    dst: 2,
+                    lhs: Value::Reg(0),
+                    rhs: Value::Reg(1),
+                },
+                Inst::Write { src: Value::Reg(2) },
+            ],
+            spans: Vec::new(),
+            vars: BTreeMap::new(),
+            globals: Default::default(),
+            vreg_count: 3,
+        }
+    }
+
+    #[test]
+    fn handle_register_reuse() {
+        let allocation = allocate(&sum_program(), 2);
+        assert_eq!(allocation.spill_slots, 0);
+        assert_eq!(allocation.location(0), Location::Reg(0));
+        assert_eq!(allocation.location(1), Location::Reg(1));
+        // both operands die at the add, so its result reuses a register
+        assert!(matches!(allocation.location(2), Location::Reg(_)));
+    }
+
+    #[test]
+    fn handle_register_pressure() {
+        let allocation = allocate(&sum_program(), 1);
+        assert_eq!(allocation.spill_slots, 1);
+        assert_eq!(allocation.location(0), Location::Reg(0));
+        assert_eq!(allocation.location(1), Location::Spill(0));
+        assert_eq!(allocation.location(2), Location::Reg(0));
+    }
+}
@@ -0,0 +1,336 @@
+//! `microc repl`: run Micro statements as they are typed.
+//!
+//! A line holds one or more statements, the last `;` optional. They are
+//! evaluated on the AST right away, against variables that last for the
+//! whole session; `read` prompts for each of its variables. Statements that
+//! ran are kept, so `:ast` and `:asm` can show the session as a program.
+
+use crate::ast::{ASTBuilder, BinaryOpKind, ExprAST, ExprKind, SyscallKind};
+use crate::codegen::CodeGenerator;
+use crate::diagnostic::Diagnostic;
+use crate::ir;
+use crate::lexer::Lexer;
+use crate::mips;
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "\
+statements run as they are entered, e.g. `read(A); write(A + 1)`
+:ast [statements]  the syntax tree of the statements, or of the session
+:asm [statements]  the MIPS code for the statements, or for the session
+:vars              the variables and their values
+:reset             forget all variables and statements
+:quit              leave, as does the end of the input
+";
+
+fn parse(source: &str) -> Result<Vec<ExprAST>, Vec<Diagnostic>> {
+    let mut lexer = Lexer::new(source);
+    lexer.recover = true;
+    let tokens = lexer.tokens();
+    let mut builder = ASTBuilder::new(tokens.into_iter());
+    let statements = builder.parse_statements();
+    let mut diagnostics = lexer.diagnostics;
+    diagnostics.append(&mut builder.diagnostics);
+    match diagnostics.is_empty() {
+        true => Ok(statements),
+        false => {
+            diagnostics.sort_by_key(|d| d.span);
+            Err(diagnostics)
+        }
+    }
+}
+
+// one line per node, children indented
+fn tree(expr: &ExprAST, depth: usize, out: &mut String) {
+    out.push_str(&"  ".repeat(depth));
+    let children: Vec<&ExprAST> = match &expr.kind {
+        ExprKind::IntLiteralExprAST { value } => {
+            out.push_str(&format!("IntLiteral {}\n", value));
+            Vec::new()
+        }
+        ExprKind::VariableExprAST { name } => {
+            out.push_str(&format!("Variable {}\n", name));
+            Vec::new()
+        }
+        ExprKind::BinaryExprAST { op, lhs, rhs } => {
+            let op = match op {
+                BinaryOpKind::Add => "+",
+                BinaryOpKind::Sub => "-",
+            };
+            out.push_str(&format!("Binary {}\n", op));
+            vec![lhs, rhs]
+        }
+        ExprKind::SyscallExprAST { calle, args } => {
+            let calle = match calle {
+                SyscallKind::Read => "read",
+                SyscallKind::Write => "write",
+            };
+            out.push_str(&format!("Syscall {}\n", calle));
+            args.iter().collect()
+        }
+        ExprKind::AssignmentAST { var, assign } => {
+            out.push_str("Assignment\n");
+            vec![var, assign]
+        }
+        ExprKind::CallAST { name } => {
+            out.push_str(&format!("Call {}\n", name));
+            Vec::new()
+        }
+    };
+    for child in children {
+        tree(child, depth + 1, out);
+    }
+}
+
+struct Session<R, W> {
+    input: R,
+    output: W,
+    vars: BTreeMap<String, i32>,
+    // the statements that ran
+    history: Vec<ExprAST>,
+}
+
+impl<R: BufRead, W: Write> Session<R, W> {
+    // a line without its newline, or `None` at the end of the input
+    fn prompt(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        write!(self.output, "{}", prompt)?;
+        self.output.flush()?;
+        let mut line = String::new();
+        if self.input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(line.trim_end_matches(['\n', '\r']).to_string()))
+    }
+
+    fn value(&self, expr: &ExprAST) -> Result<i32, String> {
+        match &expr.kind {
+            ExprKind::IntLiteralExprAST { value } => Ok(*value),
+            ExprKind::VariableExprAST { name } => self
+                .vars
+                .get(&**name)
+                .copied()
+                .ok_or_else(|| format!("`{}` has not been assigned", name)),
+            ExprKind::BinaryExprAST { op, lhs, rhs } => {
+                let (lhs, rhs) = (self.value(lhs)?, self.value(rhs)?);
+                Ok(match op {
+                    BinaryOpKind::Add => lhs.wrapping_add(rhs),
+                    BinaryOpKind::Sub => lhs.wrapping_sub(rhs),
+                })
+            }
+            _ => Err("not an expression".to_string()),
+        }
+    }
+
+    // run a statement; false if it failed, after saying why
+    fn execute(&mut self, stmt: &ExprAST) -> io::Result<bool> {
+        match &stmt.kind {
+            ExprKind::AssignmentAST { var, assign } => {
+                let ExprKind::VariableExprAST { name } = &var.kind else {
+                    unreachable!()
+                };
+                match self.value(assign) {
+                    Ok(value) => self.vars.insert(name.to_string(), value),
+                    Err(e) => return self.error(&e),
+                };
+            }
+            ExprKind::SyscallExprAST {
+                calle: SyscallKind::Write,
+                args,
+            } => {
+                // nothing is written unless every argument has a value
+                let values: Result<Vec<i32>, String> =
+                    args.iter().map(|arg| self.value(arg)).collect();
+                match values {
+                    Ok(values) => {
+                        for value in values {
+                            writeln!(self.output, "{}", value)?;
+                        }
+                    }
+                    Err(e) => return self.error(&e),
+                }
+            }
+            ExprKind::SyscallExprAST {
+                calle: SyscallKind::Read,
+                args,
+            } => {
+                for arg in args.iter() {
+                    let ExprKind::VariableExprAST { name } = &arg.kind else {
+                        unreachable!()
+                    };
+                    let value = loop {
+                        let Some(line) = self.prompt(&format!("{}? ", name))? else {
+                            return self.error(&format!("no input for `{}`", name));
+                        };
+                        match line.trim().parse::<i32>() {
+                            Ok(value) => break value,
+                            Err(_) => writeln!(self.output, "error: expected an integer")?,
+                        }
+                    };
+                    self.vars.insert(name.to_string(), value);
+                }
+            }
+            ExprKind::CallAST { name } => {
+                return self.error(&format!("no procedure `{}`, the repl has no modules", name))
+            }
+            _ => unreachable!(),
+        }
+        self.history.push(stmt.clone());
+        Ok(true)
+    }
+
+    fn error(&mut self, message: &str) -> io::Result<bool> {
+        writeln!(self.output, "error: {}", message)?;
+        Ok(false)
+    }
+
+    // the statements in `source`, or the session's if it is empty
+    fn statements(&mut self, source: &str) -> io::Result<Option<Vec<ExprAST>>> {
+        if source.trim().is_empty() {
+            return Ok(Some(self.history.clone()));
+        }
+        match parse(source) {
+            Ok(statements) => Ok(Some(statements)),
+            Err(diagnostics) => {
+                for diagnostic in diagnostics.iter() {
+                    write!(self.output, "{}", diagnostic.render(source))?;
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    // handle a `:` command; false to leave
+    fn command(&mut self, line: &str) -> io::Result<bool> {
+        let (name, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match name {
+            ":ast" => {
+                if let Some(statements) = self.statements(rest)? {
+                    let mut out = String::new();
+                    for stmt in statements.iter() {
+                        tree(stmt, 0, &mut out);
+                    }
+                    write!(self.output, "{}", out)?;
+                }
+            }
+            ":asm" => {
+                if let Some(statements) = self.statements(rest)? {
+                    let program = ir::lower(statements);
+                    let listing = CodeGenerator::new().assemble(&program);
+                    write!(self.output, "{}", mips::print(&listing))?;
+                }
+            }
+            ":vars" => {
+                if self.vars.is_empty() {
+                    writeln!(self.output, "no variables")?;
+                }
+                for (name, value) in self.vars.iter() {
+                    writeln!(self.output, "{} = {}", name, value)?;
+                }
+            }
+            ":reset" => {
+                self.vars.clear();
+                self.history.clear();
+            }
+            ":help" => write!(self.output, "{}", HELP)?,
+            ":quit" | ":q" => return Ok(false),
+            _ => writeln!(self.output, "error: unknown command `{}`, see :help", name)?,
+        }
+        Ok(true)
+    }
+}
+
+/// Read lines from `input` until the end of it or `:quit`.
+pub fn run(input: impl BufRead, output: impl Write) -> io::Result<()> {
+    let mut session = Session {
+        input,
+        output,
+        vars: BTreeMap::new(),
+        history: Vec::new(),
+    };
+    writeln!(session.output, "microc repl, :help for commands")?;
+    while let Some(line) = session.prompt("> ")? {
+        let line = line.trim();
+        if line.starts_with(':') {
+            if !session.command(line)? {
+                return Ok(());
+            }
+            continue;
+        }
+        if line.is_empty() {
+            continue;
+        }
+        match parse(line) {
+            Ok(statements) => {
+                for stmt in statements.iter() {
+                    if !session.execute(stmt)? {
+                        break;
+                    }
+                }
+            }
+            Err(diagnostics) => {
+                for diagnostic in diagnostics.iter() {
+                    write!(session.output, "{}", diagnostic.render(line))?;
+                }
+            }
+        }
+    }
+    writeln!(session.output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(input: &str) -> String {
+        let mut output = Vec::new();
+        run(input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn handle_session() {
+        let output = session(
+            "read(A, B); write(A + B)\nx\n4\n3\nC := A - (B - 10);\nwrite(C, D)\nwrite(C)\n\
+             :vars\n:reset\n:vars\nA := 2147483647 + 1; write(A)\n",
+        );
+        assert_eq!(
+            output,
+            "microc repl, :help for commands
+> A? error: expected an integer
+A? B? 7
+> > error: `D` has not been assigned
+> 11
+> A = 4
+B = 3
+C = 11
+> > no variables
+> -2147483648
+> \n"
+        );
+    }
+
+    #[test]
+    fn handle_commands() {
+        let output = session("X := 1\n:ast write(X + 1)\n:asm\nwrite(X +)\n:bogus\n:q\nwrite(X)\n");
+        let (asm_start, asm_end) = (
+            output.find("    .text").unwrap(),
+            output.rfind("    jr $ra\n").unwrap() + "    jr $ra\n".len(),
+        );
+        assert!(output[asm_start..asm_end].contains("    li $t2, 1\n"));
+        assert_eq!(
+            format!("{}{}", &output[..asm_start], &output[asm_end..]),
+            "microc repl, :help for commands
+> > Syscall write
+  Binary +
+    Variable X
+    IntLiteral 1
+> > microc: [syntax error] expected an expression, found `)`
+    --> 1:10
+      |
+    1 |write(X +)
+      |         ^
+> error: unknown command `:bogus`, see :help
+> "
+        );
+    }
+}
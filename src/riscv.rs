@@ -0,0 +1,357 @@
+//! RV32IM backend producing assembly for the RARS simulator.
+//!
+//! It shares the IR and the linear scan allocator with the MIPS backend.
+//! `read` and `write` use the RARS `ecall` services (`a7` = 5, 1 and 11),
+//! and the program exits with service 10.
+
+use crate::ast::BinaryOpKind;
+use crate::backend::{self, Backend};
+use crate::ir::{Inst, Program, VReg, Value};
+use crate::regalloc::{self, Location};
+use std::collections::BTreeMap;
+
+pub static PRELUDE: &str = r#"# Module : main
+    .text
+    .globl read
+read:
+    # call read integer
+    li a7, 5
+    ecall
+    ret
+    .globl write
+write:
+    li a7, 1
+    ecall
+    li a0, 10
+    li a7, 11
+    ecall
+    ret
+"#;
+
+// bytes reserved at the bottom of the frame for the saved ra and s0
+const FRAME_HEADER: u32 = 8;
+
+// registers handed out by the allocator. t0 and t1 are kept as scratch
+// registers for spilled operands and immediates; the runtime only
+// clobbers a0 and a7.
+pub static ALLOCATABLE: [&str; 16] = [
+    "t2", "t3", "t4", "t5", "t6", "s1", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10",
+    "s11",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Reg(&'static str),
+    Mem(u32),
+    Imm(i32),
+}
+
+#[derive(Debug, Default)]
+pub struct RiscvGenerator {
+    pub frame_size: u32,
+    pub saved_regs: Vec<&'static str>,
+    pub symbol_map: BTreeMap<String, Operand>,
+    pub asm: Vec<String>,
+    locations: Vec<Operand>,
+}
+
+// whether `imm` fits the 12-bit signed immediate of `addi`
+fn fits_imm12(imm: i32) -> bool {
+    (-2048..2048).contains(&imm)
+}
+
+impl RiscvGenerator {
+    pub fn new() -> RiscvGenerator {
+        RiscvGenerator::default()
+    }
+
+    fn operand(&self, value: Value) -> Operand {
+        match value {
+            Value::Reg(vreg) => self.locations[vreg as usize],
+            Value::Imm(imm) => Operand::Imm(imm),
+        }
+    }
+
+    // the register to read `value` from: its allocated register, or
+    // `scratch` after an `lw` from the s0 frame or an `li`
+    fn load(&mut self, value: Value, scratch: &'static str) -> &'static str {
+        match self.operand(value) {
+            Operand::Reg(reg) => reg,
+            Operand::Mem(offset) => {
+                self.asm.push(format!("lw {}, {}(s0)", scratch, offset));
+                scratch
+            }
+            Operand::Imm(imm) => {
+                self.asm.push(format!("li {}, {}", scratch, imm));
+                scratch
+            }
+        }
+    }
+
+    // where to compute `vreg`: its allocated register, or t0 when it lives
+    // in the frame, for `store` to write back
+    fn dest(&self, vreg: VReg) -> &'static str {
+        match self.locations[vreg as usize] {
+            Operand::Reg(reg) => reg,
+            _ => "t0",
+        }
+    }
+
+    // `sw` t0 to the s0 frame slot of `vreg`, if it has one
+    fn store(&mut self, vreg: VReg) {
+        if let Operand::Mem(offset) = self.locations[vreg as usize] {
+            self.asm.push(format!("sw t0, {}(s0)", offset));
+        }
+    }
+}
+
+impl Backend for RiscvGenerator {
+    type Instr = String;
+    type Error = String;
+
+    fn file_extension(&self) -> &'static str {
+        "asm"
+    }
+
+    // run the register allocator and lay out the stack frame: header, then
+    // saved registers, then spill slots, rounded up to the 16 bytes the
+    // calling convention requires
+    fn assign_locations(&mut self, program: &Program) {
+        let allocation = regalloc::allocate(program, ALLOCATABLE.len());
+        self.saved_regs = allocation
+            .used_regs()
+            .into_iter()
+            .map(|r| ALLOCATABLE[r])
+            .filter(|r| r.starts_with('s'))
+            .collect();
+        let spill_base = FRAME_HEADER + 4 * self.saved_regs.len() as u32;
+        self.frame_size = (spill_base + 4 * allocation.spill_slots).next_multiple_of(16);
+
+        self.locations = (0..program.vreg_count)
+            .map(|vreg| match allocation.locations.get(&vreg) {
+                Some(Location::Reg(r)) => Operand::Reg(ALLOCATABLE[*r]),
+                Some(Location::Spill(slot)) => Operand::Mem(spill_base + 4 * slot),
+                None => Operand::Imm(0),
+            })
+            .collect();
+        self.symbol_map = program
+            .vars
+            .iter()
+            .map(|(name, vreg)| (name.clone(), self.locations[*vreg as usize]))
+            .collect();
+    }
+
+    // `read` and `write` pass the value in a0, and arithmetic with a
+    // constant that fits 12 bits becomes `addi`
+    fn lower_instruction(&mut self, inst: &Inst) -> Result<(), String> {
+        match inst {
+            Inst::Call { .. } => return Err(backend::NO_PROCEDURES.to_string()),
+            Inst::Read { dst } => {
+                self.asm.push("call read".to_string());
+                match dst.map(|dst| self.locations[dst as usize]) {
+                    Some(Operand::Reg(reg)) => self.asm.push(format!("mv {}, a0", reg)),
+                    Some(Operand::Mem(offset)) => self.asm.push(format!("sw a0, {}(s0)", offset)),
+                    _ => {}
+                }
+            }
+            Inst::Write { src } => {
+                match self.operand(*src) {
+                    Operand::Reg(reg) => self.asm.push(format!("mv a0, {}", reg)),
+                    Operand::Mem(offset) => self.asm.push(format!("lw a0, {}(s0)", offset)),
+                    Operand::Imm(imm) => self.asm.push(format!("li a0, {}", imm)),
+                }
+                self.asm.push("call write".to_string());
+            }
+            Inst::Copy { dst, src } => {
+                let rd = self.dest(*dst);
+                match self.operand(*src) {
+                    Operand::Reg(reg) => self.asm.push(format!("mv {}, {}", rd, reg)),
+                    Operand::Mem(offset) => self.asm.push(format!("lw {}, {}(s0)", rd, offset)),
+                    Operand::Imm(imm) => self.asm.push(format!("li {}, {}", rd, imm)),
+                }
+                self.store(*dst);
+            }
+            Inst::Binary { op, dst, lhs, rhs } => {
+                // reg + imm, imm + reg and reg - imm fit in one `addi`
+                let immediate = match (op, lhs, rhs) {
+                    (_, Value::Reg(_), Value::Imm(imm)) => {
+                        let imm = match op {
+                            BinaryOpKind::Add => Some(*imm),
+                            BinaryOpKind::Sub => imm.checked_neg(),
+                        };
+                        imm.filter(|imm| fits_imm12(*imm)).map(|imm| (*lhs, imm))
+                    }
+                    (BinaryOpKind::Add, Value::Imm(imm), Value::Reg(_)) if fits_imm12(*imm) => {
+                        Some((*rhs, *imm))
+                    }
+                    _ => None,
+                };
+                if let Some((src, imm)) = immediate {
+                    let rs = self.load(src, "t0");
+                    let rd = self.dest(*dst);
+                    self.asm.push(format!("addi {}, {}, {}", rd, rs, imm));
+                } else {
+                    let rs = self.load(*lhs, "t0");
+                    let rt = self.load(*rhs, "t1");
+                    let rd = self.dest(*dst);
+                    let mnemonic = match op {
+                        BinaryOpKind::Add => "add",
+                        BinaryOpKind::Sub => "sub",
+                    };
+                    self.asm
+                        .push(format!("{} {}, {}, {}", mnemonic, rd, rs, rt));
+                }
+                self.store(*dst);
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Vec<String> {
+        self.asm.iter().map(|c| format!("    {}", c)).collect()
+    }
+
+    fn prologue(&self) -> Vec<String> {
+        let mut buf = format!(
+            "
+    .text
+    .globl main
+main:
+    # prologue area
+    addi sp, sp, -{}
+    sw ra, 0(sp)
+    sw s0, 4(sp)
+    mv s0, sp
+",
+            self.frame_size
+        );
+        for (i, reg) in self.saved_regs.iter().enumerate() {
+            buf.push_str(format!("    sw {}, {}(s0)\n", reg, FRAME_HEADER + 4 * i as u32).as_str());
+        }
+        backend::lines(&buf)
+    }
+
+    fn epilogue(&self) -> Vec<String> {
+        let mut buf = String::from("\n    # epilogue area\n");
+        for (i, reg) in self.saved_regs.iter().enumerate() {
+            buf.push_str(format!("    lw {}, {}(s0)\n", reg, FRAME_HEADER + 4 * i as u32).as_str());
+        }
+        buf.push_str(
+            format!(
+                "    mv sp, s0
+    lw s0, 4(sp)
+    lw ra, 0(sp)
+    addi sp, sp, {}
+    li a7, 10
+    ecall
+",
+                self.frame_size
+            )
+            .as_str(),
+        );
+        backend::lines(&buf)
+    }
+
+    fn runtime_support(&self) -> Vec<String> {
+        backend::lines(PRELUDE)
+    }
+
+    fn print(&self, listing: &[String]) -> String {
+        backend::print_lines(listing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::Emitter;
+    use crate::testing;
+
+    use super::*;
+
+    fn compile(source: &str) -> String {
+        RiscvGenerator::new()
+            .emit(&testing::lower_source(source))
+            .unwrap()
+    }
+
+    // just enough of RARS to run what the backend emits
+    fn run(asm: &str, mut input: Vec<i32>) -> Vec<i32> {
+        let lines: Vec<Vec<String>> = asm
+            .lines()
+            .map(|l| l.split('#').next().unwrap().trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('.'))
+            .map(|l| {
+                l.split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|s| !s.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .collect();
+        let label = |name: &str| {
+            lines
+                .iter()
+                .position(|l| l[0] == format!("{}:", name))
+                .unwrap()
+        };
+        let mut regs = BTreeMap::<String, i32>::new();
+        let mut memory = BTreeMap::<i32, i32>::new();
+        regs.insert("sp".to_string(), 0x7fff_effc);
+        let mut output = Vec::new();
+        let mut pc = label("main");
+        let mut ret = Vec::new();
+        loop {
+            let l = &lines[pc];
+            pc += 1;
+            let reg = |regs: &BTreeMap<String, i32>, r: &str| regs.get(r).copied().unwrap_or(0);
+            let mem = |regs: &BTreeMap<String, i32>, m: &str| {
+                let (offset, base) = m.trim_end_matches(')').split_once('(').unwrap();
+                offset.parse::<i32>().unwrap() + reg(regs, base)
+            };
+            match l[0].as_str() {
+                "li" => _ = regs.insert(l[1].clone(), l[2].parse().unwrap()),
+                "mv" => _ = regs.insert(l[1].clone(), reg(&regs, &l[2])),
+                "add" => _ = regs.insert(l[1].clone(), reg(&regs, &l[2]) + reg(&regs, &l[3])),
+                "sub" => _ = regs.insert(l[1].clone(), reg(&regs, &l[2]) - reg(&regs, &l[3])),
+                "addi" => {
+                    let value = reg(&regs, &l[2]) + l[3].parse::<i32>().unwrap();
+                    regs.insert(l[1].clone(), value);
+                }
+                "lw" => {
+                    let value = memory.get(&mem(&regs, &l[2])).copied().unwrap_or(0);
+                    regs.insert(l[1].clone(), value);
+                }
+                "sw" => _ = memory.insert(mem(&regs, &l[2]), reg(&regs, &l[1])),
+                "call" => {
+                    ret.push(pc);
+                    pc = label(&l[1]);
+                }
+                "ret" => pc = ret.pop().unwrap(),
+                "ecall" => match reg(&regs, "a7") {
+                    1 => output.push(reg(&regs, "a0")),
+                    5 => _ = regs.insert("a0".to_string(), input.remove(0)),
+                    10 => return output,
+                    11 => assert_eq!(reg(&regs, "a0"), '\n' as i32),
+                    service => panic!("unexpected ecall {}", service),
+                },
+                op if op.ends_with(':') => {}
+                op => panic!("unexpected instruction {}", op),
+            }
+        }
+    }
+
+    #[test]
+    fn handle_test_cases() {
+        for case in testing::test_cases() {
+            assert_eq!(run(&compile(case.source), case.input), case.expected);
+        }
+    }
+
+    #[test]
+    fn handle_frame_alignment() {
+        // enough live variables to use every s register and spill the rest
+        let asm = compile(include_str!("../TestCases/test5.m"));
+        assert!(asm.contains("addi sp, sp, -192\n"));
+        assert!(asm.contains("sw s11, 48(s0)"));
+        assert!(asm.contains("sw t0, 52(s0)"));
+    }
+}
@@ -0,0 +1,484 @@
+//! A MIPS simulator for the listings the MIPS backend emits.
+//!
+//! It runs the typed listing directly rather than machine code: the program
+//! counter is an index into the listing, and `jal` leaves the index of the
+//! next line in `$ra`. Pseudo-instructions run as themselves, so listings
+//! need not be expanded, though expanded ones run the same. The system
+//! calls are SPIM's `print_int`, `read_int`, `exit` and `print_char`, and
+//! the Linux `exit`, `read` and `write` the `--emit elf` runtime makes. The
+//! stack may grow to 1 MiB, past which a push is a stack overflow.
+
+use crate::bytecode::vm::Input;
+use crate::mips::{self, Directive, Instr, Reg};
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+
+// where SPIM starts the stack
+const STACK_TOP: u32 = 0x7fff_effc;
+// how far below it the stack may grow, 1 MiB
+const STACK_LIMIT: u32 = STACK_TOP - (1 << 20);
+
+// registers a Linux system call may change besides $v0 and $a3, which get
+// a value nothing should be reading
+const KERNEL_CLOBBERED: [Reg; 12] = [
+    Reg::AT,
+    Reg(3),
+    Reg::T0,
+    Reg::T1,
+    Reg::T2,
+    Reg::T3,
+    Reg::T4,
+    Reg::T5,
+    Reg::T6,
+    Reg::T7,
+    Reg::T8,
+    Reg::T9,
+];
+
+pub struct Machine {
+    listing: Vec<Instr>,
+    // text labels, by listing index
+    labels: BTreeMap<String, usize>,
+    // data labels, by address
+    data: BTreeMap<String, u32>,
+    memory: BTreeMap<u32, i32>,
+    regs: [i32; 32],
+    // the remainder and quotient of the last `divu`
+    hi: i32,
+    lo: i32,
+    pub pc: usize,
+    pub halted: bool,
+    // what the Linux `exit` was given, and what `write` sent to stderr
+    pub status: i32,
+    pub stderr: Vec<u8>,
+    // the last `line N` comment run past, when the code has them
+    line: Option<String>,
+}
+
+impl Machine {
+    /// A machine about to run `main`, with the data segment loaded.
+    pub fn new(listing: Vec<Instr>) -> Result<Machine, String> {
+        let mut labels = BTreeMap::new();
+        let mut memory = BTreeMap::new();
+        let mut in_data = false;
+        let mut address = mips::DATA_BASE;
+        for (i, instr) in listing.iter().enumerate() {
+            match instr {
+                // the exception handler never runs here, overflow stops
+                // the machine with its own message
+                Instr::Directive(Directive::Text | Directive::Ktext) => in_data = false,
+                Instr::Directive(Directive::Data | Directive::Kdata) => in_data = true,
+                Instr::Directive(Directive::WordChar(c)) => {
+                    memory.insert(address, *c as i32);
+                    address += 4;
+                }
+                Instr::Directive(Directive::Word(value)) => {
+                    memory.insert(address, *value);
+                    address += 4;
+                }
+                Instr::Label(name) if !in_data => {
+                    labels.insert(name.clone(), i);
+                }
+                _ => {}
+            }
+        }
+        let data = mips::layout(&listing)
+            .into_iter()
+            .filter(|(name, _)| !labels.contains_key(name))
+            .collect();
+        let pc = *labels.get("main").ok_or("no `main` label")?;
+        let mut regs = [0; 32];
+        regs[Reg::SP.0 as usize] = STACK_TOP as i32;
+        Ok(Machine {
+            listing,
+            labels,
+            data,
+            memory,
+            regs,
+            hi: 0,
+            lo: 0,
+            pc,
+            halted: false,
+            status: 0,
+            stderr: Vec::new(),
+            line: None,
+        })
+    }
+
+    pub fn reg(&self, reg: Reg) -> i32 {
+        self.regs[reg.0 as usize]
+    }
+
+    fn set(&mut self, reg: Reg, value: i32) {
+        if reg != Reg::ZERO {
+            self.regs[reg.0 as usize] = value;
+        }
+    }
+
+    /// The word at `address`; memory nothing was stored to reads as 0.
+    pub fn load(&self, address: u32) -> Result<i32, String> {
+        if !address.is_multiple_of(4) {
+            return Err(format!("unaligned address {:#010x}", address));
+        }
+        Ok(self.memory.get(&address).copied().unwrap_or(0))
+    }
+
+    fn store(&mut self, address: u32, value: i32) -> Result<(), String> {
+        if !address.is_multiple_of(4) {
+            return Err(format!("unaligned address {:#010x}", address));
+        }
+        self.memory.insert(address, value);
+        Ok(())
+    }
+
+    // bytes are stored big-endian within their word
+    fn load_byte(&self, address: u32) -> u8 {
+        let word = self.memory.get(&(address & !3)).copied().unwrap_or(0);
+        (word as u32 >> (8 * (3 - address % 4))) as u8
+    }
+
+    fn store_byte(&mut self, address: u32, value: u8) {
+        let shift = 8 * (3 - address % 4);
+        let word = self.memory.entry(address & !3).or_insert(0);
+        *word = (*word as u32 & !(0xff << shift) | (value as u32) << shift) as i32;
+    }
+
+    // a Linux system call succeeding with `result`
+    fn linux_return(&mut self, result: i32) {
+        for reg in KERNEL_CLOBBERED {
+            self.set(reg, 0xdead_beef_u32 as i32);
+        }
+        self.set(Reg::V0, result);
+        self.set(Reg::A3, 0);
+    }
+
+    fn jump(&mut self, target: &str) -> Result<(), String> {
+        self.pc = *self
+            .labels
+            .get(target)
+            .ok_or_else(|| format!("no label `{}`", target))?;
+        Ok(())
+    }
+
+    fn data_address(&self, label: &str) -> Result<u32, String> {
+        self.data
+            .get(label)
+            .copied()
+            .ok_or_else(|| format!("no data label `{}`", label))
+    }
+
+    fn address(&self, offset: i32, base: Reg) -> u32 {
+        self.reg(base).wrapping_add(offset) as u32
+    }
+
+    /// Whether the next instruction is the system call reading an integer.
+    pub fn reads_next(&self) -> bool {
+        let next = self.listing[self.pc..].iter().find(|i| i.is_instruction());
+        next == Some(&Instr::Syscall) && self.reg(Reg::V0) == 5
+    }
+
+    /// Run the next instruction, skipping labels, directives and comments.
+    /// Errors name the instruction that failed, which runs again on the
+    /// next step.
+    pub fn step(
+        &mut self,
+        input: &mut Input<impl BufRead>,
+        output: &mut impl Write,
+    ) -> Result<(), String> {
+        while let Some(instr) = self.listing.get(self.pc).filter(|i| !i.is_instruction()) {
+            if let Instr::Comment(text) = instr {
+                if let Some((line, _)) =
+                    text.split_once(':').filter(|(l, _)| l.starts_with("line "))
+                {
+                    self.line = Some(line.to_string());
+                }
+            }
+            self.pc += 1;
+        }
+        let instr = self
+            .listing
+            .get(self.pc)
+            .cloned()
+            .ok_or("execution ran past the end of the program")?;
+        self.pc += 1;
+        let result = self.execute(&instr, input, output);
+        if result.is_err() {
+            self.pc -= 1;
+        }
+        result.map_err(|e| format!("`{}`: {}", instr, e))
+    }
+
+    fn execute(
+        &mut self,
+        instr: &Instr,
+        input: &mut Input<impl BufRead>,
+        output: &mut impl Write,
+    ) -> Result<(), String> {
+        let overflow = || "arithmetic overflow".to_string();
+        match instr {
+            Instr::Li { rt, imm } => self.set(*rt, *imm),
+            Instr::Move { rd, rs } => self.set(*rd, self.reg(*rs)),
+            Instr::LwLabel { rt, label } => {
+                let value = self.load(self.data_address(label)?)?;
+                self.set(*rt, value);
+            }
+            Instr::La { rt, label } => self.set(*rt, self.data_address(label)? as i32),
+            Instr::Add { rd, rs, rt } => {
+                let value = self
+                    .reg(*rs)
+                    .checked_add(self.reg(*rt))
+                    .ok_or_else(overflow)?;
+                self.set(*rd, value);
+            }
+            Instr::Addu { rd, rs, rt } => self.set(*rd, self.reg(*rs).wrapping_add(self.reg(*rt))),
+            Instr::Sub { rd, rs, rt } => {
+                let value = self
+                    .reg(*rs)
+                    .checked_sub(self.reg(*rt))
+                    .ok_or_else(overflow)?;
+                self.set(*rd, value);
+            }
+            Instr::Subu { rd, rs, rt } => self.set(*rd, self.reg(*rs).wrapping_sub(self.reg(*rt))),
+            Instr::Addi { rt, rs, imm } => {
+                let value = self.reg(*rs).checked_add(*imm).ok_or_else(overflow)?;
+                self.set(*rt, value);
+            }
+            Instr::Addiu { rt, rs, imm } => self.set(*rt, self.reg(*rs).wrapping_add(*imm)),
+            Instr::Ori { rt, rs, imm } => self.set(*rt, self.reg(*rs) | (*imm & 0xffff)),
+            Instr::Lui { rt, imm } => self.set(*rt, imm << 16),
+            Instr::Lw { rt, offset, base } => {
+                let value = self.load(self.address(*offset, *base))?;
+                self.set(*rt, value);
+            }
+            Instr::Sw { rt, offset, base } => {
+                let address = self.address(*offset, *base);
+                // frames are pushed with `$sp`, one too many is a runaway
+                // call chain rather than memory to hand out
+                if *base == Reg::SP && address < STACK_LIMIT {
+                    return Err("stack overflow".to_string());
+                }
+                self.store(address, self.reg(*rt))?
+            }
+            Instr::Jal { target } => {
+                self.set(Reg::RA, self.pc as i32);
+                self.jump(target)?;
+            }
+            Instr::Jr { rs } => self.pc = self.reg(*rs) as usize,
+            Instr::Syscall => {
+                let write = |e: std::io::Error| format!("write: {}", e);
+                match self.reg(Reg::V0) {
+                    1 => write!(output, "{}", self.reg(Reg::A0)).map_err(write)?,
+                    5 => self.set(Reg::V0, input.read_int()?),
+                    10 => self.halted = true,
+                    11 => {
+                        let c = char::from_u32(self.reg(Reg::A0) as u32).unwrap_or('?');
+                        write!(output, "{}", c).map_err(write)?
+                    }
+                    4001 => {
+                        self.status = self.reg(Reg::A0);
+                        self.halted = true;
+                    }
+                    4003 => {
+                        let (buffer, count) = (self.reg(Reg::A1) as u32, self.reg(Reg::A2));
+                        let bytes = input
+                            .reader
+                            .fill_buf()
+                            .map_err(|e| format!("read: {}", e))?;
+                        let bytes = bytes[..bytes.len().min(count as usize)].to_vec();
+                        input.reader.consume(bytes.len());
+                        for (i, byte) in bytes.iter().enumerate() {
+                            self.store_byte(buffer + i as u32, *byte);
+                        }
+                        self.linux_return(bytes.len() as i32);
+                    }
+                    4004 => {
+                        let (buffer, count) = (self.reg(Reg::A1) as u32, self.reg(Reg::A2));
+                        let bytes: Vec<u8> = (0..count as u32)
+                            .map(|i| self.load_byte(buffer + i))
+                            .collect();
+                        match self.reg(Reg::A0) {
+                            1 => output.write_all(&bytes).map_err(write)?,
+                            2 => self.stderr.extend(&bytes),
+                            fd => return Err(format!("write: bad file descriptor {}", fd)),
+                        }
+                        self.linux_return(count);
+                    }
+                    code => return Err(format!("unknown system call {}", code)),
+                }
+            }
+            Instr::Slt { rd, rs, rt } => self.set(*rd, (self.reg(*rs) < self.reg(*rt)) as i32),
+            Instr::Divu { rs, rt } => {
+                let (lhs, rhs) = (self.reg(*rs) as u32, self.reg(*rt) as u32);
+                if rhs != 0 {
+                    self.hi = (lhs % rhs) as i32;
+                    self.lo = (lhs / rhs) as i32;
+                }
+            }
+            Instr::Mfhi { rd } => self.set(*rd, self.hi),
+            Instr::Mflo { rd } => self.set(*rd, self.lo),
+            Instr::Lbu { rt, offset, base } => {
+                let value = self.load_byte(self.address(*offset, *base));
+                self.set(*rt, value as i32);
+            }
+            Instr::Sb { rt, offset, base } => {
+                self.store_byte(self.address(*offset, *base), self.reg(*rt) as u8)
+            }
+            Instr::Beq { rs, rt, target } if self.reg(*rs) == self.reg(*rt) => self.jump(target)?,
+            Instr::Bne { rs, rt, target } if self.reg(*rs) != self.reg(*rt) => self.jump(target)?,
+            Instr::Beq { .. } | Instr::Bne { .. } => {}
+            Instr::Label(_) | Instr::Directive(_) | Instr::Comment(_) => {}
+        }
+        Ok(())
+    }
+}
+
+/// Run `listing` until it exits. When the code has `line N` comments,
+/// errors start with the line they happened on.
+pub fn run(listing: Vec<Instr>, input: impl BufRead, mut output: impl Write) -> Result<(), String> {
+    let mut machine = Machine::new(listing)?;
+    let mut input = Input::new(input);
+    while !machine.halted {
+        machine
+            .step(&mut input, &mut output)
+            .map_err(|e| match &machine.line {
+                Some(line) => format!("{}: {}", line, e),
+                None => e,
+            })?;
+    }
+    output.flush().map_err(|e| format!("write: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::codegen::CodeGenerator;
+    use crate::ir::Inst;
+    use crate::opt::{self, OptLevel};
+    use crate::testing;
+
+    use super::*;
+
+    fn execute(
+        source: &str,
+        opt_level: OptLevel,
+        expand: bool,
+        input: &str,
+    ) -> Result<String, String> {
+        let mut cg = CodeGenerator::new();
+        cg.opt_level = opt_level;
+        cg.expand_pseudo = expand;
+        execute_with(source, cg, input)
+    }
+
+    fn execute_with(source: &str, mut cg: CodeGenerator, input: &str) -> Result<String, String> {
+        let opt_level = cg.opt_level;
+        let mut program = testing::lower_source(source);
+        opt::optimize(&mut program, opt_level);
+        let mut output = Vec::new();
+        run(cg.assemble(&program), input.as_bytes(), &mut output)?;
+        Ok(String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn handle_test_cases() {
+        for case in testing::test_cases() {
+            for opt_level in [OptLevel::O0, OptLevel::O2] {
+                for expand in [false, true] {
+                    let output = execute(case.source, opt_level, expand, &case.input_text());
+                    assert_eq!(output, Ok(case.expected_text()), "{}", case.name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn handle_overflow() {
+        let source = "begin read(A); write(A + 1); end";
+        assert_eq!(
+            execute(source, OptLevel::O0, false, "2147483647"),
+            Err("`add $t2, $t2, $t1`: arithmetic overflow".to_string())
+        );
+        assert_eq!(
+            execute(source, OptLevel::O0, false, ""),
+            Err("`syscall`: read: unexpected end of input".to_string())
+        );
+
+        let mut cg = CodeGenerator::new();
+        cg.source = Some(source.to_string());
+        assert_eq!(
+            execute_with(source, cg, "2147483647"),
+            Err("line 1: `add $t2, $t2, $t1`: arithmetic overflow".to_string())
+        );
+        for opt_level in [OptLevel::O0, OptLevel::O2] {
+            let mut cg = CodeGenerator::new();
+            cg.opt_level = opt_level;
+            cg.wrapping = true;
+            let output = execute_with(
+                "begin read(A); write(A + 1, A - -2147483648); end",
+                cg,
+                "2147483647",
+            );
+            assert_eq!(output, Ok("-2147483648\n-1\n".to_string()));
+        }
+    }
+
+    #[test]
+    fn handle_stack_overflow() {
+        // a procedure calling itself, as the resolver no longer lets through
+        let mut program = testing::lower_source("begin end");
+        program.insts.push(Inst::Call {
+            target: "main.P".to_string(),
+        });
+        let mut listing = CodeGenerator::new().assemble(&program);
+        let mut cg = CodeGenerator::new();
+        cg.procedure = Some("main.P".to_string());
+        listing.extend(cg.assemble(&program));
+        let error = run(listing, "".as_bytes(), &mut Vec::new()).unwrap_err();
+        assert!(error.ends_with(": stack overflow"), "{}", error);
+    }
+
+    #[test]
+    fn handle_linux_runtime() {
+        // the stdout, stderr and exit status of `source` built for Linux
+        let execute = |source: &str, input: &str| {
+            let mut program = testing::lower_source(source);
+            opt::optimize(&mut program, OptLevel::O1);
+            let mut cg = CodeGenerator::new();
+            cg.opt_level = OptLevel::O1;
+            cg.linux = true;
+            let mut machine = Machine::new(cg.assemble(&program))?;
+            let (mut input, mut output) = (Input::new(input.as_bytes()), Vec::new());
+            while !machine.halted {
+                machine.step(&mut input, &mut output)?;
+            }
+            let text = |bytes| String::from_utf8(bytes).unwrap();
+            Ok::<_, String>((text(output), text(machine.stderr), machine.status))
+        };
+
+        // more live values than `read` and `write` leave alone without
+        // saving them
+        let source = "begin read(A, B, C, D, E, F, G, H); \
+                      write(A + B, C - D, E + F + G + H, A, B, C, D, E, F, G, H); end";
+        let input = "  1\n-2\n0 2147483647 -2147483648 7\n12345 -6789";
+        let expected = "-1\n-2147483647\n-2147478085\n1\n-2\n0\n2147483647\n\
+                        -2147483648\n7\n12345\n-6789\n";
+        assert_eq!(
+            execute(source, input),
+            Ok((expected.to_string(), String::new(), 0))
+        );
+
+        let source = "begin read(A); write(A); read(A); write(A); end";
+        assert_eq!(
+            execute(source, "4 x"),
+            Ok((
+                "4\n".to_string(),
+                "microc: expected an integer on the input\n".to_string(),
+                1
+            ))
+        );
+        let (_, stderr, status) = execute(source, "4\n").unwrap();
+        assert_eq!((stderr.is_empty(), status), (false, 1));
+
+        let error = execute("begin read(A); write(A + 1); end", "2147483647").unwrap_err();
+        assert!(error.ends_with(": arithmetic overflow"), "{}", error);
+    }
+}
@@ -0,0 +1,86 @@
+//! What the tests share: lowering a source string, and the programs in
+//! `TestCases` with the input and output their header comments give, e.g.
+//! `-- Input: (1, 2), Expected Output: (2, 3, 5)`.
+
+use crate::ast::{ASTBuilder, ExprAST};
+use crate::ir::{self, Program};
+use crate::lexer::Lexer;
+
+pub fn parse(source: &str) -> Vec<ExprAST> {
+    let mut lexer = Lexer::new(source);
+    let iter = lexer.tokenize();
+    let mut builder = ASTBuilder::new(Box::new(iter));
+    builder.parse()
+}
+
+pub fn lower_source(source: &str) -> Program {
+    ir::lower(parse(source))
+}
+
+pub struct TestCase {
+    pub name: &'static str,
+    pub source: &'static str,
+    pub input: Vec<i32>,
+    pub expected: Vec<i32>,
+}
+
+impl TestCase {
+    /// The input, one integer per line.
+    pub fn input_text(&self) -> String {
+        lines(&self.input)
+    }
+
+    /// What `write` prints.
+    pub fn expected_text(&self) -> String {
+        lines(&self.expected)
+    }
+}
+
+fn lines(values: &[i32]) -> String {
+    values.iter().map(|v| format!("{}\n", v)).collect()
+}
+
+// the integers after `label` in `header`, with or without parentheses
+fn values(header: &str, label: &str) -> Vec<i32> {
+    let Some((_, rest)) = header.split_once(label) else {
+        return Vec::new();
+    };
+    let rest = rest.trim_start_matches(':').trim_start();
+    let list = match rest.strip_prefix('(') {
+        Some(rest) => rest.split(')').next().unwrap(),
+        None => rest.split(',').next().unwrap(),
+    };
+    list.split(',').map(|v| v.trim().parse().unwrap()).collect()
+}
+
+pub fn test_cases() -> Vec<TestCase> {
+    let sources = [
+        ("test1", include_str!("../TestCases/test1.m")),
+        ("test2", include_str!("../TestCases/test2.m")),
+        ("test3", include_str!("../TestCases/test3.m")),
+        ("test4", include_str!("../TestCases/test4.m")),
+        ("test5", include_str!("../TestCases/test5.m")),
+        ("test6", include_str!("../TestCases/test6.m")),
+        ("test7", include_str!("../TestCases/test7.m")),
+        ("test8", include_str!("../TestCases/test8.m")),
+        ("test9", include_str!("../TestCases/test9.m")),
+        ("test10", include_str!("../TestCases/test10.m")),
+    ];
+    sources
+        .into_iter()
+        .map(|(name, source)| {
+            let header = source.lines().next().unwrap();
+            TestCase {
+                name,
+                source,
+                input: values(header, "Input"),
+                expected: values(header, "Expected Output"),
+            }
+        })
+        .collect()
+}
+
+/// The test case called `name`.
+pub fn test_case(name: &str) -> TestCase {
+    test_cases().into_iter().find(|c| c.name == name).unwrap()
+}
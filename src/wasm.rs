@@ -0,0 +1,399 @@
+//! WebAssembly backend producing a `.wat` text module or a `.wasm` binary.
+//!
+//! Every virtual register becomes an `i32` local of the exported `main`
+//! function, named after its variable where it has one. Intermediate values
+//! go through the operand stack. `read` and `write` are imported from the
+//! `env` module, so the host decides where input comes from and where output
+//! goes.
+
+use crate::ast::BinaryOpKind;
+use crate::backend;
+use crate::ir::{Inst, Program, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WasmInst {
+    LocalGet(u32),
+    LocalSet(u32),
+    I32Const(i32),
+    I32Add,
+    I32Sub,
+    Call(u32),
+    Drop,
+}
+
+// function indices: imports come first
+const READ: u32 = 0;
+const WRITE: u32 = 1;
+const MAIN: u32 = 2;
+
+#[derive(Debug, Default)]
+pub struct Module {
+    /// Names of the locals of `main`, indexed by virtual register.
+    pub locals: Vec<String>,
+    pub body: Vec<WasmInst>,
+}
+
+pub fn lower(program: &Program) -> Result<Module, String> {
+    let locals = (0..program.vreg_count)
+        .map(|vreg| match program.var_name(vreg) {
+            Some(name) => name.to_string(),
+            // `%` cannot start a Micro identifier, so temporaries never clash
+            None => format!("%{}", vreg),
+        })
+        .collect();
+    let mut body = Vec::new();
+    let push = |body: &mut Vec<WasmInst>, value: Value| match value {
+        Value::Reg(vreg) => body.push(WasmInst::LocalGet(vreg)),
+        Value::Imm(imm) => body.push(WasmInst::I32Const(imm)),
+    };
+    for inst in program.insts.iter() {
+        match inst {
+            Inst::Call { .. } => return Err(backend::NO_PROCEDURES.to_string()),
+            Inst::Read { dst } => {
+                body.push(WasmInst::Call(READ));
+                body.push(match dst {
+                    Some(dst) => WasmInst::LocalSet(*dst),
+                    None => WasmInst::Drop,
+                });
+            }
+            Inst::Write { src } => {
+                push(&mut body, *src);
+                body.push(WasmInst::Call(WRITE));
+            }
+            Inst::Copy { dst, src } => {
+                push(&mut body, *src);
+                body.push(WasmInst::LocalSet(*dst));
+            }
+            Inst::Binary { op, dst, lhs, rhs } => {
+                push(&mut body, *lhs);
+                push(&mut body, *rhs);
+                body.push(match op {
+                    BinaryOpKind::Add => WasmInst::I32Add,
+                    BinaryOpKind::Sub => WasmInst::I32Sub,
+                });
+                body.push(WasmInst::LocalSet(*dst));
+            }
+        }
+    }
+    Ok(Module { locals, body })
+}
+
+impl Module {
+    pub fn to_wat(&self) -> String {
+        let mut buf = String::from(
+            r#"(module
+  (import "env" "read" (func $read (result i32)))
+  (import "env" "write" (func $write (param i32)))
+  (func $main (export "main")
+"#,
+        );
+        for local in self.locals.iter() {
+            buf.push_str(format!("    (local ${} i32)\n", local).as_str());
+        }
+        for inst in self.body.iter() {
+            let text = match *inst {
+                WasmInst::LocalGet(index) => format!("local.get ${}", self.locals[index as usize]),
+                WasmInst::LocalSet(index) => format!("local.set ${}", self.locals[index as usize]),
+                WasmInst::I32Const(imm) => format!("i32.const {}", imm),
+                WasmInst::I32Add => "i32.add".to_string(),
+                WasmInst::I32Sub => "i32.sub".to_string(),
+                WasmInst::Call(READ) => "call $read".to_string(),
+                WasmInst::Call(_) => "call $write".to_string(),
+                WasmInst::Drop => "drop".to_string(),
+            };
+            buf.push_str(format!("    {}\n", text).as_str());
+        }
+        buf.push_str("  )\n)\n");
+        buf
+    }
+
+    pub fn to_binary(&self) -> Vec<u8> {
+        let mut module = b"\0asm".to_vec();
+        module.extend(1u32.to_le_bytes());
+
+        // type 0: [] -> [i32], type 1: [i32] -> [], type 2: [] -> []
+        let types = [
+            vec![0x60, 0, 1, I32],
+            vec![0x60, 1, I32, 0],
+            vec![0x60, 0, 0],
+        ];
+        section(&mut module, 1, &vector(&types));
+
+        let imports = [("read", 0), ("write", 1)].map(|(field, ty)| {
+            let mut import = name("env");
+            import.extend(name(field));
+            import.extend([0x00, ty]);
+            import
+        });
+        section(&mut module, 2, &vector(&imports));
+        section(&mut module, 3, &vector(&[vec![2]]));
+
+        let mut export = name("main");
+        export.push(0x00);
+        uleb(&mut export, MAIN);
+        section(&mut module, 7, &vector(&[export]));
+
+        let mut code = Vec::new();
+        if self.locals.is_empty() {
+            code.push(0);
+        } else {
+            code.push(1);
+            uleb(&mut code, self.locals.len() as u32);
+            code.push(I32);
+        }
+        for inst in self.body.iter() {
+            match *inst {
+                WasmInst::LocalGet(index) => {
+                    code.push(0x20);
+                    uleb(&mut code, index);
+                }
+                WasmInst::LocalSet(index) => {
+                    code.push(0x21);
+                    uleb(&mut code, index);
+                }
+                WasmInst::I32Const(imm) => {
+                    code.push(0x41);
+                    sleb(&mut code, imm);
+                }
+                WasmInst::I32Add => code.push(0x6a),
+                WasmInst::I32Sub => code.push(0x6b),
+                WasmInst::Call(function) => {
+                    code.push(0x10);
+                    uleb(&mut code, function);
+                }
+                WasmInst::Drop => code.push(0x1a),
+            }
+        }
+        code.push(0x0b);
+        let mut body = Vec::new();
+        uleb(&mut body, code.len() as u32);
+        body.extend(code);
+        section(&mut module, 10, &vector(&[body]));
+
+        // custom "name" section so debuggers show variable names
+        let locals: Vec<Vec<u8>> = self
+            .locals
+            .iter()
+            .enumerate()
+            .map(|(index, local)| {
+                let mut entry = Vec::new();
+                uleb(&mut entry, index as u32);
+                entry.extend(name(local));
+                entry
+            })
+            .collect();
+        let mut function = Vec::new();
+        uleb(&mut function, MAIN);
+        function.extend(vector(&locals));
+        let mut names = name("name");
+        names.push(2);
+        let local_names = vector(&[function]);
+        uleb(&mut names, local_names.len() as u32);
+        names.extend(local_names);
+        section(&mut module, 0, &names);
+        module
+    }
+}
+
+const I32: u8 = 0x7f;
+
+fn uleb(buf: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn sleb(buf: &mut Vec<u8>, mut value: i32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn name(text: &str) -> Vec<u8> {
+    let mut buf = Vec::new();
+    uleb(&mut buf, text.len() as u32);
+    buf.extend(text.as_bytes());
+    buf
+}
+
+fn vector(items: &[Vec<u8>]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    uleb(&mut buf, items.len() as u32);
+    for item in items.iter() {
+        buf.extend(item);
+    }
+    buf
+}
+
+fn section(module: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    module.push(id);
+    uleb(module, contents.len() as u32);
+    module.extend(contents);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+
+    use super::*;
+
+    fn compile(source: &str) -> Module {
+        lower(&testing::lower_source(source)).unwrap()
+    }
+
+    struct Reader<'a> {
+        bytes: &'a [u8],
+        pos: usize,
+    }
+
+    impl Reader<'_> {
+        fn byte(&mut self) -> u8 {
+            self.pos += 1;
+            self.bytes[self.pos - 1]
+        }
+
+        fn uleb(&mut self) -> u32 {
+            let (mut value, mut shift) = (0, 0);
+            loop {
+                let byte = self.byte();
+                value |= ((byte & 0x7f) as u32) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    return value;
+                }
+            }
+        }
+
+        fn sleb(&mut self) -> i32 {
+            let (mut value, mut shift) = (0i64, 0);
+            loop {
+                let byte = self.byte();
+                value |= ((byte & 0x7f) as i64) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    if byte & 0x40 != 0 {
+                        value |= -1 << shift;
+                    }
+                    return value as i32;
+                }
+            }
+        }
+
+        fn name(&mut self) -> String {
+            let len = self.uleb() as usize;
+            self.pos += len;
+            String::from_utf8(self.bytes[self.pos - len..self.pos].to_vec()).unwrap()
+        }
+    }
+
+    // decode the binary, check its layout, and run `main` against the
+    // imports
+    fn run(module: &[u8], mut input: Vec<i32>) -> Vec<i32> {
+        let mut reader = Reader {
+            bytes: module,
+            pos: 8,
+        };
+        assert_eq!(&module[..8], b"\0asm\x01\0\0\0");
+        let (mut sections, mut imports, mut code) = (Vec::new(), Vec::new(), Vec::new());
+        while reader.pos < module.len() {
+            let id = reader.byte();
+            let size = reader.uleb() as usize;
+            let end = reader.pos + size;
+            sections.push(id);
+            match id {
+                2 => {
+                    for _ in 0..reader.uleb() {
+                        imports.push(format!("{}.{}", reader.name(), reader.name()));
+                        assert_eq!(reader.byte(), 0x00);
+                        reader.uleb();
+                    }
+                }
+                7 => {
+                    assert_eq!(reader.uleb(), 1);
+                    assert_eq!(reader.name(), "main");
+                    assert_eq!((reader.byte(), reader.uleb()), (0x00, MAIN));
+                }
+                10 => code = module[reader.pos..end].to_vec(),
+                _ => {}
+            }
+            assert!(end <= module.len());
+            reader.pos = end;
+        }
+        assert_eq!(sections, vec![1, 2, 3, 7, 10, 0]);
+        assert_eq!(imports, vec!["env.read", "env.write"]);
+
+        let mut reader = Reader {
+            bytes: &code,
+            pos: 0,
+        };
+        assert_eq!(reader.uleb(), 1);
+        let end = reader.uleb() as usize + reader.pos;
+        let mut locals = Vec::new();
+        for _ in 0..reader.uleb() {
+            let count = reader.uleb();
+            assert_eq!(reader.byte(), I32);
+            locals.extend((0..count).map(|_| 0));
+        }
+        let (mut stack, mut output) = (Vec::<i32>::new(), Vec::new());
+        loop {
+            match reader.byte() {
+                0x20 => stack.push(locals[reader.uleb() as usize]),
+                0x21 => locals[reader.uleb() as usize] = stack.pop().unwrap(),
+                0x41 => stack.push(reader.sleb()),
+                0x6a => {
+                    let (b, a) = (stack.pop().unwrap(), stack.pop().unwrap());
+                    stack.push(a.wrapping_add(b));
+                }
+                0x6b => {
+                    let (b, a) = (stack.pop().unwrap(), stack.pop().unwrap());
+                    stack.push(a.wrapping_sub(b));
+                }
+                0x10 => match reader.uleb() {
+                    READ => stack.push(input.remove(0)),
+                    WRITE => output.push(stack.pop().unwrap()),
+                    function => panic!("call to function {}", function),
+                },
+                0x1a => _ = stack.pop().unwrap(),
+                0x0b => break,
+                opcode => panic!("unexpected opcode {:#x}", opcode),
+            }
+        }
+        assert_eq!(reader.pos, end);
+        assert!(stack.is_empty());
+        output
+    }
+
+    #[test]
+    fn handle_test_cases() {
+        for case in testing::test_cases() {
+            let module = compile(case.source).to_binary();
+            assert_eq!(run(&module, case.input), case.expected);
+        }
+    }
+
+    #[test]
+    fn handle_text_format() {
+        let module = compile("begin read(A); B := A - 70000; write(B); end");
+        let wat = module.to_wat();
+        assert!(wat.contains("    (local $A i32)\n    (local $B i32)\n"));
+        assert!(
+            wat.contains("    local.get $A\n    i32.const 70000\n    i32.sub\n    local.set $B\n")
+        );
+
+        let mut buf = Vec::new();
+        sleb(&mut buf, -70000);
+        assert_eq!(buf, vec![0x90, 0xdd, 0x7b]);
+    }
+}
@@ -0,0 +1,450 @@
+//! x86-64 Linux backend producing GNU `as` assembly in Intel syntax.
+//!
+//! The runtime talks to the kernel directly: `read` parses a decimal integer
+//! from stdin one byte at a time, exiting with status 1 when there is none,
+//! `write` formats its argument into a stack buffer, and `_start` calls
+//! `main` before exiting. There is no libc, so
+//! the output links with a bare `ld`.
+
+use crate::ast::BinaryOpKind;
+use crate::backend::{self, Backend};
+use crate::ir::{Inst, Program, Value};
+use crate::regalloc::{self, Location};
+use std::collections::BTreeMap;
+use std::process::Command;
+use std::{env, fs, process};
+
+pub static PRELUDE: &str = r#"# Module : main
+    .text
+    .globl _start
+_start:
+    call main
+    mov eax, 60
+    xor edi, edi
+    syscall
+
+# read one byte from stdin into eax, or -1 at end of input
+read_byte:
+    push rax
+    xor eax, eax
+    xor edi, edi
+    mov rsi, rsp
+    mov edx, 1
+    syscall
+    cmp rax, 1
+    jne 1f
+    movzx eax, BYTE PTR [rsp]
+    pop rcx
+    ret
+1:
+    mov eax, -1
+    pop rcx
+    ret
+
+    .section .rodata
+bad_input:
+    .ascii "microc: expected an integer on the input\n"
+    .set bad_input_len, . - bad_input
+    .text
+
+    .globl read
+read:
+    # call read integer, with the value in ebx, whether it is negative in
+    # r12d and whether a digit was read in r13d
+    push rbx
+    push r12
+    push r13
+    xor ebx, ebx
+    xor r12d, r12d
+    xor r13d, r13d
+1:
+    call read_byte
+    cmp eax, -1
+    je 3f
+    cmp eax, 32
+    jbe 1b
+    cmp eax, 45
+    jne 2f
+    mov r12d, 1
+    call read_byte
+2:
+    sub eax, 48
+    cmp eax, 9
+    ja 3f
+    imul ebx, ebx, 10
+    add ebx, eax
+    mov r13d, 1
+    call read_byte
+    jmp 2b
+3:
+    test r13d, r13d
+    jz 5f
+    mov eax, ebx
+    test r12d, r12d
+    jz 4f
+    neg eax
+4:
+    pop r13
+    pop r12
+    pop rbx
+    ret
+5:
+    mov eax, 1
+    mov edi, 2
+    lea rsi, [rip + bad_input]
+    mov edx, bad_input_len
+    syscall
+    mov eax, 60
+    mov edi, 1
+    syscall
+
+    .globl write
+write:
+    sub rsp, 32
+    movsxd rax, edi
+    mov rcx, rax
+    lea rsi, [rsp+31]
+    mov BYTE PTR [rsi], 10
+    test rax, rax
+    jns 1f
+    neg rax
+1:
+    mov r11d, 10
+2:
+    xor edx, edx
+    div r11
+    add dl, 48
+    dec rsi
+    mov BYTE PTR [rsi], dl
+    test rax, rax
+    jnz 2b
+    test rcx, rcx
+    jns 3f
+    dec rsi
+    mov BYTE PTR [rsi], 45
+3:
+    lea rdx, [rsp+32]
+    sub rdx, rsi
+    mov eax, 1
+    mov edi, 1
+    syscall
+    add rsp, 32
+    ret
+"#;
+
+// registers handed out by the allocator, as (64-bit, 32-bit) names. the
+// runtime only clobbers rax, rcx, rdx, rsi, rdi and r11, and eax is kept as
+// scratch for memory to memory moves and three-operand arithmetic.
+pub static ALLOCATABLE: [(&str, &str); 8] = [
+    ("r8", "r8d"),
+    ("r9", "r9d"),
+    ("r10", "r10d"),
+    ("rbx", "ebx"),
+    ("r12", "r12d"),
+    ("r13", "r13d"),
+    ("r14", "r14d"),
+    ("r15", "r15d"),
+];
+
+// r8 to r10 are caller-saved, the rest must be preserved by `main`
+fn is_callee_saved(reg: usize) -> bool {
+    reg >= 3
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    /// Index into `ALLOCATABLE`.
+    Reg(usize),
+    /// Offset below `rbp`.
+    Mem(u32),
+    Imm(i32),
+}
+
+impl Operand {
+    fn asm(self) -> String {
+        match self {
+            Operand::Reg(reg) => ALLOCATABLE[reg].1.to_string(),
+            Operand::Mem(offset) => format!("DWORD PTR [rbp-{}]", offset),
+            Operand::Imm(imm) => imm.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct X86Generator {
+    pub frame_size: u32,
+    pub saved_regs: Vec<usize>,
+    pub symbol_map: BTreeMap<String, Operand>,
+    pub asm: Vec<String>,
+    locations: Vec<Operand>,
+}
+
+impl X86Generator {
+    pub fn new() -> X86Generator {
+        X86Generator::default()
+    }
+
+    fn operand(&self, value: Value) -> Operand {
+        match value {
+            Value::Reg(vreg) => self.locations[vreg as usize],
+            Value::Imm(imm) => Operand::Imm(imm),
+        }
+    }
+}
+
+impl Backend for X86Generator {
+    type Instr = String;
+    type Error = String;
+
+    fn file_extension(&self) -> &'static str {
+        "s"
+    }
+
+    // run the register allocator and lay out the stack frame below rbp:
+    // saved callee-saved registers, then spill slots, rounded up to 16 bytes
+    fn assign_locations(&mut self, program: &Program) {
+        let allocation = regalloc::allocate(program, ALLOCATABLE.len());
+        self.saved_regs = allocation
+            .used_regs()
+            .into_iter()
+            .filter(|r| is_callee_saved(*r))
+            .collect();
+        let spill_base = 8 * self.saved_regs.len() as u32;
+        self.frame_size = (spill_base + 4 * allocation.spill_slots).next_multiple_of(16);
+
+        self.locations = (0..program.vreg_count)
+            .map(|vreg| match allocation.locations.get(&vreg) {
+                Some(Location::Reg(r)) => Operand::Reg(*r),
+                Some(Location::Spill(slot)) => Operand::Mem(spill_base + 4 * (slot + 1)),
+                None => Operand::Imm(0),
+            })
+            .collect();
+        self.symbol_map = program
+            .vars
+            .iter()
+            .map(|(name, vreg)| (name.clone(), self.locations[*vreg as usize]))
+            .collect();
+    }
+
+    // `read` returns in eax and `write` takes edi; arithmetic works in
+    // place on a destination register, or through eax otherwise
+    fn lower_instruction(&mut self, inst: &Inst) -> Result<(), String> {
+        match inst {
+            Inst::Call { .. } => return Err(backend::NO_PROCEDURES.to_string()),
+            Inst::Read { dst } => {
+                self.asm.push("call read".to_string());
+                if let Some(dst) = dst {
+                    let dst = self.locations[*dst as usize];
+                    self.asm.push(format!("mov {}, eax", dst.asm()));
+                }
+            }
+            Inst::Write { src } => {
+                let src = self.operand(*src);
+                self.asm.push(format!("mov edi, {}", src.asm()));
+                self.asm.push("call write".to_string());
+            }
+            Inst::Copy { dst, src } => {
+                let (dst, src) = (self.locations[*dst as usize], self.operand(*src));
+                if dst == src {
+                    return Ok(());
+                }
+                if let (Operand::Mem(_), Operand::Mem(_)) = (dst, src) {
+                    self.asm.push(format!("mov eax, {}", src.asm()));
+                    self.asm.push(format!("mov {}, eax", dst.asm()));
+                } else {
+                    self.asm.push(format!("mov {}, {}", dst.asm(), src.asm()));
+                }
+            }
+            Inst::Binary { op, dst, lhs, rhs } => {
+                let mnemonic = match op {
+                    BinaryOpKind::Add => "add",
+                    BinaryOpKind::Sub => "sub",
+                };
+                let dst = self.locations[*dst as usize];
+                let (mut lhs, mut rhs) = (self.operand(*lhs), self.operand(*rhs));
+                if *op == BinaryOpKind::Add && dst == rhs {
+                    (lhs, rhs) = (rhs, lhs);
+                }
+                // two-operand form: compute in place unless that would
+                // overwrite the right operand before it is read
+                if let Operand::Reg(_) = dst {
+                    if dst != rhs {
+                        if dst != lhs {
+                            self.asm.push(format!("mov {}, {}", dst.asm(), lhs.asm()));
+                        }
+                        self.asm
+                            .push(format!("{} {}, {}", mnemonic, dst.asm(), rhs.asm()));
+                        return Ok(());
+                    }
+                }
+                self.asm.push(format!("mov eax, {}", lhs.asm()));
+                self.asm.push(format!("{} eax, {}", mnemonic, rhs.asm()));
+                self.asm.push(format!("mov {}, eax", dst.asm()));
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Vec<String> {
+        self.asm.iter().map(|c| format!("    {}", c)).collect()
+    }
+
+    fn prologue(&self) -> Vec<String> {
+        let mut buf = String::from(
+            "    .intel_syntax noprefix
+    .text
+    .globl main
+main:
+    # prologue area
+    push rbp
+    mov rbp, rsp
+",
+        );
+        if self.frame_size > 0 {
+            buf.push_str(format!("    sub rsp, {}\n", self.frame_size).as_str());
+        }
+        for (i, reg) in self.saved_regs.iter().enumerate() {
+            buf.push_str(
+                format!(
+                    "    mov QWORD PTR [rbp-{}], {}\n",
+                    8 * (i + 1),
+                    ALLOCATABLE[*reg].0
+                )
+                .as_str(),
+            );
+        }
+        backend::lines(&buf)
+    }
+
+    fn epilogue(&self) -> Vec<String> {
+        let mut buf = String::from("\n    # epilogue area\n");
+        for (i, reg) in self.saved_regs.iter().enumerate() {
+            buf.push_str(
+                format!(
+                    "    mov {}, QWORD PTR [rbp-{}]\n",
+                    ALLOCATABLE[*reg].0,
+                    8 * (i + 1)
+                )
+                .as_str(),
+            );
+        }
+        buf.push_str("    leave\n    ret\n\n");
+        backend::lines(&buf)
+    }
+
+    fn runtime_support(&self) -> Vec<String> {
+        backend::lines(PRELUDE)
+    }
+
+    fn print(&self, listing: &[String]) -> String {
+        backend::print_lines(listing)
+    }
+}
+
+/// Assemble and link `asm` into the executable `output` with the system
+/// `as` and `ld`.
+pub fn build_executable(asm: &str, output: &str) -> Result<(), String> {
+    let stem = env::temp_dir().join(format!("microc-{}", process::id()));
+    let (source, object) = (stem.with_extension("s"), stem.with_extension("o"));
+    fs::write(&source, asm).map_err(|e| format!("cannot write `{}`: {}", source.display(), e))?;
+
+    let run = |program: &str, args: &[&std::ffi::OsStr]| -> Result<(), String> {
+        let status = Command::new(program)
+            .args(args)
+            .status()
+            .map_err(|e| format!("cannot run `{}`: {}", program, e))?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(format!("`{}` failed with {}", program, status))
+        }
+    };
+    let result = run(
+        "as",
+        &[
+            "--64".as_ref(),
+            "-o".as_ref(),
+            object.as_os_str(),
+            source.as_os_str(),
+        ],
+    )
+    .and_then(|_| run("ld", &["-o".as_ref(), output.as_ref(), object.as_os_str()]));
+
+    let _ = fs::remove_file(&source);
+    let _ = fs::remove_file(&object);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::Emitter;
+    use crate::testing;
+    use std::io::Write;
+    use std::process::Stdio;
+
+    use super::*;
+
+    fn compile(source: &str) -> String {
+        X86Generator::new()
+            .emit(&testing::lower_source(source))
+            .unwrap()
+    }
+
+    #[test]
+    fn handle_in_place_arithmetic() {
+        let asm = compile("begin read(A); B := 10 - A; write(B); end");
+        // `B` may take the register `A` dies in, so it cannot be the
+        // destination of the subtraction
+        assert!(asm.contains("    mov eax, 10\n    sub eax, r8d\n    mov r8d, eax\n"));
+        assert!(asm.contains("    mov rbp, rsp\n    call read\n"));
+    }
+
+    #[test]
+    fn handle_native_run() {
+        if Command::new("as").arg("--version").output().is_err() {
+            return;
+        }
+        let output = env::temp_dir().join(format!("microc-test-{}", process::id()));
+        let output = output.to_str().unwrap();
+        for case in testing::test_cases() {
+            build_executable(&compile(case.source), output).unwrap();
+            let mut child = Command::new(output)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(case.input_text().as_bytes())
+                .unwrap();
+            let result = child.wait_with_output().unwrap();
+            let stdout = String::from_utf8(result.stdout).unwrap();
+            assert_eq!(stdout, case.expected_text(), "{}", case.name);
+        }
+
+        build_executable(&compile("begin read(A); write(A); read(A); end"), output).unwrap();
+        for input in ["12 x", "-", "12\n"] {
+            let mut child = Command::new(output)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap();
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(input.as_bytes())
+                .unwrap();
+            let result = child.wait_with_output().unwrap();
+            assert_eq!(result.status.code(), Some(1));
+            assert_eq!(
+                String::from_utf8(result.stderr).unwrap(),
+                "microc: expected an integer on the input\n"
+            );
+        }
+        fs::remove_file(output).unwrap();
+    }
+}